@@ -0,0 +1,180 @@
+/*
+ * Per-pixel adjustments before dithering. Photos nearly always need more contrast and saturation
+ * to look like anything inside the narrow Spectra 6 gamut. Adjustments combine as tuples, applied
+ * left to right:
+ *
+ *   pixels.adjust((Brightness(10), Contrast::new(120), Saturation(150)))
+ */
+use crate::barycentric::octahedron::OctahedronProjector;
+use crate::spectra6::Spectra6Color;
+use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
+use nalgebra::geometry::Point3;
+
+pub trait Adjustment {
+    fn apply(&self, color: Rgb888) -> Rgb888;
+}
+
+// Added to every channel, -255 to 255
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Brightness(pub i16);
+
+impl Adjustment for Brightness {
+    fn apply(&self, color: Rgb888) -> Rgb888 {
+        let offset = |value: u8| (value as i16 + self.0).clamp(0, 255) as u8;
+        Rgb888::new(offset(color.r()), offset(color.g()), offset(color.b()))
+    }
+}
+
+/*
+ * Contrast curve, as a table for all 256 values. percent scales the distance from mid gray, 100
+ * leaves the image as is, 0 makes everything mid gray.
+ */
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Contrast {
+    lut: [u8; 256],
+}
+
+impl Contrast {
+    pub fn new(percent: u16) -> Self {
+        Self::from_fn(|value| {
+            let value = (value as i32 - 128) * percent as i32 / 100 + 128;
+            value.clamp(0, 255) as u8
+        })
+    }
+
+    // For other curves, e.g. an S-curve that leaves the black and white points alone
+    pub fn from_fn(curve: impl Fn(u8) -> u8) -> Self {
+        Contrast {
+            lut: core::array::from_fn(|value| curve(value as u8)),
+        }
+    }
+}
+
+impl Adjustment for Contrast {
+    fn apply(&self, color: Rgb888) -> Rgb888 {
+        let lut = |value: u8| self.lut[value as usize];
+        Rgb888::new(lut(color.r()), lut(color.g()), lut(color.b()))
+    }
+}
+
+// Percent of the original distance from gray (at the same luma), 100 leaves colors as they are
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Saturation(pub u16);
+
+impl Adjustment for Saturation {
+    fn apply(&self, color: Rgb888) -> Rgb888 {
+        let [r, g, b] = [color.r(), color.g(), color.b()].map(|value| value as i32);
+        let luma = (r * 77 + g * 150 + b * 29) >> 8;
+        let saturate =
+            |value: i32| ((value - luma) * self.0 as i32 / 100 + luma).clamp(0, 255) as u8;
+        Rgb888::new(saturate(r), saturate(g), saturate(b))
+    }
+}
+
+/*
+ * Gamut mapping that takes sRGB's primaries to the panel's: every color is split into black,
+ * white, blue, green, yellow and red (as if the panel had sRGB's primaries), and rebuilt from the
+ * panel's measured colors instead. Pure sRGB red comes out as the panel's red rather than as the
+ * nearest thing to it, so photos use the panel's full chroma instead of coming out muddy. This is
+ * what dithering with an idealized palette does, but with strength (0 to 100 percent) to blend
+ * between accurate and vivid, and without keeping a second palette.
+ */
+pub struct Vivid {
+    projector: OctahedronProjector<f32>,
+    panel: [Point3<f32>; 6],
+    strength: f32,
+}
+
+// Around the equator in hue order, after the black and white poles
+const VIVID_ORDER: [(Spectra6Color, [u8; 3]); 6] = [
+    (Spectra6Color::Black, [0, 0, 0]),
+    (Spectra6Color::White, [255, 255, 255]),
+    (Spectra6Color::Blue, [0, 0, 255]),
+    (Spectra6Color::Green, [0, 255, 0]),
+    (Spectra6Color::Yellow, [255, 255, 0]),
+    (Spectra6Color::Red, [255, 0, 0]),
+];
+
+impl Vivid {
+    // None if palette is missing one of the six colors (Clean counts as White)
+    pub fn new(palette: &[(Rgb888, Spectra6Color)], strength_percent: u8) -> Option<Self> {
+        let mut panel = [Point3::origin(); 6];
+        for (point, (target, _)) in panel.iter_mut().zip(VIVID_ORDER) {
+            let (color, _) = palette.iter().find(|(_, color)| {
+                *color == target
+                    || (target == Spectra6Color::White && *color == Spectra6Color::Clean)
+            })?;
+            *point = point_of(*color);
+        }
+        Some(Vivid {
+            projector: OctahedronProjector::new(
+                VIVID_ORDER.map(|(_, [r, g, b])| point_of(Rgb888::new(r, g, b))),
+            ),
+            panel,
+            strength: strength_percent.min(100) as f32 / 100.0,
+        })
+    }
+}
+
+fn point_of(color: Rgb888) -> Point3<f32> {
+    Point3::new(color.r() as f32, color.g() as f32, color.b() as f32)
+}
+
+impl Adjustment for Vivid {
+    fn apply(&self, color: Rgb888) -> Rgb888 {
+        let source = point_of(color);
+        let weights = self.projector.project(&source);
+        let mapped = self
+            .panel
+            .iter()
+            .zip(weights.iter())
+            .fold(Point3::origin(), |sum, (point, weight)| {
+                sum + point.coords * *weight
+            });
+        let result = source + (mapped - source) * self.strength;
+        let channel = |value: f32| (value + 0.5).clamp(0.0, 255.0) as u8;
+        Rgb888::new(channel(result.x), channel(result.y), channel(result.z))
+    }
+}
+
+impl<A: Adjustment, B: Adjustment> Adjustment for (A, B) {
+    fn apply(&self, color: Rgb888) -> Rgb888 {
+        self.1.apply(self.0.apply(color))
+    }
+}
+
+impl<A: Adjustment, B: Adjustment, C: Adjustment> Adjustment for (A, B, C) {
+    fn apply(&self, color: Rgb888) -> Rgb888 {
+        self.2.apply(self.1.apply(self.0.apply(color)))
+    }
+}
+
+pub struct Adjusted<I, A> {
+    source: I,
+    adjustment: A,
+}
+
+impl<I: Iterator<Item = Rgb888>, A: Adjustment> Iterator for Adjusted<I, A> {
+    type Item = Rgb888;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.adjustment.apply(self.source.next()?))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.source.size_hint()
+    }
+}
+
+impl<I: ExactSizeIterator<Item = Rgb888>, A: Adjustment> ExactSizeIterator for Adjusted<I, A> {}
+
+pub trait AdjustExt: Iterator<Item = Rgb888> + Sized {
+    fn adjust<A: Adjustment>(self, adjustment: A) -> Adjusted<Self, A> {
+        Adjusted {
+            source: self,
+            adjustment,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Rgb888>> AdjustExt for I {}
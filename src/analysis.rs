@@ -0,0 +1,294 @@
+use crate::adjust::{Contrast, Saturation};
+use crate::barycentric::octahedron::OctahedronProjector;
+use crate::dither::{ColorDistance, LUMA_WEIGHTS};
+use crate::image::RgbImage;
+use crate::spectra6::Spectra6Color;
+use alloc::vec::Vec;
+use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
+use nalgebra::geometry::Point3;
+use num_traits::Float;
+
+// Number of most common colors kept in ColorStats::dominant
+pub const DOMINANT_COLORS: usize = 4;
+
+// Every STEP-th pixel in both directions is sampled by default, plenty for color statistics
+pub const DEFAULT_STEP: usize = 8;
+
+pub struct ColorStats {
+    pub samples: u32,
+    pub average: Rgb888,
+    // Most common colors first, with the number of samples that fell in each
+    pub dominant: Vec<(Rgb888, u32)>,
+}
+
+impl ColorStats {
+    pub fn dominant_color(&self) -> Option<Rgb888> {
+        self.dominant.first().map(|(color, _)| *color)
+    }
+}
+
+/*
+ * Average and dominant colors in a single pass over every step-th pixel. Dominant colors come from
+ * a coarse histogram (3 bits per channel), each reported as the mean of the samples in its bucket.
+ */
+pub fn color_stats(image: &RgbImage, step: usize) -> ColorStats {
+    let step = step.max(1);
+    // Per bucket: count, sum of r, sum of g, sum of b
+    let mut buckets: Vec<[u32; 4]> = Vec::new();
+    buckets.resize(512, [0; 4]);
+    for y in (0..image.height).step_by(step) {
+        for x in (0..image.width).step_by(step) {
+            let color = image.get(x, y);
+            let bucket = &mut buckets[((color.r() as usize >> 5) << 6)
+                | ((color.g() as usize >> 5) << 3)
+                | (color.b() as usize >> 5)];
+            bucket[0] += 1;
+            bucket[1] += color.r() as u32;
+            bucket[2] += color.g() as u32;
+            bucket[3] += color.b() as u32;
+        }
+    }
+    let total = buckets.iter().fold([0u32; 4], |total, bucket| {
+        core::array::from_fn(|i| total[i] + bucket[i])
+    });
+    buckets.retain(|bucket| bucket[0] > 0);
+    buckets.sort_unstable_by(|a, b| b[0].cmp(&a[0]));
+    let dominant = buckets
+        .iter()
+        .take(DOMINANT_COLORS)
+        .map(|bucket| (bucket_mean(bucket), bucket[0]))
+        .collect();
+    ColorStats {
+        samples: total[0],
+        average: bucket_mean(&total),
+        dominant,
+    }
+}
+
+fn bucket_mean(bucket: &[u32; 4]) -> Rgb888 {
+    let [count, r, g, b] = *bucket;
+    if count == 0 {
+        return Rgb888::BLACK;
+    }
+    Rgb888::new((r / count) as u8, (g / count) as u8, (b / count) as u8)
+}
+
+/*
+ * Luma histogram and chroma of an image, gathered in a first pass to tune the dithering pass that
+ * follows. Photos come in all exposures, and what looks right on a screen is nearly always too
+ * flat for the panel, so the adjustments are worked out per image instead of fixed.
+ */
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ToneStats {
+    histogram: [u32; 256],
+    pub samples: u32,
+    // Sum of max - min channel
+    chroma: u64,
+}
+
+impl Default for ToneStats {
+    fn default() -> Self {
+        ToneStats {
+            histogram: [0; 256],
+            samples: 0,
+            chroma: 0,
+        }
+    }
+}
+
+// Average chroma that a photo should end up with, about that of a sunny outdoor scene
+const TARGET_CHROMA: u32 = 80;
+
+impl ToneStats {
+    pub fn add(&mut self, color: Rgb888) {
+        let [r, g, b] = [color.r(), color.g(), color.b()];
+        let [wr, wg, wb] = LUMA_WEIGHTS.map(u32::from);
+        let luma = (r as u32 * wr + g as u32 * wg + b as u32 * wb) >> 8;
+        self.histogram[luma as usize] += 1;
+        self.samples += 1;
+        self.chroma += (r.max(g).max(b) - r.min(g).min(b)) as u64;
+    }
+
+    // Luma that permille of the samples are at or below
+    pub fn percentile(&self, permille: u32) -> u8 {
+        let target = (self.samples as u64 * permille.min(1000) as u64).div_ceil(1000);
+        let mut seen = 0;
+        for (luma, count) in self.histogram.iter().enumerate() {
+            seen += *count as u64;
+            if seen >= target.max(1) {
+                return luma as u8;
+            }
+        }
+        255
+    }
+
+    pub fn average_chroma(&self) -> u8 {
+        self.chroma.checked_div(self.samples as u64).unwrap_or(0) as u8
+    }
+
+    /*
+     * Levels and exposure in one curve: the darkest and lightest percent are stretched to black
+     * and white, then a gamma puts the median at mid gray. Both are limited, so a night shot stays
+     * dark instead of turning into noise.
+     */
+    pub fn contrast(&self) -> Contrast {
+        let low = self.percentile(10) as f32;
+        let high = (self.percentile(990) as f32).max(low + 64.0).min(255.0);
+        let low = low.min(high - 64.0);
+        let median = ((self.percentile(500) as f32 - low) / (high - low)).clamp(0.05, 0.95);
+        let gamma = (Float::ln(0.5) / Float::ln(median)).clamp(0.5, 2.0);
+        Contrast::from_fn(|value| {
+            let value = ((value as f32 - low) / (high - low)).clamp(0.0, 1.0);
+            Float::round(Float::powf(value, gamma) * 255.0) as u8
+        })
+    }
+
+    // Boosts dull images toward TARGET_CHROMA, at most double, never less than as is
+    pub fn saturation(&self) -> Saturation {
+        let chroma = self.average_chroma().max(1) as u32;
+        Saturation((TARGET_CHROMA * 100 / chroma).clamp(100, 200) as u16)
+    }
+
+    /*
+     * Mostly gray images are all about getting the brightness right, colorful ones about picking
+     * the right hue.
+     */
+    pub fn distance(&self) -> ColorDistance {
+        if self.average_chroma() < 24 {
+            ColorDistance::Weighted(LUMA_WEIGHTS)
+        } else {
+            ColorDistance::Oklab
+        }
+    }
+}
+
+pub fn tone_stats(pixels: impl IntoIterator<Item = Rgb888>) -> ToneStats {
+    pixels
+        .into_iter()
+        .fold(ToneStats::default(), |mut stats, color| {
+            stats.add(color);
+            stats
+        })
+}
+
+// Number of colors kept in GamutCoverage::worst
+pub const WORST_COLORS: usize = 8;
+
+// Out of gamut by less than this (in 8-bit units) counts as inside, as that's just rounding
+const GAMUT_TOLERANCE: f32 = 1.0;
+
+/*
+ * How much of an image the panel can show as is, e.g. to pick between the measured and the
+ * saturated palette per image: if most of it is out of gamut, the saturated one will look better.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GamutCoverage {
+    pub pixels: u32,
+    pub inside: u32,
+    // Distance to the gamut surface, averaged over the pixels outside it
+    pub mean_distance: f32,
+    // Farthest out of gamut first, each color only once
+    pub worst: Vec<(Rgb888, f32)>,
+}
+
+impl GamutCoverage {
+    pub fn inside_permille(&self) -> u32 {
+        permille(self.inside as u64, self.pixels as u64)
+    }
+}
+
+// projector in 8-bit RGB, like the palettes. Every pixel is projected, so sample large images.
+pub fn gamut_coverage(
+    projector: &OctahedronProjector<f32>,
+    pixels: impl IntoIterator<Item = Rgb888>,
+) -> GamutCoverage {
+    let mut coverage = GamutCoverage::default();
+    let mut total_distance = 0.0f64;
+    for color in pixels {
+        coverage.pixels += 1;
+        let point = Point3::new(color.r() as f32, color.g() as f32, color.b() as f32);
+        let distance = Float::sqrt(projector.project_detailed(&point).distance_squared);
+        if distance < GAMUT_TOLERANCE {
+            coverage.inside += 1;
+            continue;
+        }
+        total_distance += distance as f64;
+        if coverage.worst.iter().any(|(worst, _)| *worst == color) {
+            continue;
+        }
+        let index = coverage
+            .worst
+            .iter()
+            .position(|(_, worst)| distance > *worst)
+            .unwrap_or(coverage.worst.len());
+        if index < WORST_COLORS {
+            coverage.worst.insert(index, (color, distance));
+            coverage.worst.truncate(WORST_COLORS);
+        }
+    }
+    let outside = coverage.pixels - coverage.inside;
+    if outside > 0 {
+        coverage.mean_distance = (total_distance / outside as f64) as f32;
+    }
+    coverage
+}
+
+// Per color pixel counts of a (dithered) frame
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FrameStats {
+    // Indexed by the color's nibble value
+    counts: [u32; 8],
+    pub pixels: u32,
+}
+
+impl FrameStats {
+    // For counting along while the frame streams past, see frame_stats otherwise
+    pub fn add(&mut self, color: Spectra6Color) {
+        self.counts[color as usize] += 1;
+        self.pixels += 1;
+    }
+
+    pub fn count(&self, color: Spectra6Color) -> u32 {
+        self.counts[color as usize]
+    }
+
+    // Red, yellow, blue and green pixels
+    pub fn saturated(&self) -> u32 {
+        [
+            Spectra6Color::Red,
+            Spectra6Color::Yellow,
+            Spectra6Color::Blue,
+            Spectra6Color::Green,
+        ]
+        .map(|color| self.count(color))
+        .iter()
+        .sum()
+    }
+
+    pub fn saturated_permille(&self) -> u32 {
+        permille(self.saturated() as u64, self.pixels as u64)
+    }
+
+    /*
+     * Rough estimate of how hard a refresh drives the panel, 0 for all white and 1000 for all
+     * saturated colors. The color waveforms are the long ones, black is somewhere in between. Only
+     * meant for comparing frames, e.g. to hold back a pathological one.
+     */
+    pub fn stress_permille(&self) -> u32 {
+        let weighted = self.count(Spectra6Color::Black) as u64 + 3 * self.saturated() as u64;
+        permille(weighted, 3 * self.pixels as u64)
+    }
+}
+
+fn permille(part: u64, total: u64) -> u32 {
+    (part * 1000).checked_div(total).unwrap_or(0) as u32
+}
+
+pub fn frame_stats(frame: impl IntoIterator<Item = Spectra6Color>) -> FrameStats {
+    frame
+        .into_iter()
+        .fold(FrameStats::default(), |mut stats, color| {
+            stats.add(color);
+            stats
+        })
+}
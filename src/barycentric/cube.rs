@@ -0,0 +1,151 @@
+use crate::barycentric::tetrahedron::TetrahedronProjector;
+use crate::barycentric::triangle::ClippingTriangleProjector;
+use alloc::vec::Vec;
+use nalgebra::base::{SVector, Scalar, Vector3, Vector4};
+use nalgebra::geometry::Point3;
+use nalgebra::{ClosedAddAssign, ClosedDivAssign, ClosedMulAssign, ClosedSubAssign, ComplexField};
+use num_traits::identities::{One, Zero};
+use num_traits::zero;
+
+pub type Vector8<T> = SVector<T, 8>;
+
+/*
+ * Projector for palettes with 8 colors at the corners of a (distorted) cube, like the RGB cube.
+ * Vertex i is the corner with x at bit 0, y at bit 1 and z at bit 2, so for an RGB-like palette:
+ * black, red, green, yellow, blue, magenta, cyan, white. With only 7 colors, e.g. ACeP, use a mix
+ * of two neighbouring colors for the missing corner and map it to either.
+ *
+ * The cube is split into the 6 tetrahedra along the diagonal from vertex 0 to vertex 7, and none of
+ * them can be flat. Points outside are projected onto the closest of the 12 surface triangles.
+ */
+pub struct CubeProjector<T: Scalar> {
+    // Vertex indices and projector of each tetrahedron
+    tetrahedra: [([usize; 4], TetrahedronProjector<T>); 6],
+    // Vertex indices and projector of each surface triangle, two per face
+    faces: [([usize; 3], ClippingTriangleProjector<T>); 12],
+}
+
+impl<
+    T: Scalar
+        + ClosedSubAssign
+        + ClosedMulAssign
+        + ClosedAddAssign
+        + ClosedDivAssign
+        + Zero
+        + One
+        + ComplexField
+        + PartialOrd,
+> CubeProjector<T>
+{
+    pub fn new(vertices: [Point3<T>; 8]) -> Self {
+        // One per order of the axes, walking from 0 to 7 one axis at a time
+        let tetrahedra = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ]
+        .map(|[first, second, _]| {
+            let indices = [0, 1 << first, (1 << first) | (1 << second), 7];
+            let projector = TetrahedronProjector::new(indices.map(|i| vertices[i].clone()));
+            (indices, projector)
+        });
+        // Faces split along the same diagonal as the tetrahedra, from their lowest to highest corner
+        let faces = core::array::from_fn(|i| {
+            let (face, half) = (i / 2, i % 2);
+            let (axis, base) = (face / 2, (face % 2) << (face / 2));
+            let (a, b) = (1 << ((axis + 1) % 3), 1 << ((axis + 2) % 3));
+            let side = if half == 0 { a } else { b };
+            let indices = [base, base | side, base | a | b];
+            let projector = ClippingTriangleProjector::new(indices.map(|i| vertices[i].clone()));
+            (indices, projector)
+        });
+        CubeProjector { tetrahedra, faces }
+    }
+
+    pub fn project(&self, pt: &Point3<T>) -> Vector8<T> {
+        let mut weights: Vector8<T> = zero();
+        // Inside if in one of the tetrahedra, keep the closest in case of rounding errors
+        let mut best: Option<(usize, Vector4<T>, T)> = None;
+        for (index, (_, tetrahedron)) in self.tetrahedra.iter().enumerate() {
+            let barycentric = tetrahedron.project(pt);
+            let min = barycentric.min();
+            if min >= zero() {
+                for (vertex, weight) in self.tetrahedra[index].0.iter().zip(barycentric.iter()) {
+                    weights[*vertex] = weight.clone();
+                }
+                return weights;
+            }
+            if best
+                .as_ref()
+                .map(|(_, _, best)| min > *best)
+                .unwrap_or(true)
+            {
+                best = Some((index, barycentric, min));
+            }
+        }
+
+        // Just between tetrahedra by rounding errors, clamp the closest one
+        let tolerance: T = nalgebra::convert(1e-4);
+        if let Some((index, barycentric, min)) = best
+            && min > -tolerance
+        {
+            let clamped = barycentric.map(|x| if x < zero() { zero() } else { x });
+            let sum = clamped.sum();
+            for (vertex, weight) in self.tetrahedra[index].0.iter().zip(clamped.iter()) {
+                weights[*vertex] = if sum > zero() {
+                    weight.clone() / sum.clone()
+                } else {
+                    weight.clone()
+                };
+            }
+            return weights;
+        }
+
+        // Outside, onto the closest point of the surface
+        let mut closest: Option<(usize, Vector3<T>, T::RealField)> = None;
+        for (index, (_, face)) in self.faces.iter().enumerate() {
+            let (barycentric, _, _) = face.clipping_project(pt);
+            let distance = (face.bary_to_point(&barycentric) - pt).norm_squared();
+            if closest
+                .as_ref()
+                .map(|(_, _, best)| distance < *best)
+                .unwrap_or(true)
+            {
+                closest = Some((index, barycentric, distance));
+            }
+        }
+        if let Some((index, barycentric, _)) = closest {
+            for (vertex, weight) in self.faces[index].0.iter().zip(barycentric.iter()) {
+                weights[*vertex] = weight.clone();
+            }
+        }
+        weights
+    }
+
+    // Like OctahedronProjector::project_many, one tetrahedron at a time over the whole batch
+    pub fn project_many(&self, points: &[Point3<T>], out: &mut [Vector8<T>]) {
+        let len = points.len().min(out.len());
+        let mut done = alloc::vec![false; len];
+        let mut local: Vec<Vector4<T>> = alloc::vec![zero(); len];
+        for (indices, tetrahedron) in self.tetrahedra.iter() {
+            tetrahedron.project_many(&points[..len], &mut local);
+            for ((local, out), done) in local.iter().zip(out.iter_mut()).zip(done.iter_mut()) {
+                if !*done && local.min() >= zero() {
+                    *out = zero();
+                    for (vertex, weight) in indices.iter().zip(local.iter()) {
+                        out[*vertex] = weight.clone();
+                    }
+                    *done = true;
+                }
+            }
+        }
+        for ((pt, out), done) in points.iter().zip(out.iter_mut()).zip(done) {
+            if !done {
+                *out = self.project(pt);
+            }
+        }
+    }
+}
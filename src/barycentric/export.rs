@@ -0,0 +1,160 @@
+/*
+ * Host-side exports of the projectors, to look at the gamut mapping in external tools: the geometry
+ * as Wavefront OBJ (MeshLab, Blender), and projections of a grid of colors as CSV (a spreadsheet,
+ * matplotlib). Only for f32 projectors in 8-bit RGB units, like the palettes.
+ *
+ * In the OBJ files every vertex is colored by its own position, and each part is its own group, so
+ * the wedges or tetrahedra can be hidden one by one.
+ */
+use crate::barycentric::octahedron::OctahedronProjector;
+use crate::barycentric::polytope::PolytopeProjector;
+use alloc::vec::Vec;
+use nalgebra::geometry::Point3;
+use std::io::{Result, Write};
+
+pub fn octahedron_obj(projector: &OctahedronProjector<f32>, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "o octahedron")?;
+    write_vertices(projector.vertices(), out)?;
+    // Same order as the projectors in OctahedronProjector
+    for wedge in 0..4 {
+        let [a, b] = [2 + wedge, 2 + ((wedge + 1) % 4)];
+        write_tetrahedron(&format_args!("wedge{}", wedge), [0, 1, a, b], out)?;
+    }
+    writeln!(out, "g faces")?;
+    for face in 0..8 {
+        write_face([face / 4, 2 + (face % 4), 2 + ((face + 1) % 4)], out)?;
+    }
+    writeln!(out, "g edges")?;
+    for edge in 0..12 {
+        let (pole, equator) = (edge / 4, edge % 4);
+        if pole < 2 {
+            write_edge([pole, 2 + equator], out)?;
+        } else {
+            write_edge([2 + equator, 2 + ((equator + 1) % 4)], out)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn polytope_obj(projector: &PolytopeProjector<f32>, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "o polytope")?;
+    write_vertices(projector.vertices(), out)?;
+    for (index, tetrahedron) in projector.tetrahedra().enumerate() {
+        write_tetrahedron(&format_args!("tetrahedron{}", index), tetrahedron, out)?;
+    }
+    writeln!(out, "g faces")?;
+    let mut edges: Vec<[usize; 2]> = Vec::new();
+    for face @ [a, b, c] in projector.faces() {
+        write_face(face, out)?;
+        for [from, to] in [[a, b], [b, c], [c, a]] {
+            let edge = [from.min(to), from.max(to)];
+            if !edges.contains(&edge) {
+                edges.push(edge);
+            }
+        }
+    }
+    writeln!(out, "g edges")?;
+    for edge in edges {
+        write_edge(edge, out)?;
+    }
+    Ok(())
+}
+
+/*
+ * Projections of a steps x steps x steps grid over the RGB cube, one row per color: the color, a
+ * weight per vertex, where those weights put it, and how far that is from the color (0 inside).
+ */
+pub fn octahedron_samples_csv(
+    projector: &OctahedronProjector<f32>,
+    steps: usize,
+    out: &mut impl Write,
+) -> Result<()> {
+    write_samples(6, steps, out, |pt| {
+        let projection = projector.project_detailed(pt);
+        (
+            projection.weights.iter().copied().collect(),
+            projection.point,
+        )
+    })
+}
+
+pub fn polytope_samples_csv(
+    projector: &PolytopeProjector<f32>,
+    steps: usize,
+    out: &mut impl Write,
+) -> Result<()> {
+    let vertices = projector.vertices();
+    write_samples(vertices.len(), steps, out, |pt| {
+        let weights = projector.project(pt);
+        let point = vertices
+            .iter()
+            .zip(weights.iter())
+            .fold(Point3::origin(), |point, (vertex, weight)| {
+                point + vertex.coords * *weight
+            });
+        (weights, point)
+    })
+}
+
+fn write_vertices(vertices: &[Point3<f32>], out: &mut impl Write) -> Result<()> {
+    for vertex in vertices {
+        let color = vertex.coords.map(|value| (value / 255.0).clamp(0.0, 1.0));
+        writeln!(
+            out,
+            "v {} {} {} {} {} {}",
+            vertex.x, vertex.y, vertex.z, color.x, color.y, color.z
+        )?;
+    }
+    Ok(())
+}
+
+// OBJ indices start at 1
+fn write_face([a, b, c]: [usize; 3], out: &mut impl Write) -> Result<()> {
+    writeln!(out, "f {} {} {}", a + 1, b + 1, c + 1)
+}
+
+fn write_edge([a, b]: [usize; 2], out: &mut impl Write) -> Result<()> {
+    writeln!(out, "l {} {}", a + 1, b + 1)
+}
+
+fn write_tetrahedron(
+    name: &core::fmt::Arguments,
+    [a, b, c, d]: [usize; 4],
+    out: &mut impl Write,
+) -> Result<()> {
+    writeln!(out, "g {}", name)?;
+    for face in [[a, b, c], [a, b, d], [a, c, d], [b, c, d]] {
+        write_face(face, out)?;
+    }
+    Ok(())
+}
+
+fn write_samples(
+    vertex_count: usize,
+    steps: usize,
+    out: &mut impl Write,
+    project: impl Fn(&Point3<f32>) -> (Vec<f32>, Point3<f32>),
+) -> Result<()> {
+    write!(out, "r,g,b")?;
+    for index in 0..vertex_count {
+        write!(out, ",w{}", index)?;
+    }
+    writeln!(out, ",x,y,z,distance")?;
+    let steps = steps.max(2);
+    let value = |step: usize| (step * 255 / (steps - 1)) as f32;
+    for r in 0..steps {
+        for g in 0..steps {
+            for b in 0..steps {
+                let pt = Point3::new(value(r), value(g), value(b));
+                let (weights, point) = project(&pt);
+                write!(out, "{},{},{}", pt.x, pt.y, pt.z)?;
+                for weight in weights {
+                    write!(out, ",{}", weight)?;
+                }
+                let distance = (point - pt).norm();
+                writeln!(out, ",{},{},{},{}", point.x, point.y, point.z, distance)?;
+            }
+        }
+    }
+    Ok(())
+}
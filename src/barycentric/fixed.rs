@@ -0,0 +1,333 @@
+/*
+ * Fixed point version of OctahedronProjector, for chips without an FPU and for results that are
+ * the same on every platform. Everything that needs a division or an inverse is worked out in f64
+ * up front, projecting is Q16.16 integer math only.
+ *
+ * Coordinates are scaled so the octahedron fits in -1.0..=1.0 at construction, so both 8-bit
+ * colors and 0.0 to 1.0 ones keep their precision.
+ */
+use core::ops::{Add, Div, Mul, Neg, Sub};
+use nalgebra::base::{Matrix3, Matrix4, Vector3};
+use nalgebra::geometry::Point3;
+
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Q16(pub i32);
+
+impl Q16 {
+    pub const FRACTION_BITS: u32 = 16;
+    pub const ZERO: Q16 = Q16(0);
+    pub const ONE: Q16 = Q16(1 << Self::FRACTION_BITS);
+
+    pub const fn from_int(value: i16) -> Self {
+        Q16((value as i32) << Self::FRACTION_BITS)
+    }
+
+    // Rounded, saturating
+    pub fn from_f64(value: f64) -> Self {
+        let value = value * Self::ONE.0 as f64;
+        let value = if value < 0.0 {
+            value - 0.5
+        } else {
+            value + 0.5
+        };
+        Q16(value.clamp(i32::MIN as f64, i32::MAX as f64) as i32)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / Self::ONE.0 as f32
+    }
+}
+
+impl Add for Q16 {
+    type Output = Q16;
+
+    fn add(self, rhs: Q16) -> Q16 {
+        Q16(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl Sub for Q16 {
+    type Output = Q16;
+
+    fn sub(self, rhs: Q16) -> Q16 {
+        Q16(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl Mul for Q16 {
+    type Output = Q16;
+
+    fn mul(self, rhs: Q16) -> Q16 {
+        let value = (self.0 as i64 * rhs.0 as i64) >> Self::FRACTION_BITS;
+        Q16(value.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+    }
+}
+
+// Saturating, and 0 when dividing by 0
+impl Div for Q16 {
+    type Output = Q16;
+
+    fn div(self, rhs: Q16) -> Q16 {
+        if rhs.0 == 0 {
+            return Q16::ZERO;
+        }
+        let value = ((self.0 as i64) << Self::FRACTION_BITS) / rhs.0 as i64;
+        Q16(value.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+    }
+}
+
+impl Neg for Q16 {
+    type Output = Q16;
+
+    fn neg(self) -> Q16 {
+        Q16(self.0.saturating_neg())
+    }
+}
+
+type Q3 = [Q16; 3];
+
+fn sub3(a: Q3, b: Q3) -> Q3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot3(a: Q3, b: Q3) -> Q16 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn q3(vector: Vector3<f64>) -> Q3 {
+    [vector.x, vector.y, vector.z].map(Q16::from_f64)
+}
+
+fn min<const N: usize>(values: &[Q16; N]) -> Q16 {
+    values.iter().copied().min().unwrap_or(Q16::ZERO)
+}
+
+struct FixedTetrahedron {
+    // Rows of the inverse of [vertices; 1 1 1 1]
+    to_barycentric: [[Q16; 4]; 4],
+}
+
+impl FixedTetrahedron {
+    fn new(vertices: [Vector3<f64>; 4]) -> Self {
+        let from_barycentric =
+            Matrix4::from_columns(&vertices.map(|vertex| Point3::from(vertex).to_homogeneous()));
+        let inverse = from_barycentric
+            .try_inverse()
+            .unwrap_or_else(Matrix4::zeros);
+        FixedTetrahedron {
+            to_barycentric: core::array::from_fn(|row| {
+                core::array::from_fn(|column| Q16::from_f64(inverse[(row, column)]))
+            }),
+        }
+    }
+
+    fn project(&self, pt: Q3) -> [Q16; 4] {
+        self.to_barycentric
+            .map(|row| row[0] * pt[0] + row[1] * pt[1] + row[2] * pt[2] + row[3])
+    }
+}
+
+struct FixedTriangle {
+    v1: Q3,
+    // Rows giving u and v from pt - v1, see TriangleProjector
+    project_matrix: [Q3; 2],
+}
+
+impl FixedTriangle {
+    fn new([v1, v2, v3]: [Vector3<f64>; 3]) -> Self {
+        let v1_to_v2 = v2 - v1;
+        let v1_to_v3 = v3 - v1;
+        let normal = v1_to_v2.cross(&v1_to_v3);
+        let premul = Matrix3::from_columns(&[-normal, v1_to_v2, v1_to_v3]);
+        let inverse = premul.try_inverse().unwrap_or_else(Matrix3::zeros);
+        FixedTriangle {
+            v1: q3(v1),
+            project_matrix: [1, 2]
+                .map(|row| core::array::from_fn(|column| Q16::from_f64(inverse[(row, column)]))),
+        }
+    }
+
+    fn project(&self, pt: Q3) -> [Q16; 3] {
+        let v1_to_pt = sub3(pt, self.v1);
+        let [u, v] = self.project_matrix.map(|row| dot3(row, v1_to_pt));
+        [Q16::ONE - u - v, u, v]
+    }
+}
+
+struct FixedLine {
+    origin: Q3,
+    direction: Q3,
+    norm_squared: Q16,
+}
+
+impl FixedLine {
+    fn new([a, b]: [Vector3<f64>; 2]) -> Self {
+        let direction = b - a;
+        FixedLine {
+            origin: q3(a),
+            direction: q3(direction),
+            norm_squared: Q16::from_f64(direction.norm_squared()),
+        }
+    }
+
+    // Barycentric coordinates clipped to the end points, and the squared distance from pt
+    fn clipping_project(&self, pt: Q3) -> ([Q16; 2], Q16) {
+        let origin_to_pt = sub3(pt, self.origin);
+        let t = if self.norm_squared == Q16::ZERO {
+            Q16::ZERO
+        } else {
+            (dot3(origin_to_pt, self.direction) / self.norm_squared).clamp(Q16::ZERO, Q16::ONE)
+        };
+        let on_line = [0, 1, 2].map(|i| self.origin[i] + self.direction[i] * t);
+        let offset = sub3(on_line, pt);
+        ([Q16::ONE - t, t], dot3(offset, offset))
+    }
+}
+
+pub struct FixedOctahedronProjector {
+    // Largest absolute coordinate of the vertices, coordinates are divided by it
+    largest: f64,
+    largest_q16: Q16,
+    wedges: [FixedTetrahedron; 4],
+    faces: [FixedTriangle; 8],
+    edges: [FixedLine; 12],
+}
+
+impl FixedOctahedronProjector {
+    // Same vertex order as OctahedronProjector: the two poles first, then the others in cyclic order
+    pub fn new(vertices: [Point3<f32>; 6]) -> Self {
+        let largest = vertices
+            .iter()
+            .flat_map(|vertex| vertex.coords.iter().map(|value| value.abs()))
+            .fold(0.0f32, f32::max) as f64;
+        let largest = if largest > 0.0 { largest } else { 1.0 };
+        let vertices = vertices.map(|vertex| vertex.coords.map(|value| value as f64 / largest));
+        let wedges = core::array::from_fn(|i| {
+            FixedTetrahedron::new([
+                vertices[0],
+                vertices[1],
+                vertices[2 + (i % 4)],
+                vertices[2 + ((i + 1) % 4)],
+            ])
+        });
+        let faces = core::array::from_fn(|i| {
+            FixedTriangle::new([
+                vertices[i / 4],
+                vertices[2 + (i % 4)],
+                vertices[2 + ((i + 1) % 4)],
+            ])
+        });
+        let edges = core::array::from_fn(|i| {
+            let (pole_index, equator_index) = (i / 4, i % 4);
+            if pole_index < 2 {
+                FixedLine::new([vertices[pole_index], vertices[2 + equator_index]])
+            } else {
+                FixedLine::new([
+                    vertices[2 + equator_index],
+                    vertices[2 + ((equator_index + 1) % 4)],
+                ])
+            }
+        });
+        FixedOctahedronProjector {
+            largest,
+            largest_q16: Q16::from_f64(largest),
+            wedges,
+            faces,
+            edges,
+        }
+    }
+
+    // For points in the units given to new, e.g. 8-bit colors
+    pub fn project_int(&self, [x, y, z]: [i16; 3]) -> [Q16; 6] {
+        self.project([x, y, z].map(|value| Q16::from_int(value) / self.largest_q16))
+    }
+
+    // For points already divided by largest
+    pub fn project(&self, pt: Q3) -> [Q16; 6] {
+        let mut edges_to_check = [false; 12];
+        let mut best: Option<([Q16; 6], Q16)> = None;
+        for (wedge_index, wedge) in self.wedges.iter().enumerate() {
+            let local = wedge.project(pt);
+            let local_min = min(&local);
+            if local_min >= Q16::ZERO {
+                return wedge_to_global(wedge_index, local);
+            }
+            if best.is_none_or(|(_, best)| best < local_min) {
+                best = Some((wedge_to_global(wedge_index, local), local_min));
+            }
+            for (pole, value) in local.iter().enumerate().take(2) {
+                if *value > Q16::ZERO {
+                    continue;
+                }
+                let face_index = (1 - pole) * 4 + wedge_index;
+                let face_local = self.faces[face_index].project(pt);
+                if min(&face_local) >= Q16::ZERO {
+                    return face_to_global(face_index, face_local);
+                }
+                if face_local[0] <= Q16::ZERO {
+                    edges_to_check[8 + (face_index % 4)] = true;
+                }
+                for equator_vertex_index in 0..2 {
+                    if face_local[1 + equator_vertex_index] <= Q16::ZERO {
+                        let other = ((face_index % 4) + (1 - equator_vertex_index)) % 4;
+                        edges_to_check[(face_index / 4) * 4 + other] = true;
+                    }
+                }
+            }
+        }
+        if edges_to_check.iter().all(|to_check| !*to_check) {
+            // In the rounding errors between wedges, clamp the closest one
+            let (best, _) = best.unwrap_or(([Q16::ZERO; 6], Q16::ZERO));
+            let best = best.map(|value| value.max(Q16::ZERO));
+            let sum = best.iter().fold(Q16::ZERO, |sum, value| sum + *value);
+            if sum > Q16::ZERO {
+                return best.map(|value| value / sum);
+            }
+            return best;
+        }
+        let mut best: Option<([Q16; 6], Q16)> = None;
+        for (edge_index, edge) in self.edges.iter().enumerate() {
+            if !edges_to_check[edge_index] {
+                continue;
+            }
+            let (local, distance) = edge.clipping_project(pt);
+            if best.is_none_or(|(_, best)| distance < best) {
+                best = Some((edge_to_global(edge_index, local), distance));
+            }
+        }
+        best.map(|(best, _)| best).unwrap_or([Q16::ZERO; 6])
+    }
+
+    // What coordinates are divided by before projecting
+    pub fn largest(&self) -> f64 {
+        self.largest
+    }
+}
+
+fn wedge_to_global(index: usize, [north, south, a, b]: [Q16; 4]) -> [Q16; 6] {
+    let mut ret = [north, south, Q16::ZERO, Q16::ZERO, Q16::ZERO, Q16::ZERO];
+    ret[2 + (index % 4)] = a;
+    ret[2 + ((index + 1) % 4)] = b;
+    ret
+}
+
+fn face_to_global(index: usize, [pole, a, b]: [Q16; 3]) -> [Q16; 6] {
+    let mut ret = [Q16::ZERO; 6];
+    ret[index / 4] = pole;
+    ret[2 + (index % 4)] = a;
+    ret[2 + ((index + 1) % 4)] = b;
+    ret
+}
+
+fn edge_to_global(index: usize, [a, b]: [Q16; 2]) -> [Q16; 6] {
+    let mut ret = [Q16::ZERO; 6];
+    let (pole_index, equator_index) = (index / 4, index % 4);
+    if pole_index < 2 {
+        ret[pole_index] = a;
+        ret[2 + equator_index] = b;
+    } else {
+        ret[2 + equator_index] = a;
+        ret[2 + ((equator_index + 1) % 4)] = b;
+    }
+    ret
+}
@@ -1,4 +1,9 @@
+pub mod cube;
+#[cfg(feature = "std")]
+pub mod export;
+pub mod fixed;
 pub mod line;
 pub mod octahedron;
+pub mod polytope;
 pub mod tetrahedron;
 pub mod triangle;
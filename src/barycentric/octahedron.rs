@@ -1,6 +1,7 @@
 use crate::barycentric::line::LineProjector;
 use crate::barycentric::tetrahedron::TetrahedronProjector;
-use crate::barycentric::triangle::TriangleProjector;
+use crate::barycentric::triangle::{ClippingTriangleProjector, TriangleProjector};
+use alloc::vec::Vec;
 use nalgebra::base::{Scalar, Vector2, Vector3, Vector4, Vector6};
 use nalgebra::geometry::Point3;
 use nalgebra::{ClosedAddAssign, ClosedDivAssign, ClosedMulAssign, ClosedSubAssign, ComplexField};
@@ -24,6 +25,18 @@ pub struct OctahedronProjector<T: Scalar> {
      * 4 go between the equatorial vertices (e.g. a->b, b->c, c->d, d->a).
      */
     edges: [LineProjector<T>; 12],
+    // Same faces as faces, for project_clamped
+    surface: [ClippingTriangleProjector<T>; 8],
+    vertices: [Point3<T>; 6],
+}
+
+// Result of project_detailed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Projection<T: Scalar> {
+    pub weights: Vector6<T>,
+    // Where the point ended up, on the surface if it was out of gamut, and how far it was moved
+    pub point: Point3<T>,
+    pub distance_squared: T,
 }
 
 impl<
@@ -78,10 +91,20 @@ impl<
             }
         });
 
+        let surface: [ClippingTriangleProjector<T>; 8] = core::array::from_fn(|i| {
+            ClippingTriangleProjector::new([
+                vertices[i / 4].clone(),
+                vertices[2 + (i % 4)].clone(),
+                vertices[2 + ((i + 1) % 4)].clone(),
+            ])
+        });
+
         OctahedronProjector {
             wedges,
             faces,
             edges,
+            surface,
+            vertices,
         }
     }
 
@@ -114,7 +137,7 @@ impl<
         let pole_index = index / 4;
         let equator_index = index % 4;
         if pole_index < 2 {
-            // There's an error here
+            // Same order as the edges are made in new: pole first, then the equator vertex
             ret[pole_index] = a;
             ret[2 + equator_index] = b;
         } else {
@@ -223,4 +246,186 @@ impl<
         // Point feel between all of the line projections with rounding errors, what to do?!
         best.unwrap().0
     }
+
+    /*
+     * Like project, but without shortcuts: points inside (up to rounding errors) get the weights of
+     * their wedge, points outside those of the closest point on any face, edge or vertex, found by
+     * trying all of them. Weights are never negative and always add up to 1.
+     *
+     * project takes the first face a point projects onto cleanly, which is only the closest one if
+     * the octahedron is convex. Measured palettes often aren't quite, and far out of gamut points
+     * can then end up on the wrong side of it.
+     */
+    pub fn project_clamped(&self, pt: &Point3<T>) -> Vector6<T> {
+        let tolerance: T = nalgebra::convert(1e-5);
+        let mut best: Option<(usize, Vector4<T>, T)> = None;
+        for (wedge_index, wedge) in self.wedges.iter().enumerate() {
+            let local = wedge.project(pt);
+            let min = local.min();
+            if best.as_ref().is_none_or(|(_, _, best)| min > *best) {
+                best = Some((wedge_index, local, min));
+            }
+        }
+        if let Some((wedge_index, local, min)) = best
+            && min > -tolerance
+        {
+            return normalized(Self::wedge_barycentric_local_to_global(wedge_index, local));
+        }
+
+        let mut closest: Option<(Vector6<T>, T::RealField)> = None;
+        let mut consider = |weights: Vector6<T>| {
+            let distance = (self.bary_to_point(&weights) - pt).norm_squared();
+            if closest.as_ref().is_none_or(|(_, best)| distance < *best) {
+                closest = Some((weights, distance));
+            }
+        };
+        for (face_index, face) in self.surface.iter().enumerate() {
+            // Clipped to its edges and vertices as needed
+            let (local, _, _) = face.clipping_project(pt);
+            consider(Self::face_barycentric_local_to_global(face_index, local));
+        }
+        for vertex_index in 0..6 {
+            let mut weights: Vector6<T> = zero();
+            weights[vertex_index] = T::one();
+            consider(weights);
+        }
+        normalized(closest.map(|(weights, _)| weights).unwrap_or_else(zero))
+    }
+
+    // project, with the point the weights make and how far it is from pt, e.g. as error to diffuse
+    pub fn project_detailed(&self, pt: &Point3<T>) -> Projection<T> {
+        let weights = self.project(pt);
+        let point = self.bary_to_point(&weights);
+        let distance_squared = T::from_real((&point - pt).norm_squared());
+        Projection {
+            weights,
+            point,
+            distance_squared,
+        }
+    }
+
+    // In the order given to new
+    pub fn vertices(&self) -> &[Point3<T>; 6] {
+        &self.vertices
+    }
+
+    pub fn bary_to_point(&self, barycentric_coords: &Vector6<T>) -> Point3<T> {
+        self.vertices
+            .iter()
+            .zip(barycentric_coords.iter())
+            .fold(Point3::origin(), |point, (vertex, weight)| {
+                point + &vertex.coords * weight.clone()
+            })
+    }
+
+    /*
+     * project for every point in points, into out (up to the shorter of the two). Goes through the
+     * points once per wedge, so each wedge's matrix stays loaded for the whole batch, and only
+     * points outside all of them take the slow path through faces and edges.
+     */
+    pub fn project_many(&self, points: &[Point3<T>], out: &mut [Vector6<T>]) {
+        let len = points.len().min(out.len());
+        let mut done = alloc::vec![false; len];
+        let mut local: Vec<Vector4<T>> = alloc::vec![zero(); len];
+        for (wedge_index, wedge) in self.wedges.iter().enumerate() {
+            wedge.project_many(&points[..len], &mut local);
+            for ((local, out), done) in local.iter().zip(out.iter_mut()).zip(done.iter_mut()) {
+                if !*done && local.min() >= zero() {
+                    *out = Self::wedge_barycentric_local_to_global(wedge_index, local.clone());
+                    *done = true;
+                }
+            }
+        }
+        for ((pt, out), done) in points.iter().zip(out.iter_mut()).zip(done) {
+            if !done {
+                *out = self.project(pt);
+            }
+        }
+    }
+
+    // See OctahedronLut, for points that are 8-bit colors
+    pub fn build_lut(&self, resolution: usize) -> OctahedronLut<T> {
+        self.build_lut_with_max(resolution, [255; 3])
+    }
+
+    // For points with each channel from 0 to max, e.g. [31, 63, 31] for Rgb565
+    pub fn build_lut_with_max(&self, resolution: usize, max: [u8; 3]) -> OctahedronLut<T> {
+        let resolution = resolution.max(2);
+        let mut weights = Vec::with_capacity(resolution * resolution * resolution);
+        for index in 0..resolution * resolution * resolution {
+            let cell = [index / (resolution * resolution), index / resolution, index]
+                .map(|value| value % resolution);
+            let [r, g, b]: [T; 3] = core::array::from_fn(|channel| {
+                nalgebra::convert(
+                    cell[channel] as f64 * max[channel] as f64 / (resolution - 1) as f64,
+                )
+            });
+            weights.push(self.project(&Point3::new(r, g, b)));
+        }
+        OctahedronLut {
+            resolution,
+            max,
+            weights,
+        }
+    }
+}
+
+/*
+ * Projections worked out up front on a resolution^3 grid, and interpolated (trilinearly) in
+ * between, so there's no searching through wedges, faces and edges per pixel. Memory is
+ * resolution^3 times 6 weights, at 17 (118 KB with f32) the weights are off by well under a percent
+ * on average for the Spectra 6 octahedron, but more where the projection bends around the edges of
+ * the gamut. Interpolated weights are never negative and still add up to 1.
+ */
+pub struct OctahedronLut<T: Scalar> {
+    resolution: usize,
+    max: [u8; 3],
+    weights: Vec<Vector6<T>>,
+}
+
+// Fractional bits of grid positions
+const LUT_FRACTION_BITS: u32 = 8;
+
+impl<T: Scalar + ClosedAddAssign + ClosedMulAssign + ComplexField + Zero> OctahedronLut<T> {
+    pub fn project(&self, color: [u8; 3]) -> Vector6<T> {
+        let one = 1usize << LUT_FRACTION_BITS;
+        let mut cells = [0usize; 3];
+        let mut fractions: [T; 3] = core::array::from_fn(|_| zero());
+        for channel in 0..3 {
+            let max = (self.max[channel] as usize).max(1);
+            let position = (color[channel] as usize).min(max) * (self.resolution - 1) * one / max;
+            let cell = (position >> LUT_FRACTION_BITS).min(self.resolution - 2);
+            cells[channel] = cell;
+            fractions[channel] = nalgebra::convert((position - cell * one) as f64 / one as f64);
+        }
+        let mut result: Vector6<T> = zero();
+        for corner in 0..8 {
+            let mut weight: T = T::one();
+            let mut index = 0;
+            for channel in 0..3 {
+                let upper = (corner >> (2 - channel)) & 1;
+                let fraction = fractions[channel].clone();
+                weight *= if upper == 1 {
+                    fraction
+                } else {
+                    T::one() - fraction
+                };
+                index = index * self.resolution + cells[channel] + upper;
+            }
+            result += &self.weights[index] * weight;
+        }
+        result
+    }
+}
+
+// Negative weights to 0, then scaled to add up to 1
+fn normalized<T: Scalar + ClosedAddAssign + ClosedDivAssign + Zero + PartialOrd>(
+    weights: Vector6<T>,
+) -> Vector6<T> {
+    let mut weights = weights.map(|x| if x < zero() { zero() } else { x });
+    let sum = weights.sum();
+    if sum > zero() {
+        weights /= sum;
+    }
+    weights
 }
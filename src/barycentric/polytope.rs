@@ -0,0 +1,265 @@
+use crate::barycentric::tetrahedron::TetrahedronProjector;
+use crate::barycentric::triangle::ClippingTriangleProjector;
+use alloc::vec::Vec;
+use nalgebra::base::{Scalar, Vector3};
+use nalgebra::geometry::Point3;
+use nalgebra::{ClosedAddAssign, ClosedDivAssign, ClosedMulAssign, ClosedSubAssign, ComplexField};
+use num_traits::identities::{One, Zero};
+use num_traits::zero;
+
+/*
+ * Like OctahedronProjector, but for any number of vertices in any arrangement. The convex hull is
+ * worked out up front, and split into tetrahedra that all share one hull vertex. Points inside are
+ * projected into the tetrahedron they're in, points outside onto the closest point of the hull.
+ * Vertices inside the hull (rather than on it) always get a weight of 0.
+ */
+pub struct PolytopeProjector<T: Scalar> {
+    vertices: Vec<Point3<T>>,
+    // Vertex indices and projector of each tetrahedron
+    tetrahedra: Vec<([usize; 4], TetrahedronProjector<T>)>,
+    // Vertex indices, a point on it, outward normal and projector of each hull face
+    faces: Vec<HullFace<T>>,
+}
+
+struct HullFace<T: Scalar> {
+    indices: [usize; 3],
+    origin: Point3<T>,
+    normal: Vector3<T>,
+    projector: ClippingTriangleProjector<T>,
+}
+
+// Relative to the size of the polytope, below this points count as on a plane
+const EPSILON: f64 = 1e-6;
+
+impl<
+    T: Scalar
+        + ClosedSubAssign
+        + ClosedMulAssign
+        + ClosedAddAssign
+        + ClosedDivAssign
+        + Zero
+        + One
+        + ComplexField
+        + PartialOrd,
+> PolytopeProjector<T>
+{
+    // None unless there are at least four vertices that aren't all in one plane
+    pub fn new(vertices: &[Point3<T>]) -> Option<Self> {
+        let hull = convex_hull(vertices)?;
+        let apex = hull[0][0];
+        let tetrahedra = hull
+            .iter()
+            .filter(|face| !face.contains(&apex))
+            .map(|[a, b, c]| {
+                let indices = [apex, *a, *b, *c];
+                let projector = TetrahedronProjector::new(indices.map(|i| vertices[i].clone()));
+                (indices, projector)
+            })
+            .collect();
+        let faces = hull
+            .iter()
+            .map(|indices| {
+                let [a, b, c] = indices.map(|i| vertices[i].clone());
+                HullFace {
+                    indices: *indices,
+                    normal: (b.clone() - a.clone()).cross(&(c.clone() - a.clone())),
+                    origin: a.clone(),
+                    projector: ClippingTriangleProjector::new([a, b, c]),
+                }
+            })
+            .collect();
+        Some(PolytopeProjector {
+            vertices: vertices.to_vec(),
+            tetrahedra,
+            faces,
+        })
+    }
+
+    // Barycentric coordinates, one per vertex in the order given to new
+    pub fn project(&self, pt: &Point3<T>) -> Vec<T> {
+        let mut weights: Vec<T> = alloc::vec![zero(); self.vertices.len()];
+        let outside = self
+            .faces
+            .iter()
+            .filter(|face| face.normal.dot(&(pt - &face.origin)) > zero());
+        let mut closest: Option<(&HullFace<T>, Vector3<T>, T)> = None;
+        for face in outside {
+            let (barycentric, _, _) = face.projector.clipping_project(pt);
+            let distance = (face.projector.bary_to_point(&barycentric) - pt).norm_squared();
+            let distance = T::from_real(distance);
+            if closest
+                .as_ref()
+                .map(|(_, _, best)| distance < *best)
+                .unwrap_or(true)
+            {
+                closest = Some((face, barycentric, distance));
+            }
+        }
+        if let Some((face, barycentric, _)) = closest {
+            for (index, weight) in face.indices.iter().zip(barycentric.iter()) {
+                weights[*index] = weight.clone();
+            }
+            return weights;
+        }
+
+        // Inside, in the tetrahedron with all coordinates positive. Rounding errors can leave a
+        // point just outside all of them, so fall back to the one it's closest to being in.
+        let mut best: Option<(usize, nalgebra::base::Vector4<T>, T)> = None;
+        for (index, (_, tetrahedron)) in self.tetrahedra.iter().enumerate() {
+            let barycentric = tetrahedron.project(pt);
+            let min = barycentric.min();
+            if best
+                .as_ref()
+                .map(|(_, _, best)| min > *best)
+                .unwrap_or(true)
+            {
+                let inside = min >= zero();
+                best = Some((index, barycentric, min));
+                if inside {
+                    break;
+                }
+            }
+        }
+        if let Some((index, barycentric, _)) = best {
+            let clamped = barycentric.map(|x| if x < zero() { zero() } else { x });
+            let sum = clamped.sum();
+            for (vertex, weight) in self.tetrahedra[index].0.iter().zip(clamped.iter()) {
+                weights[*vertex] = if sum > zero() {
+                    weight.clone() / sum.clone()
+                } else {
+                    weight.clone()
+                };
+            }
+        }
+        weights
+    }
+
+    // In the order given to new
+    pub fn vertices(&self) -> &[Point3<T>] {
+        &self.vertices
+    }
+
+    // Vertex indices of the tetrahedra the inside is split into
+    pub fn tetrahedra(&self) -> impl Iterator<Item = [usize; 4]> + '_ {
+        self.tetrahedra.iter().map(|(indices, _)| *indices)
+    }
+
+    // Vertex indices of the hull faces, wound so the normal points outward
+    pub fn faces(&self) -> impl Iterator<Item = [usize; 3]> + '_ {
+        self.faces.iter().map(|face| face.indices)
+    }
+}
+
+/*
+ * Incremental convex hull: start from a tetrahedron of far apart vertices, then add the others one
+ * by one, replacing the faces they can see by faces to the horizon. Faces are wound so their
+ * normal, (b - a) x (c - a), points outward. Palettes are small, so O(n^2) is fine.
+ */
+fn convex_hull<
+    T: Scalar
+        + ClosedSubAssign
+        + ClosedMulAssign
+        + ClosedAddAssign
+        + ClosedDivAssign
+        + Zero
+        + One
+        + ComplexField
+        + PartialOrd,
+>(
+    vertices: &[Point3<T>],
+) -> Option<Vec<[usize; 3]>> {
+    let first = vertices.first()?;
+    let size_squared = vertices
+        .iter()
+        .map(|vertex| (vertex - first).norm_squared())
+        .fold(zero(), |a: T::RealField, b| if b > a { b } else { a });
+    let size_squared = T::from_real(size_squared);
+    let epsilon: T = nalgebra::convert(EPSILON);
+    let epsilon_squared = epsilon.clone() * epsilon;
+    // Squared distance of p in front of the plane through a with normal, scaled so the limit
+    // doesn't depend on the size of the normal: in front if it's more than epsilon * size
+    let in_front = |normal: &Vector3<T>, a: &Point3<T>, p: &Point3<T>| -> bool {
+        let side = normal.dot(&(p - a));
+        side > zero()
+            && side.clone() * side
+                > epsilon_squared.clone() * size_squared.clone() * normal.dot(normal)
+    };
+    let normal_of = |[a, b, c]: [usize; 3]| -> Vector3<T> {
+        (&vertices[b] - &vertices[a]).cross(&(&vertices[c] - &vertices[a]))
+    };
+
+    // Starting tetrahedron: farthest from the first vertex, from that line, from that plane
+    let farthest = |distance: &dyn Fn(&Point3<T>) -> T| -> Option<usize> {
+        let (index, best) = vertices.iter().map(distance).enumerate().fold(
+            None,
+            |best: Option<(usize, T)>, (index, d)| match best {
+                Some((_, ref b)) if *b >= d => best,
+                _ => Some((index, d)),
+            },
+        )?;
+        (best > epsilon_squared.clone() * size_squared.clone() * size_squared.clone())
+            .then_some(index)
+    };
+    let i0 = 0;
+    let i1 = farthest(&|p| {
+        let d = (p - first).norm_squared();
+        let d = T::from_real(d);
+        d.clone() * size_squared.clone()
+    })?;
+    let direction = &vertices[i1] - first;
+    let i2 = farthest(&|p| {
+        let n = direction.cross(&(p - first));
+        n.dot(&n)
+    })?;
+    let plane = normal_of([i0, i1, i2]);
+    let plane_squared = plane.dot(&plane);
+    let i3 = farthest(&|p| {
+        let side = plane.dot(&(p - first));
+        side.clone() * side / plane_squared.clone() * size_squared.clone()
+    })?;
+
+    // Each face of the starting tetrahedron, turned around if it faces the fourth vertex
+    let mut faces: Vec<[usize; 3]> = [
+        ([i0, i1, i2], i3),
+        ([i0, i1, i3], i2),
+        ([i1, i2, i3], i0),
+        ([i2, i0, i3], i1),
+    ]
+    .into_iter()
+    .map(|(face @ [a, b, c], opposite)| {
+        if normal_of(face).dot(&(&vertices[opposite] - &vertices[a])) > zero() {
+            [a, c, b]
+        } else {
+            face
+        }
+    })
+    .collect();
+
+    for (index, vertex) in vertices.iter().enumerate() {
+        if [i0, i1, i2, i3].contains(&index) {
+            continue;
+        }
+        let (visible, hidden): (Vec<[usize; 3]>, Vec<[usize; 3]>) =
+            faces.iter().partition(|face| {
+                let [a, _, _] = **face;
+                in_front(&normal_of(**face), &vertices[a], vertex)
+            });
+        if visible.is_empty() {
+            continue;
+        }
+        // Edges of visible faces that aren't shared with another visible face
+        let horizon = visible
+            .iter()
+            .flat_map(|[a, b, c]| [[*a, *b], [*b, *c], [*c, *a]]);
+        let horizon: Vec<[usize; 2]> = horizon
+            .filter(|[a, b]| {
+                !visible
+                    .iter()
+                    .any(|[x, y, z]| [[*x, *y], [*y, *z], [*z, *x]].contains(&[*b, *a]))
+            })
+            .collect();
+        faces = hidden;
+        faces.extend(horizon.into_iter().map(|[a, b]| [a, b, index]));
+    }
+    Some(faces)
+}
@@ -32,6 +32,14 @@ impl<T: Scalar + ComplexField + ClosedMulAssign + ClosedAddAssign + ClosedDivAss
         &self.to_barycentric * pt.to_homogeneous()
     }
 
+    // project for every point in points, into out (up to the shorter of the two)
+    pub fn project_many(&self, points: &[Point3<T>], out: &mut [Vector4<T>]) {
+        let matrix = &self.to_barycentric;
+        for (pt, out) in points.iter().zip(out.iter_mut()) {
+            *out = matrix * pt.to_homogeneous();
+        }
+    }
+
     pub fn bary_to_point(&self, barycentric_coords: &Vector4<T>) -> Point3<T> {
         Point3::from_homogeneous(&self.from_barycentric * barycentric_coords).unwrap()
     }
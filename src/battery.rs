@@ -0,0 +1,119 @@
+/*
+ * Battery level from the ADC. The battery reaches the ADC pin through a resistor divider, which is
+ * switched on by a GPIO only while measuring so it doesn't drain the battery during deep sleep.
+ *
+ * The percentage follows a typical Li-ion discharge curve at light load. It's an estimate, good
+ * enough to warn before the battery runs flat, not a fuel gauge.
+ */
+use crate::spectra6::Spectra6Color;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::mono_font::ascii::FONT_10X20;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Alignment, Text};
+
+// Below this the panel refresh itself may brown out, time to stop refreshing
+pub const CRITICAL_MILLIVOLTS: u16 = 3400;
+
+// (battery millivolts, percent), from full to empty
+const DISCHARGE_CURVE: [(u16, u8); 11] = [
+    (4150, 100),
+    (4050, 90),
+    (3970, 80),
+    (3900, 70),
+    (3840, 60),
+    (3790, 50),
+    (3750, 40),
+    (3710, 30),
+    (3670, 20),
+    (3610, 10),
+    (3400, 0),
+];
+
+// Battery voltage from the voltage at the ADC pin: (adc * numerator / denominator) + offset
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DividerCalibration {
+    pub numerator: u32,
+    pub denominator: u32,
+    // Corrects for the ADC's own error, measure against a multimeter
+    pub offset_millivolts: i32,
+}
+
+impl DividerCalibration {
+    // Two equal resistors on the reTerminal E1001 and E1002
+    pub const RETERMINAL_E100X: DividerCalibration = DividerCalibration {
+        numerator: 2,
+        denominator: 1,
+        offset_millivolts: 0,
+    };
+
+    pub fn battery_millivolts(&self, adc_millivolts: u16) -> u16 {
+        let scaled = (adc_millivolts as u32 * self.numerator / self.denominator) as i32;
+        (scaled + self.offset_millivolts).clamp(0, u16::MAX as i32) as u16
+    }
+}
+
+// Mean of the samples without the lowest and highest, which catch the odd noisy reading
+pub fn filtered_average(samples: &[u16]) -> Option<u16> {
+    let sum: u32 = samples.iter().map(|x| *x as u32).sum();
+    match samples.len() {
+        0 => None,
+        1 | 2 => Some((sum / samples.len() as u32) as u16),
+        count => {
+            let min = *samples.iter().min().unwrap() as u32;
+            let max = *samples.iter().max().unwrap() as u32;
+            Some(((sum - min - max) / (count as u32 - 2)) as u16)
+        }
+    }
+}
+
+// Linear between the points of the discharge curve
+pub fn percentage(millivolts: u16) -> u8 {
+    let (full, _) = DISCHARGE_CURVE[0];
+    if millivolts >= full {
+        return 100;
+    }
+    for pair in DISCHARGE_CURVE.windows(2) {
+        let [(high_mv, high_percent), (low_mv, low_percent)] = [pair[0], pair[1]];
+        if millivolts >= low_mv {
+            let span = (high_percent - low_percent) as u32;
+            let above = (millivolts - low_mv) as u32;
+            return low_percent + (above * span / (high_mv - low_mv) as u32) as u8;
+        }
+    }
+    0
+}
+
+pub fn is_critical(millivolts: u16) -> bool {
+    millivolts < CRITICAL_MILLIVOLTS
+}
+
+// An almost empty battery and a note to charge it, in the middle of the target
+pub fn draw_low_battery<D: DrawTarget<Color = Spectra6Color>>(
+    target: &mut D,
+) -> Result<(), D::Error> {
+    let center = target.bounding_box().center();
+    let body = Rectangle::with_center(center, Size::new(160, 80));
+    body.into_styled(PrimitiveStyle::with_stroke(Spectra6Color::Black, 6))
+        .draw(target)?;
+    let terminal = Rectangle::new(
+        body.top_left + Point::new(body.size.width as i32, 25),
+        Size::new(12, 30),
+    );
+    terminal
+        .into_styled(PrimitiveStyle::with_fill(Spectra6Color::Black))
+        .draw(target)?;
+    let charge = Rectangle::new(body.top_left + Point::new(12, 12), Size::new(20, 56));
+    charge
+        .into_styled(PrimitiveStyle::with_fill(Spectra6Color::Red))
+        .draw(target)?;
+    let style = MonoTextStyle::new(&FONT_10X20, Spectra6Color::Black);
+    Text::with_alignment(
+        "Battery low, please charge",
+        center + Point::new(0, 80),
+        style,
+        Alignment::Center,
+    )
+    .draw(target)?;
+    Ok(())
+}
@@ -19,14 +19,38 @@ use esp_hal::spi::Mode as SpiMode;
 use esp_hal::spi::master::Config as SpiConfig;
 use esp_hal::spi::master::Spi;
 
-use embedded_hal_bus::spi::ExclusiveDevice;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 
 use esp_backtrace as _;
 
 extern crate alloc;
 
-use reterminal_e100x::gdep073e01::Gdep073e01State;
-use reterminal_e100x::spectra6::Spectra6Color;
+use reterminal_e100x::analysis;
+use reterminal_e100x::battery::{self, DividerCalibration};
+use reterminal_e100x::board::SpiBusManager;
+use reterminal_e100x::bootpolicy::{self, BootAction, BootCause, BootPolicy};
+use reterminal_e100x::budget::DecodeBudget;
+use reterminal_e100x::capabilities::{self, EnabledFeatures};
+use reterminal_e100x::config::{self, Config, ConfigStore};
+use reterminal_e100x::error::{DecodeError, Error};
+use reterminal_e100x::framepush::crc32_update;
+use reterminal_e100x::gdep073e01::{self, Gdep073e01State};
+use reterminal_e100x::image::{AutoRotate, Matting, RgbImage, Rotation};
+use reterminal_e100x::parallel;
+#[cfg(feature = "jpeg")]
+use reterminal_e100x::pipeline::JpegDecoder;
+use reterminal_e100x::pipeline::{Decoder, QoiDecoder};
+use reterminal_e100x::pngstream::{self, PackedPngFrame, PngRows, PngStreamError};
+use reterminal_e100x::qrcode;
+use reterminal_e100x::rawframe::{self, RawFrameError};
+use reterminal_e100x::retry::{FetchFailure, RetryPolicy};
+use reterminal_e100x::rtcstate::{self, NextWake, RtcState};
+use reterminal_e100x::spectra6::{PaletteSet, Spectra6Color, Spectra6Framebuffer};
+
+use embedded_graphics::draw_target::DrawTargetExt;
+use embedded_graphics::geometry::{Dimensions, Point, Size};
+use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
+use embedded_graphics::primitives::Rectangle;
 
 use nalgebra::base::Vector6;
 use nalgebra::geometry::Point3;
@@ -37,58 +61,33 @@ use epd_dither::noise::interleaved_gradient_noise;
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
 esp_bootloader_esp_idf::esp_app_desc!();
 
-const PALETTE: [Point3<f32>; 6] = [
-    // Black
-    Point3::new(
-        0.22676264646610847 * 255.0,
-        0.0 * 255.0, //-0.0055970314385675474,
-        0.2597094644681131 * 255.0,
-    ),
-    // White
-    Point3::new(
-        0.7000095212021477 * 255.0,
-        0.8161663966432444 * 255.0,
-        0.7861384978213591 * 255.0,
-    ),
-    // NOTE: Next 4 need to be ordered cyclically!
-    // Blue
-    Point3::new(
-        0.2391826586300411 * 255.0,
-        0.1482584219935382 * 255.0,
-        0.596627604515917 * 255.0,
-    ),
-    // Green
-    Point3::new(
-        0.37804726446284537 * 255.0,
-        0.40898865257247025 * 255.0,
-        0.3388335156024263 * 255.0,
-    ),
-    // Red
-    Point3::new(
-        0.5903086439496402 * 255.0,
-        0.14710309178681208 * 255.0,
-        0.17200386219219121 * 255.0,
-    ),
-    // Yellow
-    Point3::new(
-        0.8417257856614314 * 255.0,
-        0.9126861145185275 * 255.0,
-        0.0 * 255.0, //-0.053016650312371474,
-    ),
-];
+// Set to None to always show content as-is
+const AUTO_ROTATE: Option<AutoRotate> = Some(AutoRotate {
+    rotation: Rotation::Clockwise90,
+    min_aspect_percent: 120,
+});
 
-const PALETTE_COLORS: [Spectra6Color; 6] = [
-    Spectra6Color::Black,
-    Spectra6Color::White,
-    Spectra6Color::Blue,
-    Spectra6Color::Green,
-    Spectra6Color::Red,
-    Spectra6Color::Yellow,
-];
+// Fill for the bars when the image doesn't have the same aspect ratio as the panel
+const MATTING: Matting = Matting::Solid(Rgb888::WHITE);
+// What transparent parts of images are composited onto
+const BACKGROUND: Rgb888 = Rgb888::WHITE;
 
-fn color_to_point(color: [u8; 4]) -> Point3<f32> {
-    let [r, g, b, _] = color.map(|c| c as f32);
-    Point3::new(r, g, b)
+// Battery voltage divider on the reTerminal E100x, see reterminal_e100x::battery
+const BATTERY_CALIBRATION: DividerCalibration = DividerCalibration::RETERMINAL_E100X;
+const BATTERY_SAMPLES: usize = 8;
+// Show a warning on the panel when the battery is critically low, instead of leaving the last image
+const LOW_BATTERY_WARNING: bool = true;
+// Wakes up less often with a critically low battery, until it's charged
+const LOW_BATTERY_SLEEP: Duration = Duration::from_secs(6 * 60 * 60);
+
+// What timer and button wake-ups and power-on do, see reterminal_e100x::bootpolicy
+const BOOT_POLICY: BootPolicy = BootPolicy::DEFAULT;
+
+// Most of the 8MB PSRAM, leaving room for the download itself and the dithered frame
+const DECODE_BUDGET: DecodeBudget = DecodeBudget::from_memory(6 * 1024 * 1024);
+
+fn color_to_point(color: Rgb888) -> Point3<f32> {
+    Point3::new(color.r() as f32, color.g() as f32, color.b() as f32)
 }
 
 // TODO: Move into epd-dither
@@ -102,67 +101,82 @@ fn pick_from_barycentric_weights(weights: Vector6<f32>, offset: f32) -> usize {
     index
 }
 
-struct Button<'t> {
-    input: Input<'t>,
-    inverted: bool,
+// Buttons that wake the device from deep sleep, all pulled up and pressed when low
+struct WakeButtons<'t> {
+    refresh: esp_hal::peripherals::GPIO3<'t>,
+    right: esp_hal::peripherals::GPIO4<'t>,
+    left: esp_hal::peripherals::GPIO5<'t>,
 }
 
-impl<'t> Button<'t> {
-    pub fn new(pin: impl InputPin + 't, config: InputConfig, inverted: bool) -> Self {
-        Button {
-            input: Input::new(pin, config),
-            inverted,
-        }
-    }
-    pub fn is_pressed(&self) -> bool {
-        if self.inverted {
-            self.input.is_low()
-        } else {
-            self.input.is_high()
-        }
-    }
-
-    pub fn is_released(&self) -> bool {
-        !self.is_pressed()
-    }
+fn is_pressed(pin: impl InputPin) -> bool {
+    Input::new(pin, InputConfig::default().with_pull(Pull::Up)).is_low()
+}
 
-    pub async fn wait_for(&mut self, pressed: bool) {
-        if self.inverted ^ pressed {
-            self.input.wait_for_high().await
-        } else {
-            self.input.wait_for_low().await
-        }
-    }
+// All white, to draw warnings, the splash screen or QR codes into
+fn blank_frame() -> Spectra6Framebuffer<alloc::vec::Vec<u8>> {
+    let white = (Spectra6Color::White as u8) << 4 | Spectra6Color::White as u8;
+    Spectra6Framebuffer::new(
+        alloc::vec![white; reterminal_e100x::framepush::FRAME_BYTES],
+        gdep073e01::WIDTH,
+        gdep073e01::HEIGHT,
+    )
+    .unwrap()
+}
 
-    pub async fn wait_for_pressed(&mut self) {
-        self.wait_for(true).await
-    }
+/*
+ * RTC GPIOs that woke the device from deep sleep, bit n for RTC GPIO n. The pin levels can't tell,
+ * a short press is over by the time they're read.
+ */
+fn rtc_io_wakeup_status() -> u32 {
+    // The status bits start at bit 10 of RTC_GPIO_STATUS
+    const STATUS_SHIFT: u32 = 10;
+    let rtc_io = esp_hal::peripherals::RTC_IO::regs();
+    let status = rtc_io.status().read().bits();
+    // Cleared, or it still shows after the next wake-up by the timer
+    // Safety: write-1-to-clear, only clears the bits that were just read
+    rtc_io.status_w1tc().write(|w| unsafe { w.bits(status) });
+    status >> STATUS_SHIFT
+}
 
-    pub async fn wait_for_released(&mut self) {
-        self.wait_for(false).await
+// The buttons in woken_by are (refresh, right, left), see rtc_io_wakeup_status
+fn boot_cause(
+    reset_reason: Option<esp_hal::rtc_cntl::SocResetReason>,
+    wake_reason: esp_hal::rtc_cntl::SleepSource,
+    woken_by: (bool, bool, bool),
+) -> BootCause {
+    use esp_hal::rtc_cntl::{SleepSource, SocResetReason};
+    match (reset_reason, wake_reason, woken_by) {
+        (_, SleepSource::Timer, _) => BootCause::Timer,
+        (Some(SocResetReason::CoreDeepSleep), _, (_, true, _)) => BootCause::NextButton,
+        (Some(SocResetReason::CoreDeepSleep), _, (_, _, true)) => BootCause::PreviousButton,
+        (Some(SocResetReason::CoreDeepSleep), _, (true, _, _)) => BootCause::RefreshButton,
+        (Some(SocResetReason::ChipPowerOn), _, _) => BootCause::PowerOn,
+        _ => BootCause::Other,
     }
 }
 
-#[embassy_executor::task(pool_size = 3)]
-async fn button_task(mut button: Button<'static>, button_name: &'static str) {
-    loop {
-        loop {
-            button.wait_for_pressed().await;
-            Timer::after(Duration::from_millis(10)).await; // debounce
-            if button.is_pressed() {
-                break;
-            }
-        }
-        println!("Button {0} pressed!", button_name);
-        loop {
-            button.wait_for_released().await;
-            Timer::after(Duration::from_millis(10)).await; // debounce
-            if button.is_released() {
-                break;
-            }
-        }
-        println!("Button {0} released!", button_name);
+// What the splash screen says below the device name
+fn splash_lines(settings: &Config, battery_percent: u8) -> alloc::vec::Vec<alloc::string::String> {
+    use alloc::format;
+    use alloc::string::String;
+    let mut lines = alloc::vec::Vec::new();
+    if settings.is_provisioned() {
+        lines.push(format!("Connecting to {}", settings.ssid()));
+    } else if cfg!(feature = "ble-provisioning") {
+        lines.push(String::from(
+            "Not set up yet, connect with a phone over Bluetooth",
+        ));
+        #[cfg(feature = "ble-provisioning")]
+        lines.push(format!("to \"{}\"", ble_provisioning::NAME));
+    } else if cfg!(feature = "captive-portal") {
+        lines.push(String::from("Not set up yet, join the Wi-Fi network"));
+        #[cfg(feature = "captive-portal")]
+        lines.push(format!("\"{}\" and follow the setup page", PORTAL_SSID));
+    } else {
+        lines.push(String::from("Not set up, no Wi-Fi network or image URL"));
     }
+    lines.push(format!("Battery about {}%", battery_percent));
+    lines
 }
 
 #[embassy_executor::task]
@@ -174,7 +188,8 @@ async fn blink_task(mut led: Output<'static>) {
     }
 }
 
-#[embassy_executor::task]
+// One for the station, and one for the setup portal's access point
+#[embassy_executor::task(pool_size = 2)]
 async fn net_task(mut runner: embassy_net::Runner<'static, esp_radio::wifi::WifiDevice<'static>>) {
     runner.run().await
 }
@@ -187,18 +202,26 @@ async fn wifi_task(mut controller: esp_radio::wifi::WifiController<'static>) {
     println!("Starting WiFi");
     controller.start_async().await.unwrap();
     println!("Wifi started");
+    let mut failures = 0;
     loop {
         println!("Connecting WiFi");
         match controller.connect_async().await {
             Ok(_) => {
                 println!("Connected");
+                failures = 0;
                 controller
                     .wait_for_event(esp_radio::wifi::WifiEvent::StaDisconnected)
                     .await;
                 println!("Disconnected");
             }
             Err(e) => {
-                println!("Failed to connect to wifi: {e:?}");
+                failures += 1;
+                println!("Failed to connect to wifi ({failures} times): {e:?}");
+                #[cfg(feature = "captive-portal")]
+                if failures >= PORTAL_AFTER_FAILURES {
+                    WIFI_GAVE_UP.signal(controller);
+                    return;
+                }
                 println!("Retry in 5sec");
                 Timer::after(Duration::from_secs(5)).await;
             }
@@ -206,49 +229,1466 @@ async fn wifi_task(mut controller: esp_radio::wifi::WifiController<'static>) {
     }
 }
 
+// Answers for reterminal-xxxx.local until the device goes to sleep, see reterminal_e100x::mdns
+#[cfg(feature = "mdns")]
+#[embassy_executor::task]
+async fn mdns_task(
+    stack: embassy_net::Stack<'static>,
+    responder: reterminal_e100x::mdns::Responder,
+) {
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+    use reterminal_e100x::mdns;
+
+    let group = core::net::Ipv4Addr::from(mdns::GROUP);
+    if let Err(e) = stack.join_multicast_group(group) {
+        println!("Can't join the mDNS group: {:?}", e);
+        return;
+    }
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1536];
+    let mut tx_buffer = [0u8; 1536];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(mdns::PORT).unwrap();
+    println!("mDNS name {}", responder.host_name());
+    if let Err(e) = socket
+        .send_to(&responder.announcement(), (group, mdns::PORT))
+        .await
+    {
+        println!("mDNS announcement failed: {:?}", e);
+    }
+    let mut packet = [0u8; 1536];
+    loop {
+        let Ok((length, _)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        if let Some(reply) = responder.reply(&packet[..length]) {
+            if let Err(e) = socket.send_to(&reply, (group, mdns::PORT)).await {
+                println!("mDNS reply failed: {:?}", e);
+            }
+        }
+    }
+}
+
+// Survives deep sleep, but not a reset, see reterminal_e100x::rtcstate
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut RTC_STATE: [u8; rtcstate::STORED_SIZE] = [0; rtcstate::STORED_SIZE];
+
 static NETWORK_RESOURCES: static_cell::ConstStaticCell<embassy_net::StackResources<4>> =
     static_cell::ConstStaticCell::new(embassy_net::StackResources::new());
 
+// Failed connection attempts in a row before handing the radio to the setup portal
+#[cfg(feature = "captive-portal")]
+const PORTAL_AFTER_FAILURES: u32 = 5;
+
+#[cfg(feature = "captive-portal")]
+static WIFI_GAVE_UP: embassy_sync::signal::Signal<
+    CriticalSectionRawMutex,
+    esp_radio::wifi::WifiController<'static>,
+> = embassy_sync::signal::Signal::new();
+
 static RADIO_CONTROLLER: static_cell::StaticCell<esp_radio::Controller> =
     static_cell::StaticCell::new();
 
+/*
+ * Settings the firmware was built with, for devices that weren't provisioned yet. Whatever is saved
+ * in flash (see reterminal_e100x::config) takes precedence.
+ */
+fn built_in_config() -> Config {
+    let mut config = Config::default();
+    // Values that don't fit are left out, the device then asks to be set up instead of crashing
+    let check = |name: &str, result: Result<(), config::InvalidSetting>| {
+        if let Err(e) = result {
+            println!("Ignoring built-in {}: {}", name, Error::from(e));
+        }
+    };
+    if let Some(ssid) = option_env!("WIFI_SSID") {
+        check("WIFI_SSID", config.set_ssid(ssid));
+    }
+    if let Some(password) = option_env!("WIFI_PASSWORD") {
+        check("WIFI_PASSWORD", config.set_password(password));
+    }
+    // Separated by spaces for a slideshow
+    if let Some(urls) = option_env!("WIFI_URL") {
+        let urls = urls.split_whitespace();
+        check("WIFI_URL", config.set_image_urls(urls));
+    }
+    config
+}
+
+/*
+ * Start of the settings, in the "nvs" partition of the partition table that was flashed, so a
+ * custom table doesn't end up with settings written over the app. DEFAULT_OFFSET if there's no
+ * table, or no nvs partition with room for the two sectors.
+ */
+fn settings_offset(flash: &mut esp_storage::FlashStorage) -> u32 {
+    use embedded_storage::nor_flash::NorFlash;
+    use esp_bootloader_esp_idf::partitions::{self, DataPartitionSubType, PartitionType};
+
+    let mut buffer = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let table = match partitions::read_partition_table(flash, &mut buffer) {
+        Ok(table) => table,
+        Err(e) => {
+            println!("Can't read the partition table: {:?}", e);
+            return config::DEFAULT_OFFSET;
+        }
+    };
+    let needed = 2 * esp_storage::FlashStorage::ERASE_SIZE as u32;
+    match table.find_partition(PartitionType::Data(DataPartitionSubType::Nvs)) {
+        Ok(Some(nvs)) if nvs.len() >= needed => nvs.offset(),
+        Ok(Some(_)) => {
+            println!("The nvs partition is too small for settings");
+            config::DEFAULT_OFFSET
+        }
+        Ok(None) => {
+            println!("No nvs partition for settings");
+            config::DEFAULT_OFFSET
+        }
+        Err(e) => {
+            println!("Can't read the partition table: {:?}", e);
+            config::DEFAULT_OFFSET
+        }
+    }
+}
+
+// PEM bundle of root certificates to trust for https:// URLs. Without it the server isn't verified.
+const HTTPS_CA_CERTS: Option<&str> = option_env!("HTTPS_CA_CERTS");
+
+// Attempts and backoff for fetching the image, see reterminal_e100x::retry
+const FETCH_RETRY: RetryPolicy =
+    RetryPolicy::new(5, Duration::from_secs(1), Duration::from_secs(30));
+
 use embedded_io_async::BufRead;
-async fn get_image_data<'t>(stack: embassy_net::Stack<'t>) -> alloc::vec::Vec<u8> {
+use reqwless::client::{HttpClient, TlsConfig, TlsVerify};
+use reqwless::request::RequestBuilder;
+use reterminal_e100x::schedule;
+use reterminal_e100x::tls;
+// Response body, and when the server wants the next refresh (see reterminal_e100x::schedule)
+struct ImageData {
+    // None if it went to the PngSink instead
+    body: Option<alloc::vec::Vec<u8>>,
+    // CRC-32 of the body, whether it was kept or not
+    hash: u32,
+    next_refresh: Option<Duration>,
+}
+
+/*
+ * Where PNGs that fit the panel as they are go while they download, a row at a time, so the
+ * compressed file is never in RAM as a whole. Anything else ends up in ImageData::body.
+ */
+trait PngSink {
+    // From the first bytes of the body
+    fn accepts(&self, head: &[u8]) -> bool;
+
+    async fn show<R: embedded_io_async::Read<Error = reqwless::Error>>(
+        &mut self,
+        rows: PngRows<R>,
+    ) -> Result<(), FetchFailure>;
+}
+
+// Nothing is streamed, every body is kept
+impl PngSink for () {
+    fn accepts(&self, _head: &[u8]) -> bool {
+        false
+    }
+
+    async fn show<R: embedded_io_async::Read<Error = reqwless::Error>>(
+        &mut self,
+        _rows: PngRows<R>,
+    ) -> Result<(), FetchFailure> {
+        Err(FetchFailure::Permanent)
+    }
+}
+
+// Passes reads through, adding what was read to the CRC-32 in hash
+struct HashingReader<'h, R> {
+    source: R,
+    hash: &'h mut u32,
+}
+
+impl<R: embedded_io_async::ErrorType> embedded_io_async::ErrorType for HashingReader<'_, R> {
+    type Error = R::Error;
+}
+
+impl<R: embedded_io_async::Read> embedded_io_async::Read for HashingReader<'_, R> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let count = self.source.read(buf).await?;
+        *self.hash = crc32_update(*self.hash, &buf[..count]);
+        Ok(count)
+    }
+}
+
+async fn get_image_data<'t>(
+    stack: embassy_net::Stack<'t>,
+    url: &str,
+    rng: &esp_hal::rng::Rng,
+    sink: &mut impl PngSink,
+) -> Result<ImageData, FetchFailure> {
     // DNS Client
     let dns = embassy_net::dns::DnsSocket::new(stack);
     // TCP state
     let tcp_state = embassy_net::tcp::client::TcpClientState::<1, 4096, 4096>::new();
     let tcp = embassy_net::tcp::client::TcpClient::new(stack, &tcp_state);
 
-    println!("Attempting to do HTTP request");
-    let mut http_client = reqwless::client::HttpClient::new(&tcp, &dns);
-    const URL: &str = env!("WIFI_URL");
+    if !tls::is_https(url) {
+        let mut attempt = 0;
+        loop {
+            println!("Attempting to do HTTP request");
+            match request_image_data(&mut HttpClient::new(&tcp, &dns), url, sink).await {
+                Ok(body) => return Ok(body),
+                Err(failure) => wait_before_retry(attempt, failure, rng).await?,
+            }
+            attempt += 1;
+        }
+    }
+
+    // In PSRAM, too large for the stack
+    let mut tls_rx_buf = alloc::vec![0u8; tls::TLS_RECORD_BUFFER_SIZE];
+    let mut tls_tx_buf = alloc::vec![0u8; tls::TLS_RECORD_BUFFER_SIZE];
+    let roots: alloc::vec::Vec<alloc::vec::Vec<u8>> = match HTTPS_CA_CERTS
+        .map(tls::pem_certificates)
+        .into_iter()
+        .flatten()
+        .collect::<Result<_, _>>()
+    {
+        Ok(roots) => roots,
+        Err(e) => {
+            // Not verifying at all instead would defeat the point of setting them
+            println!("Can't use HTTPS_CA_CERTS: {}", Error::from(e));
+            return Err(FetchFailure::Permanent);
+        }
+    };
+    let mut attempt = 0;
+    loop {
+        let failure = if roots.is_empty() {
+            println!("Attempting to do HTTPS request, without verifying the server");
+            let config = TlsConfig::new(
+                tls_seed(rng),
+                &mut tls_rx_buf,
+                &mut tls_tx_buf,
+                TlsVerify::None,
+            );
+            let mut client = HttpClient::new_with_tls(&tcp, &dns, config);
+            match request_image_data(&mut client, url, sink).await {
+                Ok(body) => return Ok(body),
+                Err(failure) => failure,
+            }
+        } else {
+            // Only one root can be given to the TLS client, so try them in turn until one verifies
+            let mut failure = FetchFailure::Permanent;
+            for (index, ca) in roots.iter().enumerate() {
+                println!("Attempting to do HTTPS request, root certificate {}", index);
+                let verify = TlsVerify::Certificate { ca: ca.as_slice() };
+                let config =
+                    TlsConfig::new(tls_seed(rng), &mut tls_rx_buf, &mut tls_tx_buf, verify);
+                let mut client = HttpClient::new_with_tls(&tcp, &dns, config);
+                match request_image_data(&mut client, url, sink).await {
+                    Ok(body) => return Ok(body),
+                    Err(FetchFailure::Permanent) => {
+                        println!("Root certificate {} didn't verify the server", index)
+                    }
+                    // Network trouble, the next root won't do better
+                    Err(e) => {
+                        failure = e;
+                        break;
+                    }
+                }
+            }
+            failure
+        };
+        wait_before_retry(attempt, failure, rng).await?;
+        attempt += 1;
+    }
+}
+
+// Every TLS connection needs its own, reusing one makes the handshakes predictable
+fn tls_seed(rng: &esp_hal::rng::Rng) -> u64 {
+    (rng.random() as u64) << 32 | rng.random() as u64
+}
+
+// Waits before the next attempt, or gives up with failure
+async fn wait_before_retry(
+    attempt: u32,
+    failure: FetchFailure,
+    rng: &esp_hal::rng::Rng,
+) -> Result<(), FetchFailure> {
+    println!("Fetching image failed: {}", failure);
+    let delay = FETCH_RETRY
+        .backoff(attempt, failure, rng.random())
+        .ok_or(failure)?;
+    println!("Retrying in {} ms", delay.as_millis());
+    Timer::after(delay).await;
+    Ok(())
+}
+
+// Whether it's worth retrying depends on how far the request got
+fn fetch_failure(error: reqwless::Error, connecting: bool) -> FetchFailure {
+    match error {
+        reqwless::Error::Dns => FetchFailure::Dns,
+        reqwless::Error::Network(_) if connecting => FetchFailure::Connect,
+        reqwless::Error::Network(_) => FetchFailure::Transfer,
+        _ => FetchFailure::Permanent,
+    }
+}
+
+// The same for a PNG that broke off while streaming
+fn png_failure(error: &PngStreamError<reqwless::Error>) -> FetchFailure {
+    match error {
+        PngStreamError::Read(reqwless::Error::Network(_)) | PngStreamError::UnexpectedEof => {
+            FetchFailure::Transfer
+        }
+        _ => FetchFailure::Permanent,
+    }
+}
+
+async fn request_image_data<T, D>(
+    http_client: &mut HttpClient<'_, T, D>,
+    url: &str,
+    sink: &mut impl PngSink,
+) -> Result<ImageData, FetchFailure>
+where
+    T: embedded_nal_async::TcpConnect,
+    D: embedded_nal_async::Dns,
+{
+    let budget_headers = DECODE_BUDGET.request_headers();
+    let [budget_header] = budget_headers.as_headers();
+    let features_header = capabilities::features_header();
+    let headers = [budget_header, features_header.as_header()];
     let mut request = http_client
-        .request(reqwless::request::Method::GET, URL)
+        .request(reqwless::request::Method::GET, url)
         .await
-        .unwrap();
+        .map_err(|e| fetch_failure(e, true))?
+        .headers(&headers);
     println!("HTTP request done?");
     let mut http_rx_buf = [0u8; 4096];
-    let mut response = request
+    let response = request
         .send(&mut http_rx_buf)
         .await
-        .unwrap()
-        .body()
-        .reader();
-    println!("Reading body");
+        .map_err(|e| fetch_failure(e, false))?;
+    if !response.status.is_successful() {
+        return Err(FetchFailure::Status(response.status.0));
+    }
+    let next_refresh = schedule::next_refresh(response.headers());
+    let mut response = response.body().reader();
+    let mut hash = 0;
+    let head = response
+        .fill_buf()
+        .await
+        .map_err(|e| fetch_failure(e, false))?;
+    let streamed = sink.accepts(head);
+    if streamed {
+        println!("Streaming body");
+        let source = HashingReader {
+            source: &mut response,
+            hash: &mut hash,
+        };
+        let rows = PngRows::open(source, BACKGROUND, gdep073e01::WIDTH)
+            .await
+            .map_err(|e| png_failure(&e))?;
+        sink.show(rows).await?;
+    } else {
+        println!("Reading body");
+    }
 
+    // Whatever comes after the image data is only hashed
     let mut body = alloc::vec::Vec::new();
     loop {
-        let chunk = response.fill_buf().await.unwrap();
+        let chunk = response
+            .fill_buf()
+            .await
+            .map_err(|e| fetch_failure(e, false))?;
         if chunk.is_empty() {
             break;
         }
-        body.extend_from_slice(chunk);
+        hash = crc32_update(hash, chunk);
+        if !streamed {
+            body.extend_from_slice(chunk);
+        }
         let len = chunk.len();
         response.consume(len);
     }
     println!("Got body");
-    body
+    Ok(ImageData {
+        body: (!streamed).then_some(body),
+        hash,
+        next_refresh,
+    })
+}
+
+// How much stack below main's frame to watermark, has to fit in what's left of the stack
+#[cfg(feature = "stack-usage")]
+const STACK_PAINT_DEPTH: usize = 32 * 1024;
+
+#[cfg(feature = "stack-usage")]
+fn log_stack_usage(what: &str, watermark: &reterminal_e100x::stackusage::StackWatermark) {
+    if watermark.is_exhausted() {
+        println!(
+            "Stack usage of {}: more than {} bytes",
+            what,
+            watermark.used()
+        );
+    } else {
+        println!("Stack usage of {}: {} bytes", what, watermark.used());
+    }
+}
+
+fn decode_image(image_data: &[u8]) -> Result<RgbImage, Error> {
+    DECODE_BUDGET.check_image(image_data)?;
+    #[cfg(feature = "jpeg")]
+    if JpegDecoder::default().accepts(image_data) {
+        println!("Decode JPEG");
+        let decoder = JpegDecoder {
+            background: BACKGROUND,
+        };
+        return Ok(decoder.decode(image_data)?);
+    }
+    if QoiDecoder::default().accepts(image_data) {
+        println!("Decode QOI");
+        let decoder = QoiDecoder {
+            background: BACKGROUND,
+        };
+        return Ok(decoder.decode(image_data)?);
+    }
+    println!("Decode PNG");
+    let (header, data) = png_decoder::decode(image_data).map_err(|_| DecodeError::Failed)?;
+    println!("Header: {:?}", header);
+    Ok(RgbImage::from_rgba_over(
+        header.width as usize,
+        header.height as usize,
+        data,
+        BACKGROUND,
+    ))
+}
+
+// Rotated if that fits the panel better, and letterboxed, WIDTH x HEIGHT pixels
+fn panel_pixels(image: &RgbImage) -> impl Iterator<Item = Rgb888> + '_ {
+    image.letterboxed_pixels(
+        panel_rotation(image),
+        gdep073e01::WIDTH,
+        gdep073e01::HEIGHT,
+        MATTING,
+    )
+}
+
+fn panel_rotation(image: &RgbImage) -> Rotation {
+    let rotation = AUTO_ROTATE
+        .map(|auto_rotate| {
+            auto_rotate.rotation_for(
+                image.width,
+                image.height,
+                gdep073e01::WIDTH,
+                gdep073e01::HEIGHT,
+            )
+        })
+        .unwrap_or(Rotation::None);
+    println!("Rotation: {:?}", rotation);
+    rotation
+}
+
+fn log_frame_stats(frame_stats: &analysis::FrameStats) {
+    println!(
+        "Frame: {} saturated, {} stress (per mille)",
+        frame_stats.saturated_permille(),
+        frame_stats.stress_permille()
+    );
+}
+
+// Enough for the dither of one band, which doesn't go deep
+const APP_CORE_STACK_SIZE: usize = 8 * 1024;
+static APP_CORE_STACK: static_cell::ConstStaticCell<esp_hal::system::Stack<APP_CORE_STACK_SIZE>> =
+    static_cell::ConstStaticCell::new(esp_hal::system::Stack::new());
+
+/*
+ * Runs other on the second core and this one here, for parallel::packed_dual, and returns once
+ * both are done. The second core is parked again after, and only started once per boot.
+ */
+fn run_on_both_cores(
+    cpu_control: &mut esp_hal::system::CpuControl,
+    other: &mut (dyn FnMut() + Send),
+    this: &mut dyn FnMut(),
+) {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    static OTHER_DONE: AtomicBool = AtomicBool::new(false);
+    OTHER_DONE.store(false, Ordering::Release);
+    // Safety: other is only used until OTHER_DONE is set, and this doesn't return before that
+    let other: &'static mut (dyn FnMut() + Send) = unsafe { core::mem::transmute(other) };
+    let _guard = cpu_control
+        .start_app_core(APP_CORE_STACK.take(), move || {
+            other();
+            OTHER_DONE.store(true, Ordering::Release);
+        })
+        .unwrap();
+    this();
+    while !OTHER_DONE.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+}
+
+/*
+ * A frame drawn on the device, like the splash screen or the low battery warning, on the panel from
+ * whatever state it's in. Ends with the panel reset, its lowest power state. An error is returned
+ * with the driver to reset it.
+ */
+async fn show_frame<S, SPI, BUSY, DC, RST, DELAY>(
+    epd: Gdep073e01State<S, SPI, BUSY, DC, RST, DELAY>,
+    spi: &mut SPI,
+    delay: &mut DELAY,
+    frame: &[u8],
+) -> Result<
+    Gdep073e01State<gdep073e01::StateReset, SPI, BUSY, DC, RST, DELAY>,
+    gdep073e01::Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY, Error>,
+>
+where
+    SPI: embedded_hal_async::spi::SpiDevice,
+    BUSY: embedded_hal::digital::InputPin + embedded_hal_async::digital::Wait,
+    DC: embedded_hal::digital::OutputPin,
+    RST: embedded_hal::digital::OutputPin,
+    DELAY: embedded_hal_async::delay::DelayNs,
+{
+    let epd = epd
+        .reset(delay)
+        .await
+        .map_err(|e| e.map_error(Error::from))?;
+    let epd = epd.init(spi).await.map_err(|e| e.map_error(Error::from))?;
+    let epd = epd
+        .power_on(spi)
+        .await
+        .map_err(|e| e.map_error(Error::from))?;
+    let epd = epd
+        .update_frame_raw(spi, frame.iter().copied())
+        .await
+        .map_err(|e| e.map_error(Error::from))?;
+    let epd = epd
+        .display_frame(spi)
+        .await
+        .map_err(|e| e.map_error(Error::from))?;
+    let epd = epd
+        .power_off(spi)
+        .await
+        .map_err(|e| e.map_error(Error::from))?;
+    epd.reset(delay).await.map_err(|e| e.map_error(Error::from))
+}
+
+// Logs a panel error and resets the panel, before going to sleep
+async fn reset_failed_panel<SPI, BUSY, DC, RST, DELAY>(
+    failure: gdep073e01::Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY, Error>,
+    delay: &mut DELAY,
+) where
+    SPI: embedded_hal_async::spi::SpiDevice,
+    BUSY: embedded_hal::digital::InputPin + embedded_hal_async::digital::Wait,
+    DC: embedded_hal::digital::OutputPin,
+    RST: embedded_hal::digital::OutputPin,
+    DELAY: embedded_hal_async::delay::DelayNs,
+{
+    println!("Panel failed: {}", failure.error());
+    if let Err(e) = failure.into_driver().reset(delay).await {
+        println!("Can't reset the panel: {}", Error::from(e.into_error()));
+    }
+}
+
+// The panel while the image downloads, see PanelSink
+enum StreamPanel<SPI, BUSY, DC, RST, DELAY> {
+    Reset(Gdep073e01State<gdep073e01::StateReset, SPI, BUSY, DC, RST, DELAY>),
+    // With the last streamed image in its frame memory, not displayed yet
+    Loaded(Gdep073e01State<gdep073e01::StatePowerOn, SPI, BUSY, DC, RST, DELAY>),
+    // After a panel error
+    Failed(Gdep073e01State<gdep073e01::StateUnknown, SPI, BUSY, DC, RST, DELAY>),
+}
+
+/*
+ * Dithers PNGs that need no scaling or rotating straight into the panel's frame memory while they
+ * download. The panel is only powered on once one comes in.
+ */
+struct PanelSink<'s, SPI, BUSY, DC, RST, DELAY, Q> {
+    // Only None while the panel is in use
+    panel: Option<StreamPanel<SPI, BUSY, DC, RST, DELAY>>,
+    spi: &'s mut SPI,
+    delay: DELAY,
+    quantize: &'s Q,
+    // Of the last streamed image
+    frame_stats: analysis::FrameStats,
+}
+
+impl<SPI, BUSY, DC, RST, DELAY, Q> PanelSink<'_, SPI, BUSY, DC, RST, DELAY, Q>
+where
+    SPI: embedded_hal_async::spi::SpiDevice,
+    BUSY: embedded_hal::digital::InputPin + embedded_hal_async::digital::Wait,
+    DC: embedded_hal::digital::OutputPin,
+    RST: embedded_hal::digital::OutputPin,
+    DELAY: embedded_hal_async::delay::DelayNs,
+    Q: Fn(usize, usize, Rgb888) -> Spectra6Color,
+{
+    // Ready for a frame, with whatever was streamed so far in its frame memory
+    async fn power_on(
+        &mut self,
+    ) -> Result<
+        Gdep073e01State<gdep073e01::StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+        gdep073e01::Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY>,
+    > {
+        let epd = match self.panel.take().expect("panel in use") {
+            StreamPanel::Loaded(epd) => return Ok(epd),
+            StreamPanel::Reset(epd) => epd,
+            StreamPanel::Failed(epd) => epd.reset(&mut self.delay).await?,
+        };
+        println!("Init");
+        let epd = epd.init(self.spi).await?;
+        println!("Power on");
+        epd.power_on(self.spi).await
+    }
+
+    async fn into_powered_on(
+        mut self,
+    ) -> Result<
+        Gdep073e01State<gdep073e01::StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+        gdep073e01::Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY>,
+    > {
+        self.power_on().await
+    }
+
+    // Before deep sleep without displaying anything, the panel shouldn't stay powered on
+    async fn finish(mut self) {
+        if let Some(StreamPanel::Loaded(epd)) = self.panel.take()
+            && let Err(e) = epd.power_off(self.spi).await
+        {
+            println!("Can't power off the panel: {:?}", e);
+        }
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY, Q> PngSink for PanelSink<'_, SPI, BUSY, DC, RST, DELAY, Q>
+where
+    SPI: embedded_hal_async::spi::SpiDevice,
+    BUSY: embedded_hal::digital::InputPin + embedded_hal_async::digital::Wait,
+    DC: embedded_hal::digital::OutputPin,
+    RST: embedded_hal::digital::OutputPin,
+    DELAY: embedded_hal_async::delay::DelayNs,
+    Q: Fn(usize, usize, Rgb888) -> Spectra6Color,
+{
+    // Only what panel_pixels would show unscaled, unrotated and with plain bars
+    fn accepts(&self, head: &[u8]) -> bool {
+        let Some((width, height)) = pngstream::streamable_size(head) else {
+            return false;
+        };
+        let (panel_width, panel_height) = (gdep073e01::WIDTH, gdep073e01::HEIGHT);
+        let rotation = AUTO_ROTATE
+            .map(|auto_rotate| auto_rotate.rotation_for(width, height, panel_width, panel_height))
+            .unwrap_or(Rotation::None);
+        let unscaled = (width == panel_width && height <= panel_height)
+            || (width <= panel_width && height == panel_height);
+        unscaled && rotation == Rotation::None && matches!(MATTING, Matting::Solid(_))
+    }
+
+    async fn show<R: embedded_io_async::Read<Error = reqwless::Error>>(
+        &mut self,
+        rows: PngRows<R>,
+    ) -> Result<(), FetchFailure> {
+        let fill = match MATTING {
+            Matting::Solid(color) => color,
+            _ => BACKGROUND,
+        };
+        let epd = match self.power_on().await {
+            Ok(epd) => epd,
+            Err(e) => {
+                println!("Panel failed: {:?}", e);
+                self.panel = Some(StreamPanel::Failed(e.into_driver()));
+                return Err(FetchFailure::Permanent);
+            }
+        };
+        self.frame_stats = analysis::FrameStats::default();
+        let frame_stats = &mut self.frame_stats;
+        let quantize = self.quantize;
+        let frame = PackedPngFrame::new(
+            rows,
+            gdep073e01::WIDTH,
+            gdep073e01::HEIGHT,
+            fill,
+            |x, y, color| {
+                let color = quantize(x, y, color);
+                frame_stats.add(color);
+                color
+            },
+        );
+        let Some(mut frame) = frame else {
+            self.panel = Some(StreamPanel::Loaded(epd));
+            return Err(FetchFailure::Permanent);
+        };
+        // Not reserving the bus, the transfer waits for the download and would hold it that long
+        println!("Decode, dither and update frame");
+        match epd.update_frame_from_reader(self.spi, &mut frame).await {
+            Ok(epd) => self.panel = Some(StreamPanel::Loaded(epd)),
+            Err(e) => {
+                println!("Panel failed: {:?}", e);
+                self.panel = Some(StreamPanel::Failed(e.into_driver()));
+                return Err(FetchFailure::Permanent);
+            }
+        }
+        // The rest of the frame is fill, which the next attempt replaces
+        match frame.error() {
+            Some(e) => {
+                println!("Image ended early: {:?}", e);
+                Err(png_failure(e))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "frame-push")]
+const FRAME_PUSH_PORT: u16 = 9100;
+#[cfg(feature = "frame-push")]
+const FRAME_PUSH_WINDOW: Duration = Duration::from_secs(60);
+
+/*
+ * Accepts pushed frames (see reterminal_e100x::framepush) for a while and displays them. An error
+ * from the panel ends the window, with the driver to reset it.
+ */
+#[cfg(feature = "frame-push")]
+async fn frame_push_window<SPI, BUSY, DC, RST, DELAY>(
+    stack: embassy_net::Stack<'_>,
+    mut epd: Gdep073e01State<gdep073e01::StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+    spi: &mut SPI,
+    rtc_state: &mut RtcState,
+) -> Result<
+    Gdep073e01State<gdep073e01::StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+    gdep073e01::Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY, Error>,
+>
+where
+    SPI: embedded_hal_async::spi::SpiDevice,
+    BUSY: embedded_hal::digital::InputPin + embedded_hal_async::digital::Wait,
+    DC: embedded_hal::digital::OutputPin,
+    RST: embedded_hal::digital::OutputPin,
+    DELAY: embedded_hal_async::delay::DelayNs,
+{
+    let mut rx_buffer = [0u8; 4096];
+    let mut tx_buffer = [0u8; 64];
+    let mut frame = alloc::vec::Vec::new();
+    let deadline = embassy_time::Instant::now() + FRAME_PUSH_WINDOW;
+    println!("Listening for pushed frames on port {}", FRAME_PUSH_PORT);
+    loop {
+        let mut socket = embassy_net::tcp::TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        match embassy_time::with_deadline(deadline, socket.accept(FRAME_PUSH_PORT)).await {
+            Err(_) => break,
+            Ok(Err(e)) => {
+                println!("Accept failed: {:?}", e);
+                continue;
+            }
+            Ok(Ok(())) => {}
+        }
+        let received = reterminal_e100x::framepush::receive_frame(&mut socket, &mut frame).await;
+        socket.close();
+        let _ = socket.flush().await;
+        match received {
+            Ok(()) => {
+                println!("Displaying pushed frame");
+                epd = epd
+                    .update_frame_from_reader(spi, frame.as_slice())
+                    .await
+                    .map_err(|e| e.map_error(Error::from))?;
+                epd = epd
+                    .display_frame(spi)
+                    .await
+                    .map_err(|e| e.map_error(Error::from))?;
+                rtc_state.forget_image();
+            }
+            Err(e) => println!("Frame push failed: {:?}", e),
+        }
+    }
+    Ok(epd)
+}
+
+#[cfg(feature = "mqtt")]
+const MQTT_BROKER: &str = env!("MQTT_BROKER");
+#[cfg(feature = "mqtt")]
+const MQTT_TOPIC: &str = env!("MQTT_TOPIC");
+// Has to be unique on the broker, it drops the older connection otherwise
+#[cfg(feature = "mqtt")]
+const MQTT_CLIENT_ID: &str = match option_env!("MQTT_CLIENT_ID") {
+    Some(client_id) => client_id,
+    None => "reterminal_e100x",
+};
+#[cfg(feature = "mqtt")]
+const MQTT_USERNAME: Option<&str> = option_env!("MQTT_USERNAME");
+#[cfg(feature = "mqtt")]
+const MQTT_PASSWORD: Option<&str> = option_env!("MQTT_PASSWORD");
+#[cfg(feature = "mqtt")]
+const MQTT_WINDOW: Duration = Duration::from_secs(300);
+// A raw frame with one pixel per byte, and no RLE
+#[cfg(feature = "mqtt")]
+const MQTT_MAX_PAYLOAD: usize = rawframe::HEADER_SIZE + gdep073e01::WIDTH * gdep073e01::HEIGHT;
+
+// Waits for refresh requests over MQTT (see reterminal_e100x::mqtt) for a while and shows them
+#[cfg(feature = "mqtt")]
+async fn mqtt_window<SPI, BUSY, DC, RST, DELAY>(
+    stack: embassy_net::Stack<'_>,
+    mut epd: Gdep073e01State<gdep073e01::StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+    spi: &mut SPI,
+    rng: &esp_hal::rng::Rng,
+    dither: &impl Fn(usize, usize, Rgb888) -> Spectra6Color,
+    rtc_state: &mut RtcState,
+) -> Result<
+    Gdep073e01State<gdep073e01::StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+    gdep073e01::Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY, Error>,
+>
+where
+    SPI: embedded_hal_async::spi::SpiDevice,
+    BUSY: embedded_hal::digital::InputPin + embedded_hal_async::digital::Wait,
+    DC: embedded_hal::digital::OutputPin,
+    RST: embedded_hal::digital::OutputPin,
+    DELAY: embedded_hal_async::delay::DelayNs,
+{
+    use reterminal_e100x::mqtt::{self, MqttClient, MqttError, PushMessage};
+
+    let deadline = embassy_time::Instant::now() + MQTT_WINDOW;
+    let address = match stack
+        .dns_query(MQTT_BROKER, embassy_net::dns::DnsQueryType::A)
+        .await
+    {
+        Ok(addresses) if !addresses.is_empty() => addresses[0],
+        other => {
+            println!("Can't resolve MQTT broker: {:?}", other);
+            return Ok(epd);
+        }
+    };
+    let mut rx_buffer = [0u8; 4096];
+    let mut tx_buffer = [0u8; 256];
+    let mut socket = embassy_net::tcp::TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    if let Err(e) = socket.connect((address, mqtt::DEFAULT_PORT)).await {
+        println!("Can't connect to MQTT broker: {:?}", e);
+        return Ok(epd);
+    }
+    // Longer than the window, so there's no need to ping
+    let keep_alive = (MQTT_WINDOW.as_secs() + 60) as u16;
+    let credentials = MQTT_USERNAME.zip(MQTT_PASSWORD);
+    let mut client =
+        match MqttClient::connect(&mut socket, MQTT_CLIENT_ID, credentials, keep_alive).await {
+            Ok(client) => client,
+            Err(e) => {
+                println!("MQTT connect failed: {:?}", e);
+                return Ok(epd);
+            }
+        };
+    if let Err(e) = client.subscribe(MQTT_TOPIC).await {
+        println!("MQTT subscribe failed: {:?}", e);
+        return Ok(epd);
+    }
+    println!("Waiting for messages on {}", MQTT_TOPIC);
+    let mut topic = alloc::string::String::new();
+    let mut payload = alloc::vec::Vec::new();
+    loop {
+        let received = embassy_time::with_deadline(
+            deadline,
+            client.next_publish(&mut topic, &mut payload, MQTT_MAX_PAYLOAD),
+        )
+        .await;
+        match received {
+            // Stopping halfway through a packet is fine, the connection is closed anyway
+            Err(_) => break,
+            Ok(Ok(())) => {}
+            Ok(Err(MqttError::TooLarge(length))) => {
+                println!("Ignoring {} byte message", length);
+                continue;
+            }
+            Ok(Err(e)) => {
+                println!("MQTT failed: {:?}", e);
+                break;
+            }
+        }
+        println!("Message on {}", topic);
+        let shown = match mqtt::parse_message(payload.as_slice()) {
+            Some(PushMessage::Frame(frame)) => {
+                let shown;
+                (epd, shown) = show_data(epd, spi, frame, dither).await?;
+                shown.is_ok()
+            }
+            Some(PushMessage::QrCode(payload)) => {
+                let mut frame = blank_frame();
+                let area = frame.bounding_box();
+                match qrcode::draw_qr_code(&mut frame, payload, area) {
+                    Ok(_) => {
+                        println!("Update QR code");
+                        let bytes = frame.as_bytes().iter().copied();
+                        epd = epd
+                            .update_frame_raw(spi, bytes)
+                            .await
+                            .map_err(|e| e.map_error(Error::from))?;
+                        epd = epd
+                            .display_frame(spi)
+                            .await
+                            .map_err(|e| e.map_error(Error::from))?;
+                        true
+                    }
+                    Err(e) => {
+                        println!("Not showing QR code: {:?}", e);
+                        false
+                    }
+                }
+            }
+            Some(PushMessage::Url(url)) => {
+                match get_image_data(stack, url, rng, &mut ()).await {
+                    Ok(ImageData {
+                        body: Some(body), ..
+                    }) => {
+                        let shown;
+                        (epd, shown) = show_data(epd, spi, body.as_slice(), dither).await?;
+                        shown.is_ok()
+                    }
+                    // Only a sink takes the body
+                    Ok(_) => false,
+                    Err(e) => {
+                        println!("Not fetching image: {}", e);
+                        false
+                    }
+                }
+            }
+            None => {
+                println!("Not a URL or a frame, ignoring");
+                false
+            }
+        };
+        if shown {
+            rtc_state.forget_image();
+        }
+    }
+    let _ = client.disconnect().await;
+    socket.close();
+    let _ = socket.flush().await;
+    Ok(epd)
+}
+
+#[cfg(feature = "http-upload")]
+const UPLOAD_WINDOW: Duration = Duration::from_secs(300);
+// A full size PNG is well below this, PSRAM has room for it next to the decoded image
+#[cfg(feature = "http-upload")]
+const UPLOAD_MAX_BODY: usize = 2 * 1024 * 1024;
+#[cfg(feature = "http-upload")]
+const UPLOAD_PATH: &str = "/image";
+
+/*
+ * Accepts images and frames posted to UPLOAD_PATH for a while and shows them, e.g.
+ *   curl --data-binary @image.png http://<device>/image
+ * Uploads only go to the panel, there's no image cache in flash to write them to, the next wake-up
+ * fetches from the configured URL again. A panel error is answered with a 500 and returned with the
+ * driver, so the caller can reset the panel.
+ */
+#[cfg(feature = "http-upload")]
+async fn upload_window<SPI, BUSY, DC, RST, DELAY>(
+    stack: embassy_net::Stack<'_>,
+    mut epd: Gdep073e01State<gdep073e01::StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+    spi: &mut SPI,
+    dither: &impl Fn(usize, usize, Rgb888) -> Spectra6Color,
+    rtc_state: &mut RtcState,
+) -> Result<
+    Gdep073e01State<gdep073e01::StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+    gdep073e01::Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY, Error>,
+>
+where
+    SPI: embedded_hal_async::spi::SpiDevice,
+    BUSY: embedded_hal::digital::InputPin + embedded_hal_async::digital::Wait,
+    DC: embedded_hal::digital::OutputPin,
+    RST: embedded_hal::digital::OutputPin,
+    DELAY: embedded_hal_async::delay::DelayNs,
+{
+    use reterminal_e100x::httpserver;
+
+    let mut rx_buffer = [0u8; 4096];
+    let mut tx_buffer = [0u8; 256];
+    let deadline = embassy_time::Instant::now() + UPLOAD_WINDOW;
+    println!(
+        "Accepting uploads on port {} at {}",
+        httpserver::DEFAULT_PORT,
+        UPLOAD_PATH
+    );
+    loop {
+        let mut socket = embassy_net::tcp::TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        match embassy_time::with_deadline(deadline, socket.accept(httpserver::DEFAULT_PORT)).await {
+            Err(_) => break,
+            Ok(Err(e)) => {
+                println!("Accept failed: {:?}", e);
+                continue;
+            }
+            Ok(Ok(())) => {}
+        }
+        let received = httpserver::read_request(&mut socket, UPLOAD_MAX_BODY).await;
+        let (status, message) = match received {
+            Ok(request) if request.path != UPLOAD_PATH => (404, "not found\n"),
+            Ok(request) if request.method != "POST" => (405, "use POST\n"),
+            Ok(request) => {
+                println!("Uploaded {} bytes", request.body.len());
+                match show_data(epd, spi, request.body.as_slice(), dither).await {
+                    Ok((shown, Ok(()))) => {
+                        epd = shown;
+                        rtc_state.forget_image();
+                        (200, "shown\n")
+                    }
+                    Ok((shown, Err(_))) => {
+                        epd = shown;
+                        (415, "not an image or frame the device can show\n")
+                    }
+                    Err(e) => {
+                        println!("Display failed: {:?}", e.error());
+                        let body = b"display failed\n";
+                        let _ =
+                            httpserver::write_response(&mut socket, 500, "text/plain", body).await;
+                        socket.close();
+                        let _ = socket.flush().await;
+                        return Err(e);
+                    }
+                }
+            }
+            Err(e) => {
+                println!("Upload failed: {:?}", e);
+                match e.status() {
+                    Some(413) => (413, "too large\n"),
+                    Some(status) => (status, "bad request\n"),
+                    // The connection is gone, nothing to answer on
+                    None => (0, ""),
+                }
+            }
+        };
+        if status != 0 {
+            let body = message.as_bytes();
+            let _ = httpserver::write_response(&mut socket, status, "text/plain", body).await;
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+    Ok(epd)
+}
+
+/*
+ * Raw frames, packed frames and images, on the panel, for data that arrives after the first
+ * refresh. The inner result is whether data could be shown, an error from the panel itself is
+ * returned as a whole, with the driver to reset it.
+ */
+#[cfg(any(feature = "mqtt", feature = "http-upload"))]
+async fn show_data<SPI, BUSY, DC, RST, DELAY>(
+    epd: Gdep073e01State<gdep073e01::StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+    spi: &mut SPI,
+    data: &[u8],
+    dither: &impl Fn(usize, usize, Rgb888) -> Spectra6Color,
+) -> Result<
+    (
+        Gdep073e01State<gdep073e01::StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+        Result<(), Error>,
+    ),
+    gdep073e01::Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY, Error>,
+>
+where
+    SPI: embedded_hal_async::spi::SpiDevice,
+    BUSY: embedded_hal::digital::InputPin + embedded_hal_async::digital::Wait,
+    DC: embedded_hal::digital::OutputPin,
+    RST: embedded_hal::digital::OutputPin,
+    DELAY: embedded_hal_async::delay::DelayNs,
+{
+    let epd = if rawframe::is_raw_frame(data) {
+        match rawframe::parse(data) {
+            Ok(frame) => {
+                println!("Update pre-dithered frame");
+                epd.update_frame_raw(spi, frame.bytes())
+                    .await
+                    .map_err(|e| e.map_error(Error::from))?
+            }
+            Err(e) => {
+                let e = Error::from(e);
+                println!("Not showing frame: {}", e);
+                return Ok((epd, Err(e)));
+            }
+        }
+    } else if reterminal_e100x::framepush::validate_packed_frame(data, None).is_ok() {
+        println!("Update packed frame");
+        epd.update_frame_from_reader(spi, data)
+            .await
+            .map_err(|e| e.map_error(Error::from))?
+    } else {
+        match decode_image(data) {
+            Ok(image) => {
+                println!("Dither and update frame");
+                let pixels = panel_pixels(&image).enumerate().map(|(index, color)| {
+                    dither(index % gdep073e01::WIDTH, index / gdep073e01::WIDTH, color)
+                });
+                epd.update_frame(spi, pixels)
+                    .await
+                    .map_err(|e| e.map_error(Error::from))?
+            }
+            Err(e) => {
+                println!("Not showing image: {}", e);
+                return Ok((epd, Err(e)));
+            }
+        }
+    };
+    println!("Display frame");
+    let epd = epd
+        .display_frame(spi)
+        .await
+        .map_err(|e| e.map_error(Error::from))?;
+    Ok((epd, Ok(())))
+}
+
+#[cfg(feature = "captive-portal")]
+const PORTAL_SSID: &str = "reTerminal setup";
+// Without anyone setting it up, sleep and try the saved settings again after that
+#[cfg(feature = "captive-portal")]
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+#[cfg(feature = "captive-portal")]
+const PORTAL_MAX_FORM: usize = 1024;
+
+#[cfg(feature = "captive-portal")]
+static PORTAL_RESOURCES: static_cell::ConstStaticCell<embassy_net::StackResources<4>> =
+    static_cell::ConstStaticCell::new(embassy_net::StackResources::new());
+
+/*
+ * Opens an access point with the setup form (see reterminal_e100x::portal), and restarts once new
+ * settings are saved. Returns if nobody did that for a while.
+ */
+#[cfg(feature = "captive-portal")]
+async fn captive_portal<F: embedded_storage::nor_flash::NorFlash>(
+    spawner: Spawner,
+    mut controller: esp_radio::wifi::WifiController<'static>,
+    device: esp_radio::wifi::WifiDevice<'static>,
+    settings: &Config,
+    config_store: &mut ConfigStore<F>,
+    seed: u64,
+) {
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+    use reterminal_e100x::httpserver;
+    use reterminal_e100x::portal::{self, DhcpServer};
+
+    // Stopping fails if it never started, that's fine
+    let _ = controller.stop_async().await;
+    let ap_config = esp_radio::wifi::ModeConfig::AccessPoint(
+        esp_radio::wifi::AccessPointConfig::default().with_ssid(PORTAL_SSID.into()),
+    );
+    controller.set_config(&ap_config).unwrap();
+    controller.start_async().await.unwrap();
+    let address = core::net::Ipv4Addr::from(portal::ADDRESS);
+    let net_config = embassy_net::Config::ipv4_static(embassy_net::StaticConfigV4 {
+        address: embassy_net::Ipv4Cidr::new(address, portal::PREFIX_LENGTH),
+        gateway: None,
+        dns_servers: Default::default(),
+    });
+    let (stack, runner) = embassy_net::new(device, net_config, PORTAL_RESOURCES.take(), seed);
+    spawner.spawn(net_task(runner)).unwrap();
+    stack.wait_config_up().await;
+    println!("Setup portal on {}, http://{}", PORTAL_SSID, address);
+
+    let dhcp = async {
+        let mut rx_meta = [PacketMetadata::EMPTY; 4];
+        let mut tx_meta = [PacketMetadata::EMPTY; 4];
+        let mut rx_buffer = [0u8; 1024];
+        let mut tx_buffer = [0u8; 1024];
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        socket.bind(portal::DHCP_SERVER_PORT).unwrap();
+        let mut server = DhcpServer::default();
+        let mut packet = [0u8; 576];
+        loop {
+            let Ok((length, _)) = socket.recv_from(&mut packet).await else {
+                continue;
+            };
+            // Clients don't have an address yet, so replies are broadcast
+            if let Some(reply) = server.reply(&packet[..length]) {
+                let broadcast = (core::net::Ipv4Addr::BROADCAST, portal::DHCP_CLIENT_PORT);
+                if let Err(e) = socket.send_to(&reply, broadcast).await {
+                    println!("DHCP reply failed: {:?}", e);
+                }
+            }
+        }
+    };
+    let dns = async {
+        let mut rx_meta = [PacketMetadata::EMPTY; 4];
+        let mut tx_meta = [PacketMetadata::EMPTY; 4];
+        let mut rx_buffer = [0u8; 1024];
+        let mut tx_buffer = [0u8; 1024];
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        socket.bind(portal::DNS_PORT).unwrap();
+        let mut packet = [0u8; 512];
+        loop {
+            let Ok((length, metadata)) = socket.recv_from(&mut packet).await else {
+                continue;
+            };
+            if let Some(reply) = portal::dns_reply(&packet[..length]) {
+                if let Err(e) = socket.send_to(&reply, metadata.endpoint).await {
+                    println!("DNS reply failed: {:?}", e);
+                }
+            }
+        }
+    };
+    let http = async {
+        let mut rx_buffer = [0u8; 2048];
+        let mut tx_buffer = [0u8; 2048];
+        loop {
+            let mut socket =
+                embassy_net::tcp::TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+            socket.set_timeout(Some(Duration::from_secs(10)));
+            if let Err(e) = socket.accept(httpserver::DEFAULT_PORT).await {
+                println!("Accept failed: {:?}", e);
+                continue;
+            }
+            let mut saved = false;
+            let page = match httpserver::read_request(&mut socket, PORTAL_MAX_FORM).await {
+                Ok(request) if request.method == "POST" && request.path == portal::SETUP_PATH => {
+                    match portal::apply_form(settings, &request.body) {
+                        Ok(new_settings) => match config_store.save(&new_settings) {
+                            Ok(()) => {
+                                saved = true;
+                                alloc::string::String::from(portal::saved_page())
+                            }
+                            Err(e) => {
+                                println!("Can't save settings: {}", Error::from(e));
+                                portal::setup_page(settings, Some("saving the settings failed"))
+                            }
+                        },
+                        Err(e) => portal::setup_page(settings, Some(e.0)),
+                    }
+                }
+                // Anything else, like the pages phones probe to detect a captive portal
+                Ok(_) => portal::setup_page(settings, None),
+                Err(e) => {
+                    println!("Setup request failed: {:?}", e);
+                    if let Some(status) = e.status() {
+                        let _ = httpserver::write_response(&mut socket, status, "text/plain", &[])
+                            .await;
+                    }
+                    socket.close();
+                    let _ = socket.flush().await;
+                    continue;
+                }
+            };
+            let content_type = "text/html; charset=utf-8";
+            let _ =
+                httpserver::write_response(&mut socket, 200, content_type, page.as_bytes()).await;
+            socket.close();
+            let _ = socket.flush().await;
+            if saved {
+                println!("Settings saved, restarting");
+                Timer::after(Duration::from_millis(500)).await;
+                esp_hal::system::software_reset();
+            }
+        }
+    };
+    let serve = embassy_futures::join::join3(dhcp, dns, http);
+    let _ = embassy_time::with_timeout(PORTAL_TIMEOUT, serve).await;
+    println!("Setup portal timed out");
+}
+
+// GATT server for provisioning settings from a phone, see reterminal_e100x::provisioning
+#[cfg(feature = "ble-provisioning")]
+mod ble_provisioning {
+    use embassy_time::Duration;
+    use esp_println::println;
+    use reterminal_e100x::config::Config;
+    use reterminal_e100x::provisioning::{self, Provisioning};
+    use trouble_host::prelude::*;
+
+    pub const NAME: &str = "reTerminal E100x";
+    // Without anyone setting it up, sleep and ask again on the next wake-up
+    pub const TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+    type Value = heapless::Vec<u8, { provisioning::MAX_VALUE_LENGTH }>;
+
+    // Keys for pairing, the hardware RNG is true random while the radio is on
+    struct RadioRng(esp_hal::rng::Rng);
+
+    impl rand_core::RngCore for RadioRng {
+        fn next_u32(&mut self) -> u32 {
+            self.0.random()
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            rand_core::impls::next_u64_via_u32(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            rand_core::impls::fill_bytes_via_next(self, dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl rand_core::CryptoRng for RadioRng {}
+
+    #[gatt_server]
+    struct Server {
+        provisioning: ProvisioningService,
+    }
+
+    #[gatt_service(uuid = provisioning::SERVICE_UUID)]
+    struct ProvisioningService {
+        #[characteristic(uuid = provisioning::SSID_UUID, write)]
+        ssid: Value,
+        #[characteristic(uuid = provisioning::PASSWORD_UUID, write)]
+        password: Value,
+        #[characteristic(uuid = provisioning::IMAGE_URL_UUID, write)]
+        image_url: Value,
+        #[characteristic(uuid = provisioning::SLEEP_INTERVAL_UUID, write)]
+        sleep_interval: u32,
+        #[characteristic(uuid = provisioning::APPLY_UUID, write)]
+        apply: u8,
+    }
+
+    // Advertises until a phone writes settings that are enough to fetch an image
+    pub async fn provision(
+        radio: &'static esp_radio::Controller<'static>,
+        bt: esp_hal::peripherals::BT<'static>,
+        config: Config,
+    ) -> Config {
+        let connector =
+            esp_radio::ble::controller::BleConnector::new(radio, bt, Default::default())
+                .expect("Failed to initialize BLE controller");
+        let controller: bt_hci::controller::ExternalController<_, 20> =
+            bt_hci::controller::ExternalController::new(connector);
+        let mut resources: HostResources<DefaultPacketPool, 1, 2> = HostResources::new();
+        let stack = trouble_host::new(controller, &mut resources)
+            .set_random_generator_seed(&mut RadioRng(esp_hal::rng::Rng::new()));
+        let Host {
+            mut peripheral,
+            mut runner,
+            ..
+        } = stack.build();
+        let server = Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+            name: NAME,
+            appearance: &appearance::UNKNOWN,
+        }))
+        .unwrap();
+        let mut provisioning = Provisioning::new(config);
+        let sessions = async {
+            loop {
+                match serve(&mut peripheral, &server, &mut provisioning).await {
+                    Ok(Some(config)) => return config,
+                    Ok(None) => println!("Disconnected before settings were applied"),
+                    Err(e) => println!("BLE failed: {:?}", e),
+                }
+            }
+        };
+        let host = async {
+            loop {
+                if let Err(e) = runner.run().await {
+                    println!("BLE host failed: {:?}", e);
+                }
+            }
+        };
+        // Dropping the host and connector shuts BLE down, Wi-Fi has the radio to itself after
+        match embassy_futures::select::select(host, sessions).await {
+            embassy_futures::select::Either::First(_) => unreachable!(),
+            embassy_futures::select::Either::Second(config) => config,
+        }
+    }
+
+    // One connection, the settings if they were applied before it closed
+    async fn serve<C: Controller>(
+        peripheral: &mut Peripheral<'_, C, DefaultPacketPool>,
+        server: &Server<'_>,
+        provisioning: &mut Provisioning,
+    ) -> Result<Option<Config>, BleHostError<C::Error>> {
+        let mut adv_data = [0u8; 31];
+        let adv_len = AdStructure::encode_slice(
+            &[
+                AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+                AdStructure::ServiceUuids128(&[provisioning::SERVICE_UUID.to_le_bytes()]),
+            ],
+            &mut adv_data,
+        )?;
+        // Doesn't fit next to the service UUID
+        let mut scan_data = [0u8; 31];
+        let scan_len = AdStructure::encode_slice(
+            &[AdStructure::CompleteLocalName(NAME.as_bytes())],
+            &mut scan_data,
+        )?;
+        println!("Advertising as {}", NAME);
+        let advertiser = peripheral
+            .advertise(
+                &Default::default(),
+                Advertisement::ConnectableScannableUndirected {
+                    adv_data: &adv_data[..adv_len],
+                    scan_data: &scan_data[..scan_len],
+                },
+            )
+            .await?;
+        let connection = advertiser.accept().await?.with_attribute_server(server)?;
+        println!("Phone connected");
+        let service = &server.provisioning;
+        let handles = [
+            (service.ssid.handle, provisioning::Characteristic::Ssid),
+            (
+                service.password.handle,
+                provisioning::Characteristic::Password,
+            ),
+            (
+                service.image_url.handle,
+                provisioning::Characteristic::ImageUrl,
+            ),
+            (
+                service.sleep_interval.handle,
+                provisioning::Characteristic::SleepInterval,
+            ),
+            (service.apply.handle, provisioning::Characteristic::Apply),
+        ];
+        loop {
+            let event = match connection.next().await {
+                GattConnectionEvent::Disconnected { .. } => return Ok(None),
+                GattConnectionEvent::Gatt { event } => event,
+                _ => continue,
+            };
+            let characteristic = match &event {
+                GattEvent::Write(write) => handles
+                    .iter()
+                    .find(|(handle, _)| *handle == write.handle())
+                    .map(|(_, characteristic)| (*characteristic, write.data())),
+                _ => None,
+            };
+            let encrypted = matches!(
+                connection.raw().security_level(),
+                Ok(SecurityLevel::Encrypted | SecurityLevel::EncryptedAuthenticated)
+            );
+            let written = match characteristic {
+                Some((characteristic, _)) if characteristic.needs_encryption() && !encrypted => {
+                    println!("Refused {:?} before pairing", characteristic);
+                    Some(Err(AttErrorCode::INSUFFICIENT_ENCRYPTION))
+                }
+                Some((characteristic, data)) => {
+                    Some(provisioning.write(characteristic, data).map_err(|e| {
+                        println!("Rejected setting: {}", e.0);
+                        AttErrorCode::VALUE_NOT_ALLOWED
+                    }))
+                }
+                None => None,
+            };
+            let reply = match written {
+                Some(Err(code)) => event.reject(code),
+                _ => event.accept(),
+            };
+            match reply {
+                Ok(reply) => reply.send().await,
+                Err(e) => println!("GATT reply failed: {:?}", e),
+            }
+            if let Some(Ok(Some(config))) = written {
+                println!("Settings applied");
+                return Ok(Some(config));
+            }
+        }
+    }
 }
 
 #[esp_rtos::main]
@@ -258,58 +1698,46 @@ async fn main(spawner: Spawner) -> ! {
     // generator version: 1.0.1
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
-    let mut gpio_btn_reset = peripherals.GPIO3;
-    let btn_reset_state = esp_hal::gpio::Input::new(
-        gpio_btn_reset.reborrow(),
-        esp_hal::gpio::InputConfig::default().with_pull(Pull::Up),
-    )
-    .is_low();
+    let mut wake_buttons = WakeButtons {
+        refresh: peripherals.GPIO3,
+        right: peripherals.GPIO4,
+        left: peripherals.GPIO5,
+    };
+    let btn_reset_state = is_pressed(wake_buttons.refresh.reborrow());
+    // Which button woke the device, even if it was already released again
+    let rtc_io_status = rtc_io_wakeup_status();
+    let woken_by = |pin: &dyn esp_hal::gpio::RtcPin| rtc_io_status & (1 << pin.rtc_number()) != 0;
+    let btn_woken_by = (
+        woken_by(&wake_buttons.refresh),
+        woken_by(&wake_buttons.right),
+        woken_by(&wake_buttons.left),
+    );
     let mut rtc = esp_hal::rtc_cntl::Rtc::new(peripherals.LPWR);
+    // The second core only helps dithering, see run_on_both_cores
+    let mut cpu_control = esp_hal::system::CpuControl::new(peripherals.CPU_CTRL);
 
     let time_since_boot = rtc.time_since_boot();
 
     println!(
         "Device booting up - {reset_reason:?} - {wake_reason:?} - {btn_reset_state:?} - {time_since_boot:?}"
     );
+    println!("Woken by buttons (refresh, right, left): {btn_woken_by:?}");
+    println!("Features: {}", EnabledFeatures);
 
     esp_alloc::heap_allocator!(#[esp_hal::ram(reclaimed)] size: 73744);
     esp_alloc::psram_allocator!(peripherals.PSRAM, esp_hal::psram);
 
+    // Safety: nothing else touches it, and it's only written right before deep sleep
+    let stored_state = unsafe { (&raw const RTC_STATE).read() };
+    let mut rtc_state = RtcState::load(&stored_state).unwrap_or_default();
+    println!("RTC state: {:?}", rtc_state);
+    let boot_cause = boot_cause(reset_reason, wake_reason, btn_woken_by);
+    let boot_action = BOOT_POLICY.action(boot_cause, rtc_state.next_wake);
+    println!("Boot cause {:?}, {:?}", boot_cause, boot_action);
+
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     esp_rtos::start(timg0.timer0);
 
-    /*
-    spawner
-        .spawn(button_task(
-            Button::new(
-                peripherals.GPIO3,
-                InputConfig::default().with_pull(Pull::Up),
-                true,
-            )
-            "Refresh",
-        ))
-        .unwrap();
-    */
-    spawner
-        .spawn(button_task(
-            Button::new(
-                peripherals.GPIO4,
-                InputConfig::default().with_pull(Pull::Up),
-                true,
-            ),
-            "Right",
-        ))
-        .unwrap();
-    spawner
-        .spawn(button_task(
-            Button::new(
-                peripherals.GPIO5,
-                InputConfig::default().with_pull(Pull::Up),
-                true,
-            ),
-            "Left",
-        ))
-        .unwrap();
     spawner
         .spawn(blink_task(Output::new(
             peripherals.GPIO6,
@@ -318,29 +1746,228 @@ async fn main(spawner: Spawner) -> ! {
         )))
         .unwrap();
 
+    let battery_millivolts = {
+        use esp_hal::analog::adc::{Adc, AdcCalCurve, AdcConfig, Attenuation};
+        let mut divider = Output::new(peripherals.GPIO21, Level::High, OutputConfig::default());
+        // Let the divider settle
+        Timer::after(Duration::from_millis(10)).await;
+        let mut adc_config = AdcConfig::new();
+        let mut pin = adc_config
+            .enable_pin_with_cal::<_, AdcCalCurve<esp_hal::peripherals::ADC1<'_>>>(
+                peripherals.GPIO1,
+                Attenuation::_11dB,
+            );
+        let mut adc = Adc::new(peripherals.ADC1, adc_config);
+        let samples: [u16; BATTERY_SAMPLES] = core::array::from_fn(|_| {
+            loop {
+                if let Ok(millivolts) = adc.read_oneshot(&mut pin) {
+                    break millivolts;
+                }
+            }
+        });
+        divider.set_low();
+        BATTERY_CALIBRATION.battery_millivolts(battery::filtered_average(&samples).unwrap())
+    };
+    println!(
+        "Battery: {} mV, about {}%",
+        battery_millivolts,
+        battery::percentage(battery_millivolts)
+    );
+
+    let epd_spi_bus = Spi::new(
+        peripherals.SPI2,
+        SpiConfig::default()
+            .with_write_bit_order(esp_hal::spi::BitOrder::MsbFirst)
+            .with_frequency(esp_hal::time::Rate::from_mhz(20))
+            .with_mode(SpiMode::_0),
+    )
+    .unwrap();
+    let epd_spi_bus = epd_spi_bus
+        .with_sck(peripherals.GPIO7)
+        .with_mosi(peripherals.GPIO9)
+        .into_async();
+    // Other SPI peripherals (e.g. an SD card) can get their own device on this
+    let epd_spi_bus = SpiBusManager::<CriticalSectionRawMutex, _>::new(epd_spi_bus);
+
+    let mut epd_spi_dev = epd_spi_bus
+        .priority_device(
+            Output::new(peripherals.GPIO20, Level::Low, OutputConfig::default()),
+            embassy_time::Delay,
+        )
+        .unwrap();
+
+    let epd = Gdep073e01State::new(
+        &mut epd_spi_dev,
+        Input::new(
+            peripherals.GPIO13,
+            InputConfig::default().with_pull(Pull::Up),
+        ),
+        Output::new(peripherals.GPIO11, Level::Low, OutputConfig::default()),
+        Output::new(peripherals.GPIO12, Level::Low, OutputConfig::default()),
+        &mut embassy_time::Delay,
+    );
+
+    if battery::is_critical(battery_millivolts) {
+        println!("Battery critically low");
+        // Already on the panel if it went to sleep for the battery, a refresh only costs more charge
+        if LOW_BATTERY_WARNING && rtc_state.next_wake != NextWake::LowBattery {
+            let mut frame = blank_frame();
+            let Ok(()) = battery::draw_low_battery(&mut frame);
+            let mut delay = embassy_time::Delay;
+            let shown = show_frame(epd, &mut epd_spi_dev, &mut delay, frame.as_bytes()).await;
+            rtc_state.forget_image();
+            if let Err(failure) = shown {
+                reset_failed_panel(failure, &mut delay).await;
+                // Not on the panel, so the next wake-up tries the warning again
+                rtc_state.next_wake = NextWake::Retry;
+                deep_sleep(&mut rtc, &mut wake_buttons, LOW_BATTERY_SLEEP, &rtc_state);
+            }
+        }
+        rtc_state.next_wake = NextWake::LowBattery;
+        deep_sleep(&mut rtc, &mut wake_buttons, LOW_BATTERY_SLEEP, &rtc_state);
+    }
+
+    let mut flash = esp_storage::FlashStorage::new(peripherals.FLASH);
+    let settings_offset = settings_offset(&mut flash);
+    let mut config_store = ConfigStore::new(flash, settings_offset);
+    #[cfg_attr(not(feature = "ble-provisioning"), allow(unused_mut))]
+    let mut settings = match config_store.load() {
+        Ok(Some(settings)) => settings,
+        Ok(None) => built_in_config(),
+        Err(e) => {
+            println!("Can't read settings: {}", Error::from(e));
+            built_in_config()
+        }
+    };
+
+    let epd = if boot_action == BootAction::Splash {
+        println!("Showing the splash screen");
+        let mut frame = blank_frame();
+        let lines = splash_lines(&settings, battery::percentage(battery_millivolts));
+        let lines: alloc::vec::Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+        // Joining the setup network and opening the setup page, for a phone to scan
+        #[cfg(feature = "captive-portal")]
+        let qr_payloads = if settings.is_provisioned() {
+            alloc::vec::Vec::new()
+        } else {
+            let address = core::net::Ipv4Addr::from(reterminal_e100x::portal::ADDRESS);
+            alloc::vec![
+                qrcode::wifi_payload(PORTAL_SSID, ""),
+                alloc::format!("http://{}{}", address, reterminal_e100x::portal::SETUP_PATH),
+            ]
+        };
+        #[cfg(not(feature = "captive-portal"))]
+        let qr_payloads: alloc::vec::Vec<alloc::string::String> = alloc::vec::Vec::new();
+        // The text above the QR codes, if there are any
+        let size = frame.bounding_box().size;
+        let text_height = if qr_payloads.is_empty() {
+            size.height
+        } else {
+            size.height * 3 / 5
+        };
+        let text_area = Rectangle::new(Point::zero(), Size::new(size.width, text_height));
+        let title = "reTerminal E100x";
+        let Ok(()) = bootpolicy::draw_splash(&mut frame.cropped(&text_area), title, &lines);
+        let qr_width = size.width / qr_payloads.len().max(1) as u32;
+        for (index, payload) in qr_payloads.iter().enumerate() {
+            let area = Rectangle::new(
+                Point::new(index as i32 * qr_width as i32, text_height as i32),
+                Size::new(qr_width, size.height - text_height),
+            );
+            if let Err(e) = qrcode::draw_qr_code(&mut frame, payload.as_bytes(), area) {
+                println!("Can't draw QR code: {:?}", e);
+            }
+        }
+        let mut delay = embassy_time::Delay;
+        let shown = show_frame(epd, &mut epd_spi_dev, &mut delay, frame.as_bytes()).await;
+        rtc_state.forget_image();
+        match shown {
+            Ok(epd) => epd,
+            Err(failure) => {
+                reset_failed_panel(failure, &mut delay).await;
+                rtc_state.next_wake = NextWake::Retry;
+                let interval = settings.sleep_interval();
+                deep_sleep(&mut rtc, &mut wake_buttons, interval, &rtc_state);
+            }
+        }
+    } else {
+        println!("Reset");
+        epd.reset(&mut embassy_time::Delay).await.unwrap()
+    };
+
     let radio_init = RADIO_CONTROLLER
         .init(esp_radio::init().expect("Failed to initialize Wi-Fi/BLE controller"));
+
+    if !settings.is_provisioned() {
+        #[cfg(feature = "ble-provisioning")]
+        {
+            println!("Not provisioned, waiting for settings over BLE");
+            let provision =
+                ble_provisioning::provision(radio_init, peripherals.BT, settings.clone());
+            settings = match embassy_time::with_timeout(ble_provisioning::TIMEOUT, provision).await
+            {
+                Ok(settings) => settings,
+                Err(_) => {
+                    println!("BLE provisioning timed out");
+                    rtc_state.next_wake = NextWake::Unprovisioned;
+                    let interval = settings.sleep_interval();
+                    deep_sleep(&mut rtc, &mut wake_buttons, interval, &rtc_state);
+                }
+            };
+            if let Err(e) = config_store.save(&settings) {
+                // Still good for this wake-up, the next one asks again
+                println!("Can't save settings: {}", Error::from(e));
+            }
+        }
+        #[cfg(not(any(feature = "ble-provisioning", feature = "captive-portal")))]
+        {
+            println!("Not provisioned, no Wi-Fi network or image URL");
+            rtc_state.next_wake = NextWake::Unprovisioned;
+            deep_sleep(
+                &mut rtc,
+                &mut wake_buttons,
+                settings.sleep_interval(),
+                &rtc_state,
+            );
+        }
+    }
+    let sleep_interval = settings.sleep_interval();
     let (mut wifi_controller, interfaces) =
         esp_radio::wifi::new(radio_init, peripherals.WIFI, Default::default())
             .expect("Failed to initialize Wi-Fi controller");
 
-    const SSID: &str = env!("WIFI_SSID");
-    const PASSWORD: &str = env!("WIFI_PASSWORD");
-
     let wifi_sta_device = interfaces.sta;
+    #[cfg(feature = "mdns")]
+    let mac_address = wifi_sta_device.mac_address();
+
+    let rng = esp_hal::rng::Rng::new();
+    let seed = (rng.random() as u64) << 32 | rng.random() as u64;
+
+    #[cfg(feature = "captive-portal")]
+    if !settings.is_provisioned() {
+        println!("Not provisioned, starting the setup portal");
+        captive_portal(
+            spawner,
+            wifi_controller,
+            interfaces.ap,
+            &settings,
+            &mut config_store,
+            seed,
+        )
+        .await;
+        rtc_state.next_wake = NextWake::Unprovisioned;
+        deep_sleep(&mut rtc, &mut wake_buttons, sleep_interval, &rtc_state);
+    }
 
     let sta_config = embassy_net::Config::dhcpv4(Default::default());
 
     let station_config = esp_radio::wifi::ModeConfig::Client(
         esp_radio::wifi::ClientConfig::default()
-            .with_ssid(SSID.into())
-            .with_password(PASSWORD.into()),
+            .with_ssid(settings.ssid().into())
+            .with_password(settings.password().into()),
     );
     wifi_controller.set_config(&station_config).unwrap();
 
-    let rng = esp_hal::rng::Rng::new();
-    let seed = (rng.random() as u64) << 32 | rng.random() as u64;
-
     let (net_stack, net_runner) =
         embassy_net::new(wifi_sta_device, sta_config, NETWORK_RESOURCES.take(), seed);
 
@@ -348,102 +1975,250 @@ async fn main(spawner: Spawner) -> ! {
     spawner.spawn(net_task(net_runner)).unwrap();
 
     println!("Waiting for network link...");
+    #[cfg(feature = "captive-portal")]
+    if let embassy_futures::select::Either::Second(wifi_controller) =
+        embassy_futures::select::select(net_stack.wait_config_up(), WIFI_GAVE_UP.wait()).await
+    {
+        println!(
+            "Can't connect to {}, starting the setup portal",
+            settings.ssid()
+        );
+        captive_portal(
+            spawner,
+            wifi_controller,
+            interfaces.ap,
+            &settings,
+            &mut config_store,
+            seed,
+        )
+        .await;
+        rtc_state.next_wake = NextWake::Unprovisioned;
+        deep_sleep(&mut rtc, &mut wake_buttons, sleep_interval, &rtc_state);
+    }
     net_stack.wait_link_up().await;
     println!("Link up, waiting for config up");
     net_stack.wait_config_up().await;
     println!("Network config up! {:?}", net_stack.config_v4());
+    #[cfg(feature = "mdns")]
+    if let Some(net_config) = net_stack.config_v4() {
+        use reterminal_e100x::mdns::{self, Responder};
+        let address = net_config.address.address().octets();
+        #[cfg_attr(not(feature = "http-upload"), allow(unused_mut))]
+        let mut responder = Responder::new(mdns::hostname(mac_address), address);
+        #[cfg(feature = "http-upload")]
+        responder.add_service(mdns::Service {
+            service_type: "_http._tcp",
+            port: reterminal_e100x::httpserver::DEFAULT_PORT,
+            txt: alloc::vec![alloc::format!("path={}", UPLOAD_PATH)],
+        });
+        spawner.spawn(mdns_task(net_stack, responder)).unwrap();
+    }
 
-    let png_data = get_image_data(net_stack).await;
-    println!("Decode PNG");
-    let (header, data) = png_decoder::decode(png_data.as_slice()).unwrap();
-    println!("Header: {:?}", header);
-    let data = data.into_iter();
+    let page_count = settings.image_urls().len();
+    rtc_state.step_slideshow(boot_action.page_step(), page_count);
+    let image_url = settings.image_url_at(rtc_state.slideshow_index as usize);
+    if settings.image_urls().len() > 1 {
+        println!("Slide {}: {}", rtc_state.slideshow_index, image_url);
+    }
+    println!("Creating decomposer");
+    let palettes = PaletteSet::factory();
+    println!("Palette: {}", palettes.selected_name());
+    let palette: [Point3<f32>; 6] =
+        core::array::from_fn(|index| color_to_point(palettes.selected()[index].0));
+    let palette_colors: [Spectra6Color; 6] =
+        core::array::from_fn(|index| palettes.selected()[index].1);
+    let decomposer = Decomposer6C::new(&palette).unwrap();
+    let dither = |x: usize, y: usize, color: Rgb888| {
+        let barycentric: Vector6<f32> =
+            decomposer.decompose(&color_to_point(color), Decomposer6CAxisStrategy::Closest);
+        let noise = interleaved_gradient_noise(x as f32, y as f32);
+        palette_colors[pick_from_barycentric_weights(barycentric, noise)]
+    };
 
-    let epd_spi_bus = Spi::new(
-        peripherals.SPI2,
-        SpiConfig::default()
-            .with_write_bit_order(esp_hal::spi::BitOrder::MsbFirst)
-            .with_frequency(esp_hal::time::Rate::from_mhz(20))
-            .with_mode(SpiMode::_0),
-    )
-    .unwrap();
-    let epd_spi_bus = epd_spi_bus
-        .with_sck(peripherals.GPIO7)
-        .with_mosi(peripherals.GPIO9)
-        .into_async();
+    let mut sink = PanelSink {
+        panel: Some(StreamPanel::Reset(epd)),
+        spi: &mut epd_spi_dev,
+        delay: embassy_time::Delay,
+        quantize: &dither,
+        frame_stats: analysis::FrameStats::default(),
+    };
+    let image_data = match get_image_data(net_stack, image_url, &rng, &mut sink).await {
+        Ok(data) => data,
+        Err(e) => {
+            // Leave whatever is on the panel, and try again next wake-up
+            println!("Not fetching image: {}", e);
+            sink.finish().await;
+            rtc_state.fetch_failures = rtc_state.fetch_failures.saturating_add(1);
+            rtc_state.next_wake = NextWake::Retry;
+            deep_sleep(&mut rtc, &mut wake_buttons, sleep_interval, &rtc_state);
+        }
+    };
+    let sleep_interval = match image_data.next_refresh {
+        Some(next_refresh) => {
+            println!(
+                "Server asks for the next refresh in {} s",
+                next_refresh.as_secs()
+            );
+            next_refresh
+        }
+        None => sleep_interval,
+    };
+    rtc_state.fetch_failures = 0;
+    rtc_state.next_wake = NextWake::Refresh;
+    // Saves a refresh, and the power that takes. A button press always refreshes.
+    if boot_cause == BootCause::Timer && rtc_state.last_image_hash == Some(image_data.hash) {
+        println!("Image unchanged, not refreshing");
+        sink.finish().await;
+        deep_sleep(&mut rtc, &mut wake_buttons, sleep_interval, &rtc_state);
+    }
+    rtc_state.last_image_hash = Some(image_data.hash);
+    // Pre-dithered frames go to the panel as they are, without decoding or dithering
+    let raw_frame = match image_data.body.as_deref().map(rawframe::parse) {
+        Some(Ok(frame)) => Some(frame),
+        None | Some(Err(RawFrameError::NotRawFrame)) => None,
+        Some(Err(e)) => {
+            println!("Not showing frame: {}", Error::from(e));
+            sink.finish().await;
+            deep_sleep(&mut rtc, &mut wake_buttons, sleep_interval, &rtc_state);
+        }
+    };
+    // PNGs that fit the panel as they are were already streamed into it, anything else is decoded
+    let image = match image_data.body.as_deref() {
+        Some(data) if raw_frame.is_none() => match decode_image(data) {
+            Ok(image) => Some(image),
+            Err(e) => {
+                // Leave whatever is on the panel, and try again next wake-up
+                println!("Not showing image: {}", e);
+                sink.finish().await;
+                deep_sleep(&mut rtc, &mut wake_buttons, sleep_interval, &rtc_state);
+            }
+        },
+        _ => None,
+    };
 
-    let mut epd_spi_dev = ExclusiveDevice::new(
-        epd_spi_bus,
-        Output::new(peripherals.GPIO20, Level::Low, OutputConfig::default()),
-        embassy_time::Delay,
-    )
-    .unwrap();
+    /*
+     * The driver from a panel result. After a panel error the panel is reset, which is also its
+     * lowest power state, and the image is tried again next wake-up.
+     */
+    macro_rules! panel_or_sleep {
+        ($result:expr) => {{
+            let result = $result;
+            match result {
+                Ok(epd) => epd,
+                Err(e) => {
+                    reset_failed_panel(e.map_error(Error::from), &mut embassy_time::Delay).await;
+                    rtc_state.forget_image();
+                    rtc_state.next_wake = NextWake::Retry;
+                    deep_sleep(&mut rtc, &mut wake_buttons, sleep_interval, &rtc_state);
+                }
+            }
+        }};
+    }
 
-    let epd = Gdep073e01State::new(
-        &mut epd_spi_dev,
-        Input::new(
-            peripherals.GPIO13,
-            InputConfig::default().with_pull(Pull::Up),
-        ),
-        Output::new(peripherals.GPIO11, Level::Low, OutputConfig::default()),
-        Output::new(peripherals.GPIO12, Level::Low, OutputConfig::default()),
-        &mut embassy_time::Delay,
-    );
+    let streamed_stats = sink.frame_stats;
+    let epd = panel_or_sleep!(sink.into_powered_on().await);
+    let epd = if let Some(frame) = raw_frame {
+        println!("Update pre-dithered frame");
+        panel_or_sleep!(epd.update_frame_raw(&mut epd_spi_dev, frame.bytes()).await)
+    } else if let Some(image) = image {
+        let reservation = epd_spi_bus.reserve();
+        #[cfg(feature = "stack-usage")]
+        // Safety: main's frame is the deepest one on this stack right now
+        let transfer_watermark =
+            unsafe { reterminal_e100x::stackusage::StackWatermark::paint(STACK_PAINT_DEPTH) };
+        let start_dither = esp_hal::xtensa_lx::timer::get_cycle_count();
 
-    println!("Creating decomposer");
-    let decomposer = Decomposer6C::new(&PALETTE).unwrap();
-
-    println!("Setting up dithering iterator");
-    let data = data.map(color_to_point);
-    // let data = data.map(|x| x * 0.8);
-    let data = data.enumerate().map(|(index, color)| {
-        let barycentric: Vector6<f32> = decomposer.decompose(&color, Decomposer6CAxisStrategy::Closest);
-        let x = index % 800;
-        let y = index / 800;
-        let noise = interleaved_gradient_noise(x as f32, y as f32);
-        let index = pick_from_barycentric_weights(barycentric, noise);
-        PALETTE_COLORS[index].clone()
-    });
+        /*
+         * Dithered into a frame on both cores, half each. The noise only depends on the position,
+         * so the bands need no overlap to hide the seam.
+         */
+        println!("Dither on both cores");
+        let rotation = panel_rotation(&image);
+        let (image, dither) = (&image, &dither);
+        let band_pixels = move |band: parallel::Band| {
+            image
+                .letterboxed_pixels(rotation, gdep073e01::WIDTH, gdep073e01::HEIGHT, MATTING)
+                .enumerate()
+                .skip(band.start_row * gdep073e01::WIDTH)
+                .take(band.rows().len() * gdep073e01::WIDTH)
+                .map(move |(index, color)| {
+                    dither(index % gdep073e01::WIDTH, index / gdep073e01::WIDTH, color)
+                })
+        };
+        let mut frame = alloc::vec![0u8; reterminal_e100x::framepush::FRAME_BYTES];
+        parallel::packed_dual(
+            gdep073e01::WIDTH,
+            gdep073e01::HEIGHT,
+            0,
+            &mut frame,
+            band_pixels,
+            band_pixels,
+            |other, this| run_on_both_cores(&mut cpu_control, other, this),
+        );
+        let end_dither = esp_hal::xtensa_lx::timer::get_cycle_count();
+        let frame_stats = Spectra6Framebuffer::new(&frame, gdep073e01::WIDTH, gdep073e01::HEIGHT)
+            .map(|frame| analysis::frame_stats(frame.rows().flatten()))
+            .unwrap_or_default();
 
-    println!("Dithering");
-    let start_dither = esp_hal::xtensa_lx::timer::get_cycle_count();
-    let data: alloc::vec::Vec<Spectra6Color> = data.collect();
-    let end_dither = esp_hal::xtensa_lx::timer::get_cycle_count();
-    let dither_duration_cycles = end_dither.wrapping_sub(start_dither);
-    println!("Duration: {:?} seconds", (dither_duration_cycles as f32)/(240_000_000.0));
-
-    println!("Reset");
-    let epd = epd.reset(&mut embassy_time::Delay).await.unwrap();
-    println!("Init");
-    let epd = epd.init(&mut epd_spi_dev).await.unwrap();
-    println!("Power on");
-    let epd = epd.power_on(&mut epd_spi_dev).await.unwrap();
-    println!("Update frame");
-    let epd = epd.update_frame(&mut epd_spi_dev, data).await.unwrap();
+        println!("Update frame");
+        let update_frame = epd.update_frame_raw(&mut epd_spi_dev, frame.iter().copied());
+        // Futures live in main's task, so this adds to what the executor has to reserve for it
+        #[cfg(feature = "stack-usage")]
+        println!(
+            "Frame transfer future: {} bytes",
+            core::mem::size_of_val(&update_frame)
+        );
+        let epd = panel_or_sleep!(update_frame.await);
+        #[cfg(feature = "stack-usage")]
+        log_stack_usage("dithering and the frame transfer", &transfer_watermark);
+        drop(reservation);
+        let dither_duration_cycles = end_dither.wrapping_sub(start_dither);
+        println!("Duration: {:?} seconds", (dither_duration_cycles as f32)/(240_000_000.0));
+        log_frame_stats(&frame_stats);
+        epd
+    } else {
+        log_frame_stats(&streamed_stats);
+        epd
+    };
     println!("Display frame");
-    let epd = epd.display_frame(&mut epd_spi_dev).await.unwrap();
+    let epd = panel_or_sleep!(epd.display_frame(&mut epd_spi_dev).await);
     // Quick hack to allow clearing the screen for storage:
     let epd = if esp_hal::gpio::Input::new(
-        gpio_btn_reset.reborrow(),
+        wake_buttons.refresh.reborrow(),
         esp_hal::gpio::InputConfig::default().with_pull(Pull::Up),
     )
     .is_low()
     {
         println!("Clearing screen before power off");
-        let epd = epd
-            .update_frame(
-                &mut epd_spi_dev,
-                (0..(800 * 480)).map(|_| Spectra6Color::Clean),
-            )
-            .await
-            .unwrap();
-        epd.display_frame(&mut epd_spi_dev).await.unwrap()
+        let epd = panel_or_sleep!(epd.clear(&mut epd_spi_dev, Spectra6Color::Clean).await);
+        rtc_state.forget_image();
+        panel_or_sleep!(epd.display_frame(&mut epd_spi_dev).await)
     } else {
         epd
     };
 
+    #[cfg(feature = "frame-push")]
+    let epd =
+        panel_or_sleep!(frame_push_window(net_stack, epd, &mut epd_spi_dev, &mut rtc_state).await);
+    #[cfg(feature = "http-upload")]
+    let epd = panel_or_sleep!(
+        upload_window(net_stack, epd, &mut epd_spi_dev, &dither, &mut rtc_state).await
+    );
+    #[cfg(feature = "mqtt")]
+    let epd = panel_or_sleep!(
+        mqtt_window(
+            net_stack,
+            epd,
+            &mut epd_spi_dev,
+            &rng,
+            &dither,
+            &mut rtc_state
+        )
+        .await
+    );
+
     println!("Power off");
-    let epd = epd.power_off(&mut epd_spi_dev).await.unwrap();
+    let epd = panel_or_sleep!(epd.power_off(&mut epd_spi_dev).await);
     // TODO: Display deep sleep
     println!("Done");
     let _ = epd;
@@ -452,18 +2227,40 @@ async fn main(spawner: Spawner) -> ! {
     let _ = spawner;
 
     println!("Deep sleep!");
+    deep_sleep(&mut rtc, &mut wake_buttons, sleep_interval, &rtc_state);
+}
+
+fn deep_sleep(
+    rtc: &mut esp_hal::rtc_cntl::Rtc<'_>,
+    wake_buttons: &mut WakeButtons<'_>,
+    sleep_interval: Duration,
+    rtc_state: &RtcState,
+) -> ! {
+    // Safety: main is the only one using it, and it doesn't run anymore after this
+    unsafe { (&raw mut RTC_STATE).write(rtc_state.store()) };
 
     let wakeup_pins: &mut [(
         &mut dyn esp_hal::gpio::RtcPin,
         esp_hal::rtc_cntl::sleep::WakeupLevel,
-    )] = &mut [(
-        &mut gpio_btn_reset,
-        esp_hal::rtc_cntl::sleep::WakeupLevel::Low,
-    )];
+    )] = &mut [
+        (
+            &mut wake_buttons.refresh,
+            esp_hal::rtc_cntl::sleep::WakeupLevel::Low,
+        ),
+        (
+            &mut wake_buttons.right,
+            esp_hal::rtc_cntl::sleep::WakeupLevel::Low,
+        ),
+        (
+            &mut wake_buttons.left,
+            esp_hal::rtc_cntl::sleep::WakeupLevel::Low,
+        ),
+    ];
     let pin_wake_source = esp_hal::rtc_cntl::sleep::RtcioWakeupSource::new(wakeup_pins);
 
-    let timer_wake_source =
-        esp_hal::rtc_cntl::sleep::TimerWakeupSource::new(core::time::Duration::from_secs(10 * 60));
+    let timer_wake_source = esp_hal::rtc_cntl::sleep::TimerWakeupSource::new(
+        core::time::Duration::from_micros(sleep_interval.as_micros()),
+    );
     let wake_sources: &[&dyn esp_hal::rtc_cntl::sleep::WakeSource] =
         &[&timer_wake_source, &pin_wake_source];
 
@@ -0,0 +1,104 @@
+/*
+ * Lets the async drivers run on blocking embedded-hal implementations: Blocking<T> implements the
+ * async traits on top of the blocking ones, and block_on runs the resulting futures to completion.
+ * As nothing ever actually waits, the futures finish on their first poll, BUSY is busy-polled.
+ */
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use embedded_hal::digital::{ErrorType as DigitalErrorType, InputPin, OutputPin};
+use embedded_hal::spi::{ErrorType as SpiErrorType, Operation};
+
+#[repr(transparent)]
+pub struct Blocking<T>(pub T);
+
+impl<T> Blocking<T> {
+    // Wraps a borrowed peripheral, for APIs that hand out &mut SPI or &mut DELAY per call
+    pub fn from_mut(inner: &mut T) -> &mut Self {
+        // SAFETY: Blocking is repr(transparent), so it has the same layout as T
+        unsafe { &mut *(inner as *mut T as *mut Self) }
+    }
+}
+
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+impl<T: SpiErrorType> SpiErrorType for Blocking<T> {
+    type Error = T::Error;
+}
+
+impl<T: embedded_hal::spi::SpiDevice> embedded_hal_async::spi::SpiDevice for Blocking<T> {
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        self.0.transaction(operations)
+    }
+}
+
+impl<T: DigitalErrorType> DigitalErrorType for Blocking<T> {
+    type Error = T::Error;
+}
+
+impl<T: InputPin> InputPin for Blocking<T> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        self.0.is_high()
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        self.0.is_low()
+    }
+}
+
+impl<T: OutputPin> OutputPin for Blocking<T> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.set_high()
+    }
+}
+
+impl<T: InputPin> embedded_hal_async::digital::Wait for Blocking<T> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        while self.0.is_low()? {}
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        while self.0.is_high()? {}
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_low().await?;
+        self.wait_for_high().await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_high().await?;
+        self.wait_for_low().await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        if self.0.is_high()? {
+            self.wait_for_low().await
+        } else {
+            self.wait_for_high().await
+        }
+    }
+}
+
+impl<T: embedded_hal::delay::DelayNs> embedded_hal_async::delay::DelayNs for Blocking<T> {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.0.delay_ns(ns)
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * reTerminal E100x board support. The panel sits on SPI2, which is also where an SD card (or other
+ * SPI peripheral) would go. SpiBusManager hands out an SpiDevice per chip select, each transaction
+ * locks the bus for its duration. A frame transfer is thousands of transactions in a row though,
+ * and the panel doesn't like long pauses in the middle of one, so it can reserve the bus: other
+ * devices then wait until the reservation is dropped, only priority devices keep going.
+ */
+use core::sync::atomic::{AtomicUsize, Ordering};
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::{ErrorType, Operation};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::spi::{SpiBus, SpiDevice};
+use embedded_hal_bus::spi::DeviceError;
+
+pub struct SpiBusManager<M: RawMutex, BUS> {
+    bus: Mutex<M, BUS>,
+    reservations: AtomicUsize,
+}
+
+impl<M: RawMutex, BUS> SpiBusManager<M, BUS> {
+    pub const fn new(bus: BUS) -> Self {
+        SpiBusManager {
+            bus: Mutex::new(bus),
+            reservations: AtomicUsize::new(0),
+        }
+    }
+
+    // Sets CS high (inactive), like embedded-hal-bus does
+    pub fn device<CS: OutputPin, DELAY>(
+        &self,
+        cs: CS,
+        delay: DELAY,
+    ) -> Result<SharedSpiDevice<'_, M, BUS, CS, DELAY>, CS::Error> {
+        SharedSpiDevice::new(self, cs, delay, false)
+    }
+
+    // A device that keeps going while the bus is reserved, for the panel
+    pub fn priority_device<CS: OutputPin, DELAY>(
+        &self,
+        cs: CS,
+        delay: DELAY,
+    ) -> Result<SharedSpiDevice<'_, M, BUS, CS, DELAY>, CS::Error> {
+        SharedSpiDevice::new(self, cs, delay, true)
+    }
+
+    // Holds off non-priority devices until the reservation is dropped
+    pub fn reserve(&self) -> Reservation<'_> {
+        self.reservations.fetch_add(1, Ordering::AcqRel);
+        Reservation {
+            reservations: &self.reservations,
+        }
+    }
+
+    fn is_reserved(&self) -> bool {
+        self.reservations.load(Ordering::Acquire) > 0
+    }
+}
+
+pub struct Reservation<'a> {
+    reservations: &'a AtomicUsize,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.reservations.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+pub struct SharedSpiDevice<'a, M: RawMutex, BUS, CS, DELAY> {
+    manager: &'a SpiBusManager<M, BUS>,
+    cs: CS,
+    delay: DELAY,
+    priority: bool,
+}
+
+impl<'a, M: RawMutex, BUS, CS: OutputPin, DELAY> SharedSpiDevice<'a, M, BUS, CS, DELAY> {
+    fn new(
+        manager: &'a SpiBusManager<M, BUS>,
+        mut cs: CS,
+        delay: DELAY,
+        priority: bool,
+    ) -> Result<Self, CS::Error> {
+        cs.set_high()?;
+        Ok(SharedSpiDevice {
+            manager,
+            cs,
+            delay,
+            priority,
+        })
+    }
+}
+
+impl<M, BUS, CS, DELAY> ErrorType for SharedSpiDevice<'_, M, BUS, CS, DELAY>
+where
+    M: RawMutex,
+    BUS: ErrorType,
+    CS: OutputPin,
+{
+    type Error = DeviceError<BUS::Error, CS::Error>;
+}
+
+impl<M, BUS, CS, DELAY> SpiDevice for SharedSpiDevice<'_, M, BUS, CS, DELAY>
+where
+    M: RawMutex,
+    BUS: SpiBus,
+    CS: OutputPin,
+    DELAY: DelayNs,
+{
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        let mut bus = loop {
+            if self.priority || !self.manager.is_reserved() {
+                let bus = self.manager.bus.lock().await;
+                // Could have been reserved while waiting for the lock
+                if self.priority || !self.manager.is_reserved() {
+                    break bus;
+                }
+            }
+            yield_now().await;
+        };
+
+        self.cs.set_low().map_err(DeviceError::Cs)?;
+        let mut result = Ok(());
+        for operation in operations {
+            result = match operation {
+                Operation::Read(buf) => bus.read(buf).await,
+                Operation::Write(buf) => bus.write(buf).await,
+                Operation::Transfer(read, write) => bus.transfer(read, write).await,
+                Operation::TransferInPlace(buf) => bus.transfer_in_place(buf).await,
+                Operation::DelayNs(ns) => match bus.flush().await {
+                    Ok(()) => {
+                        self.delay.delay_ns(*ns).await;
+                        Ok(())
+                    }
+                    err => err,
+                },
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        // Always release CS, even when the bus failed
+        let flush_result = bus.flush().await;
+        let cs_result = self.cs.set_high();
+        result.and(flush_result).map_err(DeviceError::Spi)?;
+        cs_result.map_err(DeviceError::Cs)
+    }
+}
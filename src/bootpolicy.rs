@@ -0,0 +1,113 @@
+/*
+ * What to do depending on why the device is running: woken up by the timer, by one of the buttons,
+ * or just powered on. The table is a plain struct so a build can change it, e.g. make the timer
+ * always show the first page, or skip the splash screen.
+ *
+ * The cause itself is worked out by the firmware from the reset reason, the wake-up source and
+ * which button woke the device, none of which this crate knows about.
+ */
+use crate::rtcstate::NextWake;
+use crate::spectra6::Spectra6Color;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::mono_font::ascii::FONT_10X20;
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::{Alignment, Text};
+
+// Pixels between the baselines of the splash screen's lines
+const LINE_HEIGHT: i32 = 30;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BootCause {
+    // Power applied, not a wake-up from deep sleep
+    PowerOn,
+    Timer,
+    RefreshButton,
+    NextButton,
+    PreviousButton,
+    // Software or watchdog reset
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BootAction {
+    RefreshCurrent,
+    NextPage,
+    PreviousPage,
+    // Show the splash screen (or how to set the device up), then the current page
+    Splash,
+}
+
+impl BootAction {
+    // How far to move through the image URLs
+    pub fn page_step(&self) -> isize {
+        match self {
+            Self::NextPage => 1,
+            Self::PreviousPage => -1,
+            Self::RefreshCurrent | Self::Splash => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BootPolicy {
+    pub power_on: BootAction,
+    pub timer: BootAction,
+    pub refresh_button: BootAction,
+    pub next_button: BootAction,
+    pub previous_button: BootAction,
+    pub other: BootAction,
+}
+
+impl BootPolicy {
+    // With a single image URL the next page is the same one, with several it's a slideshow
+    pub const DEFAULT: BootPolicy = BootPolicy {
+        power_on: BootAction::Splash,
+        timer: BootAction::NextPage,
+        refresh_button: BootAction::RefreshCurrent,
+        next_button: BootAction::NextPage,
+        previous_button: BootAction::PreviousPage,
+        other: BootAction::RefreshCurrent,
+    };
+
+    // next_wake is what the device went to sleep for, from RtcState
+    pub fn action(&self, cause: BootCause, next_wake: NextWake) -> BootAction {
+        match cause {
+            /*
+             * Only a regular refresh moves on. After a failure, a low battery or waiting for
+             * settings, the page that should have been shown gets its turn instead of being
+             * skipped.
+             */
+            BootCause::Timer if next_wake != NextWake::Refresh => BootAction::RefreshCurrent,
+            BootCause::Timer => self.timer,
+            BootCause::PowerOn => self.power_on,
+            BootCause::RefreshButton => self.refresh_button,
+            BootCause::NextButton => self.next_button,
+            BootCause::PreviousButton => self.previous_button,
+            BootCause::Other => self.other,
+        }
+    }
+}
+
+impl Default for BootPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+// The device name with lines below it, e.g. how to set it up, centered on the target
+pub fn draw_splash<D: DrawTarget<Color = Spectra6Color>>(
+    target: &mut D,
+    title: &str,
+    lines: &[&str],
+) -> Result<(), D::Error> {
+    let bounds = target.bounding_box();
+    let top = bounds.center() - Point::new(0, (lines.len() as i32 + 2) * LINE_HEIGHT / 2);
+    let title_style = MonoTextStyle::new(&FONT_10X20, Spectra6Color::Red);
+    Text::with_alignment(title, top, title_style, Alignment::Center).draw(target)?;
+    let style = MonoTextStyle::new(&FONT_10X20, Spectra6Color::Black);
+    for (index, line) in lines.iter().enumerate() {
+        let position = top + Point::new(0, (index as i32 + 2) * LINE_HEIGHT);
+        Text::with_alignment(line, position, style, Alignment::Center).draw(target)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+/*
+ * Keeps a server mistake, like serving a 4000x3000 photo, from running the device out of memory.
+ * The limit is advertised with the request so a server can scale down for us, and the dimensions
+ * in the image header are checked before anything gets decoded.
+ */
+use crate::qoi::qoi_dimensions;
+use arrayvec::ArrayString;
+use core::fmt::Write;
+use embedded_graphics::pixelcolor::Rgb888;
+
+// Request header with the largest decoded image (width * height) the device accepts
+pub const MAX_PIXELS_HEADER: &str = "X-Max-Image-Pixels";
+
+// Decoding holds the RGBA output and the RgbImage converted from it at the same time
+pub const BYTES_PER_PIXEL: usize = 4 + core::mem::size_of::<Rgb888>();
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BudgetError {
+    // Not an image header we know
+    UnknownFormat,
+    TooLarge { width: u32, height: u32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DecodeBudget {
+    pub max_pixels: u32,
+}
+
+impl DecodeBudget {
+    pub const fn new(max_pixels: u32) -> Self {
+        DecodeBudget { max_pixels }
+    }
+
+    // Budget for decoding in at most bytes of memory
+    pub const fn from_memory(bytes: usize) -> Self {
+        Self::new((bytes / BYTES_PER_PIXEL) as u32)
+    }
+
+    pub fn check(&self, width: u32, height: u32) -> Result<(), BudgetError> {
+        if width as u64 * height as u64 > self.max_pixels as u64 {
+            Err(BudgetError::TooLarge { width, height })
+        } else {
+            Ok(())
+        }
+    }
+
+    // Only looks at the header, so this is cheap enough to do on every download
+    pub fn check_png(&self, data: &[u8]) -> Result<(), BudgetError> {
+        let (width, height) = png_dimensions(data).ok_or(BudgetError::UnknownFormat)?;
+        self.check(width, height)
+    }
+
+    pub fn check_jpeg(&self, data: &[u8]) -> Result<(), BudgetError> {
+        let (width, height) = jpeg_dimensions(data).ok_or(BudgetError::UnknownFormat)?;
+        self.check(width, height)
+    }
+
+    // Any format there's a header check for
+    pub fn check_image(&self, data: &[u8]) -> Result<(), BudgetError> {
+        let (width, height) = png_dimensions(data)
+            .or_else(|| jpeg_dimensions(data))
+            .or_else(|| qoi_dimensions(data))
+            .ok_or(BudgetError::UnknownFormat)?;
+        self.check(width, height)
+    }
+
+    pub fn request_headers(&self) -> BudgetHeaders {
+        let mut max_pixels = ArrayString::new();
+        // u32 always fits in 10 digits
+        let _ = write!(max_pixels, "{}", self.max_pixels);
+        BudgetHeaders { max_pixels }
+    }
+}
+
+// Owns the formatted values, as the HTTP client only borrows them
+pub struct BudgetHeaders {
+    max_pixels: ArrayString<10>,
+}
+
+impl BudgetHeaders {
+    pub fn as_headers(&self) -> [(&str, &str); 1] {
+        [(MAX_PIXELS_HEADER, self.max_pixels.as_str())]
+    }
+}
+
+// Width and height from the IHDR chunk, which has to come right after the signature
+pub fn png_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") || data.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(data.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(data.get(20..24)?.try_into().ok()?);
+    Some((width, height))
+}
+
+// Width and height from the first start-of-frame segment, which has to come before the scan data
+pub fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut position = 2;
+    loop {
+        if *data.get(position)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(position + 1)?;
+        match marker {
+            // Fill byte before a marker
+            0xFF => position += 1,
+            // Markers without a length
+            0x01 | 0xD0..=0xD7 => position += 2,
+            // Start of scan without a frame header before it
+            0xDA => return None,
+            // Start of frame, except DHT, JPG and DAC that share the range
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                let height =
+                    u16::from_be_bytes(data.get(position + 5..position + 7)?.try_into().ok()?);
+                let width =
+                    u16::from_be_bytes(data.get(position + 7..position + 9)?.try_into().ok()?);
+                return Some((width as u32, height as u32));
+            }
+            _ => {
+                let length =
+                    u16::from_be_bytes(data.get(position + 2..position + 4)?.try_into().ok()?);
+                position += 2 + length as usize;
+            }
+        }
+    }
+}
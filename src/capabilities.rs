@@ -0,0 +1,66 @@
+/*
+ * Which optional features this build has, so a server (or fleet tooling) can tell differently
+ * built devices apart instead of guessing, e.g. only offer delta updates to devices that can apply
+ * them. Sent as a request header with every download, and logged at boot.
+ */
+use arrayvec::ArrayString;
+use core::fmt::{Display, Formatter, Write};
+
+// Request header with the enabled features, comma separated
+pub const FEATURES_HEADER: &str = "X-Device-Features";
+
+// Every Cargo feature, and whether this build has it
+pub const FEATURES: &[(&str, bool)] = &[
+    ("ble-provisioning", cfg!(feature = "ble-provisioning")),
+    ("captive-portal", cfg!(feature = "captive-portal")),
+    ("delta-ota", cfg!(feature = "delta-ota")),
+    ("frame-push", cfg!(feature = "frame-push")),
+    ("http-upload", cfg!(feature = "http-upload")),
+    ("jpeg", cfg!(feature = "jpeg")),
+    ("mdns", cfg!(feature = "mdns")),
+    ("mqtt", cfg!(feature = "mqtt")),
+    ("stack-usage", cfg!(feature = "stack-usage")),
+    ("std", cfg!(feature = "std")),
+    ("trace-spi", cfg!(feature = "trace-spi")),
+    ("waveshare", cfg!(feature = "waveshare")),
+];
+
+pub fn enabled_features() -> impl Iterator<Item = &'static str> {
+    FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+}
+
+// Comma separated, the same as in the header
+pub struct EnabledFeatures;
+
+impl Display for EnabledFeatures {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        for (index, name) in enabled_features().enumerate() {
+            if index > 0 {
+                f.write_char(',')?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn features_header() -> FeaturesHeader {
+    let mut value = ArrayString::new();
+    // All names together are well below the capacity
+    let _ = write!(value, "{}", EnabledFeatures);
+    FeaturesHeader { value }
+}
+
+// Owns the formatted value, as the HTTP client only borrows it
+pub struct FeaturesHeader {
+    value: ArrayString<128>,
+}
+
+impl FeaturesHeader {
+    pub fn as_header(&self) -> (&str, &str) {
+        (FEATURES_HEADER, self.value.as_str())
+    }
+}
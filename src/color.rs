@@ -0,0 +1,77 @@
+/*
+ * Color space conversions for the dithering side. sRGB is what images come in, linear light is
+ * what error should be diffused in, and Oklab is where distances match what people see.
+ */
+use num_traits::Float;
+
+// 8-bit sRGB to linear light, 0.0 to 1.0
+pub fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        Float::powf((value + 0.055) / 1.055, 2.4)
+    }
+}
+
+// Linear light (clamped to 0.0 to 1.0) back to 8-bit sRGB, rounded
+pub fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * Float::powf(value, 1.0 / 2.4) - 0.055
+    };
+    Float::round(value * 255.0) as u8
+}
+
+// srgb_to_linear for every 8-bit value, as powf per pixel channel is too slow
+pub struct LinearLut([f32; 256]);
+
+impl Default for LinearLut {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinearLut {
+    pub fn new() -> Self {
+        LinearLut(core::array::from_fn(|value| srgb_to_linear(value as u8)))
+    }
+
+    pub fn get(&self, value: u8) -> f32 {
+        self.0[value as usize]
+    }
+
+    pub fn rgb(&self, [r, g, b]: [u8; 3]) -> [f32; 3] {
+        [self.get(r), self.get(g), self.get(b)]
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Oklab {
+    pub l: f32,
+    pub a: f32,
+    pub b: f32,
+}
+
+impl Oklab {
+    // From linear sRGB, see https://bottosson.github.io/posts/oklab/
+    #[allow(clippy::excessive_precision)]
+    pub fn from_linear([r, g, b]: [f32; 3]) -> Self {
+        let l = 0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b;
+        let m = 0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b;
+        let s = 0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b;
+        let (l, m, s) = (Float::cbrt(l), Float::cbrt(m), Float::cbrt(s));
+        Oklab {
+            l: 0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+            a: 1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+            b: 0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+        }
+    }
+
+    pub fn distance_squared(&self, other: &Oklab) -> f32 {
+        let (dl, da, db) = (self.l - other.l, self.a - other.a, self.b - other.b);
+        dl * dl + da * da + db * db
+    }
+}
@@ -0,0 +1,341 @@
+/*
+ * Per-device settings in flash, so one firmware image can be provisioned for any network and
+ * image server: Wi-Fi credentials, the image URL and how long to sleep between refreshes. They live
+ * in the NVS partition, though not in ESP-IDF's NVS format, nothing else on the device reads them.
+ *
+ * Two erase sectors are used in turn. A save goes to the one not holding the current settings,
+ * with a higher sequence number, so a power cut halfway leaves the previous settings intact. Load
+ * takes the newest copy with a valid CRC. All numbers are little endian.
+ *
+ *   magic "RCFG", sequence (u32), entries length (u16), reserved (0), CRC-32 of entries, entries
+ *   entry: key (u8), value length (u16), value
+ *
+ * Unknown keys are skipped, so settings saved by newer firmware still load. The image URL key
+ * appears once per URL when there's a slideshow of several.
+ */
+use crate::framepush::crc32;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use embassy_time::Duration;
+use embedded_storage::nor_flash::NorFlash;
+
+pub const MAGIC: &[u8; 4] = b"RCFG";
+pub const HEADER_SIZE: usize = 16;
+// First sector of the default partition table's "nvs" partition, two sectors are used. Only for
+// when the flashed partition table has no nvs partition to take the offset from.
+pub const DEFAULT_OFFSET: u32 = 0x9000;
+
+pub const DEFAULT_SLEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// Shorter wears out the battery, longer than a day is probably a mistake
+pub const MIN_SLEEP_INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_SLEEP_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// Images in a slideshow, all of them have to fit in a flash sector too
+pub const MAX_IMAGE_URLS: usize = 16;
+
+const KEY_SSID: u8 = 1;
+const KEY_PASSWORD: u8 = 2;
+const KEY_IMAGE_URL: u8 = 3;
+const KEY_SLEEP_INTERVAL: u8 = 4;
+
+// Value that isn't allowed for the setting, like a 40 character SSID
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InvalidSetting(pub &'static str);
+
+pub enum ConfigError<E> {
+    FlashError(E),
+    // All settings together don't fit in a sector
+    TooLarge,
+}
+
+impl<E: Debug> Debug for ConfigError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::FlashError(x) => write!(f, "FlashError({:?})", x),
+            Self::TooLarge => write!(f, "TooLarge"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Config {
+    ssid: String,
+    password: String,
+    // Shown in turn, one per timer wake-up
+    image_urls: Vec<String>,
+    sleep_interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            ssid: String::new(),
+            password: String::new(),
+            image_urls: Vec::new(),
+            sleep_interval: DEFAULT_SLEEP_INTERVAL,
+        }
+    }
+}
+
+impl Config {
+    // Enough to connect and fetch an image
+    pub fn is_provisioned(&self) -> bool {
+        !self.ssid.is_empty() && !self.image_urls.is_empty()
+    }
+
+    pub fn ssid(&self) -> &str {
+        &self.ssid
+    }
+
+    pub fn set_ssid(&mut self, ssid: &str) -> Result<(), InvalidSetting> {
+        if ssid.is_empty() || ssid.len() > 32 {
+            return Err(InvalidSetting("SSID has to be 1 to 32 bytes"));
+        }
+        self.ssid = ssid.into();
+        Ok(())
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    // Empty for an open network
+    pub fn set_password(&mut self, password: &str) -> Result<(), InvalidSetting> {
+        if !password.is_empty() && !(8..=64).contains(&password.len()) {
+            return Err(InvalidSetting("password has to be 8 to 64 characters"));
+        }
+        self.password = password.into();
+        Ok(())
+    }
+
+    // The first one, empty if there's none
+    pub fn image_url(&self) -> &str {
+        self.image_urls.first().map_or("", |url| url.as_str())
+    }
+
+    pub fn image_urls(&self) -> &[String] {
+        &self.image_urls
+    }
+
+    // Slideshow position, wraps around. Empty if there's no URL.
+    pub fn image_url_at(&self, index: usize) -> &str {
+        match self.image_urls.len() {
+            0 => "",
+            count => &self.image_urls[index % count],
+        }
+    }
+
+    // Just this one, no slideshow
+    pub fn set_image_url(&mut self, url: &str) -> Result<(), InvalidSetting> {
+        self.set_image_urls([url])
+    }
+
+    // All or nothing, nothing changes if one of them is invalid
+    pub fn set_image_urls<'t>(
+        &mut self,
+        urls: impl IntoIterator<Item = &'t str>,
+    ) -> Result<(), InvalidSetting> {
+        let mut image_urls = Vec::new();
+        for url in urls {
+            if image_urls.len() == MAX_IMAGE_URLS {
+                return Err(InvalidSetting("too many image URLs"));
+            }
+            check_image_url(url)?;
+            image_urls.push(url.into());
+        }
+        if image_urls.is_empty() {
+            return Err(InvalidSetting("no image URL"));
+        }
+        self.image_urls = image_urls;
+        Ok(())
+    }
+
+    pub fn add_image_url(&mut self, url: &str) -> Result<(), InvalidSetting> {
+        if self.image_urls.len() == MAX_IMAGE_URLS {
+            return Err(InvalidSetting("too many image URLs"));
+        }
+        check_image_url(url)?;
+        self.image_urls.push(url.into());
+        Ok(())
+    }
+
+    pub fn sleep_interval(&self) -> Duration {
+        self.sleep_interval
+    }
+
+    pub fn set_sleep_interval(&mut self, interval: Duration) -> Result<(), InvalidSetting> {
+        if !(MIN_SLEEP_INTERVAL..=MAX_SLEEP_INTERVAL).contains(&interval) {
+            return Err(InvalidSetting("sleep interval out of range"));
+        }
+        self.sleep_interval = interval;
+        Ok(())
+    }
+
+    fn to_entries(&self) -> Vec<u8> {
+        let mut entries = Vec::new();
+        let mut put = |key: u8, value: &[u8]| {
+            entries.push(key);
+            entries.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            entries.extend_from_slice(value);
+        };
+        put(KEY_SSID, self.ssid.as_bytes());
+        put(KEY_PASSWORD, self.password.as_bytes());
+        for url in &self.image_urls {
+            put(KEY_IMAGE_URL, url.as_bytes());
+        }
+        let seconds = self.sleep_interval.as_secs() as u32;
+        put(KEY_SLEEP_INTERVAL, &seconds.to_le_bytes());
+        entries
+    }
+
+    // Values that don't pass the setters are left at their default
+    fn from_entries(mut entries: &[u8]) -> Self {
+        let mut config = Config::default();
+        while let [key, low, high, rest @ ..] = entries {
+            let length = u16::from_le_bytes([*low, *high]) as usize;
+            let Some((value, rest)) = rest.split_at_checked(length) else {
+                break;
+            };
+            entries = rest;
+            let text = core::str::from_utf8(value).ok();
+            let _ = match (*key, text) {
+                (KEY_SSID, Some(ssid)) => config.set_ssid(ssid),
+                (KEY_PASSWORD, Some(password)) => config.set_password(password),
+                (KEY_IMAGE_URL, Some(url)) => config.add_image_url(url),
+                (KEY_SLEEP_INTERVAL, _) => match value.try_into() {
+                    Ok(seconds) => config.set_sleep_interval(Duration::from_secs(
+                        u32::from_le_bytes(seconds) as u64,
+                    )),
+                    Err(_) => Ok(()),
+                },
+                _ => Ok(()),
+            };
+        }
+        config
+    }
+}
+
+fn check_image_url(url: &str) -> Result<(), InvalidSetting> {
+    let is_http = url
+        .get(..7)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("http://"));
+    if !is_http && !crate::tls::is_https(url) {
+        return Err(InvalidSetting("image URL has to be http:// or https://"));
+    }
+    Ok(())
+}
+
+pub struct ConfigStore<F> {
+    flash: F,
+    offset: u32,
+}
+
+// Sector and sequence number of the newest saved settings
+struct Saved {
+    sector: u32,
+    sequence: u32,
+    config: Config,
+}
+
+impl<F: NorFlash> ConfigStore<F> {
+    // offset is the start of two erase sectors to keep the settings in
+    pub fn new(flash: F, offset: u32) -> Self {
+        ConfigStore { flash, offset }
+    }
+
+    // None if nothing was ever saved
+    pub fn load(&mut self) -> Result<Option<Config>, ConfigError<F::Error>> {
+        Ok(self.newest()?.map(|saved| saved.config))
+    }
+
+    pub fn save(&mut self, config: &Config) -> Result<(), ConfigError<F::Error>> {
+        let entries = config.to_entries();
+        if HEADER_SIZE + entries.len() > F::ERASE_SIZE {
+            return Err(ConfigError::TooLarge);
+        }
+        let (sector, sequence) = match self.newest()? {
+            Some(saved) => (1 - saved.sector, saved.sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let mut record = Vec::with_capacity(HEADER_SIZE + entries.len());
+        record.extend_from_slice(MAGIC);
+        record.extend_from_slice(&sequence.to_le_bytes());
+        record.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        record.extend_from_slice(&[0, 0]);
+        record.extend_from_slice(&crc32(&entries).to_le_bytes());
+        record.extend_from_slice(&entries);
+        // Erased flash reads as 0xFF, pad with that to the write size
+        record.resize(record.len().next_multiple_of(Self::ALIGN), 0xFF);
+        let start = self.sector_offset(sector);
+        self.flash
+            .erase(start, start + F::ERASE_SIZE as u32)
+            .map_err(ConfigError::FlashError)?;
+        self.flash
+            .write(start, &record)
+            .map_err(ConfigError::FlashError)
+    }
+
+    // Back to defaults (and whatever the firmware was built with)
+    pub fn erase(&mut self) -> Result<(), ConfigError<F::Error>> {
+        self.flash
+            .erase(self.offset, self.sector_offset(2))
+            .map_err(ConfigError::FlashError)
+    }
+
+    const ALIGN: usize = if F::READ_SIZE > F::WRITE_SIZE {
+        F::READ_SIZE
+    } else {
+        F::WRITE_SIZE
+    };
+
+    fn sector_offset(&self, sector: u32) -> u32 {
+        self.offset + sector * F::ERASE_SIZE as u32
+    }
+
+    fn newest(&mut self) -> Result<Option<Saved>, ConfigError<F::Error>> {
+        let mut newest: Option<Saved> = None;
+        for sector in 0..2 {
+            let Some((sequence, config)) = self.read_sector(sector)? else {
+                continue;
+            };
+            // Sequence numbers wrap, newer is less than half the range ahead
+            let is_newer = newest
+                .as_ref()
+                .is_none_or(|saved| (sequence.wrapping_sub(saved.sequence) as i32) > 0);
+            if is_newer {
+                newest = Some(Saved {
+                    sector,
+                    sequence,
+                    config,
+                });
+            }
+        }
+        Ok(newest)
+    }
+
+    fn read_sector(&mut self, sector: u32) -> Result<Option<(u32, Config)>, ConfigError<F::Error>> {
+        let start = self.sector_offset(sector);
+        let mut header = [0u8; HEADER_SIZE];
+        self.flash
+            .read(start, &mut header)
+            .map_err(ConfigError::FlashError)?;
+        if &header[..4] != MAGIC {
+            return Ok(None);
+        }
+        let sequence = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let length = u16::from_le_bytes([header[8], header[9]]) as usize;
+        let crc = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+        if HEADER_SIZE + length > F::ERASE_SIZE {
+            return Ok(None);
+        }
+        let mut entries = alloc::vec![0u8; length.next_multiple_of(Self::ALIGN)];
+        self.flash
+            .read(start + HEADER_SIZE as u32, &mut entries)
+            .map_err(ConfigError::FlashError)?;
+        entries.truncate(length);
+        if crc32(&entries) != crc {
+            return Ok(None);
+        }
+        Ok(Some((sequence, Config::from_entries(&entries))))
+    }
+}
@@ -0,0 +1,233 @@
+/*
+ * Text on the panel without an image server: titles, wrapped paragraphs and label/value rows,
+ * laid out top to bottom in ProFont. Works on any Spectra6 DrawTarget, usually a white
+ * Spectra6Framebuffer that then goes to the panel with update_frame_raw.
+ *
+ *   let mut dashboard = Dashboard::new(&mut frame, DashboardStyle::DEFAULT);
+ *   dashboard.title("Living room")?;
+ *   dashboard.value("Temperature", "21.5 C")?;
+ *   dashboard.paragraph("Window open in the kitchen")?;
+ *
+ * Mono fonts only have ASCII, anything else shows as '?'. Lines that don't fit below the last one
+ * are left out, is_full says when that happens.
+ */
+use crate::spectra6::Spectra6Color;
+use alloc::vec::Vec;
+use embedded_graphics::mono_font::{MonoFont, MonoTextStyle};
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Alignment, Baseline, Text, TextStyleBuilder};
+
+#[derive(Clone, Copy)]
+pub struct DashboardStyle {
+    pub title_font: &'static MonoFont<'static>,
+    pub body_font: &'static MonoFont<'static>,
+    pub text_color: Spectra6Color,
+    // Titles, and the line under them
+    pub accent_color: Spectra6Color,
+    // Space around the dashboard, in pixels
+    pub margin: u32,
+    // Extra space between lines, in pixels
+    pub line_spacing: u32,
+}
+
+impl DashboardStyle {
+    // Readable from across a room on the 800x480 panel
+    pub const DEFAULT: DashboardStyle = DashboardStyle {
+        title_font: &profont::PROFONT_24_POINT,
+        body_font: &profont::PROFONT_18_POINT,
+        text_color: Spectra6Color::Black,
+        accent_color: Spectra6Color::Red,
+        margin: 24,
+        line_spacing: 6,
+    };
+}
+
+impl Default for DashboardStyle {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+pub struct Dashboard<'t, D> {
+    target: &'t mut D,
+    style: DashboardStyle,
+    area: Rectangle,
+    // Top of the next line
+    y: i32,
+    full: bool,
+}
+
+impl<'t, D: DrawTarget<Color = Spectra6Color>> Dashboard<'t, D> {
+    pub fn new(target: &'t mut D, style: DashboardStyle) -> Self {
+        let area = target.bounding_box().offset(-(style.margin as i32));
+        Dashboard {
+            target,
+            style,
+            area,
+            y: area.top_left.y,
+            full: false,
+        }
+    }
+
+    // Whether a line was left out for lack of space
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    // What's left below the last line, to draw something else into
+    pub fn remaining(&self) -> Rectangle {
+        let bottom = self.area.top_left.y + self.area.size.height as i32;
+        Rectangle::new(
+            Point::new(self.area.top_left.x, self.y),
+            Size::new(self.area.size.width, (bottom - self.y).max(0) as u32),
+        )
+    }
+
+    // Empty space, e.g. between sections
+    pub fn gap(&mut self, pixels: u32) {
+        self.y += pixels as i32;
+    }
+
+    // In the title font and accent color, wrapped, with a line under it
+    pub fn title(&mut self, text: &str) -> Result<(), D::Error> {
+        let style = MonoTextStyle::new(self.style.title_font, self.style.accent_color);
+        for line in wrap(text, self.columns(self.style.title_font)) {
+            self.line(line, style)?;
+        }
+        if self.fits(3) {
+            let left = self.area.top_left.x;
+            let right = left + self.area.size.width as i32 - 1;
+            Line::new(Point::new(left, self.y), Point::new(right, self.y))
+                .into_styled(PrimitiveStyle::with_stroke(self.style.accent_color, 3))
+                .draw(self.target)?;
+        }
+        self.gap(3 + self.style.line_spacing * 2);
+        Ok(())
+    }
+
+    // Body text, wrapped at spaces and newlines
+    pub fn paragraph(&mut self, text: &str) -> Result<(), D::Error> {
+        let style = MonoTextStyle::new(self.style.body_font, self.style.text_color);
+        for line in wrap(text, self.columns(self.style.body_font)) {
+            self.line(line, style)?;
+        }
+        Ok(())
+    }
+
+    // The label on the left, the value right-aligned, the label cut short if both don't fit
+    pub fn value(&mut self, label: &str, value: &str) -> Result<(), D::Error> {
+        let font = self.style.body_font;
+        let style = MonoTextStyle::new(font, self.style.text_color);
+        if !self.fits(font.character_size.height) {
+            self.full = true;
+            return Ok(());
+        }
+        let value_columns = value.chars().count();
+        // At least one space between the two
+        let label_columns = self.columns(font).saturating_sub(value_columns + 1);
+        let label = match label.char_indices().nth(label_columns) {
+            Some((end, _)) => &label[..end],
+            None => label,
+        };
+        draw_text(self.target, label, self.left_edge(), style, Alignment::Left)?;
+        draw_text(
+            self.target,
+            value,
+            self.right_edge(),
+            style,
+            Alignment::Right,
+        )?;
+        self.advance(font);
+        Ok(())
+    }
+
+    // Characters of font that fit across
+    fn columns(&self, font: &MonoFont) -> usize {
+        let advance = font.character_size.width + font.character_spacing;
+        (self.area.size.width / advance.max(1)) as usize
+    }
+
+    fn fits(&self, height: u32) -> bool {
+        self.y + height as i32 <= self.area.top_left.y + self.area.size.height as i32
+    }
+
+    fn left_edge(&self) -> Point {
+        Point::new(self.area.top_left.x, self.y)
+    }
+
+    // Right-aligned text ends on this column
+    fn right_edge(&self) -> Point {
+        Point::new(
+            self.area.top_left.x + self.area.size.width as i32 - 1,
+            self.y,
+        )
+    }
+
+    fn advance(&mut self, font: &MonoFont) {
+        self.y += (font.character_size.height + self.style.line_spacing) as i32;
+    }
+
+    fn line(
+        &mut self,
+        text: &str,
+        style: MonoTextStyle<'_, Spectra6Color>,
+    ) -> Result<(), D::Error> {
+        let font = style.font;
+        if !self.fits(font.character_size.height) {
+            self.full = true;
+            return Ok(());
+        }
+        draw_text(self.target, text, self.left_edge(), style, Alignment::Left)?;
+        self.advance(font);
+        Ok(())
+    }
+}
+
+// One line with its top at position, which is the left or right end depending on alignment
+pub fn draw_text<D: DrawTarget<Color = Spectra6Color>>(
+    target: &mut D,
+    text: &str,
+    position: Point,
+    style: MonoTextStyle<'_, Spectra6Color>,
+    alignment: Alignment,
+) -> Result<(), D::Error> {
+    let text_style = TextStyleBuilder::new()
+        .alignment(alignment)
+        .baseline(Baseline::Top)
+        .build();
+    Text::with_text_style(text, position, style, text_style).draw(target)?;
+    Ok(())
+}
+
+/*
+ * Lines of at most columns characters, broken at the last space that fits. Words longer than a
+ * line are broken wherever the line ends. Newlines start a new line, empty lines are kept.
+ */
+pub fn wrap(text: &str, columns: usize) -> Vec<&str> {
+    let columns = columns.max(1);
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut rest = paragraph.trim_end();
+        if rest.is_empty() {
+            lines.push(rest);
+        }
+        while !rest.is_empty() {
+            let Some((end, _)) = rest.char_indices().nth(columns) else {
+                lines.push(rest);
+                break;
+            };
+            let split = if rest[end..].starts_with(' ') {
+                end
+            } else {
+                match rest[..end].rfind(' ') {
+                    Some(space) if space > 0 => space,
+                    _ => end,
+                }
+            };
+            lines.push(rest[..split].trim_end());
+            rest = rest[split..].trim_start();
+        }
+    }
+    lines
+}
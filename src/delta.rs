@@ -0,0 +1,301 @@
+/*
+ * Delta firmware updates: instead of the full image, download a patch against the firmware that's
+ * running now, and rebuild the new image from both into the inactive OTA partition. Routine
+ * updates only change a small part of the image, so the patch is a fraction of the size.
+ *
+ * The patch is bsdiff's control/diff/extra stream, uncompressed (HTTP can compress it), with a
+ * header naming the exact base and target images:
+ *
+ *   magic "RDLT", base length (u32 LE), base SHA-256, target length (u32 LE), target SHA-256
+ *   then until the target is complete:
+ *     diff length (u32 LE), extra length (u32 LE), seek (i32 LE)
+ *     diff bytes: added (wrapping) to the base bytes at the current base position
+ *     extra bytes: copied as-is
+ *     the base position then moves on by diff length + seek
+ *
+ * The base is hashed before anything gets written. If it's not the image the patch was made for,
+ * apply fails with BaseMismatch and the caller should fall back to downloading the full image.
+ */
+use embedded_io_async::{ErrorKind, ErrorType, Read, ReadExactError, Write};
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use sha2::{Digest, Sha256};
+
+pub const MAGIC: &[u8; 4] = b"RDLT";
+pub const HEADER_LEN: usize = 4 + 4 + 32 + 4 + 32;
+
+const CHUNK: usize = 256;
+// Room to align base reads to READ_SIZE on both ends
+const MAX_READ_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PatchHeader {
+    pub base_len: u32,
+    pub base_sha256: [u8; 32],
+    pub target_len: u32,
+    pub target_sha256: [u8; 32],
+}
+
+pub enum DeltaError<P, B, W> {
+    // Not a patch, or cut off halfway
+    InvalidPatch,
+    // The running firmware isn't what the patch was made for, get the full image instead
+    BaseMismatch,
+    // Patch applied, but the result isn't the expected image. Don't boot it.
+    TargetMismatch,
+    PatchError(ReadExactError<P>),
+    BaseError(B),
+    WriteError(W),
+}
+
+impl<P, B, W> core::fmt::Debug for DeltaError<P, B, W>
+where
+    P: core::fmt::Debug,
+    B: core::fmt::Debug,
+    W: core::fmt::Debug,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidPatch => write!(f, "InvalidPatch"),
+            Self::BaseMismatch => write!(f, "BaseMismatch"),
+            Self::TargetMismatch => write!(f, "TargetMismatch"),
+            Self::PatchError(x) => write!(f, "PatchError({:?})", x),
+            Self::BaseError(x) => write!(f, "BaseError({:?})", x),
+            Self::WriteError(x) => write!(f, "WriteError({:?})", x),
+        }
+    }
+}
+
+impl PatchHeader {
+    pub fn parse(bytes: &[u8; HEADER_LEN]) -> Option<Self> {
+        if &bytes[0..4] != MAGIC {
+            return None;
+        }
+        Some(PatchHeader {
+            base_len: u32::from_le_bytes(bytes[4..8].try_into().ok()?),
+            base_sha256: bytes[8..40].try_into().ok()?,
+            target_len: u32::from_le_bytes(bytes[40..44].try_into().ok()?),
+            target_sha256: bytes[44..76].try_into().ok()?,
+        })
+    }
+}
+
+/*
+ * Rebuilds the target image from base (the running firmware) and patch into target, e.g. a
+ * PartitionWriter on the inactive OTA partition. Only mark that partition bootable when this
+ * returns Ok.
+ */
+pub async fn apply<P, B, W>(
+    patch: &mut P,
+    base: &mut B,
+    target: &mut W,
+) -> Result<PatchHeader, DeltaError<P::Error, B::Error, W::Error>>
+where
+    P: Read,
+    B: ReadNorFlash,
+    W: Write,
+{
+    let mut header = [0u8; HEADER_LEN];
+    patch
+        .read_exact(&mut header)
+        .await
+        .map_err(DeltaError::PatchError)?;
+    let header = PatchHeader::parse(&header).ok_or(DeltaError::InvalidPatch)?;
+    if header.base_len as usize > base.capacity() {
+        return Err(DeltaError::BaseMismatch);
+    }
+
+    let mut base_buffer = [0u8; CHUNK];
+    let mut base_hash = Sha256::new();
+    let mut position = 0u32;
+    while position < header.base_len {
+        let len = (header.base_len - position).min(CHUNK as u32) as usize;
+        read_base(base, position, &mut base_buffer[..len]).await?;
+        base_hash.update(&base_buffer[..len]);
+        position += len as u32;
+    }
+    if base_hash.finalize().as_slice() != header.base_sha256 {
+        return Err(DeltaError::BaseMismatch);
+    }
+
+    let mut patch_buffer = [0u8; CHUNK];
+    let mut target_hash = Sha256::new();
+    let mut written = 0u32;
+    let mut base_position: i64 = 0;
+    while written < header.target_len {
+        let mut control = [0u8; 12];
+        patch
+            .read_exact(&mut control)
+            .await
+            .map_err(DeltaError::PatchError)?;
+        let diff_len = u32::from_le_bytes(control[0..4].try_into().unwrap());
+        let extra_len = u32::from_le_bytes(control[4..8].try_into().unwrap());
+        let seek = i32::from_le_bytes(control[8..12].try_into().unwrap());
+        if diff_len as u64 + extra_len as u64 > (header.target_len - written) as u64
+            || base_position < 0
+            || base_position + diff_len as i64 > header.base_len as i64
+        {
+            return Err(DeltaError::InvalidPatch);
+        }
+
+        let mut remaining = diff_len;
+        while remaining > 0 {
+            let len = remaining.min(CHUNK as u32) as usize;
+            patch
+                .read_exact(&mut patch_buffer[..len])
+                .await
+                .map_err(DeltaError::PatchError)?;
+            read_base(base, base_position as u32, &mut base_buffer[..len]).await?;
+            for (out, base) in patch_buffer[..len].iter_mut().zip(&base_buffer[..len]) {
+                *out = out.wrapping_add(*base);
+            }
+            target_hash.update(&patch_buffer[..len]);
+            target
+                .write_all(&patch_buffer[..len])
+                .await
+                .map_err(DeltaError::WriteError)?;
+            base_position += len as i64;
+            remaining -= len as u32;
+        }
+
+        let mut remaining = extra_len;
+        while remaining > 0 {
+            let len = remaining.min(CHUNK as u32) as usize;
+            patch
+                .read_exact(&mut patch_buffer[..len])
+                .await
+                .map_err(DeltaError::PatchError)?;
+            target_hash.update(&patch_buffer[..len]);
+            target
+                .write_all(&patch_buffer[..len])
+                .await
+                .map_err(DeltaError::WriteError)?;
+            remaining -= len as u32;
+        }
+
+        written += diff_len + extra_len;
+        base_position += seek as i64;
+    }
+    target.flush().await.map_err(DeltaError::WriteError)?;
+    if target_hash.finalize().as_slice() != header.target_sha256 {
+        return Err(DeltaError::TargetMismatch);
+    }
+    Ok(header)
+}
+
+// Flash reads have to be aligned to READ_SIZE, patches don't care about that
+async fn read_base<B: ReadNorFlash, P, W>(
+    base: &mut B,
+    offset: u32,
+    out: &mut [u8],
+) -> Result<(), DeltaError<P, B::Error, W>> {
+    let read_size = B::READ_SIZE.max(1);
+    debug_assert!(read_size <= MAX_READ_SIZE);
+    let start = offset as usize / read_size * read_size;
+    let end = (offset as usize + out.len()).div_ceil(read_size) * read_size;
+    let mut scratch = [0u8; CHUNK + 2 * MAX_READ_SIZE];
+    base.read(start as u32, &mut scratch[..end - start])
+        .await
+        .map_err(DeltaError::BaseError)?;
+    let skip = offset as usize - start;
+    out.copy_from_slice(&scratch[skip..skip + out.len()]);
+    Ok(())
+}
+
+/*
+ * The inactive OTA partition as the target for apply. Sectors are erased as the image reaches
+ * them, and writes go out in whole CHUNKs, flush pads the last one with 0xFF. Flush only once, at
+ * the end, apply does that.
+ */
+pub struct PartitionWriter<F> {
+    partition: F,
+    position: u32,
+    erased: u32,
+    buffer: [u8; CHUNK],
+    buffered: usize,
+}
+
+#[derive(Debug)]
+pub enum PartitionWriteError<E> {
+    FlashError(E),
+    // The image doesn't fit in the partition
+    Full,
+}
+
+impl<E: core::fmt::Debug> embedded_io_async::Error for PartitionWriteError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::FlashError(_) => ErrorKind::Other,
+            Self::Full => ErrorKind::OutOfMemory,
+        }
+    }
+}
+
+impl<F: NorFlash> PartitionWriter<F> {
+    // partition starts at 0, e.g. a region of the flash for the next OTA slot
+    pub fn new(partition: F) -> Self {
+        debug_assert!(CHUNK.is_multiple_of(F::WRITE_SIZE.max(1)));
+        PartitionWriter {
+            partition,
+            position: 0,
+            erased: 0,
+            buffer: [0xFF; CHUNK],
+            buffered: 0,
+        }
+    }
+
+    // Bytes written to flash so far, not counting what's still buffered
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    pub fn into_inner(self) -> F {
+        self.partition
+    }
+
+    async fn write_buffer(&mut self, len: usize) -> Result<(), PartitionWriteError<F::Error>> {
+        let end = self.position + len as u32;
+        if end as usize > self.partition.capacity() {
+            return Err(PartitionWriteError::Full);
+        }
+        while self.erased < end {
+            let sector_end = self.erased + F::ERASE_SIZE as u32;
+            self.partition
+                .erase(self.erased, sector_end)
+                .await
+                .map_err(PartitionWriteError::FlashError)?;
+            self.erased = sector_end;
+        }
+        self.partition
+            .write(self.position, &self.buffer[..len])
+            .await
+            .map_err(PartitionWriteError::FlashError)?;
+        self.position = end;
+        self.buffered = 0;
+        Ok(())
+    }
+}
+
+impl<F: NorFlash> ErrorType for PartitionWriter<F> {
+    type Error = PartitionWriteError<F::Error>;
+}
+
+impl<F: NorFlash> Write for PartitionWriter<F> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let len = buf.len().min(CHUNK - self.buffered);
+        self.buffer[self.buffered..self.buffered + len].copy_from_slice(&buf[..len]);
+        self.buffered += len;
+        if self.buffered == CHUNK {
+            self.write_buffer(CHUNK).await?;
+        }
+        Ok(len)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        if self.buffered == 0 {
+            return Ok(());
+        }
+        let len = self.buffered.next_multiple_of(F::WRITE_SIZE.max(1));
+        self.buffer[self.buffered..len].fill(0xFF);
+        self.write_buffer(len).await
+    }
+}
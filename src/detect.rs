@@ -0,0 +1,59 @@
+/*
+ * Best-effort detection of which panel is populated, for boards that ship with either the 6-color
+ * or a black/white panel on the same connector. Without MISO there's no status to read back, so
+ * this goes by how long BUSY stays asserted after a harmless command (PowerOn), which differs per
+ * panel/controller combination.
+ */
+use core::ops::Range;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PanelVariant {
+    Spectra6,
+    BlackWhite,
+    Unknown,
+}
+
+// BUSY durations (in microseconds) seen for a variant
+#[derive(Clone, Debug)]
+pub struct BusySignature {
+    pub variant: PanelVariant,
+    pub busy_us: Range<u32>,
+}
+
+/*
+ * Signatures are specific to the board and panels, measure them once with each SKU (the measured
+ * time is returned alongside the variant) and keep some margin on both sides.
+ */
+#[derive(Clone, Debug)]
+pub struct DetectionConfig<'a> {
+    pub signatures: &'a [BusySignature],
+    pub poll_interval_us: u32,
+    pub timeout_us: u32,
+}
+
+impl<'a> DetectionConfig<'a> {
+    pub fn new(signatures: &'a [BusySignature]) -> Self {
+        DetectionConfig {
+            signatures,
+            poll_interval_us: 1_000,
+            timeout_us: 5_000_000,
+        }
+    }
+
+    pub fn classify(&self, busy_us: Option<u32>) -> PanelVariant {
+        let Some(busy_us) = busy_us else {
+            return PanelVariant::Unknown;
+        };
+        self.signatures
+            .iter()
+            .find(|signature| signature.busy_us.contains(&busy_us))
+            .map_or(PanelVariant::Unknown, |signature| signature.variant)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Detection {
+    pub variant: PanelVariant,
+    // None if BUSY didn't clear within the timeout
+    pub busy_us: Option<u32>,
+}
@@ -1,16 +1,54 @@
 use arrayvec::ArrayVec;
 use core::fmt::Debug;
+use core::future::Future;
 use core::marker::PhantomData;
+use embassy_time::{Duration, with_timeout};
 use embedded_hal::digital::{InputPin, OutputPin};
 use embedded_hal_async::delay::DelayNs;
 use embedded_hal_async::digital::Wait;
 use embedded_hal_async::spi::SpiDevice;
+use embedded_io_async::{Read, ReadExactError};
+
+/*
+ * With the trace-spi feature, every command and data transfer is logged with a timestamp, to diff
+ * the actual byte stream against vendor example code. Without it, this compiles to nothing.
+ */
+#[cfg(feature = "trace-spi")]
+macro_rules! trace_spi {
+    ($($arg:tt)*) => {
+        esp_println::println!(
+            "[spi {:>10} us] {}",
+            embassy_time::Instant::now().as_micros(),
+            format_args!($($arg)*)
+        )
+    };
+}
+
+#[cfg(not(feature = "trace-spi"))]
+macro_rules! trace_spi {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+// Short data is logged in full, longer data only by length
+#[cfg(feature = "trace-spi")]
+const TRACE_MAX_BYTES: usize = 16;
 
 /* Maybe import from epd-waveshare? */
 pub trait Command: Copy {
     fn address(self) -> u8;
 }
 
+// Plain command byte, for registers that don't have a name in any of the drivers (yet)
+impl Command for u8 {
+    fn address(self) -> u8 {
+        self
+    }
+}
+
 pub enum DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>
 where
     SPI: SpiDevice,
@@ -22,6 +60,8 @@ where
     BUSYError(BUSY::Error),
     DCError(DC::Error),
     RSTError(RST::Error),
+    // An operation took longer than its limit in Timeouts
+    Timeout,
 }
 
 impl<SPI, BUSY, DC, RST> Debug for DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>
@@ -37,16 +77,113 @@ where
             Self::BUSYError(x) => write!(f, "BUSYError({:?})", x),
             Self::DCError(x) => write!(f, "DCError({:?})", x),
             Self::RSTError(x) => write!(f, "RSTError({:?})", x),
+            Self::Timeout => write!(f, "Timeout"),
         }
     }
 }
 
+pub enum DataFromReaderError<E, SPI, BUSY, DC, RST>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    ReadError(ReadExactError<E>),
+    InterfaceError(DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>),
+}
+
+impl<E, SPI, BUSY, DC, RST> Debug for DataFromReaderError<E, SPI, BUSY, DC, RST>
+where
+    E: Debug,
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::ReadError(x) => write!(f, "ReadError({:?})", x),
+            Self::InterfaceError(x) => write!(f, "InterfaceError({:?})", x),
+        }
+    }
+}
+
+impl<E, SPI, BUSY, DC, RST> From<DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>>
+    for DataFromReaderError<E, SPI, BUSY, DC, RST>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    fn from(value: DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>) -> Self {
+        Self::InterfaceError(value)
+    }
+}
+
+/*
+ * Limits for the separate steps of every operation, so a wedged panel (or bus) can't keep the
+ * firmware from going back to sleep. Busy waits are the long ones, a full Spectra 6 refresh takes
+ * about 20 seconds, and longer in the cold.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    // Each SPI transfer, which is at most a chunk of data
+    pub spi: Duration,
+    pub busy: Duration,
+    // The whole reset sequence
+    pub reset: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            spi: Duration::from_secs(1),
+            busy: Duration::from_secs(60),
+            reset: Duration::from_secs(1),
+        }
+    }
+}
+
+/*
+ * How the controller tells commands from data. Some breakouts strap it for 3-wire SPI, where
+ * there's no D/C line and every byte is sent as a 9-bit word with the D/C bit in front. Those are
+ * bit-packed into bytes here, so a plain 8-bit SPI peripheral works. Pass NoPin for DC then.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpiWiring {
+    FourWire,
+    ThreeWire,
+}
+
+// Stands in for a pin that isn't connected, e.g. DC with 3-wire SPI
+pub struct NoPin;
+
+impl embedded_hal::digital::ErrorType for NoPin {
+    type Error = core::convert::Infallible;
+}
+
+impl OutputPin for NoPin {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
 pub struct DisplayInterfaceAsync<SPI, BUSY, DC, RST, DELAY, const SINGLE_BYTE_WRITE: bool> {
     _spi: PhantomData<SPI>,
     _delay: PhantomData<DELAY>,
     busy: BUSY,
     dc: DC,
     rst: RST,
+    timeouts: Timeouts,
+    wiring: SpiWiring,
+    // Level of the D/C line (or bit, with 3-wire SPI), high for data
+    is_data: bool,
 }
 
 impl<SPI, BUSY, DC, RST, DELAY, const SINGLE_BYTE_WRITE: bool>
@@ -65,18 +202,84 @@ where
             busy,
             dc,
             rst,
+            timeouts: Timeouts::default(),
+            wiring: SpiWiring::FourWire,
+            is_data: false,
+        }
+    }
+
+    pub fn wiring(&self) -> SpiWiring {
+        self.wiring
+    }
+
+    pub fn set_wiring(&mut self, wiring: SpiWiring) {
+        self.wiring = wiring;
+    }
+
+    fn set_dc(
+        &mut self,
+        is_data: bool,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.is_data = is_data;
+        if self.wiring == SpiWiring::ThreeWire {
+            return Ok(());
         }
+        if is_data {
+            self.dc.set_high()
+        } else {
+            self.dc.set_low()
+        }
+        .map_err(DisplayInterfaceAsyncError::DCError)
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    async fn with_timeout<R, E>(
+        timeout: Duration,
+        op: impl Future<Output = Result<R, E>>,
+        map_err: impl FnOnce(E) -> DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>,
+    ) -> Result<R, DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        with_timeout(timeout, op)
+            .await
+            .map_err(|_| DisplayInterfaceAsyncError::Timeout)?
+            .map_err(map_err)
+    }
+
+    async fn spi_write(
+        &self,
+        spi: &mut SPI,
+        data: &[u8],
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        Self::with_timeout(
+            self.timeouts.spi,
+            spi.write(data),
+            DisplayInterfaceAsyncError::SPIError,
+        )
+        .await
     }
 
-    async fn write(&mut self, spi: &mut SPI, data: &[u8]) -> Result<(), SPI::Error> {
+    async fn write(
+        &mut self,
+        spi: &mut SPI,
+        data: &[u8],
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        if self.wiring == SpiWiring::ThreeWire {
+            return self.write_iter(spi, data.iter().copied()).await;
+        }
         // See description in epd-waveshare/src/interface.rs
         if cfg!(target_os = "linux") {
             for data_chunk in data.chunks(4096) {
-                spi.write(data_chunk).await?;
+                self.spi_write(spi, data_chunk).await?;
             }
             Ok(())
         } else {
-            spi.write(data).await
+            self.spi_write(spi, data).await
         }
     }
 
@@ -84,17 +287,56 @@ where
         &mut self,
         spi: &mut SPI,
         data: impl IntoIterator<Item = u8>,
-    ) -> Result<(), SPI::Error> {
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        if self.wiring == SpiWiring::ThreeWire {
+            return self.write_iter_9bit(spi, data).await;
+        }
         let mut buffer = ArrayVec::<u8, 128>::new();
         for v in data.into_iter() {
             if buffer.is_full() {
-                spi.write(buffer.as_slice()).await?;
+                self.spi_write(spi, buffer.as_slice()).await?;
                 buffer.clear();
             }
             buffer.push(v);
         }
         if !buffer.is_empty() {
-            spi.write(buffer.as_slice()).await?;
+            self.spi_write(spi, buffer.as_slice()).await?;
+        }
+        Ok(())
+    }
+
+    /*
+     * 9-bit words, D/C bit first and then the byte MSB first, packed back to back into bytes. The
+     * buffer holds a whole number of words (16 * 8 words in 16 * 9 bytes), so every transfer starts
+     * on a word boundary. The last one is padded with zero bits, which the controller drops when CS
+     * goes high.
+     */
+    async fn write_iter_9bit(
+        &mut self,
+        spi: &mut SPI,
+        data: impl IntoIterator<Item = u8>,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        let mut buffer = ArrayVec::<u8, { 16 * 9 }>::new();
+        let mut bits: u32 = 0;
+        let mut bit_count = 0;
+        for v in data.into_iter() {
+            bits = bits << 9 | (self.is_data as u32) << 8 | v as u32;
+            bit_count += 9;
+            while bit_count >= 8 {
+                bit_count -= 8;
+                buffer.push((bits >> bit_count) as u8);
+            }
+            bits &= (1 << bit_count) - 1;
+            if buffer.is_full() {
+                self.spi_write(spi, buffer.as_slice()).await?;
+                buffer.clear();
+            }
+        }
+        if bit_count > 0 {
+            buffer.push((bits << (8 - bit_count)) as u8);
+        }
+        if !buffer.is_empty() {
+            self.spi_write(spi, buffer.as_slice()).await?;
         }
         Ok(())
     }
@@ -104,12 +346,9 @@ where
         spi: &mut SPI,
         command: T,
     ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
-        self.dc
-            .set_low()
-            .map_err(DisplayInterfaceAsyncError::DCError)?;
-        self.write(spi, &[command.address()])
-            .await
-            .map_err(DisplayInterfaceAsyncError::SPIError)?;
+        trace_spi!("cmd 0x{:02X}", command.address());
+        self.set_dc(false)?;
+        self.write(spi, &[command.address()]).await?;
         Ok(())
     }
 
@@ -118,19 +357,27 @@ where
         spi: &mut SPI,
         data: &[u8],
     ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
-        self.dc
-            .set_high()
-            .map_err(DisplayInterfaceAsyncError::DCError)?;
+        #[cfg(feature = "trace-spi")]
+        if data.len() <= TRACE_MAX_BYTES {
+            trace_spi!("data {:02X?}", data);
+        } else {
+            trace_spi!("data {} bytes", data.len());
+        }
+        self.write_data(spi, data).await
+    }
+
+    async fn write_data(
+        &mut self,
+        spi: &mut SPI,
+        data: &[u8],
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.set_dc(true)?;
         if SINGLE_BYTE_WRITE {
             for val in data.iter().copied() {
-                self.write(spi, &[val])
-                    .await
-                    .map_err(DisplayInterfaceAsyncError::SPIError)?;
+                self.write(spi, &[val]).await?;
             }
         } else {
-            self.write(spi, data)
-                .await
-                .map_err(DisplayInterfaceAsyncError::SPIError)?;
+            self.write(spi, data).await?;
         }
         Ok(())
     }
@@ -140,20 +387,17 @@ where
         spi: &mut SPI,
         data: impl IntoIterator<Item = u8>,
     ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
-        self.dc
-            .set_high()
-            .map_err(DisplayInterfaceAsyncError::DCError)?;
+        let mut len = 0;
+        let data = data.into_iter().inspect(|_| len += 1);
+        self.set_dc(true)?;
         if SINGLE_BYTE_WRITE {
-            for val in data.into_iter() {
-                self.write(spi, &[val])
-                    .await
-                    .map_err(DisplayInterfaceAsyncError::SPIError)?;
+            for val in data {
+                self.write(spi, &[val]).await?;
             }
         } else {
-            self.write_iter(spi, data)
-                .await
-                .map_err(DisplayInterfaceAsyncError::SPIError)?;
+            self.write_iter(spi, data).await?;
         }
+        trace_spi!("data {} bytes", len);
         Ok(())
     }
 
@@ -167,6 +411,40 @@ where
         self.data(spi, data).await
     }
 
+    // Sends each command with its data in order, init sequences are mostly just that
+    pub async fn run_init_sequence<T: Command>(
+        &mut self,
+        spi: &mut SPI,
+        sequence: &[(T, &[u8])],
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        for &(command, data) in sequence {
+            self.cmd_with_data(spi, command, data).await?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Sends a command and reads back its response. Needs the data line to be readable, i.e. MISO
+     * connected or a 3-wire SPI setup, without it this returns whatever the bus floats to.
+     */
+    pub async fn cmd_read<T: Command>(
+        &mut self,
+        spi: &mut SPI,
+        command: T,
+        buffer: &mut [u8],
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.cmd(spi, command).await?;
+        self.set_dc(true)?;
+        Self::with_timeout(
+            self.timeouts.spi,
+            spi.read(buffer),
+            DisplayInterfaceAsyncError::SPIError,
+        )
+        .await?;
+        trace_spi!("read {:02X?}", buffer);
+        Ok(())
+    }
+
     pub async fn data_x_times(
         &mut self,
         spi: &mut SPI,
@@ -176,22 +454,82 @@ where
         self.data_iter(spi, (0..repetitions).map(|_| val)).await
     }
 
+    // Streams exactly len bytes from reader as data, without buffering more than a small chunk
+    pub async fn data_from_reader<R: Read>(
+        &mut self,
+        spi: &mut SPI,
+        reader: &mut R,
+        len: usize,
+    ) -> Result<(), DataFromReaderError<R::Error, SPI, BUSY, DC, RST>> {
+        trace_spi!("data {} bytes from reader", len);
+        let mut buffer = [0u8; 128];
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = &mut buffer[..remaining.min(128)];
+            reader
+                .read_exact(chunk)
+                .await
+                .map_err(DataFromReaderError::ReadError)?;
+            self.write_data(spi, chunk).await?;
+            remaining -= chunk.len();
+        }
+        Ok(())
+    }
+
     pub async fn wait_until_idle(
         &mut self,
         is_busy_low: bool,
     ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
-        // TODO: Add a proper timeout here?
+        trace_spi!("wait for idle");
+        let timeout = self.timeouts.busy;
         if is_busy_low {
-            self.busy
-                .wait_for_high()
-                .await
-                .map_err(DisplayInterfaceAsyncError::BUSYError)
+            Self::with_timeout(
+                timeout,
+                self.busy.wait_for_high(),
+                DisplayInterfaceAsyncError::BUSYError,
+            )
+            .await?;
         } else {
-            self.busy
-                .wait_for_low()
-                .await
-                .map_err(DisplayInterfaceAsyncError::BUSYError)
+            Self::with_timeout(
+                timeout,
+                self.busy.wait_for_low(),
+                DisplayInterfaceAsyncError::BUSYError,
+            )
+            .await?;
+        }
+        trace_spi!("idle");
+        Ok(())
+    }
+
+    pub fn is_busy(
+        &mut self,
+        is_busy_low: bool,
+    ) -> Result<bool, DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        if is_busy_low {
+            self.busy.is_low()
+        } else {
+            self.busy.is_high()
         }
+        .map_err(DisplayInterfaceAsyncError::BUSYError)
+    }
+
+    // Polls BUSY until idle, returns roughly how long that took, or None if it took too long
+    pub async fn measure_busy_us(
+        &mut self,
+        delay: &mut DELAY,
+        is_busy_low: bool,
+        poll_interval_us: u32,
+        timeout_us: u32,
+    ) -> Result<Option<u32>, DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        let mut elapsed_us = 0;
+        while self.is_busy(is_busy_low)? {
+            if elapsed_us >= timeout_us {
+                return Ok(None);
+            }
+            delay.delay_us(poll_interval_us).await;
+            elapsed_us += poll_interval_us;
+        }
+        Ok(Some(elapsed_us))
     }
 
     pub async fn reset(
@@ -201,18 +539,16 @@ where
         duration_us: u32,
         final_delay_us: u32,
     ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
-        self.rst
-            .set_high()
-            .map_err(DisplayInterfaceAsyncError::RSTError)?;
-        delay.delay_us(initial_delay_us).await;
-        self.rst
-            .set_low()
-            .map_err(DisplayInterfaceAsyncError::RSTError)?;
-        delay.delay_us(duration_us).await;
-        self.rst
-            .set_high()
-            .map_err(DisplayInterfaceAsyncError::RSTError)?;
-        delay.delay_us(final_delay_us).await;
-        Ok(())
+        let timeout = self.timeouts.reset;
+        let reset = async {
+            self.rst.set_high()?;
+            delay.delay_us(initial_delay_us).await;
+            self.rst.set_low()?;
+            delay.delay_us(duration_us).await;
+            self.rst.set_high()?;
+            delay.delay_us(final_delay_us).await;
+            Ok(())
+        };
+        Self::with_timeout(timeout, reset, DisplayInterfaceAsyncError::RSTError).await
     }
 }
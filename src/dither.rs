@@ -1,7 +1,14 @@
+use crate::barycentric::octahedron::{OctahedronLut, OctahedronProjector};
+use crate::color::{LinearLut, Oklab};
 use alloc::vec::Vec;
 use core::marker::PhantomData;
-use core::ops::{AddAssign, Div, DivAssign, Mul, MulAssign};
-use embedded_graphics::pixelcolor::{BinaryColor, RgbColor};
+use core::ops::{AddAssign, Div, DivAssign, Mul};
+use embedded_graphics::pixelcolor::{
+    Bgr555, Bgr565, Bgr666, Bgr888, BinaryColor, Gray2, Gray4, Gray8, GrayColor, Rgb555, Rgb565,
+    Rgb666, Rgb888, RgbColor,
+};
+use nalgebra::geometry::Point3;
+use num_traits::{Float, SaturatingAdd, SaturatingMul};
 
 pub trait DitherPalette {
     type SourceColor;
@@ -10,19 +17,32 @@ pub trait DitherPalette {
         + Clone
         + Mul<usize, Output = Self::QuantizationError>
         + AddAssign<Self::QuantizationError>
-        + Div<usize>;
+        + Div<usize>
+        + LimitQuantizationError;
 
     fn get_closest(
         &self,
         source: Self::SourceColor,
         diffused_error: <Self::QuantizationError as Div<usize>>::Output,
     ) -> (Self::TargetColor, Self::QuantizationError);
+
+    // Brightness of the source, 0 to 255, for methods that depend on it
+    fn intensity(&self, _source: &Self::SourceColor) -> u8 {
+        128
+    }
 }
 
 pub trait ForwardErrorDiffusionMethod {
+    // Whether get_targets_for needs the intensity, so it's only worked out when needed
+    const INTENSITY_DEPENDENT: bool = false;
+
     fn get_max_y_target(&self) -> usize;
     fn get_divisor(&self) -> usize;
     fn get_targets(&self) -> impl Iterator<Item = (isize, usize, usize)>;
+
+    fn get_targets_for(&self, _intensity: u8) -> impl Iterator<Item = (isize, usize, usize)> {
+        self.get_targets()
+    }
 }
 
 pub struct FloydSteinberg;
@@ -103,10 +123,330 @@ impl ForwardErrorDiffusionMethod for Atkinson {
     }
 }
 
+pub struct Sierra3;
+
+impl ForwardErrorDiffusionMethod for Sierra3 {
+    #[inline(always)]
+    fn get_max_y_target(&self) -> usize {
+        2
+    }
+    #[inline(always)]
+    fn get_divisor(&self) -> usize {
+        32
+    }
+    #[inline(always)]
+    fn get_targets(&self) -> impl Iterator<Item = (isize, usize, usize)> {
+        [
+            // First row
+            (1, 0, 5),
+            (2, 0, 3),
+            // Second row
+            (-2, 1, 2),
+            (-1, 1, 4),
+            (0, 1, 5),
+            (1, 1, 4),
+            (2, 1, 2),
+            // Third row
+            (-1, 2, 2),
+            (0, 2, 3),
+            (1, 2, 2),
+        ]
+        .into_iter()
+    }
+}
+
+pub struct Sierra2;
+
+impl ForwardErrorDiffusionMethod for Sierra2 {
+    #[inline(always)]
+    fn get_max_y_target(&self) -> usize {
+        1
+    }
+    #[inline(always)]
+    fn get_divisor(&self) -> usize {
+        16
+    }
+    #[inline(always)]
+    fn get_targets(&self) -> impl Iterator<Item = (isize, usize, usize)> {
+        [
+            // First row
+            (1, 0, 4),
+            (2, 0, 3),
+            // Second row
+            (-2, 1, 1),
+            (-1, 1, 2),
+            (0, 1, 3),
+            (1, 1, 2),
+            (2, 1, 1),
+        ]
+        .into_iter()
+    }
+}
+
+// Nearly as good as Floyd-Steinberg, but only three neighbours to update
+pub struct SierraLite;
+
+impl ForwardErrorDiffusionMethod for SierraLite {
+    #[inline(always)]
+    fn get_max_y_target(&self) -> usize {
+        1
+    }
+    #[inline(always)]
+    fn get_divisor(&self) -> usize {
+        4
+    }
+    #[inline(always)]
+    fn get_targets(&self) -> impl Iterator<Item = (isize, usize, usize)> {
+        [(1, 0, 2), (-1, 1, 1), (0, 1, 1)].into_iter()
+    }
+}
+
+/*
+ * Ostromoukhov's variable-coefficient error diffusion ("A Simple and Efficient Error-Diffusion
+ * Algorithm", SIGGRAPH 2001): Floyd-Steinberg's cost, but the weights for the three neighbours
+ * depend on the intensity, which gets rid of the worm artifacts in highlights and shadows. The
+ * paper scans serpentine, here it's left to right like the other methods.
+ */
+pub struct Ostromoukhov;
+
+// The paper's table for intensities 0 to 127 (mirrored for the rest): right, down left, down, sum
+const OSTROMOUKHOV_COEFFICIENTS: [(u16, u16, u16, u16); 128] = [
+    (13, 0, 5, 18),
+    (13, 0, 5, 18),
+    (21, 0, 10, 31),
+    (7, 0, 4, 11),
+    (8, 0, 5, 13),
+    (47, 3, 28, 78),
+    (23, 3, 13, 39),
+    (15, 3, 8, 26),
+    (22, 6, 11, 39),
+    (43, 15, 20, 78),
+    (7, 3, 3, 13),
+    (501, 224, 211, 936),
+    (249, 116, 103, 468),
+    (165, 80, 67, 312),
+    (123, 62, 49, 234),
+    (489, 256, 191, 936),
+    (81, 44, 31, 156),
+    (483, 272, 181, 936),
+    (60, 35, 22, 117),
+    (53, 32, 19, 104),
+    (237, 148, 83, 468),
+    (471, 304, 161, 936),
+    (3, 2, 1, 6),
+    (481, 314, 185, 980),
+    (354, 226, 155, 735),
+    (1389, 866, 685, 2940),
+    (227, 138, 125, 490),
+    (267, 158, 163, 588),
+    (327, 188, 220, 735),
+    (61, 34, 45, 140),
+    (627, 338, 505, 1470),
+    (1227, 638, 1075, 2940),
+    (20, 10, 19, 49),
+    (1937, 1000, 1767, 4704),
+    (977, 520, 855, 2352),
+    (657, 360, 551, 1568),
+    (71, 40, 57, 168),
+    (2005, 1160, 1539, 4704),
+    (337, 200, 247, 784),
+    (2039, 1240, 1425, 4704),
+    (257, 160, 171, 588),
+    (691, 440, 437, 1568),
+    (1045, 680, 627, 2352),
+    (301, 200, 171, 672),
+    (177, 120, 95, 392),
+    (2141, 1480, 1083, 4704),
+    (1079, 760, 513, 2352),
+    (725, 520, 323, 1568),
+    (137, 100, 57, 294),
+    (2209, 1640, 855, 4704),
+    (53, 40, 19, 112),
+    (2243, 1720, 741, 4704),
+    (565, 440, 171, 1176),
+    (759, 600, 209, 1568),
+    (1147, 920, 285, 2352),
+    (2311, 1880, 513, 4704),
+    (97, 80, 19, 196),
+    (335, 280, 57, 672),
+    (1181, 1000, 171, 2352),
+    (793, 680, 95, 1568),
+    (599, 520, 57, 1176),
+    (2413, 2120, 171, 4704),
+    (405, 360, 19, 784),
+    (2447, 2200, 57, 4704),
+    (11, 10, 0, 21),
+    (158, 151, 3, 312),
+    (178, 179, 7, 364),
+    (1030, 1091, 63, 2184),
+    (248, 277, 21, 546),
+    (318, 375, 35, 728),
+    (458, 571, 63, 1092),
+    (878, 1159, 147, 2184),
+    (5, 7, 1, 13),
+    (172, 181, 37, 390),
+    (97, 76, 22, 195),
+    (72, 41, 17, 130),
+    (119, 47, 29, 195),
+    (4, 1, 1, 6),
+    (4, 1, 1, 6),
+    (4, 1, 1, 6),
+    (4, 1, 1, 6),
+    (4, 1, 1, 6),
+    (4, 1, 1, 6),
+    (4, 1, 1, 6),
+    (4, 1, 1, 6),
+    (4, 1, 1, 6),
+    (65, 18, 17, 100),
+    (95, 29, 26, 150),
+    (185, 62, 53, 300),
+    (30, 11, 9, 50),
+    (35, 14, 11, 60),
+    (85, 37, 28, 150),
+    (55, 26, 19, 100),
+    (80, 41, 29, 150),
+    (155, 86, 59, 300),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (5, 3, 2, 10),
+    (305, 176, 119, 600),
+    (155, 86, 59, 300),
+    (105, 56, 39, 200),
+    (80, 41, 29, 150),
+    (65, 32, 23, 120),
+    (55, 26, 19, 100),
+    (335, 152, 113, 600),
+    (85, 37, 28, 150),
+    (115, 48, 37, 200),
+    (35, 14, 11, 60),
+    (355, 136, 109, 600),
+    (30, 11, 9, 50),
+    (365, 128, 107, 600),
+    (185, 62, 53, 300),
+    (25, 8, 7, 40),
+    (95, 29, 26, 150),
+    (385, 112, 103, 600),
+    (65, 18, 17, 100),
+    (395, 104, 101, 600),
+    (4, 1, 1, 6),
+];
+
+const OSTROMOUKHOV_DIVISOR: usize = 64;
+
+// Coefficients scaled to OSTROMOUKHOV_DIVISOR, which keeps the accumulated error well inside i16
+const OSTROMOUKHOV_WEIGHTS: [[u8; 3]; 128] = {
+    let mut weights = [[0u8; 3]; 128];
+    let mut index = 0;
+    while index < 128 {
+        let (right, down_left, down, sum) = OSTROMOUKHOV_COEFFICIENTS[index];
+        assert!(right + down_left + down == sum, "coefficients don't add up");
+        let sum = sum as usize;
+        let right = (right as usize * OSTROMOUKHOV_DIVISOR + sum / 2) / sum;
+        let down_left = (down_left as usize * OSTROMOUKHOV_DIVISOR + sum / 2) / sum;
+        let down = OSTROMOUKHOV_DIVISOR - right - down_left;
+        weights[index] = [right as u8, down_left as u8, down as u8];
+        index += 1;
+    }
+    weights
+};
+
+impl ForwardErrorDiffusionMethod for Ostromoukhov {
+    const INTENSITY_DEPENDENT: bool = true;
+
+    #[inline(always)]
+    fn get_max_y_target(&self) -> usize {
+        1
+    }
+    #[inline(always)]
+    fn get_divisor(&self) -> usize {
+        OSTROMOUKHOV_DIVISOR
+    }
+    #[inline(always)]
+    fn get_targets(&self) -> impl Iterator<Item = (isize, usize, usize)> {
+        self.get_targets_for(128)
+    }
+    #[inline(always)]
+    fn get_targets_for(&self, intensity: u8) -> impl Iterator<Item = (isize, usize, usize)> {
+        let intensity = intensity.min(255 - intensity) as usize;
+        let [right, down_left, down] = OSTROMOUKHOV_WEIGHTS[intensity];
+        [
+            (1, 0, right as usize),
+            (-1, 1, down_left as usize),
+            (0, 1, down as usize),
+        ]
+        .into_iter()
+    }
+}
+
+/*
+ * Limits on the error a pixel receives from its neighbours. Colors far outside the panel's gamut
+ * leave error that can never be worked off, and without a limit it's carried along until it shows
+ * up as a colored streak in whatever comes next.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ErrorLimit {
+    // Most error per channel, in the palette's units
+    pub max: Option<u16>,
+    // Part of the received error that's kept, so it decays as it travels
+    pub keep_percent: u8,
+}
+
+impl ErrorLimit {
+    pub const NONE: ErrorLimit = ErrorLimit {
+        max: None,
+        keep_percent: 100,
+    };
+
+    pub const fn clamp(max: u16) -> Self {
+        ErrorLimit {
+            max: Some(max),
+            keep_percent: 100,
+        }
+    }
+
+    // keep_percent above 100 is taken as 100, the error never grows
+    pub const fn decay(keep_percent: u8) -> Self {
+        ErrorLimit {
+            max: None,
+            keep_percent: if keep_percent > 100 {
+                100
+            } else {
+                keep_percent
+            },
+        }
+    }
+}
+
+impl Default for ErrorLimit {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+pub trait LimitQuantizationError {
+    // Clamps every channel to -max..=max, then scales it to keep_percent (at most 100)
+    fn limit(self, max: i32, keep_percent: u8) -> Self;
+}
+
+/*
+ * B holds the error for the rows ahead, a Vec by default. with_buffer takes a slice or array
+ * instead, for firmware without an allocator.
+ */
 pub struct ForwardErrorDiffusion<
     PALETTE: DitherPalette,
     METHOD: ForwardErrorDiffusionMethod,
     I: Iterator<Item = PALETTE::SourceColor>,
+    B = Vec<<PALETTE as DitherPalette>::QuantizationError>,
 > {
     palette: PALETTE,
     method: METHOD,
@@ -114,7 +454,10 @@ pub struct ForwardErrorDiffusion<
     width: usize,
     x: usize,
     y: usize,
-    diffusion: Vec<PALETTE::QuantizationError>,
+    diffusion: B,
+    limit: Option<ErrorLimit>,
+    // Percentage of each pixel's error that gets spread
+    strength: u8,
 }
 
 impl<
@@ -125,7 +468,7 @@ impl<
 {
     pub fn new(palette: PALETTE, method: METHOD, source: I, width: usize) -> Self {
         let mut diffusion = Vec::new();
-        diffusion.resize_with(width * (method.get_max_y_target() + 1), Default::default);
+        diffusion.resize_with(buffer_len(&method, width), Default::default);
         ForwardErrorDiffusion {
             palette,
             method,
@@ -134,14 +477,67 @@ impl<
             y: 0,
             diffusion,
             source,
+            limit: None,
+            strength: 100,
         }
     }
 }
+
+// Length of the error buffer for method at width
+pub fn buffer_len<METHOD: ForwardErrorDiffusionMethod>(method: &METHOD, width: usize) -> usize {
+    width * (method.get_max_y_target() + 1)
+}
+
 impl<
     PALETTE: DitherPalette,
     METHOD: ForwardErrorDiffusionMethod,
     I: Iterator<Item = PALETTE::SourceColor>,
-> ForwardErrorDiffusion<PALETTE, METHOD, I>
+    B: AsMut<[PALETTE::QuantizationError]>,
+> ForwardErrorDiffusion<PALETTE, METHOD, I, B>
+{
+    // None if buffer is shorter than buffer_len, anything after that is left alone
+    pub fn with_buffer(
+        palette: PALETTE,
+        method: METHOD,
+        source: I,
+        width: usize,
+        mut buffer: B,
+    ) -> Option<Self> {
+        let len = buffer_len(&method, width);
+        buffer.as_mut().get_mut(..len)?.fill(Default::default());
+        Some(ForwardErrorDiffusion {
+            palette,
+            method,
+            width,
+            x: 0,
+            y: 0,
+            diffusion: buffer,
+            source,
+            limit: None,
+            strength: 100,
+        })
+    }
+
+    /*
+     * Spreads only strength percent (0 to 100) of the error. Less than full strength leaves flat
+     * areas that are close to a palette color clean, instead of speckled, at the cost of accuracy.
+     */
+    pub fn with_strength(mut self, strength: u8) -> Self {
+        self.strength = strength.min(100);
+        self
+    }
+
+    pub fn with_error_limit(mut self, limit: ErrorLimit) -> Self {
+        self.limit = (limit != ErrorLimit::NONE).then_some(limit);
+        self
+    }
+}
+impl<
+    PALETTE: DitherPalette,
+    METHOD: ForwardErrorDiffusionMethod,
+    I: Iterator<Item = PALETTE::SourceColor>,
+    B,
+> ForwardErrorDiffusion<PALETTE, METHOD, I, B>
 {
     fn get_diffusion_index(&self, x: usize, y: usize) -> usize {
         let y = y % (self.method.get_max_y_target() + 1);
@@ -153,24 +549,42 @@ impl<
     PALETTE: DitherPalette,
     METHOD: ForwardErrorDiffusionMethod,
     I: Iterator<Item = PALETTE::SourceColor>,
-> Iterator for ForwardErrorDiffusion<PALETTE, METHOD, I>
+    B: AsMut<[PALETTE::QuantizationError]>,
+> Iterator for ForwardErrorDiffusion<PALETTE, METHOD, I, B>
 {
     type Item = PALETTE::TargetColor;
 
+    #[inline(always)]
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
         let source_color = self.source.next()?;
+        let intensity = if METHOD::INTENSITY_DEPENDENT {
+            self.palette.intensity(&source_color)
+        } else {
+            0
+        };
         let index = self.get_diffusion_index(self.x, self.y);
-        let source_error = core::mem::take(&mut self.diffusion[index]);
-        let (target_color, error) = self
+        let mut source_error = core::mem::take(&mut self.diffusion.as_mut()[index]);
+        if let Some(limit) = self.limit {
+            // The error is still multiplied by the divisor here
+            let divisor = self.method.get_divisor() as i32;
+            let max = limit
+                .max
+                .map_or(i32::MAX, |max| (max as i32).saturating_mul(divisor));
+            source_error = source_error.limit(max, limit.keep_percent);
+        }
+        let (target_color, mut error) = self
             .palette
             .get_closest(source_color, source_error / self.method.get_divisor());
+        if self.strength < 100 {
+            error = error.limit(i32::MAX, self.strength);
+        }
         // Spread error over next pixels
-        for (dx, dy, mul) in self.method.get_targets() {
+        for (dx, dy, mul) in self.method.get_targets_for(intensity) {
             if let (Some(tx), Some(ty)) = (self.x.checked_add_signed(dx), self.y.checked_add(dy))
                 && tx < self.width
             {
                 let tindex = self.get_diffusion_index(tx, ty);
-                self.diffusion[tindex] += error.clone() * mul;
+                self.diffusion.as_mut()[tindex] += error.clone() * mul;
             }
         }
         // Adjust pointer for next pixel
@@ -181,10 +595,293 @@ impl<
         }
         Some(target_color)
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.source.size_hint()
+    }
+}
+
+impl<
+    PALETTE: DitherPalette,
+    METHOD: ForwardErrorDiffusionMethod,
+    I: ExactSizeIterator<Item = PALETTE::SourceColor>,
+    B: AsMut<[PALETTE::QuantizationError]>,
+> ExactSizeIterator for ForwardErrorDiffusion<PALETTE, METHOD, I, B>
+{
+}
+
+/*
+ * Knuth's dot diffusion. Pixels are dithered in the order of their class in an 8x8 tile, and each
+ * passes its error on to the neighbours of a higher class (orthogonal ones get twice the share of
+ * diagonal ones), so error never travels further than a tile. The frame is worked through one tile
+ * row (8 rows) at a time, only error into the row below is carried over to the next. Splitting the
+ * frame into bands at a tile row therefore only loses that one row of error, a much fainter seam
+ * than with Floyd-Steinberg, but not none.
+ */
+pub struct DotDiffusion<PALETTE: DitherPalette, I: Iterator<Item = PALETTE::SourceColor>> {
+    palette: PALETTE,
+    source: I,
+    width: usize,
+    // The current band, and error for it plus the row below
+    colors: Vec<Option<PALETTE::SourceColor>>,
+    error: Vec<PALETTE::QuantizationError>,
+    targets: Vec<Option<PALETTE::TargetColor>>,
+    // Next of targets to yield
+    index: usize,
+}
+
+// From Knuth, "Digital halftones by dot diffusion" (1987)
+const DOT_CLASSES: [[u8; 8]; 8] = [
+    [34, 48, 40, 32, 29, 15, 23, 31],
+    [42, 58, 56, 53, 21, 5, 7, 10],
+    [50, 62, 61, 45, 13, 1, 2, 18],
+    [38, 46, 54, 37, 25, 17, 9, 26],
+    [28, 14, 22, 30, 35, 49, 41, 33],
+    [20, 4, 6, 11, 43, 59, 57, 52],
+    [12, 0, 3, 19, 51, 63, 60, 44],
+    [24, 16, 8, 27, 39, 47, 55, 36],
+];
+const DOT_SIZE: usize = 8;
+// Every pixel's error is split into this many parts over its neighbours
+const DOT_DIVISOR: usize = 12;
+
+fn dot_class(x: usize, y: usize) -> u8 {
+    DOT_CLASSES[y % DOT_SIZE][x % DOT_SIZE]
+}
+
+impl<PALETTE: DitherPalette, I: Iterator<Item = PALETTE::SourceColor>> DotDiffusion<PALETTE, I> {
+    pub fn new(palette: PALETTE, source: I, width: usize) -> Self {
+        let mut error = Vec::new();
+        error.resize_with((DOT_SIZE + 1) * width, Default::default);
+        DotDiffusion {
+            palette,
+            source,
+            width,
+            colors: Vec::with_capacity(DOT_SIZE * width),
+            error,
+            targets: Vec::with_capacity(DOT_SIZE * width),
+            index: 0,
+        }
+    }
+
+    // Reads and dithers the next band, false if the source is done
+    fn next_band(&mut self) -> bool {
+        self.colors.clear();
+        self.colors
+            .extend(self.source.by_ref().take(DOT_SIZE * self.width).map(Some));
+        if self.colors.is_empty() || self.width == 0 {
+            return false;
+        }
+        let rows = self.colors.len().div_ceil(self.width);
+        self.targets.clear();
+        self.targets.resize_with(self.colors.len(), || None);
+        self.index = 0;
+
+        let mut order: [(usize, usize); DOT_SIZE * DOT_SIZE] = [(0, 0); DOT_SIZE * DOT_SIZE];
+        for (y, row) in DOT_CLASSES.iter().enumerate() {
+            for (x, class) in row.iter().enumerate() {
+                order[*class as usize] = (x, y);
+            }
+        }
+        for (tile_x, y) in order {
+            for x in (tile_x..self.width).step_by(DOT_SIZE) {
+                let index = y * self.width + x;
+                let Some(color) = self.colors.get_mut(index).and_then(Option::take) else {
+                    continue;
+                };
+                let error = core::mem::take(&mut self.error[index]);
+                let (target, error) = self.palette.get_closest(color, error / DOT_DIVISOR);
+                self.targets[index] = Some(target);
+                self.spread(x, y, rows, error);
+            }
+        }
+
+        // Error into the row below goes on to the next band
+        self.error.rotate_left(DOT_SIZE * self.width);
+        for error in self.error[self.width..].iter_mut() {
+            *error = Default::default();
+        }
+        true
+    }
+
+    fn spread(&mut self, x: usize, y: usize, rows: usize, error: PALETTE::QuantizationError) {
+        let class = dot_class(x, y);
+        let neighbours = [
+            (-1, -1, 1),
+            (0, -1, 2),
+            (1, -1, 1),
+            (-1, 0, 2),
+            (1, 0, 2),
+            (-1, 1, 1),
+            (0, 1, 2),
+            (1, 1, 1),
+        ]
+        .into_iter()
+        .filter_map(|(dx, dy, weight)| {
+            let tx = x.checked_add_signed(dx).filter(|tx| *tx < self.width)?;
+            // Rows above the band are done already
+            let ty = y.checked_add_signed(dy).filter(|ty| *ty <= rows)?;
+            (dot_class(tx, ty) > class).then_some((ty * self.width + tx, weight))
+        });
+        let total: usize = neighbours.clone().map(|(_, weight)| weight).sum();
+        // Shares that add up to exactly DOT_DIVISOR
+        let mut sum = 0;
+        for (index, weight) in neighbours {
+            let share = (sum + weight) * DOT_DIVISOR / total - sum * DOT_DIVISOR / total;
+            sum += weight;
+            self.error[index] += error.clone() * share;
+        }
+    }
+}
+
+impl<PALETTE: DitherPalette, I: Iterator<Item = PALETTE::SourceColor>> Iterator
+    for DotDiffusion<PALETTE, I>
+{
+    type Item = PALETTE::TargetColor;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.targets.len() && !self.next_band() {
+            return None;
+        }
+        let target = self.targets[self.index].take();
+        self.index += 1;
+        target
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let buffered = self.targets.len() - self.index;
+        let (lower, upper) = self.source.size_hint();
+        (
+            lower.saturating_add(buffered),
+            upper.and_then(|upper| upper.checked_add(buffered)),
+        )
+    }
+}
+
+impl<PALETTE: DitherPalette, I: ExactSizeIterator<Item = PALETTE::SourceColor>> ExactSizeIterator
+    for DotDiffusion<PALETTE, I>
+{
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NoiseKind {
+    White,
+    // Jorge Jimenez' interleaved gradient noise, less clumpy than white noise and just as cheap
+    InterleavedGradient,
+}
+
+/*
+ * Dithering without any state carried between pixels: every pixel gets a random offset (in the
+ * palette's error units, -amplitude/2 to amplitude/2 per channel) before picking the closest color.
+ * Noisier than error diffusion, but needs no row buffers, and has no pattern like ordered dithering.
+ */
+pub struct NoiseDither<PALETTE, I, T, const CHANNELS: usize>
+where
+    PALETTE: DitherPalette<QuantizationError = DefaultQuantizationError<T, CHANNELS>>,
+    I: Iterator<Item = PALETTE::SourceColor>,
+{
+    palette: PALETTE,
+    source: I,
+    width: usize,
+    x: usize,
+    y: usize,
+    kind: NoiseKind,
+    // xorshift32 state for white noise, pattern offset for interleaved gradient noise
+    state: u32,
+    amplitude: i32,
+}
+
+impl<PALETTE, I, T, const CHANNELS: usize> NoiseDither<PALETTE, I, T, CHANNELS>
+where
+    PALETTE: DitherPalette<QuantizationError = DefaultQuantizationError<T, CHANNELS>>,
+    I: Iterator<Item = PALETTE::SourceColor>,
+{
+    // seed is best taken from the hardware RNG, so repeated frames don't repeat the noise
+    pub fn new(
+        palette: PALETTE,
+        source: I,
+        width: usize,
+        kind: NoiseKind,
+        seed: u32,
+        amplitude: i32,
+    ) -> Self {
+        NoiseDither {
+            palette,
+            source,
+            width,
+            x: 0,
+            y: 0,
+            kind,
+            // xorshift gets stuck at 0
+            state: seed.max(1),
+            amplitude,
+        }
+    }
+
+    // 0.0 to 1.0
+    fn noise(&mut self, channel: usize) -> f32 {
+        match self.kind {
+            NoiseKind::White => {
+                self.state ^= self.state << 13;
+                self.state ^= self.state >> 17;
+                self.state ^= self.state << 5;
+                (self.state >> 8) as f32 / (1 << 24) as f32
+            }
+            NoiseKind::InterleavedGradient => {
+                // Channels get their own part of the pattern, so they don't all move together
+                let x = (self.x as u32).wrapping_add(self.state & 0xFF) + 17 * channel as u32;
+                let y = (self.y as u32).wrapping_add(self.state >> 8 & 0xFF) + 29 * channel as u32;
+                let inner = Float::fract(0.06711056 * x as f32 + 0.00583715 * y as f32);
+                Float::fract(52.982_918 * inner)
+            }
+        }
+    }
+}
+
+impl<PALETTE, I, T, const CHANNELS: usize> Iterator for NoiseDither<PALETTE, I, T, CHANNELS>
+where
+    PALETTE: DitherPalette<QuantizationError = DefaultQuantizationError<T, CHANNELS>>,
+    I: Iterator<Item = PALETTE::SourceColor>,
+    T: Copy + Default + DivAssign + TryFrom<usize> + TryFrom<i32>,
+{
+    type Item = PALETTE::TargetColor;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let source_color = self.source.next()?;
+        let mut offset = [T::default(); CHANNELS];
+        for (channel, offset) in offset.iter_mut().enumerate() {
+            let noise = ((self.noise(channel) - 0.5) * self.amplitude as f32) as i32;
+            *offset = T::try_from(noise).unwrap_or_default();
+        }
+        let (target_color, _) = self
+            .palette
+            .get_closest(source_color, DefaultQuantizationError(offset));
+        self.x += 1;
+        if self.x >= self.width {
+            self.x = 0;
+            self.y += 1;
+        }
+        Some(target_color)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.source.size_hint()
+    }
 }
 
+impl<PALETTE, I, T, const CHANNELS: usize> ExactSizeIterator
+    for NoiseDither<PALETTE, I, T, CHANNELS>
+where
+    PALETTE: DitherPalette<QuantizationError = DefaultQuantizationError<T, CHANNELS>>,
+    I: ExactSizeIterator<Item = PALETTE::SourceColor>,
+    T: Copy + Default + DivAssign + TryFrom<usize> + TryFrom<i32>,
+{
+}
+
+// Error per channel, public so FnPalette closures can work with it
 #[derive(Clone)]
-pub struct DefaultQuantizationError<T, const CHANNELS: usize>([T; CHANNELS]);
+pub struct DefaultQuantizationError<T, const CHANNELS: usize>(pub [T; CHANNELS]);
 
 impl<T, const CHANNELS: usize> Default for DefaultQuantizationError<T, CHANNELS>
 where
@@ -195,21 +892,22 @@ where
     }
 }
 
+// Saturating, so an error that's too large for T gets stuck at the limit instead of wrapping around
 impl<T, const CHANNELS: usize> AddAssign for DefaultQuantizationError<T, CHANNELS>
 where
-    T: AddAssign,
+    T: SaturatingAdd,
     T: Copy,
 {
     fn add_assign(&mut self, rhs: Self) {
         for i in 0..CHANNELS {
-            self.0[i] += rhs.0[i];
+            self.0[i] = self.0[i].saturating_add(&rhs.0[i]);
         }
     }
 }
 
 impl<T, const CHANNELS: usize> Mul<usize> for DefaultQuantizationError<T, CHANNELS>
 where
-    T: MulAssign,
+    T: SaturatingMul,
     T: Copy,
     T: TryFrom<usize>,
     T: Default,
@@ -218,7 +916,7 @@ where
 
     fn mul(mut self, rhs: usize) -> Self {
         for i in 0..CHANNELS {
-            self.0[i] *= rhs.try_into().unwrap_or(Default::default());
+            self.0[i] = self.0[i].saturating_mul(&rhs.try_into().unwrap_or(Default::default()));
         }
         self
     }
@@ -241,35 +939,138 @@ where
     }
 }
 
+impl<T: ErrorChannel, const CHANNELS: usize> LimitQuantizationError
+    for DefaultQuantizationError<T, CHANNELS>
+{
+    fn limit(mut self, max: i32, keep_percent: u8) -> Self {
+        let keep_percent = keep_percent.min(100) as i64;
+        for value in self.0.iter_mut() {
+            let limited = (*value).into().clamp(-max, max) as i64 * keep_percent / 100;
+            *value = T::saturate(limited as i32);
+        }
+        self
+    }
+}
+
+/*
+ * Channel type of the error RgbColorToPalette accumulates. i16 is plenty for 8-bit sources with the
+ * kernels here, and half the row buffer of i32. i32 leaves room for larger kernels and sources.
+ */
+pub trait ErrorChannel:
+    Copy
+    + Default
+    + Into<i32>
+    + TryFrom<i32>
+    + TryFrom<usize>
+    + SaturatingAdd
+    + SaturatingMul
+    + DivAssign
+{
+    fn saturate(value: i32) -> Self;
+}
+
+impl ErrorChannel for i16 {
+    fn saturate(value: i32) -> Self {
+        value.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+}
+
+impl ErrorChannel for i32 {
+    fn saturate(value: i32) -> Self {
+        value
+    }
+}
+
 fn arr3zip<A, B, C, F: Fn(A, B) -> C>(a: [A; 3], b: [B; 3], f: F) -> [C; 3] {
     let [a0, a1, a2] = a;
     let [b0, b1, b2] = b;
     [f(a0, b0), f(a1, b1), f(a2, b2)]
 }
 
-fn rgb_to_arr<C: RgbColor>(c: C) -> [u8; 3] {
-    [c.r(), c.g(), c.b()]
+/*
+ * Colors the palettes below can dither from, straight from the decoder without converting to
+ * Rgb888 first. Channels stay in the color's own bit depth, and so does the quantization error.
+ * Gray is all three channels the same.
+ */
+pub trait DitherSource: Copy {
+    const MAX: [u8; 3];
+
+    fn channels(self) -> [u8; 3];
+}
+
+macro_rules! rgb_dither_source {
+    ($($color:ty),*) => {
+        $(
+            impl DitherSource for $color {
+                const MAX: [u8; 3] = [<$color>::MAX_R, <$color>::MAX_G, <$color>::MAX_B];
+
+                #[inline(always)]
+                fn channels(self) -> [u8; 3] {
+                    [self.r(), self.g(), self.b()]
+                }
+            }
+        )*
+    };
+}
+
+rgb_dither_source!(
+    Rgb555, Bgr555, Rgb565, Bgr565, Rgb666, Bgr666, Rgb888, Bgr888
+);
+
+macro_rules! gray_dither_source {
+    ($($color:ty: $max:expr),*) => {
+        $(
+            impl DitherSource for $color {
+                const MAX: [u8; 3] = [$max; 3];
+
+                #[inline(always)]
+                fn channels(self) -> [u8; 3] {
+                    [self.luma(); 3]
+                }
+            }
+        )*
+    };
 }
 
-const fn rgb_max_arr<C: RgbColor>() -> [u8; 3] {
-    [C::MAX_R, C::MAX_G, C::MAX_B]
+gray_dither_source!(Gray2: 3, Gray4: 15, Gray8: 255);
+
+fn rgb_to_arr<C: DitherSource>(c: C) -> [u8; 3] {
+    c.channels()
 }
 
-pub struct RgbColorToBinaryColor<RGB: RgbColor>(PhantomData<RGB>);
+const fn rgb_max_arr<C: DitherSource>() -> [u8; 3] {
+    C::MAX
+}
 
-impl<RGB: RgbColor> Default for RgbColorToBinaryColor<RGB> {
+// Channel values of a FROM color in the bit depth of TO
+fn rescale<FROM: DitherSource, TO: DitherSource>(color: [u8; 3]) -> [i16; 3] {
+    if FROM::MAX == TO::MAX {
+        return color.map(i16::from);
+    }
+    arr3zip(
+        color,
+        arr3zip(FROM::MAX, TO::MAX, |from, to| (from, to)),
+        |value, (from, to)| {
+            ((value as u32 * to as u32 + from as u32 / 2) / (from as u32).max(1)) as i16
+        },
+    )
+}
+
+pub struct RgbColorToBinaryColor<RGB: DitherSource>(PhantomData<RGB>);
+
+impl<RGB: DitherSource> Default for RgbColorToBinaryColor<RGB> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<RGB: RgbColor> RgbColorToBinaryColor<RGB> {
+impl<RGB: DitherSource> RgbColorToBinaryColor<RGB> {
     pub const fn new() -> Self {
         RgbColorToBinaryColor(PhantomData)
     }
 }
 
-impl<RGB: RgbColor> DitherPalette for RgbColorToBinaryColor<RGB> {
+impl<RGB: DitherSource> DitherPalette for RgbColorToBinaryColor<RGB> {
     type SourceColor = RGB;
     type TargetColor = BinaryColor;
     type QuantizationError = DefaultQuantizationError<i16, 1>;
@@ -282,29 +1083,233 @@ impl<RGB: RgbColor> DitherPalette for RgbColorToBinaryColor<RGB> {
         let source = rgb_to_arr(source);
         let total: i16 = source.into_iter().map(|x| x as i16).sum();
         let total = total + error.0[0];
-        let max: i16 = RGB::MAX_R as i16 + RGB::MAX_G as i16 + RGB::MAX_B as i16;
+        let max: i16 = RGB::MAX.into_iter().map(|x| x as i16).sum();
         if total > max / 2 {
             (BinaryColor::On, DefaultQuantizationError([total - max]))
         } else {
             (BinaryColor::Off, DefaultQuantizationError([total]))
         }
     }
+
+    fn intensity(&self, source: &Self::SourceColor) -> u8 {
+        rgb_intensity(source)
+    }
 }
 
-pub struct RgbColorToPalette<'t, RGB: RgbColor, T>(&'t [(RGB, T)]);
+/*
+ * N evenly spaced grey levels, for greyscale panels. Targets are the level, 0 for black up to N - 1
+ * for white. The error is in 8-bit luma, whatever the source's bit depth.
+ */
+pub struct RgbColorToGray<S: DitherSource, const N: usize>(PhantomData<S>);
 
-impl<'t, RGB: RgbColor, T> RgbColorToPalette<'t, RGB, T> {
+impl<S: DitherSource, const N: usize> Default for RgbColorToGray<S, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: DitherSource, const N: usize> RgbColorToGray<S, N> {
+    pub const fn new() -> Self {
+        assert!(N >= 2 && N <= 256, "needs 2 to 256 levels");
+        RgbColorToGray(PhantomData)
+    }
+}
+
+impl<S: DitherSource, const N: usize> DitherPalette for RgbColorToGray<S, N> {
+    type SourceColor = S;
+    type TargetColor = u8;
+    type QuantizationError = DefaultQuantizationError<i16, 1>;
+
+    fn get_closest(
+        &self,
+        source: Self::SourceColor,
+        error: Self::QuantizationError,
+    ) -> (Self::TargetColor, Self::QuantizationError) {
+        let steps = (N - 1) as i32;
+        let value = (rgb_intensity(&source) as i32 + error.0[0] as i32).clamp(0, 255);
+        let level = (value * steps + 127) / 255;
+        (
+            level as u8,
+            DefaultQuantizationError([(value - level * 255 / steps) as i16]),
+        )
+    }
+
+    fn intensity(&self, source: &Self::SourceColor) -> u8 {
+        rgb_intensity(source)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ColorDistance {
+    // Squared distance of the raw channel values, cheapest
+    Rgb,
+    // Squared distance with each channel's square multiplied by its weight, see LUMA_WEIGHTS
+    Weighted([u8; 3]),
+    // Perceptual, picks better colors for e.g. skin tones and sky
+    Oklab,
+}
+
+// Rec. 601 luma weights out of 256, green matters most and blue least
+pub const LUMA_WEIGHTS: [u8; 3] = [77, 150, 29];
+
+// S is the source color, by default the same as the palette's. E is the error channel type.
+pub struct RgbColorToPalette<'t, RGB: DitherSource, T, S: DitherSource = RGB, E = i16> {
+    palette: &'t [(RGB, T)],
+    // Per channel, for ColorDistance::Rgb and Weighted
+    weights: [i32; 3],
+    // Only for ColorDistance::Oklab, with the palette converted up front
+    oklab: Option<OklabPalette>,
+    // Nearest palette index for every LUT_BITS-bit color, see with_lut
+    lut: Option<Vec<u8>>,
+    source: PhantomData<(S, E)>,
+}
+
+// Bits per channel of the lookup table, 32x32x32 entries
+const LUT_BITS: u32 = 5;
+
+struct OklabPalette {
+    lut: LinearLut,
+    colors: Vec<Oklab>,
+}
+
+impl<'t, RGB: DitherSource, T, S: DitherSource> RgbColorToPalette<'t, RGB, T, S> {
     pub const fn new(palette: &'t [(RGB, T)]) -> Self {
-        RgbColorToPalette(palette)
+        RgbColorToPalette {
+            palette,
+            weights: [1; 3],
+            oklab: None,
+            lut: None,
+            source: PhantomData,
+        }
+    }
+
+    pub fn with_distance(palette: &'t [(RGB, T)], distance: ColorDistance) -> Self {
+        let weights = match distance {
+            ColorDistance::Weighted(weights) => weights.map(i32::from),
+            _ => [1; 3],
+        };
+        let oklab = match distance {
+            ColorDistance::Rgb | ColorDistance::Weighted(_) => None,
+            ColorDistance::Oklab => {
+                let lut = LinearLut::new();
+                let colors = palette
+                    .iter()
+                    .map(|(color, _)| oklab_of::<RGB>(&lut, rgb_to_arr(*color).map(|c| c as i16)))
+                    .collect();
+                Some(OklabPalette { lut, colors })
+            }
+        };
+        RgbColorToPalette {
+            palette,
+            weights,
+            oklab,
+            lut: None,
+            source: PhantomData,
+        }
+    }
+
+    // Accumulates error as E instead of i16
+    pub fn with_error_channel<E: ErrorChannel>(self) -> RgbColorToPalette<'t, RGB, T, S, E> {
+        RgbColorToPalette {
+            palette: self.palette,
+            weights: self.weights,
+            oklab: self.oklab,
+            lut: self.lut,
+            source: PhantomData,
+        }
+    }
+}
+
+impl<'t, RGB: DitherSource, T, S: DitherSource, E> RgbColorToPalette<'t, RGB, T, S, E> {
+    /*
+     * Looks the nearest color up in a 32 KB table made here, instead of searching the palette for
+     * every pixel. Colors are rounded to 5 bits for the lookup, so near the boundary between two
+     * palette colors it can pick the other one, which the error diffusion evens out.
+     */
+    pub fn with_lut(mut self) -> Self {
+        if self.palette.len() > 256 {
+            return self;
+        }
+        self.lut = None;
+        let size = 1usize << LUT_BITS;
+        let mut lut = Vec::with_capacity(size * size * size);
+        for index in 0..size * size * size {
+            let cell = [2 * LUT_BITS, LUT_BITS, 0].map(|shift| (index >> shift) & (size - 1));
+            // Center of the cell in 8-bit
+            let color = cell.map(|value| ((value << (8 - LUT_BITS)) | (1 << (7 - LUT_BITS))) as u8);
+            lut.push(self.nearest_index(rescale::<Rgb888, S>(color)) as u8);
+        }
+        self.lut = Some(lut);
+        self
+    }
+
+    // source in S's channel values
+    fn nearest_index(&self, source: [i16; 3]) -> usize {
+        if let Some(lut) = &self.lut {
+            let [r, g, b] = to_8bit::<S>(source).map(|value| (value >> (8 - LUT_BITS)) as usize);
+            return lut[(r << (2 * LUT_BITS)) | (g << LUT_BITS) | b] as usize;
+        }
+        match &self.oklab {
+            None => self
+                .palette
+                .iter()
+                .map(|(palette_source, _)| {
+                    arr3zip(
+                        source,
+                        rescale::<RGB, S>(rgb_to_arr(*palette_source)),
+                        |s, p| s - p,
+                    )
+                    .iter()
+                    .zip(self.weights)
+                    .map(|(error, weight)| {
+                        let error = *error as i32;
+                        error * error * weight
+                    })
+                    .sum::<i32>()
+                })
+                .enumerate()
+                .min_by_key(|(_, distance)| *distance)
+                .map(|(index, _)| index),
+            Some(oklab) => {
+                let source = oklab_of::<S>(&oklab.lut, source);
+                oklab
+                    .colors
+                    .iter()
+                    .map(|color| source.distance_squared(color))
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map(|(index, _)| index)
+            }
+        }
+        .unwrap()
     }
 }
-impl<'t, RGB: RgbColor, T> DitherPalette for RgbColorToPalette<'t, RGB, T>
+
+fn oklab_of<RGB: DitherSource>(lut: &LinearLut, color: [i16; 3]) -> Oklab {
+    Oklab::from_linear(lut.rgb(to_8bit::<RGB>(color)))
+}
+
+// Rec. 601 luma, 0 to 255
+fn rgb_intensity<RGB: DitherSource>(color: &RGB) -> u8 {
+    let [r, g, b] = to_8bit::<RGB>(rgb_to_arr(*color).map(|c| c as i16));
+    ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8
+}
+
+// For colors with fewer bits per channel, as the sRGB tables are 8-bit
+fn to_8bit<RGB: DitherSource>(color: [i16; 3]) -> [u8; 3] {
+    arr3zip(color, rgb_max_arr::<RGB>(), |value, max| {
+        (value.clamp(0, max as i16) as u32 * 255 / (max as u32).max(1)) as u8
+    })
+}
+
+impl<'t, RGB: DitherSource, T, S: DitherSource, E: ErrorChannel> DitherPalette
+    for RgbColorToPalette<'t, RGB, T, S, E>
 where
     T: Clone,
 {
-    type SourceColor = RGB;
+    type SourceColor = S;
     type TargetColor = T;
-    type QuantizationError = DefaultQuantizationError<i16, 3>;
+    type QuantizationError = DefaultQuantizationError<E, 3>;
 
     fn get_closest(
         &self,
@@ -312,27 +1317,288 @@ where
         error: Self::QuantizationError,
     ) -> (Self::TargetColor, Self::QuantizationError) {
         let source = rgb_to_arr(source);
+        let source_adjusted: [i32; 3] = arr3zip(source, error.0, |source, error| {
+            source as i32 + error.into()
+        });
         let source_adjusted: [i16; 3] =
-            arr3zip(source, error.0, |source, error| (source as i16) + error);
-        let source_adjusted: [i16; 3] =
-            arr3zip(source_adjusted, rgb_max_arr::<RGB>(), |source, max| {
-                source.clamp(0, max as i16)
-            });
-        let options = self.0.iter();
-        let options = options.map(|(palette_source, palette_target)| {
-            let errors: [i16; 3] = arr3zip(source_adjusted, rgb_to_arr(*palette_source), |s, p| {
-                s - (p as i16)
+            arr3zip(source_adjusted, rgb_max_arr::<S>(), |source, max| {
+                source.clamp(0, max as i32) as i16
             });
-            let distance: i32 = errors
-                .iter()
-                .map(|error| {
-                    let error = *error as i32;
-                    error * error
-                })
-                .sum();
-            (distance, DefaultQuantizationError(errors), palette_target)
+        let errors = |palette_source: RGB| -> [E; 3] {
+            arr3zip(
+                source_adjusted,
+                rescale::<RGB, S>(rgb_to_arr(palette_source)),
+                |s, p| E::saturate(s as i32 - p as i32),
+            )
+        };
+        let index = self.nearest_index(source_adjusted);
+        let (palette_source, palette_target) = &self.palette[index];
+        (
+            palette_target.clone(),
+            DefaultQuantizationError(errors(*palette_source)),
+        )
+    }
+
+    fn intensity(&self, source: &Self::SourceColor) -> u8 {
+        rgb_intensity(source)
+    }
+}
+
+// Full scale of linear light in LinearRgbToPalette, 12 bits is plenty for 8-bit sRGB input
+const LINEAR_MAX: i32 = 4095;
+
+/*
+ * Like RgbColorToPalette, but with both the image and the palette converted from sRGB to linear
+ * light first, and the error diffused in linear light. Diffusing gamma-encoded error darkens the
+ * midtones, as a mix of black and white pixels looks lighter than its sRGB average.
+ */
+pub struct LinearRgbToPalette<'t, RGB: DitherSource, T, S: DitherSource = RGB> {
+    palette: &'t [(RGB, T)],
+    linear_palette: Vec<[i32; 3]>,
+    lut: [i32; 256],
+    source: PhantomData<S>,
+}
+
+impl<'t, RGB: DitherSource, T, S: DitherSource> LinearRgbToPalette<'t, RGB, T, S> {
+    pub fn new(palette: &'t [(RGB, T)]) -> Self {
+        let float_lut = LinearLut::new();
+        let lut = core::array::from_fn(|value| {
+            (float_lut.get(value as u8) * LINEAR_MAX as f32 + 0.5) as i32
+        });
+        let linear_palette = palette
+            .iter()
+            .map(|(color, _)| {
+                to_8bit::<RGB>(rgb_to_arr(*color).map(|c| c as i16)).map(|c| lut[c as usize])
+            })
+            .collect();
+        LinearRgbToPalette {
+            palette,
+            linear_palette,
+            lut,
+            source: PhantomData,
+        }
+    }
+}
+
+impl<'t, RGB: DitherSource, T, S: DitherSource> DitherPalette for LinearRgbToPalette<'t, RGB, T, S>
+where
+    T: Clone,
+{
+    type SourceColor = S;
+    type TargetColor = T;
+    type QuantizationError = DefaultQuantizationError<i32, 3>;
+
+    fn get_closest(
+        &self,
+        source: Self::SourceColor,
+        error: Self::QuantizationError,
+    ) -> (Self::TargetColor, Self::QuantizationError) {
+        let source = to_8bit::<S>(rgb_to_arr(source).map(|c| c as i16));
+        let source_adjusted: [i32; 3] = arr3zip(source, error.0, |source, error| {
+            (self.lut[source as usize] + error).clamp(0, LINEAR_MAX)
         });
-        let (_, error, palette_target) = options.min_by_key(|(distance, _, _)| *distance).unwrap();
-        (palette_target.clone(), error)
+        let errors =
+            |linear: &[i32; 3]| -> [i32; 3] { arr3zip(source_adjusted, *linear, |s, p| s - p) };
+        let index = self
+            .linear_palette
+            .iter()
+            .map(|linear| {
+                errors(linear)
+                    .iter()
+                    .map(|error| *error as i64 * *error as i64)
+                    .sum::<i64>()
+            })
+            .enumerate()
+            .min_by_key(|(_, distance)| *distance)
+            .map(|(index, _)| index)
+            .unwrap();
+        (
+            self.palette[index].1.clone(),
+            DefaultQuantizationError(errors(&self.linear_palette[index])),
+        )
+    }
+
+    fn intensity(&self, source: &Self::SourceColor) -> u8 {
+        rgb_intensity(source)
+    }
+}
+
+// Fixed point 1.0 for the barycentric weights in OctahedronPalette
+const WEIGHT_ONE: i32 = 1 << 12;
+
+/*
+ * The six Spectra 6 colors span an octahedron in RGB: black and white as the poles, the four
+ * others around the equator. Every pixel is projected into it (clipped to its surface when out of
+ * gamut), giving a weight per vertex color. Those weights are what gets dithered, so each color
+ * shows up in proportion to its weight over an area, and the error never leaves the gamut.
+ */
+pub struct OctahedronPalette<RGB, T> {
+    projector: OctahedronProjector<f32>,
+    // See with_lut
+    lut: Option<OctahedronLut<f32>>,
+    targets: [T; 6],
+    rgb: PhantomData<RGB>,
+}
+
+// RGB is the source color, the palette can be of any other DitherSource
+impl<RGB: DitherSource, T: Clone> OctahedronPalette<RGB, T> {
+    /*
+     * Palette in the projector's order: the two poles (black and white) first, then the other
+     * four in cyclic order, like the SPECTRA_6_PALETTE consts. None unless there's six colors.
+     */
+    pub fn new<P: DitherSource>(palette: &[(P, T)]) -> Option<Self> {
+        let palette: &[(P, T); 6] = palette.try_into().ok()?;
+        Some(OctahedronPalette {
+            projector: OctahedronProjector::new(palette.each_ref().map(|(color, _)| {
+                let [r, g, b] = rescale::<P, RGB>(rgb_to_arr(*color)).map(|c| c as f32);
+                Point3::new(r, g, b)
+            })),
+            lut: None,
+            targets: palette.each_ref().map(|(_, target)| target.clone()),
+            rgb: PhantomData,
+        })
+    }
+
+    // Interpolates projections from a resolution^3 grid instead, see OctahedronLut
+    pub fn with_lut(mut self, resolution: usize) -> Self {
+        self.lut = Some(
+            self.projector
+                .build_lut_with_max(resolution, rgb_max_arr::<RGB>()),
+        );
+        self
+    }
+}
+
+impl<RGB: DitherSource, T: Clone> DitherPalette for OctahedronPalette<RGB, T> {
+    type SourceColor = RGB;
+    type TargetColor = T;
+    type QuantizationError = DefaultQuantizationError<i32, 6>;
+
+    fn get_closest(
+        &self,
+        source: Self::SourceColor,
+        error: Self::QuantizationError,
+    ) -> (Self::TargetColor, Self::QuantizationError) {
+        let weights = match &self.lut {
+            Some(lut) => lut.project(rgb_to_arr(source)),
+            None => {
+                let [r, g, b] = rgb_to_arr(source).map(|c| c as f32);
+                self.projector.project(&Point3::new(r, g, b))
+            }
+        };
+        let adjusted: [i32; 6] = core::array::from_fn(|index| {
+            (weights[index] * WEIGHT_ONE as f32) as i32 + error.0[index]
+        });
+        let index = (0..6).max_by_key(|&index| adjusted[index]).unwrap_or(0);
+        let mut error = adjusted;
+        error[index] -= WEIGHT_ONE;
+        (self.targets[index].clone(), DefaultQuantizationError(error))
+    }
+
+    fn intensity(&self, source: &Self::SourceColor) -> u8 {
+        rgb_intensity(source)
+    }
+}
+
+/*
+ * Wraps another palette, but maps pixels that are close to black or white straight to those,
+ * without taking in or passing on any error. UI text and lines on a flat background stay crisp,
+ * while photos in the same frame are dithered by the inner palette as usual. A pixel is near black
+ * when all of its channels are at most black_max (in 8-bit), near white when all are at least
+ * white_min.
+ */
+pub struct TextThreshold<PALETTE: DitherPalette> {
+    palette: PALETTE,
+    black: PALETTE::TargetColor,
+    white: PALETTE::TargetColor,
+    black_max: u8,
+    white_min: u8,
+}
+
+impl<PALETTE: DitherPalette> TextThreshold<PALETTE> {
+    pub fn new(palette: PALETTE, black: PALETTE::TargetColor, white: PALETTE::TargetColor) -> Self {
+        TextThreshold {
+            palette,
+            black,
+            white,
+            black_max: 32,
+            white_min: 224,
+        }
+    }
+
+    pub fn with_thresholds(mut self, black_max: u8, white_min: u8) -> Self {
+        self.black_max = black_max;
+        self.white_min = white_min;
+        self
+    }
+}
+
+impl<PALETTE: DitherPalette> DitherPalette for TextThreshold<PALETTE>
+where
+    PALETTE::SourceColor: DitherSource,
+    PALETTE::TargetColor: Clone,
+{
+    type SourceColor = PALETTE::SourceColor;
+    type TargetColor = PALETTE::TargetColor;
+    type QuantizationError = PALETTE::QuantizationError;
+
+    fn get_closest(
+        &self,
+        source: Self::SourceColor,
+        error: <Self::QuantizationError as Div<usize>>::Output,
+    ) -> (Self::TargetColor, Self::QuantizationError) {
+        let channels = to_8bit::<Self::SourceColor>(rgb_to_arr(source).map(|c| c as i16));
+        if channels.iter().all(|value| *value <= self.black_max) {
+            (self.black.clone(), Default::default())
+        } else if channels.iter().all(|value| *value >= self.white_min) {
+            (self.white.clone(), Default::default())
+        } else {
+            self.palette.get_closest(source, error)
+        }
+    }
+
+    fn intensity(&self, source: &Self::SourceColor) -> u8 {
+        self.palette.intensity(source)
+    }
+}
+
+/*
+ * A palette from a closure, for trying out quantizers without a type of their own:
+ *
+ *   FnPalette::new(|color: Rgb888, error: DefaultQuantizationError<i16, 1>| { ... })
+ *
+ * The closure gets the source color and the error diffused onto it, and returns the target color
+ * and the error it leaves.
+ */
+pub struct FnPalette<S, T, E, F> {
+    f: F,
+    types: PhantomData<fn(S, E) -> T>,
+}
+
+impl<S, T, E, F: Fn(S, E) -> (T, E)> FnPalette<S, T, E, F> {
+    pub const fn new(f: F) -> Self {
+        FnPalette {
+            f,
+            types: PhantomData,
+        }
+    }
+}
+
+impl<S, T, E, F> DitherPalette for FnPalette<S, T, E, F>
+where
+    F: Fn(S, E) -> (T, E),
+    E: Default
+        + Clone
+        + Mul<usize, Output = E>
+        + AddAssign<E>
+        + Div<usize, Output = E>
+        + LimitQuantizationError,
+{
+    type SourceColor = S;
+    type TargetColor = T;
+    type QuantizationError = E;
+
+    fn get_closest(&self, source: S, error: E) -> (T, E) {
+        (self.f)(source, error)
     }
 }
@@ -0,0 +1,329 @@
+/*
+ * Crate-wide error, by class of failure, for the decisions an application makes on failure: a
+ * network error is worth retrying soon, a decode error means keeping the old image until the
+ * server changes its mind, a display error may need a panel reset. The module-local errors keep
+ * the details, and convert into this one with ?. Pin and bus errors are generic, so only their
+ * embedded-hal ErrorKind is kept.
+ */
+use crate::budget::BudgetError;
+use crate::config::{ConfigError, InvalidSetting};
+use crate::displayinterface::{DataFromReaderError, DisplayInterfaceAsyncError};
+use crate::framepush::{FrameValidationError, ReceiveError};
+use crate::gdep073e01::{PowerOnCheckedError, UpdateFrameCheckedError, WrongFrameSize};
+use crate::pipeline::PipelineError;
+use crate::rawframe::RawFrameError;
+use crate::tls::PemError;
+use crate::typestate::TypestateError;
+use core::fmt::{Display, Formatter};
+use embedded_hal::digital::{self, InputPin, OutputPin};
+use embedded_hal::spi;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiDevice;
+use embedded_io_async::{self as io, ReadExactError};
+use embedded_storage::nor_flash::{NorFlashError, NorFlashErrorKind};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    // Connecting, or the connection dropped halfway
+    Network(IoError),
+    // Got data, but not something that can be shown
+    Decode(DecodeError),
+    Dither(&'static str),
+    Display(DisplayError),
+    // Missing or invalid settings, retrying won't help
+    Config(&'static str),
+    // Reading or writing (flash) storage
+    Storage(IoError),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IoError {
+    UnexpectedEof,
+    Other(io::ErrorKind),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    // None of the decoders recognizes the data
+    UnknownFormat,
+    Failed,
+    TooLarge { width: u32, height: u32 },
+    // Known format, but a variant of it the decoder can't handle
+    Unsupported,
+    InvalidFrame(FrameValidationError),
+    InvalidRawFrame(RawFrameError),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DisplayError {
+    Spi(spi::ErrorKind),
+    Busy(digital::ErrorKind),
+    Dc(digital::ErrorKind),
+    Rst(digital::ErrorKind),
+    Timeout,
+    WrongFrameSize(WrongFrameSize),
+    PowerNotGood { flags: u8 },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Network(x) => write!(f, "network: {}", x),
+            Self::Decode(x) => write!(f, "decode: {}", x),
+            Self::Dither(x) => write!(f, "dither: {}", x),
+            Self::Display(x) => write!(f, "display: {}", x),
+            Self::Config(x) => write!(f, "config: {}", x),
+            Self::Storage(x) => write!(f, "storage: {}", x),
+        }
+    }
+}
+
+impl core::error::Error for Error {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::Network(x) | Self::Storage(x) => Some(x),
+            Self::Decode(x) => Some(x),
+            Self::Display(x) => Some(x),
+            Self::Dither(_) | Self::Config(_) => None,
+        }
+    }
+}
+
+impl Display for IoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::UnexpectedEof => write!(f, "unexpected end of data"),
+            Self::Other(kind) => write!(f, "{:?}", kind),
+        }
+    }
+}
+
+impl core::error::Error for IoError {}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::UnknownFormat => write!(f, "unknown format"),
+            Self::Failed => write!(f, "decoding failed"),
+            Self::TooLarge { width, height } => write!(f, "{}x{} is too large", width, height),
+            Self::Unsupported => write!(f, "unsupported variant of the format"),
+            Self::InvalidFrame(x) => write!(f, "invalid frame: {:?}", x),
+            Self::InvalidRawFrame(x) => write!(f, "invalid raw frame: {:?}", x),
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+impl Display for DisplayError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Spi(kind) => write!(f, "SPI: {}", kind),
+            Self::Busy(kind) => write!(f, "BUSY pin: {}", kind),
+            Self::Dc(kind) => write!(f, "DC pin: {}", kind),
+            Self::Rst(kind) => write!(f, "RST pin: {}", kind),
+            Self::Timeout => write!(f, "timed out"),
+            Self::WrongFrameSize(x) => {
+                write!(f, "frame is {} bytes, expected {}", x.actual, x.expected)
+            }
+            Self::PowerNotGood { flags } => write!(f, "power not good, flags {:#04X}", flags),
+        }
+    }
+}
+
+impl core::error::Error for DisplayError {}
+
+impl From<DecodeError> for Error {
+    fn from(value: DecodeError) -> Self {
+        Self::Decode(value)
+    }
+}
+
+impl From<DisplayError> for Error {
+    fn from(value: DisplayError) -> Self {
+        Self::Display(value)
+    }
+}
+
+impl<E: io::Error> From<ReadExactError<E>> for IoError {
+    fn from(value: ReadExactError<E>) -> Self {
+        match value {
+            ReadExactError::UnexpectedEof => Self::UnexpectedEof,
+            ReadExactError::Other(e) => Self::Other(e.kind()),
+        }
+    }
+}
+
+impl From<BudgetError> for DecodeError {
+    fn from(value: BudgetError) -> Self {
+        match value {
+            BudgetError::UnknownFormat => Self::UnknownFormat,
+            BudgetError::TooLarge { width, height } => Self::TooLarge { width, height },
+        }
+    }
+}
+
+impl From<PipelineError> for DecodeError {
+    fn from(value: PipelineError) -> Self {
+        match value {
+            PipelineError::NoDecoder => Self::UnknownFormat,
+            PipelineError::DecodeFailed => Self::Failed,
+            PipelineError::TooLarge { width, height } => Self::TooLarge { width, height },
+            PipelineError::Unsupported => Self::Unsupported,
+        }
+    }
+}
+
+impl From<BudgetError> for Error {
+    fn from(value: BudgetError) -> Self {
+        Self::Decode(value.into())
+    }
+}
+
+impl From<PipelineError> for Error {
+    fn from(value: PipelineError) -> Self {
+        Self::Decode(value.into())
+    }
+}
+
+impl From<RawFrameError> for Error {
+    fn from(value: RawFrameError) -> Self {
+        Self::Decode(DecodeError::InvalidRawFrame(value))
+    }
+}
+
+impl From<PemError> for Error {
+    fn from(value: PemError) -> Self {
+        match value {
+            PemError::Unterminated => Self::Config("root certificate without an END line"),
+            PemError::InvalidBase64 => Self::Config("root certificate isn't valid base64"),
+        }
+    }
+}
+
+impl From<InvalidSetting> for Error {
+    fn from(value: InvalidSetting) -> Self {
+        Self::Config(value.0)
+    }
+}
+
+impl<E: NorFlashError> From<ConfigError<E>> for Error {
+    fn from(value: ConfigError<E>) -> Self {
+        match value {
+            ConfigError::FlashError(x) => Self::Storage(IoError::Other(match x.kind() {
+                NorFlashErrorKind::NotAligned | NorFlashErrorKind::OutOfBounds => {
+                    io::ErrorKind::InvalidInput
+                }
+                _ => io::ErrorKind::Other,
+            })),
+            ConfigError::TooLarge => Self::Config("settings don't fit in a flash sector"),
+        }
+    }
+}
+
+impl From<FrameValidationError> for Error {
+    fn from(value: FrameValidationError) -> Self {
+        Self::Decode(DecodeError::InvalidFrame(value))
+    }
+}
+
+impl<E: io::Error> From<ReceiveError<E>> for Error {
+    fn from(value: ReceiveError<E>) -> Self {
+        match value {
+            ReceiveError::ReadError(x) => Self::Network(x.into()),
+            ReceiveError::WriteError(x) => Self::Network(IoError::Other(x.kind())),
+            ReceiveError::Invalid(x) => x.into(),
+        }
+    }
+}
+
+impl<SPI, BUSY, DC, RST> From<DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> for DisplayError
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    fn from(value: DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>) -> Self {
+        use digital::Error as _;
+        use spi::Error as _;
+        match value {
+            DisplayInterfaceAsyncError::SPIError(x) => Self::Spi(x.kind()),
+            DisplayInterfaceAsyncError::BUSYError(x) => Self::Busy(x.kind()),
+            DisplayInterfaceAsyncError::DCError(x) => Self::Dc(x.kind()),
+            DisplayInterfaceAsyncError::RSTError(x) => Self::Rst(x.kind()),
+            DisplayInterfaceAsyncError::Timeout => Self::Timeout,
+        }
+    }
+}
+
+impl<SPI, BUSY, DC, RST> From<DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> for Error
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    fn from(value: DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>) -> Self {
+        Self::Display(value.into())
+    }
+}
+
+// The reader is where the frame is kept, usually flash
+impl<E, SPI, BUSY, DC, RST> From<DataFromReaderError<E, SPI, BUSY, DC, RST>> for Error
+where
+    E: io::Error,
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    fn from(value: DataFromReaderError<E, SPI, BUSY, DC, RST>) -> Self {
+        match value {
+            DataFromReaderError::ReadError(x) => Self::Storage(x.into()),
+            DataFromReaderError::InterfaceError(x) => x.into(),
+        }
+    }
+}
+
+// Drops the driver, match on the error first to keep it
+impl<SPI, BUSY, DC, RST, DELAY> From<UpdateFrameCheckedError<SPI, BUSY, DC, RST, DELAY>> for Error
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    fn from(value: UpdateFrameCheckedError<SPI, BUSY, DC, RST, DELAY>) -> Self {
+        match value {
+            UpdateFrameCheckedError::WrongFrameSize(_, x) => {
+                Self::Display(DisplayError::WrongFrameSize(x))
+            }
+            UpdateFrameCheckedError::InterfaceError(x) => x.into(),
+        }
+    }
+}
+
+impl<SPI, BUSY, DC, RST> From<PowerOnCheckedError<SPI, BUSY, DC, RST>> for Error
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    fn from(value: PowerOnCheckedError<SPI, BUSY, DC, RST>) -> Self {
+        match value {
+            PowerOnCheckedError::PowerNotGood { flags } => {
+                Self::Display(DisplayError::PowerNotGood { flags })
+            }
+            PowerOnCheckedError::InterfaceError(x) => x.into(),
+        }
+    }
+}
+
+// Drops the driver, use TypestateError::into_driver first to keep it
+impl<D, E: Into<Error>> From<TypestateError<D, E>> for Error {
+    fn from(value: TypestateError<D, E>) -> Self {
+        value.into_error().into()
+    }
+}
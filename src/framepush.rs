@@ -0,0 +1,125 @@
+/*
+ * Tiny protocol to push an already packed frame to the device, e.g. from a script:
+ *
+ *   client -> device: length (u32 LE), CRC-32 of the payload (u32 LE), payload
+ *   device -> client: ACK, or NAK followed by a single reason byte
+ *
+ * The payload is a packed Spectra 6 frame, exactly FRAME_BYTES long. Validation is shared with
+ * any other path that receives packed frames.
+ */
+use crate::gdep073e01::{HEIGHT, WIDTH};
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use embedded_io_async::{Read, ReadExactError, Write};
+
+pub const FRAME_BYTES: usize = WIDTH * HEIGHT / 2;
+
+pub const ACK: u8 = 0x06;
+pub const NAK: u8 = 0x15;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrameValidationError {
+    WrongLength { expected: usize, actual: usize },
+    CrcMismatch { expected: u32, actual: u32 },
+}
+
+impl FrameValidationError {
+    // Reason byte sent after a NAK
+    pub fn code(&self) -> u8 {
+        match self {
+            FrameValidationError::WrongLength { .. } => 1,
+            FrameValidationError::CrcMismatch { .. } => 2,
+        }
+    }
+}
+
+pub enum ReceiveError<E> {
+    ReadError(ReadExactError<E>),
+    WriteError(E),
+    Invalid(FrameValidationError),
+}
+
+impl<E: Debug> Debug for ReceiveError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::ReadError(x) => write!(f, "ReadError({:?})", x),
+            Self::WriteError(x) => write!(f, "WriteError({:?})", x),
+            Self::Invalid(x) => write!(f, "Invalid({:?})", x),
+        }
+    }
+}
+
+// CRC-32 (IEEE 802.3), as produced by e.g. zlib.crc32 or binascii.crc32
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+// The CRC-32 of what crc was for, followed by data, for data that comes in pieces
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+pub fn validate_frame_length(length: usize) -> Result<(), FrameValidationError> {
+    if length != FRAME_BYTES {
+        return Err(FrameValidationError::WrongLength {
+            expected: FRAME_BYTES,
+            actual: length,
+        });
+    }
+    Ok(())
+}
+
+// Checks a received packed frame, the CRC is optional as not every transport carries one
+pub fn validate_packed_frame(data: &[u8], crc: Option<u32>) -> Result<(), FrameValidationError> {
+    validate_frame_length(data.len())?;
+    if let Some(expected) = crc {
+        let actual = crc32(data);
+        if actual != expected {
+            return Err(FrameValidationError::CrcMismatch { expected, actual });
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Receives a single frame into buffer and acknowledges it. The length is checked before reading
+ * the payload, so a bogus header doesn't make us allocate or wait for data that never comes.
+ */
+pub async fn receive_frame<S: Read + Write>(
+    socket: &mut S,
+    buffer: &mut Vec<u8>,
+) -> Result<(), ReceiveError<S::Error>> {
+    let mut header = [0u8; 8];
+    socket
+        .read_exact(&mut header)
+        .await
+        .map_err(ReceiveError::ReadError)?;
+    let length = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let validated = match validate_frame_length(length) {
+        Ok(()) => {
+            buffer.resize(length, 0);
+            socket
+                .read_exact(buffer.as_mut_slice())
+                .await
+                .map_err(ReceiveError::ReadError)?;
+            validate_packed_frame(buffer.as_slice(), Some(crc))
+        }
+        Err(e) => Err(e),
+    };
+    match validated {
+        Ok(()) => socket.write_all(&[ACK]).await,
+        Err(e) => socket.write_all(&[NAK, e.code()]).await,
+    }
+    .map_err(ReceiveError::WriteError)?;
+    socket.flush().await.map_err(ReceiveError::WriteError)?;
+    validated.map_err(ReceiveError::Invalid)
+}
@@ -1,9 +1,22 @@
-use crate::displayinterface::{DisplayInterfaceAsync, DisplayInterfaceAsyncError};
-use crate::spectra6::{Spectra6Color, SpectraPacker};
+use crate::detect::{Detection, DetectionConfig};
+use crate::displayinterface::{
+    DataFromReaderError, DisplayInterfaceAsync, DisplayInterfaceAsyncError, SpiWiring, Timeouts,
+};
+use crate::dither::{DitherPalette, ForwardErrorDiffusionMethod};
+use crate::shadow::ShadowFrame;
+use crate::spectra6::{DitherPack, Spectra6Color, SpectraPacker};
+use crate::typestate::{Panel, TypestateDriver, TypestateError, TypestateResult};
+pub use crate::typestate::{
+    StateBusy, StatePowerOff, StatePowerOn, StateReset, StateStandby, StateUnknown,
+};
 use embedded_hal::digital::{InputPin, OutputPin};
 use embedded_hal_async::delay::DelayNs;
 use embedded_hal_async::digital::Wait;
 use embedded_hal_async::spi::SpiDevice;
+use embedded_io_async::Read;
+
+pub const WIDTH: usize = 800;
+pub const HEIGHT: usize = 480;
 
 const SINGLE_BYTE_WRITE: bool = true;
 const IS_BUSY_LOW: bool = true;
@@ -31,8 +44,10 @@ enum Command {
     CDI = 0x50,
     TCON_SETTING = 0x60, // TCON
     TRES = 0x61,
+    GetStatus = 0x71, // FLG
     T_VDCS = 0x84,
     PWS = 0xE3,
+    TemperatureSetting = 0xE5, // TSSET, force temperature used for waveform selection
     CMDH = 0xAA,
 }
 
@@ -60,6 +75,14 @@ where
         }
     }
 
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.interface.set_timeouts(timeouts)
+    }
+
+    pub fn set_wiring(&mut self, wiring: SpiWiring) {
+        self.interface.set_wiring(wiring)
+    }
+
     pub async fn reset(
         &mut self,
         delay: &mut DELAY,
@@ -70,56 +93,76 @@ where
     pub async fn init(
         &mut self,
         spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.init_with_sequence(spi, InitSequence::default()).await
+    }
+
+    pub async fn init_with_sequence(
+        &mut self,
+        spi: &mut SPI,
+        sequence: InitSequence,
     ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
         // NOTE: Call after reset
-        //self.interface.reset(delay, 10_000, 10_000, 10_000).await?;
+        self.interface.run_init_sequence(spi, sequence.0).await
+    }
 
+    pub async fn set_pll(
+        &mut self,
+        spi: &mut SPI,
+        pll: u8,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
         self.interface
-            .cmd_with_data(spi, Command::CMDH, &[0x49, 0x55, 0x20, 0x08, 0x09, 0x18])
-            .await?;
-        self.interface
-            .cmd_with_data(spi, Command::PowerSetting, &[0x3F])
-            .await?;
-        self.interface
-            .cmd_with_data(spi, Command::PanelSetting, &[0x5F, 0x69])
-            .await?;
-        self.interface
-            .cmd_with_data(spi, Command::POFS, &[0x00, 0x54, 0x00, 0x44])
-            .await?;
-        self.interface
-            .cmd_with_data(spi, Command::BoosterSoftStart1, &[0x40, 0x1F, 0x1F, 0x2C])
-            .await?;
-        self.interface
-            .cmd_with_data(spi, Command::BoosterSoftStart2, &[0x6F, 0x1F, 0x17, 0x49])
-            .await?;
-        self.interface
-            .cmd_with_data(spi, Command::BoosterSoftStart3, &[0x6F, 0x1F, 0x1F, 0x22])
-            .await?;
-        self.interface
-            .cmd_with_data(spi, Command::PllControl, &[0x03])
-            .await?; // esphome does 0x03, example code for 0x08
-        self.interface
-            .cmd_with_data(spi, Command::CDI, &[0x3F])
-            .await?;
-        self.interface
-            .cmd_with_data(spi, Command::TCON_SETTING, &[0x02, 0x00])
-            .await?;
+            .cmd_with_data(spi, Command::PllControl, &[pll])
+            .await
+    }
+
+    // Overrides the temperature the controller uses to pick its waveforms
+    pub async fn set_temperature(
+        &mut self,
+        spi: &mut SPI,
+        celsius: i8,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
         self.interface
-            .cmd_with_data(spi, Command::TRES, &[0x03, 0x20, 0x01, 0xE0])
-            .await?;
+            .cmd_with_data(spi, Command::TemperatureSetting, &[celsius as u8])
+            .await
+    }
+
+    pub async fn wait_until_idle(
+        &mut self,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface.wait_until_idle(IS_BUSY_LOW).await
+    }
+
+    pub async fn read_status(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<u8, DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        let mut flags = [0u8];
         self.interface
-            .cmd_with_data(spi, Command::T_VDCS, &[0x01])
+            .cmd_read(spi, Command::GetStatus, &mut flags)
             .await?;
+        Ok(flags[0])
+    }
+
+    pub async fn measure_busy_us(
+        &mut self,
+        delay: &mut DELAY,
+        poll_interval_us: u32,
+        timeout_us: u32,
+    ) -> Result<Option<u32>, DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
         self.interface
-            .cmd_with_data(spi, Command::PWS, &[0x2F])
-            .await?;
-        Ok(())
+            .measure_busy_us(delay, IS_BUSY_LOW, poll_interval_us, timeout_us)
+            .await
     }
 
-    pub async fn wait_until_idle(
+    // Escape hatch for experimenting with undocumented registers
+    pub async fn send_raw(
         &mut self,
+        spi: &mut SPI,
+        command: u8,
+        data: &[u8],
     ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
-        self.interface.wait_until_idle(IS_BUSY_LOW).await
+        self.interface.cmd_with_data(spi, command, data).await
     }
 
     pub async fn update_frame_raw(
@@ -134,6 +177,19 @@ where
         Ok(())
     }
 
+    pub async fn update_frame_from_reader<R: Read>(
+        &mut self,
+        spi: &mut SPI,
+        reader: &mut R,
+    ) -> Result<(), DataFromReaderError<R::Error, SPI, BUSY, DC, RST>> {
+        self.interface
+            .cmd(spi, Command::DataStartTransmission)
+            .await?;
+        self.interface
+            .data_from_reader(spi, reader, WIDTH * HEIGHT / 2)
+            .await
+    }
+
     pub async fn update_frame(
         &mut self,
         spi: &mut SPI,
@@ -143,6 +199,41 @@ where
             .await
     }
 
+    /*
+     * Dithers pixels (WIDTH per row) while sending them, so only the diffusion method's few error
+     * rows are in RAM instead of the whole frame. For error limits, make the ForwardErrorDiffusion
+     * yourself and pass it to update_frame_raw in a DitherPack.
+     */
+    pub async fn update_frame_dithered<PALETTE, METHOD>(
+        &mut self,
+        spi: &mut SPI,
+        palette: PALETTE,
+        method: METHOD,
+        pixels: impl IntoIterator<Item = PALETTE::SourceColor>,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>>
+    where
+        PALETTE: DitherPalette<TargetColor = Spectra6Color>,
+        METHOD: ForwardErrorDiffusionMethod,
+    {
+        let packed = DitherPack::new(palette, method, pixels.into_iter(), WIDTH);
+        self.update_frame_raw(spi, packed).await
+    }
+
+    pub async fn clear_frame(
+        &mut self,
+        spi: &mut SPI,
+        color: Spectra6Color,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        let packed = (color as u8) << 4 | (color as u8);
+        self.interface
+            .cmd(spi, Command::DataStartTransmission)
+            .await?;
+        self.interface
+            .data_x_times(spi, packed, WIDTH * HEIGHT / 2)
+            .await?;
+        Ok(())
+    }
+
     pub async fn display_frame(
         &mut self,
         spi: &mut SPI,
@@ -169,69 +260,191 @@ where
             .await
         //NOTE: Must wait here
     }
+
+    // Only a reset wakes the controller up again, RAM content is lost
+    pub async fn deep_sleep(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface
+            .cmd_with_data(spi, Command::DeepSleep, &[0xA5])
+            .await
+    }
+}
+
+// Rated operating range of the GDEP073E01 panel
+pub const MIN_TEMPERATURE_CELSIUS: i8 = 0;
+pub const MAX_TEMPERATURE_CELSIUS: i8 = 50;
+
+// PLL setting from the default init sequence
+const DEFAULT_PLL: u8 = 0x03;
+
+// Register settings sent by init, the sources only differ in the PLL (frame rate) setting
+#[derive(Clone, Copy)]
+pub struct InitSequence(&'static [(Command, &'static [u8])]);
+
+impl InitSequence {
+    pub const ESPHOME: InitSequence = InitSequence(&ESPHOME_SEQUENCE);
+    pub const VENDOR_EXAMPLE: InitSequence = InitSequence(&VENDOR_EXAMPLE_SEQUENCE);
+}
+
+impl Default for InitSequence {
+    fn default() -> Self {
+        InitSequence::ESPHOME
+    }
+}
+
+const ESPHOME_SEQUENCE: [(Command, &[u8]); 13] = init_sequence(&[DEFAULT_PLL]);
+const VENDOR_EXAMPLE_SEQUENCE: [(Command, &[u8]); 13] = init_sequence(&[0x08]);
+
+const fn init_sequence(pll: &'static [u8]) -> [(Command, &'static [u8]); 13] {
+    [
+        (Command::CMDH, &[0x49, 0x55, 0x20, 0x08, 0x09, 0x18]),
+        (Command::PowerSetting, &[0x3F]),
+        (Command::PanelSetting, &[0x5F, 0x69]),
+        (Command::POFS, &[0x00, 0x54, 0x00, 0x44]),
+        (Command::BoosterSoftStart1, &[0x40, 0x1F, 0x1F, 0x2C]),
+        (Command::BoosterSoftStart2, &[0x6F, 0x1F, 0x17, 0x49]),
+        (Command::BoosterSoftStart3, &[0x6F, 0x1F, 0x1F, 0x22]),
+        (Command::PllControl, pll),
+        (Command::CDI, &[0x3F]),
+        (Command::TCON_SETTING, &[0x02, 0x00]),
+        (Command::TRES, &[0x03, 0x20, 0x01, 0xE0]),
+        (Command::T_VDCS, &[0x01]),
+        (Command::PWS, &[0x2F]),
+    ]
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TemperatureDecision {
+    // Refresh, using this PLL (frame rate) setting
+    Refresh { pll: u8 },
+    // Don't refresh at all, the result would look bad or could harm the panel
+    Refuse,
+}
+
+// Lets firmware decide how to handle the ambient temperature before a refresh
+pub trait TemperaturePolicy {
+    fn decide(&self, celsius: i8) -> TemperatureDecision;
+}
+
+/*
+ * Refuses outside the rated range, and optionally switches to a different PLL setting when it's
+ * cold, as the particles move slower then.
+ */
+pub struct RatedTemperaturePolicy {
+    pub min_celsius: i8,
+    pub max_celsius: i8,
+    pub cold_below_celsius: i8,
+    pub pll: u8,
+    pub cold_pll: u8,
+}
+
+impl Default for RatedTemperaturePolicy {
+    fn default() -> Self {
+        RatedTemperaturePolicy {
+            min_celsius: MIN_TEMPERATURE_CELSIUS,
+            max_celsius: MAX_TEMPERATURE_CELSIUS,
+            cold_below_celsius: 10,
+            pll: DEFAULT_PLL,
+            cold_pll: DEFAULT_PLL,
+        }
+    }
 }
 
-pub struct StateUnknown;
-pub struct StateReset;
-pub struct StatePowerOff;
-pub struct StateBusy<T>(T);
-pub struct StatePowerOn;
+impl TemperaturePolicy for RatedTemperaturePolicy {
+    fn decide(&self, celsius: i8) -> TemperatureDecision {
+        if celsius < self.min_celsius || celsius > self.max_celsius {
+            TemperatureDecision::Refuse
+        } else if celsius < self.cold_below_celsius {
+            TemperatureDecision::Refresh { pll: self.cold_pll }
+        } else {
+            TemperatureDecision::Refresh { pll: self.pll }
+        }
+    }
+}
 
-pub struct Gdep073e01State<STATE, SPI, BUSY, DC, RST, DELAY> {
-    display: Gdep073e01<SPI, BUSY, DC, RST, DELAY>,
-    state: STATE,
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WrongFrameSize {
+    pub expected: usize,
+    pub actual: usize,
 }
 
-#[allow(dead_code)] // Allow display in here, even if it's likely never used.
-pub struct Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY>
+pub enum UpdateFrameCheckedError<SPI, BUSY, DC, RST, DELAY>
 where
     SPI: SpiDevice,
     BUSY: InputPin + Wait,
     DC: OutputPin,
     RST: OutputPin,
-    DELAY: DelayNs,
 {
-    display: Gdep073e01State<StateUnknown, SPI, BUSY, DC, RST, DELAY>,
-    error: DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>,
+    // Refused before sending anything, the panel is still powered on and can take another frame
+    WrongFrameSize(
+        Gdep073e01State<StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+        WrongFrameSize,
+    ),
+    InterfaceError(Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY>),
 }
 
-impl<SPI, BUSY, DC, RST, DELAY> core::fmt::Debug for Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY>
+impl<SPI, BUSY, DC, RST, DELAY> core::fmt::Debug
+    for UpdateFrameCheckedError<SPI, BUSY, DC, RST, DELAY>
 where
     SPI: SpiDevice,
     BUSY: InputPin + Wait,
     DC: OutputPin,
     RST: OutputPin,
-    DELAY: DelayNs,
 {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        self.error.fmt(f)
+        match self {
+            Self::WrongFrameSize(_, x) => write!(f, "WrongFrameSize({:?})", x),
+            Self::InterfaceError(x) => write!(f, "InterfaceError({:?})", x),
+        }
     }
 }
 
-type Gdep073e01StateResult<STATE, SPI, BUSY, DC, RST, DELAY> = Result<
-    Gdep073e01State<STATE, SPI, BUSY, DC, RST, DELAY>,
-    Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY>,
->;
+// Set in the FLG register once the boost converters reached regulation after PowerOn
+const STATUS_POWER_ON: u8 = 1 << 2;
 
-impl<SPI, BUSY, DC, RST, DELAY> Gdep073e01State<StateUnknown, SPI, BUSY, DC, RST, DELAY>
+pub enum PowerOnCheckedError<SPI, BUSY, DC, RST>
 where
     SPI: SpiDevice,
     BUSY: InputPin + Wait,
     DC: OutputPin,
     RST: OutputPin,
-    DELAY: DelayNs,
 {
-    pub fn new(_: &mut SPI, busy: BUSY, dc: DC, rst: RST, _: &mut DELAY) -> Self {
-        Self {
-            display: Gdep073e01 {
-                interface: DisplayInterfaceAsync::new(busy, dc, rst),
-            },
-            state: StateUnknown,
+    // Power on finished, but the controller doesn't report power good. Contains the FLG register.
+    PowerNotGood { flags: u8 },
+    InterfaceError(DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>),
+}
+
+impl<SPI, BUSY, DC, RST> core::fmt::Debug for PowerOnCheckedError<SPI, BUSY, DC, RST>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::PowerNotGood { flags } => write!(f, "PowerNotGood {{ flags: {:#04X} }}", flags),
+            Self::InterfaceError(x) => write!(f, "InterfaceError({:?})", x),
         }
     }
 }
 
-impl<STATE, SPI, BUSY, DC, RST, DELAY> Gdep073e01State<STATE, SPI, BUSY, DC, RST, DELAY>
+impl<SPI, BUSY, DC, RST> From<DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>>
+    for PowerOnCheckedError<SPI, BUSY, DC, RST>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    fn from(value: DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>) -> Self {
+        Self::InterfaceError(value)
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> Panel for Gdep073e01<SPI, BUSY, DC, RST, DELAY>
 where
     SPI: SpiDevice,
     BUSY: InputPin + Wait,
@@ -239,31 +452,58 @@ where
     RST: OutputPin,
     DELAY: DelayNs,
 {
-    fn map_state_from_result<R, NEWSTATE, F: FnOnce(STATE, R) -> NEWSTATE>(
-        self,
-        ret: Result<R, DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>>,
-        f: F,
-    ) -> Gdep073e01StateResult<NEWSTATE, SPI, BUSY, DC, RST, DELAY> {
-        match ret {
-            Ok(result) => Ok(Gdep073e01State {
-                display: self.display,
-                state: f(self.state, result),
-            }),
-            Err(error) => Err(Gdep073e01StateError {
-                display: Gdep073e01State {
-                    display: self.display,
-                    state: StateUnknown,
-                },
-                error,
-            }),
-        }
+    type Delay = DELAY;
+    type Error = DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>;
+
+    async fn reset(&mut self, delay: &mut DELAY) -> Result<(), Self::Error> {
+        Gdep073e01::reset(self, delay).await
     }
-    pub async fn reset(
-        mut self,
-        delay: &mut DELAY,
-    ) -> Gdep073e01StateResult<StateReset, SPI, BUSY, DC, RST, DELAY> {
-        let res = self.display.reset(delay).await;
-        self.map_state_from_result(res, |_, _| StateReset)
+
+    async fn wait_until_idle(&mut self) -> Result<(), Self::Error> {
+        Gdep073e01::wait_until_idle(self).await
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) {
+        Gdep073e01::set_timeouts(self, timeouts)
+    }
+
+    fn set_wiring(&mut self, wiring: SpiWiring) {
+        Gdep073e01::set_wiring(self, wiring)
+    }
+}
+
+pub type Gdep073e01State<STATE, SPI, BUSY, DC, RST, DELAY> =
+    TypestateDriver<Gdep073e01<SPI, BUSY, DC, RST, DELAY>, STATE>;
+
+pub type Gdep073e01StateError<
+    SPI,
+    BUSY,
+    DC,
+    RST,
+    DELAY,
+    E = DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>,
+> = TypestateError<Gdep073e01<SPI, BUSY, DC, RST, DELAY>, E>;
+
+type Gdep073e01StateResult<
+    STATE,
+    SPI,
+    BUSY,
+    DC,
+    RST,
+    DELAY,
+    E = DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>,
+> = TypestateResult<Gdep073e01<SPI, BUSY, DC, RST, DELAY>, STATE, E>;
+
+impl<SPI, BUSY, DC, RST, DELAY> Gdep073e01State<StateUnknown, SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub fn new(spi: &mut SPI, busy: BUSY, dc: DC, rst: RST, delay: &mut DELAY) -> Self {
+        Self::from_display(Gdep073e01::new(spi, busy, dc, rst, delay))
     }
 }
 
@@ -282,6 +522,15 @@ where
         let res = self.display.init(spi).await;
         self.map_state_from_result(res, |_, _| StatePowerOff)
     }
+
+    pub async fn init_with_sequence(
+        mut self,
+        spi: &mut SPI,
+        sequence: InitSequence,
+    ) -> Gdep073e01StateResult<StatePowerOff, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.init_with_sequence(spi, sequence).await;
+        self.map_state_from_result(res, |_, _| StatePowerOff)
+    }
 }
 
 impl<SPI, BUSY, DC, RST, DELAY> Gdep073e01State<StatePowerOff, SPI, BUSY, DC, RST, DELAY>
@@ -292,6 +541,87 @@ where
     RST: OutputPin,
     DELAY: DelayNs,
 {
+    /*
+     * Asks policy what to do at the given ambient temperature (from the board sensor or elsewhere)
+     * and configures the controller accordingly. Nothing is sent when the policy refuses, it's up
+     * to the caller to then skip the refresh.
+     */
+    pub async fn apply_temperature(
+        mut self,
+        spi: &mut SPI,
+        celsius: i8,
+        policy: &impl TemperaturePolicy,
+    ) -> Result<(Self, TemperatureDecision), Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY>> {
+        let decision = policy.decide(celsius);
+        let res = match decision {
+            TemperatureDecision::Refresh { pll } => {
+                match self.display.set_temperature(spi, celsius).await {
+                    Ok(()) => self.display.set_pll(spi, pll).await,
+                    Err(e) => Err(e),
+                }
+            }
+            TemperatureDecision::Refuse => Ok(()),
+        };
+        let display = self.map_state_from_result(res, |s, _| s)?;
+        Ok((display, decision))
+    }
+
+    /*
+     * Sends an arbitrary command with data. Only allowed while powered off, so whatever it changes
+     * is picked up by the next power on. Use wait_until_idle afterwards if the command makes the
+     * controller busy.
+     */
+    pub async fn send_raw(
+        mut self,
+        spi: &mut SPI,
+        command: u8,
+        data: &[u8],
+    ) -> Gdep073e01StateResult<StatePowerOff, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.send_raw(spi, command, data).await;
+        self.map_state_from_result(res, |s, _| s)
+    }
+
+    pub async fn wait_until_idle(
+        mut self,
+    ) -> Gdep073e01StateResult<StatePowerOff, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.wait_until_idle().await;
+        self.map_state_from_result(res, |s, _| s)
+    }
+
+    /*
+     * Powers on and off once, timing how long BUSY stays asserted, and matches that against the
+     * signatures in config. If BUSY doesn't clear within the timeout, this still waits for it before
+     * powering off again.
+     */
+    pub async fn detect_variant(
+        mut self,
+        spi: &mut SPI,
+        delay: &mut DELAY,
+        config: &DetectionConfig<'_>,
+    ) -> Result<(Self, Detection), Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY>> {
+        let res = async {
+            self.display.power_on(spi).await?;
+            let busy_us = self
+                .display
+                .measure_busy_us(delay, config.poll_interval_us, config.timeout_us)
+                .await?;
+            self.display.wait_until_idle().await?;
+            self.display.power_off(spi).await?;
+            self.display.wait_until_idle().await?;
+            Ok(busy_us)
+        }
+        .await;
+        let busy_us = res.as_ref().ok().copied().flatten();
+        let display = self.map_state_from_result(res, |s, _| s)?;
+        Ok((
+            display,
+            Detection {
+                variant: config.classify(busy_us),
+                busy_us,
+            },
+        ))
+    }
+
     pub async fn power_on_no_wait(
         mut self,
         spi: &mut SPI,
@@ -305,10 +635,39 @@ where
     ) -> Gdep073e01StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
         self.power_on_no_wait(spi).await?.wait().await
     }
+
+    /*
+     * Like power_on, but reads back the status afterwards and fails if the boost converters didn't
+     * reach regulation, rather than going on to a washed-out refresh. Needs a readable data line,
+     * see DisplayInterfaceAsync::cmd_read.
+     */
+    pub async fn power_on_checked(
+        mut self,
+        spi: &mut SPI,
+    ) -> Gdep073e01StateResult<
+        StatePowerOn,
+        SPI,
+        BUSY,
+        DC,
+        RST,
+        DELAY,
+        PowerOnCheckedError<SPI, BUSY, DC, RST>,
+    > {
+        let res = async {
+            self.display.power_on(spi).await?;
+            self.display.wait_until_idle().await?;
+            let flags = self.display.read_status(spi).await?;
+            if flags & STATUS_POWER_ON == 0 {
+                return Err(PowerOnCheckedError::PowerNotGood { flags });
+            }
+            Ok(())
+        }
+        .await;
+        self.map_state_from_result(res, |_, _| StatePowerOn)
+    }
 }
 
-impl<DONESTATE, SPI, BUSY, DC, RST, DELAY>
-    Gdep073e01State<StateBusy<DONESTATE>, SPI, BUSY, DC, RST, DELAY>
+impl<SPI, BUSY, DC, RST, DELAY> Gdep073e01State<StateStandby, SPI, BUSY, DC, RST, DELAY>
 where
     SPI: SpiDevice,
     BUSY: InputPin + Wait,
@@ -316,9 +675,20 @@ where
     RST: OutputPin,
     DELAY: DelayNs,
 {
-    pub async fn wait(mut self) -> Gdep073e01StateResult<DONESTATE, SPI, BUSY, DC, RST, DELAY> {
-        let res = self.display.wait_until_idle().await;
-        self.map_state_from_result(res, |StateBusy(x), _| x)
+    // Powers back on, after which display_frame shows the frame still in RAM
+    pub async fn resume_no_wait(
+        mut self,
+        spi: &mut SPI,
+    ) -> Gdep073e01StateResult<StateBusy<StatePowerOn>, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.power_on(spi).await;
+        self.map_state_from_result(res, |_, _| StateBusy(StatePowerOn))
+    }
+
+    pub async fn resume(
+        self,
+        spi: &mut SPI,
+    ) -> Gdep073e01StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
+        self.resume_no_wait(spi).await?.wait().await
     }
 }
 
@@ -345,6 +715,25 @@ where
         self.power_off_no_wait(spi).await?.wait().await
     }
 
+    /*
+     * Powers down the analog rails like power_off, but the frame RAM is kept and known to be valid,
+     * so the same frame can be refreshed again after resume without uploading it again.
+     */
+    pub async fn standby_no_wait(
+        mut self,
+        spi: &mut SPI,
+    ) -> Gdep073e01StateResult<StateBusy<StateStandby>, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.power_off(spi).await;
+        self.map_state_from_result(res, |_, _| StateBusy(StateStandby))
+    }
+
+    pub async fn standby(
+        self,
+        spi: &mut SPI,
+    ) -> Gdep073e01StateResult<StateStandby, SPI, BUSY, DC, RST, DELAY> {
+        self.standby_no_wait(spi).await?.wait().await
+    }
+
     pub async fn update_frame(
         mut self,
         spi: &mut SPI,
@@ -354,6 +743,87 @@ where
         self.map_state_from_result(res, |s, _| s)
     }
 
+    // Already packed bytes, e.g. a pre-dithered frame from rawframe
+    pub async fn update_frame_raw(
+        mut self,
+        spi: &mut SPI,
+        data: impl IntoIterator<Item = u8>,
+    ) -> Gdep073e01StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.update_frame_raw(spi, data).await;
+        self.map_state_from_result(res, |s, _| s)
+    }
+
+    pub async fn update_frame_dithered<PALETTE, METHOD>(
+        mut self,
+        spi: &mut SPI,
+        palette: PALETTE,
+        method: METHOD,
+        pixels: impl IntoIterator<Item = PALETTE::SourceColor>,
+    ) -> Gdep073e01StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY>
+    where
+        PALETTE: DitherPalette<TargetColor = Spectra6Color>,
+        METHOD: ForwardErrorDiffusionMethod,
+    {
+        let res = self
+            .display
+            .update_frame_dithered(spi, palette, method, pixels)
+            .await;
+        self.map_state_from_result(res, |s, _| s)
+    }
+
+    // Like update_frame, but refuses (without sending anything) when pixels isn't exactly one frame
+    pub async fn update_frame_checked<I>(
+        self,
+        spi: &mut SPI,
+        pixels: I,
+    ) -> Result<Self, UpdateFrameCheckedError<SPI, BUSY, DC, RST, DELAY>>
+    where
+        I: IntoIterator<Item = Spectra6Color>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let pixels = pixels.into_iter();
+        if pixels.len() != WIDTH * HEIGHT {
+            let size = WrongFrameSize {
+                expected: WIDTH * HEIGHT,
+                actual: pixels.len(),
+            };
+            return Err(UpdateFrameCheckedError::WrongFrameSize(self, size));
+        }
+        self.update_frame(spi, pixels)
+            .await
+            .map_err(UpdateFrameCheckedError::InterfaceError)
+    }
+
+    // Streams an already packed frame, e.g. straight from a socket, without holding it in RAM
+    pub async fn update_frame_from_reader<R: Read>(
+        mut self,
+        spi: &mut SPI,
+        mut reader: R,
+    ) -> Gdep073e01StateResult<
+        StatePowerOn,
+        SPI,
+        BUSY,
+        DC,
+        RST,
+        DELAY,
+        DataFromReaderError<R::Error, SPI, BUSY, DC, RST>,
+    > {
+        let res = self
+            .display
+            .update_frame_from_reader(spi, &mut reader)
+            .await;
+        self.map_state_from_result(res, |s, _| s)
+    }
+
+    pub async fn clear(
+        mut self,
+        spi: &mut SPI,
+        color: Spectra6Color,
+    ) -> Gdep073e01StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.clear_frame(spi, color).await;
+        self.map_state_from_result(res, |s, _| s)
+    }
+
     pub async fn display_frame_no_wait(
         mut self,
         spi: &mut SPI,
@@ -367,4 +837,42 @@ where
     ) -> Gdep073e01StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
         self.display_frame_no_wait(spi).await?.wait().await
     }
+
+    /*
+     * Uploads the frame, but only does the (slow, power hungry) refresh if it differs from the one
+     * recorded in shadow. Returns whether the panel was refreshed.
+     */
+    pub async fn display_if_changed(
+        mut self,
+        spi: &mut SPI,
+        pixels: impl IntoIterator<Item = Spectra6Color>,
+        shadow: &mut ShadowFrame,
+    ) -> Result<
+        (
+            Gdep073e01State<StatePowerOn, SPI, BUSY, DC, RST, DELAY>,
+            bool,
+        ),
+        Gdep073e01StateError<SPI, BUSY, DC, RST, DELAY>,
+    > {
+        let mut tracker = shadow.track(SpectraPacker(pixels.into_iter()));
+        let res = self.display.update_frame_raw(spi, &mut tracker).await;
+        let changed = if res.is_ok() {
+            tracker.finish()
+        } else {
+            shadow.invalidate();
+            true
+        };
+        let display = self.map_state_from_result(res, |s, _| s)?;
+        if !changed {
+            return Ok((display, false));
+        }
+        match display.display_frame(spi).await {
+            Ok(display) => Ok((display, true)),
+            Err(e) => {
+                // Not on the panel after all, so the same frame again still gets refreshed
+                shadow.invalidate();
+                Err(e)
+            }
+        }
+    }
 }
@@ -0,0 +1,208 @@
+/*
+ * Just enough of an HTTP/1.1 server for pages and uploads on the local network: one request per
+ * connection, bodies with a Content-Length (no chunked uploads), and the connection is closed after
+ * the response. Browsers and curl are both happy with that.
+ */
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use embedded_io_async::{Read, Write};
+
+pub const DEFAULT_PORT: u16 = 80;
+
+// Request line and headers together, anything longer is refused
+pub const MAX_HEAD_SIZE: usize = 2048;
+
+pub enum HttpError<E> {
+    ReadError(E),
+    WriteError(E),
+    // Connection closed before the whole request was in
+    UnexpectedEof,
+    BadRequest(&'static str),
+    // Body longer than the limit, it wasn't read
+    TooLarge(usize),
+}
+
+impl<E: Debug> Debug for HttpError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::ReadError(x) => write!(f, "ReadError({:?})", x),
+            Self::WriteError(x) => write!(f, "WriteError({:?})", x),
+            Self::UnexpectedEof => write!(f, "UnexpectedEof"),
+            Self::BadRequest(x) => write!(f, "BadRequest({:?})", x),
+            Self::TooLarge(x) => write!(f, "TooLarge({})", x),
+        }
+    }
+}
+
+impl<E> HttpError<E> {
+    // Status to answer with, None if the connection is gone anyway
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::ReadError(_) | Self::WriteError(_) | Self::UnexpectedEof => None,
+            Self::BadRequest(_) => Some(400),
+            Self::TooLarge(_) => Some(413),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Request {
+    pub method: String,
+    // Without the query string
+    pub path: String,
+    pub query: String,
+    // Without parameters like charset, lowercase
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+/*
+ * Reads one request, with a body of at most max_body bytes. A larger body is refused before it's
+ * read, so it can't run the device out of memory.
+ */
+pub async fn read_request<S: Read>(
+    socket: &mut S,
+    max_body: usize,
+) -> Result<Request, HttpError<S::Error>> {
+    let mut buffer = Vec::new();
+    let head_length = loop {
+        if let Some(end) = find_head_end(&buffer) {
+            break end;
+        }
+        if buffer.len() >= MAX_HEAD_SIZE {
+            return Err(HttpError::BadRequest("headers too long"));
+        }
+        let start = buffer.len();
+        buffer.resize(start + 512, 0);
+        let read = socket
+            .read(&mut buffer[start..])
+            .await
+            .map_err(HttpError::ReadError)?;
+        buffer.truncate(start + read);
+        if read == 0 {
+            return Err(HttpError::UnexpectedEof);
+        }
+    };
+    let head = core::str::from_utf8(&buffer[..head_length])
+        .map_err(|_| HttpError::BadRequest("headers aren't UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (Some(method), Some(target), Some(_version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err(HttpError::BadRequest("malformed request line"));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.into(),
+        path: path.into(),
+        query: query.into(),
+        content_type: String::new(),
+        body: Vec::new(),
+    };
+    let mut content_length = 0;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| HttpError::BadRequest("invalid Content-Length"))?;
+        } else if name.eq_ignore_ascii_case("content-type") {
+            let media_type = value.split(';').next().unwrap_or("").trim();
+            request.content_type = media_type.to_ascii_lowercase();
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(HttpError::BadRequest("chunked bodies aren't supported"));
+        }
+    }
+    if content_length > max_body {
+        return Err(HttpError::TooLarge(content_length));
+    }
+    // Whatever came in with the headers is the start of the body
+    let body_start = head_length + 4;
+    let already = (buffer.len() - body_start).min(content_length);
+    request.body.reserve_exact(content_length);
+    request
+        .body
+        .extend_from_slice(&buffer[body_start..body_start + already]);
+    request.body.resize(content_length, 0);
+    let mut filled = already;
+    while filled < content_length {
+        let read = socket
+            .read(&mut request.body[filled..])
+            .await
+            .map_err(HttpError::ReadError)?;
+        if read == 0 {
+            return Err(HttpError::UnexpectedEof);
+        }
+        filled += read;
+    }
+    Ok(request)
+}
+
+pub async fn write_response<S: Write>(
+    socket: &mut S,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), HttpError<S::Error>> {
+    write_response_with_headers(socket, status, content_type, &[], body).await
+}
+
+// Extra headers as (name, value), e.g. a Location for redirects
+pub async fn write_response_with_headers<S: Write>(
+    socket: &mut S,
+    status: u16,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<(), HttpError<S::Error>> {
+    use core::fmt::Write as _;
+    let mut head = String::new();
+    let _ = write!(
+        head,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len()
+    );
+    for (name, value) in headers {
+        let _ = write!(head, "{}: {}\r\n", name, value);
+    }
+    head.push_str("\r\n");
+    socket
+        .write_all(head.as_bytes())
+        .await
+        .map_err(HttpError::WriteError)?;
+    socket
+        .write_all(body)
+        .await
+        .map_err(HttpError::WriteError)?;
+    socket.flush().await.map_err(HttpError::WriteError)
+}
+
+// Offset of the empty line ending the headers
+fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        302 => "Found",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
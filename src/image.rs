@@ -0,0 +1,359 @@
+use crate::analysis;
+use alloc::vec::Vec;
+use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
+
+// Decoded image, row-major, no padding between rows.
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Rgb888>,
+}
+
+impl RgbImage {
+    pub fn new(width: usize, height: usize, pixels: Vec<Rgb888>) -> Self {
+        RgbImage {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    // From the PNG decoder's [r, g, b, a], transparent parts composited onto white
+    pub fn from_rgba(width: usize, height: usize, data: impl IntoIterator<Item = [u8; 4]>) -> Self {
+        Self::from_rgba_over(width, height, data, Rgb888::WHITE)
+    }
+
+    pub fn from_rgba_over(
+        width: usize,
+        height: usize,
+        data: impl IntoIterator<Item = [u8; 4]>,
+        background: Rgb888,
+    ) -> Self {
+        let pixels = data
+            .into_iter()
+            .map(|rgba| composite(rgba, background))
+            .collect();
+        Self::new(width, height, pixels)
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Rgb888 {
+        self.pixels[x + y * self.width]
+    }
+
+    // Dimensions after rotating
+    pub fn rotated_size(&self, rotation: Rotation) -> (usize, usize) {
+        if rotation.swaps_axes() {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        }
+    }
+
+    // Where x,y of the rotated image is in the image itself
+    fn unrotated_position(&self, rotation: Rotation, x: usize, y: usize) -> (usize, usize) {
+        match rotation {
+            Rotation::None => (x, y),
+            Rotation::Clockwise90 => (y, self.height - 1 - x),
+            Rotation::Rotate180 => (self.width - 1 - x, self.height - 1 - y),
+            Rotation::CounterClockwise90 => (self.width - 1 - y, x),
+        }
+    }
+
+    // Pixel at x,y of the image as it would look after rotating
+    pub fn get_rotated(&self, rotation: Rotation, x: usize, y: usize) -> Rgb888 {
+        let (sx, sy) = self.unrotated_position(rotation, x, y);
+        self.get(sx, sy)
+    }
+
+    // Pixels of the rotated image, in row-major order, without copying the image
+    pub fn rotated_pixels(&self, rotation: Rotation) -> impl ExactSizeIterator<Item = Rgb888> + '_ {
+        let (width, height) = self.rotated_size(rotation);
+        (0..width * height)
+            .map(move |index| self.get_rotated(rotation, index % width, index / width))
+    }
+
+    /*
+     * Scales the (rotated) image to fit within target_width x target_height while preserving the
+     * aspect ratio (nearest neighbour), centers it, and fills the remaining bars according to
+     * matting. Yields exactly target_width * target_height pixels in row-major order.
+     */
+    pub fn letterboxed_pixels(
+        &self,
+        rotation: Rotation,
+        target_width: usize,
+        target_height: usize,
+        matting: Matting,
+    ) -> impl ExactSizeIterator<Item = Rgb888> + '_ {
+        let geometry =
+            LetterboxGeometry::new(self.rotated_size(rotation), target_width, target_height);
+        let LetterboxGeometry {
+            fit_width,
+            fit_height,
+            offset_x,
+            offset_y,
+            ..
+        } = geometry;
+        // Bars are either left/right (vertical) or top/bottom
+        let vertical_bars = fit_width < target_width;
+        let fill = match matting {
+            Matting::Solid(color) => Fill::Solid(color),
+            // Nothing to blur or take the color from, it's all bars
+            _ if geometry.is_empty() => Fill::Solid(Rgb888::WHITE),
+            Matting::Dominant => Fill::Solid(
+                analysis::color_stats(self, analysis::DEFAULT_STEP)
+                    .dominant_color()
+                    .unwrap_or(Rgb888::WHITE),
+            ),
+            Matting::BlurredEdge => Fill::Edges(self.blurred_edges(rotation, vertical_bars)),
+        };
+        (0..target_width * target_height).map(move |index| {
+            let x = index % target_width;
+            let y = index / target_width;
+            if let Some((sx, sy)) = geometry.source_position(x, y) {
+                return self.get_rotated(rotation, sx, sy);
+            }
+            match &fill {
+                Fill::Solid(color) => *color,
+                Fill::Edges(edges) => {
+                    // Map position along the bar back to source coordinates
+                    let (along, along_offset, along_fit, before) = if vertical_bars {
+                        (y, offset_y, fit_height, x < offset_x)
+                    } else {
+                        (x, offset_x, fit_width, y < offset_y)
+                    };
+                    let along = along.clamp(along_offset, along_offset + along_fit - 1);
+                    let index = (along - along_offset) * edges.len() / along_fit;
+                    edges[index][if before { 0 } else { 1 }]
+                }
+            }
+        })
+    }
+
+    /*
+     * Heavily blurred colors just inside each edge the bars will be next to. One entry per source
+     * row (vertical bars) or column (horizontal bars), first element is the left/top edge, second
+     * the right/bottom one.
+     */
+    fn blurred_edges(&self, rotation: Rotation, vertical_bars: bool) -> Vec<[Rgb888; 2]> {
+        const DEPTH: usize = 4;
+        const RADIUS: usize = 8;
+        let (width, height) = self.rotated_size(rotation);
+        let (along_len, across_len) = if vertical_bars {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        if across_len == 0 {
+            return Vec::new();
+        }
+        let depth = DEPTH.min(across_len);
+        (0..along_len)
+            .map(|along| {
+                let start = along.saturating_sub(RADIUS);
+                let end = (along + RADIUS + 1).min(along_len);
+                [false, true].map(|far_edge| {
+                    let mut sum = [0u32; 3];
+                    let mut count = 0u32;
+                    for along in start..end {
+                        for across in 0..depth {
+                            let across = if far_edge {
+                                across_len - 1 - across
+                            } else {
+                                across
+                            };
+                            let color = if vertical_bars {
+                                self.get_rotated(rotation, across, along)
+                            } else {
+                                self.get_rotated(rotation, along, across)
+                            };
+                            sum[0] += color.r() as u32;
+                            sum[1] += color.g() as u32;
+                            sum[2] += color.b() as u32;
+                            count += 1;
+                        }
+                    }
+                    let [r, g, b] = sum.map(|channel| (channel / count) as u8);
+                    Rgb888::new(r, g, b)
+                })
+            })
+            .collect()
+    }
+}
+
+/*
+ * Alpha blends a pixel onto background. Fully transparent pixels often have whatever color the
+ * encoder liked in r, g and b, so just dropping alpha shows garbage there.
+ */
+pub fn composite([r, g, b, a]: [u8; 4], background: Rgb888) -> Rgb888 {
+    let blend = |value: u8, background: u8| {
+        ((value as u32 * a as u32 + background as u32 * (255 - a as u32) + 127) / 255) as u8
+    };
+    Rgb888::new(
+        blend(r, background.r()),
+        blend(g, background.g()),
+        blend(b, background.b()),
+    )
+}
+
+/*
+ * Size an image is scaled to keeping its aspect ratio: the largest that fits inside the target, or
+ * with cover the smallest that covers it. Nothing of an empty image or target fits, that's (0, 0).
+ */
+pub(crate) fn scaled_size(
+    (width, height): (usize, usize),
+    (target_width, target_height): (usize, usize),
+    cover: bool,
+) -> (usize, usize) {
+    if width == 0 || height == 0 || target_width == 0 || target_height == 0 {
+        return (0, 0);
+    }
+    // Whether the image is wider than the target, relative to the height
+    let wider = width * target_height > height * target_width;
+    if wider != cover {
+        (target_width, (height * target_width / width).max(1))
+    } else {
+        ((width * target_height / height).max(1), target_height)
+    }
+}
+
+// Where the (rotated) image ends up when letterboxed
+#[derive(Clone, Copy)]
+struct LetterboxGeometry {
+    width: usize,
+    height: usize,
+    fit_width: usize,
+    fit_height: usize,
+    offset_x: usize,
+    offset_y: usize,
+}
+
+impl LetterboxGeometry {
+    fn new((width, height): (usize, usize), target_width: usize, target_height: usize) -> Self {
+        let (fit_width, fit_height) =
+            scaled_size((width, height), (target_width, target_height), false);
+        LetterboxGeometry {
+            width,
+            height,
+            fit_width,
+            fit_height,
+            offset_x: (target_width - fit_width) / 2,
+            offset_y: (target_height - fit_height) / 2,
+        }
+    }
+
+    // Empty image or target, so there's nothing but bars
+    fn is_empty(&self) -> bool {
+        self.fit_width == 0
+    }
+
+    // Position in the rotated image for x,y on the target, None if that's in the bars
+    fn source_position(&self, x: usize, y: usize) -> Option<(usize, usize)> {
+        if x >= self.offset_x
+            && x < self.offset_x + self.fit_width
+            && y >= self.offset_y
+            && y < self.offset_y + self.fit_height
+        {
+            Some((
+                (x - self.offset_x) * self.width / self.fit_width,
+                (y - self.offset_y) * self.height / self.fit_height,
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/*
+ * An image the decoder didn't get all the way through, e.g. a truncated download, or a progressive
+ * JPEG that ran out of data after a few scans. Only the first valid_pixels pixels (row-major) are
+ * real, the rest is filled in. Showing that is better than aborting the whole refresh.
+ */
+pub struct PartialImage {
+    pub image: RgbImage,
+    pub valid_pixels: usize,
+}
+
+impl PartialImage {
+    // Takes however many pixels the decoder produced, over background, which also fills the rest
+    pub fn from_rgba_over(
+        width: usize,
+        height: usize,
+        data: impl IntoIterator<Item = [u8; 4]>,
+        background: Rgb888,
+    ) -> Self {
+        let data = data.into_iter().take(width * height);
+        let mut image = RgbImage::from_rgba_over(width, height, data, background);
+        let valid_pixels = image.pixels.len();
+        image.pixels.resize(width * height, background);
+        PartialImage {
+            image,
+            valid_pixels,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.valid_pixels >= self.image.pixels.len()
+    }
+}
+
+// What to show in the bars when the image doesn't fill the panel
+#[derive(Clone, Copy)]
+pub enum Matting {
+    // Fixed color, e.g. Rgb888::WHITE or Rgb888::BLACK
+    Solid(Rgb888),
+    // Most common color in the image
+    Dominant,
+    // Blurred extension of the image edges next to the bars
+    BlurredEdge,
+}
+
+enum Fill {
+    Solid(Rgb888),
+    Edges(Vec<[Rgb888; 2]>),
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Rotation {
+    None,
+    Clockwise90,
+    Rotate180,
+    CounterClockwise90,
+}
+
+impl Rotation {
+    pub fn swaps_axes(self) -> bool {
+        matches!(self, Rotation::Clockwise90 | Rotation::CounterClockwise90)
+    }
+}
+
+/*
+ * Rotates content when its orientation clearly disagrees with the panel, e.g. a portrait photo on
+ * a landscape panel, which would otherwise end up letterboxed as a tiny image in the middle.
+ */
+#[derive(Clone, Copy)]
+pub struct AutoRotate {
+    // Which way to turn the content when it doesn't match
+    pub rotation: Rotation,
+    // Long side must be at least this many percent of the short side before rotating, so
+    // near-square content is left alone.
+    pub min_aspect_percent: usize,
+}
+
+impl AutoRotate {
+    pub fn rotation_for(
+        &self,
+        content_width: usize,
+        content_height: usize,
+        panel_width: usize,
+        panel_height: usize,
+    ) -> Rotation {
+        let content_portrait = content_height * 100 >= content_width * self.min_aspect_percent;
+        let content_landscape = content_width * 100 >= content_height * self.min_aspect_percent;
+        let panel_portrait = panel_height > panel_width;
+        if (content_portrait && !panel_portrait) || (content_landscape && panel_portrait) {
+            self.rotation
+        } else {
+            Rotation::None
+        }
+    }
+}
@@ -0,0 +1,415 @@
+/*
+ * Streaming zlib/deflate decompression (RFC 1950/1951), as an async Read over the compressed data,
+ * so PNG rows can be decoded while the data is still coming in. Holds the 32KB window and a few
+ * bytes of input, nothing else. Huffman codes are decoded a bit at a time, like zlib's puff: slow
+ * next to a table-driven decoder, but small, and the network is slower still.
+ *
+ * The Adler-32 at the end isn't checked.
+ */
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use embedded_io_async::{ErrorKind, ErrorType, Read};
+
+const WINDOW_SIZE: usize = 32768;
+const MAX_BITS: usize = 15;
+const MAX_LITERAL_CODES: usize = 288;
+const MAX_DISTANCE_CODES: usize = 30;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// Order the code length code lengths come in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+pub enum InflateError<E> {
+    Read(E),
+    UnexpectedEof,
+    Invalid(&'static str),
+}
+
+impl<E: Debug> Debug for InflateError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Read(x) => write!(f, "Read({:?})", x),
+            Self::UnexpectedEof => write!(f, "UnexpectedEof"),
+            Self::Invalid(x) => write!(f, "Invalid({:?})", x),
+        }
+    }
+}
+
+impl<E: embedded_io_async::Error> embedded_io_async::Error for InflateError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Read(x) => x.kind(),
+            Self::UnexpectedEof | Self::Invalid(_) => ErrorKind::InvalidData,
+        }
+    }
+}
+
+// Canonical Huffman code, as the number of codes per length and the symbols in code order
+struct Huffman<const N: usize> {
+    counts: [u16; MAX_BITS + 1],
+    symbols: [u16; N],
+}
+
+impl<const N: usize> Huffman<N> {
+    fn new(lengths: &[u8]) -> Result<Self, &'static str> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+        // Over-subscribed codes can't be decoded, incomplete ones only miss some symbols
+        let mut left = 1i32;
+        for count in &counts[1..] {
+            left = (left << 1) - *count as i32;
+            if left < 0 {
+                return Err("over-subscribed Huffman code");
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = [0u16; N];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                symbols[offsets[*length as usize] as usize] = symbol as u16;
+                offsets[*length as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    ZlibHeader,
+    BlockHeader,
+    Stored { remaining: u16 },
+    Compressed,
+    Copy { length: u16, distance: u16 },
+    Done,
+}
+
+// Input, and the bits of it not used yet (the next one in bit 0)
+struct BitReader<R> {
+    source: R,
+    input: [u8; 64],
+    input_position: usize,
+    input_length: usize,
+    bits: u32,
+    bit_count: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    async fn next_input(&mut self) -> Result<Option<u8>, InflateError<R::Error>> {
+        if self.input_position >= self.input_length {
+            self.input_length = self
+                .source
+                .read(&mut self.input)
+                .await
+                .map_err(InflateError::Read)?;
+            self.input_position = 0;
+            if self.input_length == 0 {
+                return Ok(None);
+            }
+        }
+        self.input_position += 1;
+        Ok(Some(self.input[self.input_position - 1]))
+    }
+
+    // At least count (up to 25) bits in the buffer, or fewer at the end of the data
+    async fn fill(&mut self, count: u32) -> Result<(), InflateError<R::Error>> {
+        while self.bit_count < count {
+            match self.next_input().await? {
+                Some(byte) => {
+                    self.bits |= (byte as u32) << self.bit_count;
+                    self.bit_count += 8;
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    async fn take(&mut self, count: u32) -> Result<u32, InflateError<R::Error>> {
+        self.fill(count).await?;
+        if self.bit_count < count {
+            return Err(InflateError::UnexpectedEof);
+        }
+        let value = self.bits & ((1u32 << count) - 1);
+        self.bits = self.bits.checked_shr(count).unwrap_or(0);
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    async fn decode<const N: usize>(
+        &mut self,
+        huffman: &Huffman<N>,
+    ) -> Result<u16, InflateError<R::Error>> {
+        self.fill(MAX_BITS as u32).await?;
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..=MAX_BITS {
+            if self.bit_count == 0 {
+                return Err(InflateError::UnexpectedEof);
+            }
+            code |= (self.bits & 1) as i32;
+            self.bits >>= 1;
+            self.bit_count -= 1;
+            let count = huffman.counts[length] as i32;
+            if code - count < first {
+                return Ok(huffman.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::Invalid("no such Huffman code"))
+    }
+}
+
+pub struct Inflater<R> {
+    input: BitReader<R>,
+    window: Vec<u8>,
+    // Bytes written so far, the window wraps around
+    window_position: usize,
+    state: State,
+    last_block: bool,
+    literals: Huffman<MAX_LITERAL_CODES>,
+    distances: Huffman<MAX_DISTANCE_CODES>,
+}
+
+impl<R: Read> Inflater<R> {
+    // zlib wrapped, as in PNG
+    pub fn new(source: R) -> Self {
+        let mut ret = Self::raw(source);
+        ret.state = State::ZlibHeader;
+        ret
+    }
+
+    // Bare deflate, without the zlib header
+    pub fn raw(source: R) -> Self {
+        Inflater {
+            input: BitReader {
+                source,
+                input: [0; 64],
+                input_position: 0,
+                input_length: 0,
+                bits: 0,
+                bit_count: 0,
+            },
+            window: alloc::vec![0; WINDOW_SIZE],
+            window_position: 0,
+            state: State::BlockHeader,
+            last_block: false,
+            literals: Huffman {
+                counts: [0; MAX_BITS + 1],
+                symbols: [0; MAX_LITERAL_CODES],
+            },
+            distances: Huffman {
+                counts: [0; MAX_BITS + 1],
+                symbols: [0; MAX_DISTANCE_CODES],
+            },
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    async fn read_zlib_header(&mut self) -> Result<(), InflateError<R::Error>> {
+        let cmf = self.input.take(8).await?;
+        let flg = self.input.take(8).await?;
+        if cmf & 0x0F != 8 || cmf >> 4 > 7 || !(cmf << 8 | flg).is_multiple_of(31) {
+            return Err(InflateError::Invalid("not a zlib stream"));
+        }
+        if flg & 0x20 != 0 {
+            return Err(InflateError::Invalid("preset dictionary"));
+        }
+        self.state = State::BlockHeader;
+        Ok(())
+    }
+
+    async fn read_block_header(&mut self) -> Result<(), InflateError<R::Error>> {
+        if self.last_block {
+            self.state = State::Done;
+            return Ok(());
+        }
+        self.last_block = self.input.take(1).await? == 1;
+        self.state = match self.input.take(2).await? {
+            0 => {
+                // Stored, starts at the next byte
+                let skip = self.input.bit_count % 8;
+                self.input.take(skip).await?;
+                let length = self.input.take(16).await? as u16;
+                let inverse = self.input.take(16).await? as u16;
+                if length != !inverse {
+                    return Err(InflateError::Invalid("stored block length mismatch"));
+                }
+                State::Stored { remaining: length }
+            }
+            1 => {
+                let mut lengths = [0u8; MAX_LITERAL_CODES + MAX_DISTANCE_CODES];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..MAX_LITERAL_CODES].fill(8);
+                lengths[MAX_LITERAL_CODES..].fill(5);
+                self.set_codes(&lengths)?;
+                State::Compressed
+            }
+            2 => {
+                self.read_dynamic_codes().await?;
+                State::Compressed
+            }
+            _ => return Err(InflateError::Invalid("reserved block type")),
+        };
+        Ok(())
+    }
+
+    // Literal/length code lengths first, distance code lengths from MAX_LITERAL_CODES
+    fn set_codes(&mut self, lengths: &[u8]) -> Result<(), InflateError<R::Error>> {
+        let (literals, distances) = lengths.split_at(MAX_LITERAL_CODES);
+        self.literals = Huffman::new(literals).map_err(InflateError::Invalid)?;
+        self.distances = Huffman::new(distances).map_err(InflateError::Invalid)?;
+        Ok(())
+    }
+
+    async fn read_dynamic_codes(&mut self) -> Result<(), InflateError<R::Error>> {
+        let literal_count = self.input.take(5).await? as usize + 257;
+        let distance_count = self.input.take(5).await? as usize + 1;
+        let code_length_count = self.input.take(4).await? as usize + 4;
+        if literal_count > 286 || distance_count > MAX_DISTANCE_CODES {
+            return Err(InflateError::Invalid("too many codes"));
+        }
+        let mut code_lengths = [0u8; 19];
+        for index in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+            code_lengths[*index] = self.input.take(3).await? as u8;
+        }
+        let code_length_code = Huffman::<19>::new(&code_lengths).map_err(InflateError::Invalid)?;
+        let mut lengths = [0u8; MAX_LITERAL_CODES + MAX_DISTANCE_CODES];
+        let total = literal_count + distance_count;
+        let mut index = 0;
+        while index < total {
+            let symbol = self.input.decode(&code_length_code).await?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    if index == 0 {
+                        return Err(InflateError::Invalid("repeat without a length"));
+                    }
+                    (lengths[index - 1], 3 + self.input.take(2).await? as usize)
+                }
+                17 => (0, 3 + self.input.take(3).await? as usize),
+                _ => (0, 11 + self.input.take(7).await? as usize),
+            };
+            if index + repeat > total {
+                return Err(InflateError::Invalid("code lengths overflow"));
+            }
+            lengths[index..index + repeat].fill(value);
+            index += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err(InflateError::Invalid("no end of block code"));
+        }
+        // Move the distances up to where set_codes expects them
+        lengths.copy_within(literal_count..total, MAX_LITERAL_CODES);
+        lengths[literal_count..MAX_LITERAL_CODES].fill(0);
+        lengths[MAX_LITERAL_CODES + distance_count..].fill(0);
+        self.set_codes(&lengths)
+    }
+
+    fn output(&mut self, byte: u8) -> u8 {
+        self.window[self.window_position % WINDOW_SIZE] = byte;
+        self.window_position += 1;
+        byte
+    }
+
+    // Next output byte, or None at the end of the data
+    async fn next_byte(&mut self) -> Result<Option<u8>, InflateError<R::Error>> {
+        loop {
+            match self.state {
+                State::ZlibHeader => self.read_zlib_header().await?,
+                State::BlockHeader => self.read_block_header().await?,
+                State::Done => return Ok(None),
+                State::Stored { remaining: 0 } => self.state = State::BlockHeader,
+                State::Stored { remaining } => {
+                    self.state = State::Stored {
+                        remaining: remaining - 1,
+                    };
+                    let byte = self.input.take(8).await? as u8;
+                    return Ok(Some(self.output(byte)));
+                }
+                State::Copy { length, distance } => {
+                    self.state = if length > 1 {
+                        State::Copy {
+                            length: length - 1,
+                            distance,
+                        }
+                    } else {
+                        State::Compressed
+                    };
+                    let from = self.window_position - distance as usize;
+                    return Ok(Some(self.output(self.window[from % WINDOW_SIZE])));
+                }
+                State::Compressed => {
+                    let symbol = self.input.decode(&self.literals).await? as usize;
+                    if symbol < 256 {
+                        return Ok(Some(self.output(symbol as u8)));
+                    }
+                    if symbol == 256 {
+                        self.state = State::BlockHeader;
+                        continue;
+                    }
+                    let symbol = symbol - 257;
+                    if symbol >= LENGTH_BASE.len() {
+                        return Err(InflateError::Invalid("invalid length code"));
+                    }
+                    let extra = self.input.take(LENGTH_EXTRA[symbol] as u32).await?;
+                    let length = LENGTH_BASE[symbol] + extra as u16;
+                    let symbol = self.input.decode(&self.distances).await? as usize;
+                    if symbol >= DISTANCE_BASE.len() {
+                        return Err(InflateError::Invalid("invalid distance code"));
+                    }
+                    let extra = self.input.take(DISTANCE_EXTRA[symbol] as u32).await?;
+                    let distance = DISTANCE_BASE[symbol] + extra as u16;
+                    if distance as usize > self.window_position {
+                        return Err(InflateError::Invalid("distance before the start"));
+                    }
+                    self.state = State::Copy { length, distance };
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> ErrorType for Inflater<R> {
+    type Error = InflateError<R::Error>;
+}
+
+impl<R: Read> Read for Inflater<R> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        for (written, byte) in buf.iter_mut().enumerate() {
+            match self.next_byte().await? {
+                Some(value) => *byte = value,
+                None => return Ok(written),
+            }
+        }
+        Ok(buf.len())
+    }
+}
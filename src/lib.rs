@@ -1,6 +1,54 @@
 #![no_std]
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+pub mod adjust;
+pub mod analysis;
+pub mod barycentric;
+pub mod battery;
+pub mod blocking;
+pub mod board;
+pub mod bootpolicy;
+pub mod budget;
+pub mod capabilities;
+pub mod color;
+pub mod config;
+pub mod dashboard;
+#[cfg(feature = "delta-ota")]
+pub mod delta;
+pub mod detect;
 pub mod displayinterface;
 pub mod dither;
+pub mod error;
+pub mod framepush;
 pub mod gdep073e01;
+pub mod httpserver;
+pub mod image;
+pub mod inflate;
+pub mod maintenance;
+pub mod mdns;
+pub mod mqtt;
+pub mod multidisplay;
+pub mod parallel;
+pub mod pipeline;
+pub mod pngstream;
+pub mod portal;
+pub mod provisioning;
+pub mod qoi;
+pub mod qrcode;
+pub mod rawframe;
+pub mod resize;
+pub mod retry;
+pub mod rtcstate;
+pub mod scene;
+pub mod schedule;
+pub mod shadow;
+pub mod sharpen;
 pub mod spectra6;
+pub mod ssd1677;
+pub mod stackusage;
+pub mod tls;
+pub mod typestate;
+pub mod uc8276;
+#[cfg(feature = "waveshare")]
+pub mod waveshare;
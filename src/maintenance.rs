@@ -0,0 +1,145 @@
+/*
+ * Long-lived installations slowly build up ghosting, which the clean cycle (see
+ * Spectra6Color::Clean) gets rid of. This schedules it at a fixed time, e.g. weekly on Monday at
+ * 03:00, no matter whether the content changed. All times are Unix timestamps in seconds, shifted
+ * by utc_offset_secs to get local time.
+ */
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const SECONDS_PER_WEEK: u64 = 7 * SECONDS_PER_DAY;
+// 1970-01-01 was a Thursday
+const EPOCH_WEEKDAY: u64 = 3;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Weekday {
+    Monday = 0,
+    Tuesday = 1,
+    Wednesday = 2,
+    Thursday = 3,
+    Friday = 4,
+    Saturday = 5,
+    Sunday = 6,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MaintenancePolicy {
+    // How often to clean, in seconds
+    pub period_secs: u64,
+    // Where in the period to clean, in seconds since the start of a (local) Monday for weekly
+    // periods, or since local midnight for daily ones
+    pub offset_secs: u64,
+    // The device only wakes up every so often, so a slot counts as hit if we're this late at most.
+    // Keeps a device that was off at the scheduled time from cleaning in the middle of the day.
+    pub max_delay_secs: u64,
+    pub utc_offset_secs: i32,
+}
+
+impl MaintenancePolicy {
+    pub fn weekly(weekday: Weekday, hour: u8, minute: u8) -> Self {
+        MaintenancePolicy {
+            period_secs: SECONDS_PER_WEEK,
+            offset_secs: weekday as u64 * SECONDS_PER_DAY + hour as u64 * 3600 + minute as u64 * 60,
+            max_delay_secs: 2 * 3600,
+            utc_offset_secs: 0,
+        }
+    }
+
+    pub fn daily(hour: u8, minute: u8) -> Self {
+        MaintenancePolicy {
+            period_secs: SECONDS_PER_DAY,
+            offset_secs: hour as u64 * 3600 + minute as u64 * 60,
+            max_delay_secs: 2 * 3600,
+            utc_offset_secs: 0,
+        }
+    }
+
+    pub fn with_utc_offset(self, utc_offset_secs: i32) -> Self {
+        MaintenancePolicy {
+            utc_offset_secs,
+            ..self
+        }
+    }
+
+    // Local time, shifted so weekly periods start on Monday
+    fn local(&self, now: u64) -> u64 {
+        let local = now.saturating_add_signed(self.utc_offset_secs as i64);
+        if self.period_secs == SECONDS_PER_WEEK {
+            local + EPOCH_WEEKDAY * SECONDS_PER_DAY
+        } else {
+            local
+        }
+    }
+
+    // Start of the most recent slot at or before now
+    pub fn last_slot(&self, now: u64) -> u64 {
+        let local = self.local(now);
+        let since_slot =
+            (local + self.period_secs - self.offset_secs % self.period_secs) % self.period_secs;
+        now - since_slot.min(now)
+    }
+
+    pub fn next_slot(&self, now: u64) -> u64 {
+        self.last_slot(now) + self.period_secs
+    }
+
+    // Lets the scheduler make sure the device is awake in time for the next clean
+    pub fn seconds_until_next(&self, now: u64) -> u64 {
+        self.next_slot(now) - now
+    }
+
+    /*
+     * Whether to run the clean cycle now, given when it last ran (None if unknown, e.g. after a
+     * power loss). This is independent of quiet hours, cleaning at night is the whole point.
+     */
+    pub fn is_due(&self, now: u64, last_clean: Option<u64>) -> bool {
+        let slot = self.last_slot(now);
+        now - slot <= self.max_delay_secs && last_clean.is_none_or(|last| last < slot)
+    }
+}
+
+// Time of day during which normal content refreshes are skipped, may wrap around midnight
+#[derive(Clone, Copy, Debug)]
+pub struct QuietHours {
+    // Seconds since local midnight
+    pub start_secs: u32,
+    pub end_secs: u32,
+    pub utc_offset_secs: i32,
+}
+
+impl QuietHours {
+    pub fn contains(&self, now: u64) -> bool {
+        let local = now.saturating_add_signed(self.utc_offset_secs as i64);
+        let time_of_day = (local % SECONDS_PER_DAY) as u32;
+        if self.start_secs <= self.end_secs {
+            time_of_day >= self.start_secs && time_of_day < self.end_secs
+        } else {
+            time_of_day >= self.start_secs || time_of_day < self.end_secs
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MaintenanceAction {
+    // Run the clean cycle, then show the content again
+    Clean,
+    // Show new content as usual
+    Refresh,
+    // Leave the panel alone
+    Skip,
+}
+
+// Combines the maintenance schedule with quiet hours into what to do on this wake-up
+pub fn decide(
+    policy: &MaintenancePolicy,
+    quiet_hours: Option<&QuietHours>,
+    now: u64,
+    last_clean: Option<u64>,
+) -> MaintenanceAction {
+    if policy.is_due(now, last_clean) {
+        MaintenanceAction::Clean
+    } else if quiet_hours.is_some_and(|quiet_hours| quiet_hours.contains(now)) {
+        MaintenanceAction::Skip
+    } else {
+        MaintenanceAction::Refresh
+    }
+}
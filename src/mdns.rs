@@ -0,0 +1,239 @@
+/*
+ * Multicast DNS responder, so the device can be reached as reterminal-xxxx.local and its services
+ * show up in DNS-SD browsers (avahi-browse, dns-sd, Discovery apps) without knowing its address.
+ * The name ends in the last two bytes of the MAC address, so devices on one network don't clash.
+ *
+ * Only answers what it owns: the host's A record, and PTR, SRV and TXT records for each service.
+ * Answers always go to the multicast group, which every client accepts, even ones that asked for a
+ * unicast reply. No probing for conflicts, the MAC address suffix takes care of that well enough.
+ */
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+pub const PORT: u16 = 5353;
+pub const GROUP: [u8; 4] = [224, 0, 0, 251];
+
+// Seconds, what RFC 6762 recommends for records with a host name
+const TTL: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// Records only we have, others can drop what they cached for the name
+const CACHE_FLUSH: u16 = 0x8000;
+
+const SERVICES_NAME: &str = "_services._dns-sd._udp.local";
+
+// reterminal-xxxx, without .local
+pub fn hostname(mac_address: [u8; 6]) -> String {
+    let mut name = String::new();
+    let _ = write!(
+        name,
+        "reterminal-{:02x}{:02x}",
+        mac_address[4], mac_address[5]
+    );
+    name
+}
+
+pub struct Service {
+    // e.g. "_http._tcp"
+    pub service_type: &'static str,
+    pub port: u16,
+    // key=value strings
+    pub txt: Vec<String>,
+}
+
+pub struct Responder {
+    hostname: String,
+    address: [u8; 4],
+    services: Vec<Service>,
+}
+
+impl Responder {
+    pub fn new(hostname: String, address: [u8; 4]) -> Self {
+        Responder {
+            hostname,
+            address,
+            services: Vec::new(),
+        }
+    }
+
+    pub fn add_service(&mut self, service: Service) {
+        self.services.push(service);
+    }
+
+    // reterminal-xxxx.local
+    pub fn host_name(&self) -> String {
+        let mut name = self.hostname.clone();
+        name.push_str(".local");
+        name
+    }
+
+    // Response to send to the group, None if none of the questions are for us
+    pub fn reply(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let header = query.get(..12)?;
+        // Responses from others, not questions
+        if header[2] & 0x80 != 0 {
+            return None;
+        }
+        let question_count = u16::from_be_bytes([header[4], header[5]]);
+        let mut records = Vec::new();
+        let mut offset = 12;
+        for _ in 0..question_count {
+            let (name, next) = read_name(query, offset)?;
+            let question = query.get(next..next + 4)?;
+            let record_type = u16::from_be_bytes([question[0], question[1]]);
+            offset = next + 4;
+            for record in self.records() {
+                let matches = record_type == TYPE_ANY || record_type == record.record_type;
+                if matches && record.name.eq_ignore_ascii_case(&name) && !records.contains(&record)
+                {
+                    records.push(record);
+                }
+            }
+        }
+        if records.is_empty() {
+            return None;
+        }
+        Some(encode_response(&records))
+    }
+
+    // All records, unsolicited, to send once the network is up so browsers see us right away
+    pub fn announcement(&self) -> Vec<u8> {
+        encode_response(&self.records())
+    }
+
+    fn records(&self) -> Vec<Record> {
+        let host = self.host_name();
+        let mut records = alloc::vec![Record {
+            name: host.clone(),
+            record_type: TYPE_A,
+            unique: true,
+            data: self.address.to_vec(),
+        }];
+        for service in &self.services {
+            let mut service_name = String::from(service.service_type);
+            service_name.push_str(".local");
+            let mut instance = self.hostname.clone();
+            instance.push('.');
+            instance.push_str(&service_name);
+            records.push(Record {
+                name: String::from(SERVICES_NAME),
+                record_type: TYPE_PTR,
+                unique: false,
+                data: encode_name(&service_name),
+            });
+            records.push(Record {
+                name: service_name,
+                record_type: TYPE_PTR,
+                unique: false,
+                data: encode_name(&instance),
+            });
+            // Priority and weight don't matter with only one host
+            let mut srv = alloc::vec![0, 0, 0, 0];
+            srv.extend_from_slice(&service.port.to_be_bytes());
+            srv.extend_from_slice(&encode_name(&host));
+            records.push(Record {
+                name: instance.clone(),
+                record_type: TYPE_SRV,
+                unique: true,
+                data: srv,
+            });
+            // An empty TXT record is a single empty string
+            let mut txt = Vec::new();
+            for entry in &service.txt {
+                txt.push(entry.len().min(255) as u8);
+                txt.extend_from_slice(&entry.as_bytes()[..entry.len().min(255)]);
+            }
+            if txt.is_empty() {
+                txt.push(0);
+            }
+            records.push(Record {
+                name: instance,
+                record_type: TYPE_TXT,
+                unique: true,
+                data: txt,
+            });
+        }
+        records
+    }
+}
+
+#[derive(PartialEq)]
+struct Record {
+    name: String,
+    record_type: u16,
+    // Sent with the cache flush bit
+    unique: bool,
+    data: Vec<u8>,
+}
+
+fn encode_response(records: &[Record]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    // ID 0, authoritative answer, no questions
+    packet.extend_from_slice(&[0, 0, 0x84, 0, 0, 0]);
+    packet.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+    for record in records {
+        packet.extend_from_slice(&encode_name(&record.name));
+        packet.extend_from_slice(&record.record_type.to_be_bytes());
+        let class = if record.unique {
+            CLASS_IN | CACHE_FLUSH
+        } else {
+            CLASS_IN
+        };
+        packet.extend_from_slice(&class.to_be_bytes());
+        packet.extend_from_slice(&TTL.to_be_bytes());
+        packet.extend_from_slice(&(record.data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&record.data);
+    }
+    packet
+}
+
+// Dotted name as labels, without compression
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        encoded.push(label.len().min(63) as u8);
+        encoded.extend_from_slice(&label.as_bytes()[..label.len().min(63)]);
+    }
+    encoded.push(0);
+    encoded
+}
+
+// Dotted name at offset, following compression pointers, and the offset after it
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // Each pointer has to go back, so a loop of pointers can't go on forever
+    let mut limit = offset;
+    loop {
+        let length = *packet.get(offset)? as usize;
+        match length {
+            0 => break,
+            0xC0.. => {
+                let pointer = ((length & 0x3F) << 8) | *packet.get(offset + 1)? as usize;
+                if pointer >= limit {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                limit = pointer;
+                offset = pointer;
+            }
+            0x40.. => return None,
+            _ => {
+                let label = packet.get(offset + 1..offset + 1 + length)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(core::str::from_utf8(label).ok()?);
+                offset += 1 + length;
+            }
+        }
+    }
+    Some((name, end.unwrap_or(offset + 1)))
+}
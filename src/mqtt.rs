@@ -0,0 +1,288 @@
+/*
+ * Just enough MQTT 3.1.1 to wait for refresh requests: connect, subscribe at QoS 0 and receive
+ * publishes. A dashboard publishes to the device's topic when something changed, instead of the
+ * device polling on a timer. The message is either a URL to fetch the image from, a frame itself
+ * (raw frame container or a plain packed frame, see rawframe and framepush), or QR_PREFIX followed
+ * by something to show as a QR code.
+ *
+ * Everything runs over any embedded-io-async socket, TLS or not, one packet at a time.
+ */
+use crate::framepush::validate_packed_frame;
+use crate::rawframe::is_raw_frame;
+use crate::tls::is_https;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use embedded_io_async::{Read, ReadExactError, Write};
+
+pub const DEFAULT_PORT: u16 = 1883;
+// Messages starting with this are shown as a QR code of the rest, see crate::qrcode
+pub const QR_PREFIX: &[u8] = b"QR:";
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+pub enum MqttError<E> {
+    ReadError(ReadExactError<E>),
+    WriteError(E),
+    // CONNACK return code, e.g. 4 for a bad user name or password and 5 for not authorized
+    Refused(u8),
+    SubscribeFailed,
+    Protocol(&'static str),
+    // A publish larger than the limit, its payload was skipped
+    TooLarge(usize),
+}
+
+impl<E: Debug> Debug for MqttError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::ReadError(x) => write!(f, "ReadError({:?})", x),
+            Self::WriteError(x) => write!(f, "WriteError({:?})", x),
+            Self::Refused(x) => write!(f, "Refused({})", x),
+            Self::SubscribeFailed => write!(f, "SubscribeFailed"),
+            Self::Protocol(x) => write!(f, "Protocol({:?})", x),
+            Self::TooLarge(x) => write!(f, "TooLarge({})", x),
+        }
+    }
+}
+
+// What a received message asks for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PushMessage<'t> {
+    Url(&'t str),
+    // Raw frame container, or exactly one packed frame
+    Frame(&'t [u8]),
+    // Payload for a QR code, without QR_PREFIX
+    QrCode(&'t [u8]),
+}
+
+pub fn parse_message(payload: &[u8]) -> Option<PushMessage<'_>> {
+    if is_raw_frame(payload) || validate_packed_frame(payload, None).is_ok() {
+        return Some(PushMessage::Frame(payload));
+    }
+    if let Some(qr_code) = payload.strip_prefix(QR_PREFIX) {
+        return Some(PushMessage::QrCode(qr_code));
+    }
+    let url = core::str::from_utf8(payload).ok()?.trim();
+    let is_http = url
+        .get(..7)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("http://"));
+    if is_http || is_https(url) {
+        Some(PushMessage::Url(url))
+    } else {
+        None
+    }
+}
+
+pub struct MqttClient<S> {
+    socket: S,
+    next_packet_id: u16,
+}
+
+impl<S: Read + Write> MqttClient<S> {
+    /*
+     * Connects with a clean session, so nothing is queued for us from before. Credentials are
+     * (user name, password). The broker drops the connection after 1.5 keep_alive seconds without
+     * packets from us, 0 disables that.
+     */
+    pub async fn connect(
+        mut socket: S,
+        client_id: &str,
+        credentials: Option<(&str, &str)>,
+        keep_alive: u16,
+    ) -> Result<Self, MqttError<S::Error>> {
+        let mut packet = Vec::new();
+        put_string(&mut packet, "MQTT");
+        // Protocol level 4 is 3.1.1, flag 0x02 is clean session
+        let flags = if credentials.is_some() { 0xC2 } else { 0x02 };
+        packet.extend_from_slice(&[4, flags]);
+        packet.extend_from_slice(&keep_alive.to_be_bytes());
+        put_string(&mut packet, client_id);
+        if let Some((user_name, password)) = credentials {
+            put_string(&mut packet, user_name);
+            put_string(&mut packet, password);
+        }
+        write_packet(&mut socket, CONNECT, &packet).await?;
+        let mut client = MqttClient {
+            socket,
+            next_packet_id: 1,
+        };
+        let (header, length) = client.read_header().await?;
+        let mut connack = [0u8; 2];
+        if header != CONNACK || length != connack.len() {
+            return Err(MqttError::Protocol("expected CONNACK"));
+        }
+        client.read_exact(&mut connack).await?;
+        match connack[1] {
+            0 => Ok(client),
+            code => Err(MqttError::Refused(code)),
+        }
+    }
+
+    // At QoS 0, a missed refresh request is fixed by the next one
+    pub async fn subscribe(&mut self, topic: &str) -> Result<(), MqttError<S::Error>> {
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.wrapping_add(1).max(1);
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&packet_id.to_be_bytes());
+        put_string(&mut packet, topic);
+        packet.push(0);
+        write_packet(&mut self.socket, SUBSCRIBE, &packet).await?;
+        let (header, length) = self.read_header().await?;
+        let mut suback = [0u8; 3];
+        if header != SUBACK || length != suback.len() {
+            return Err(MqttError::Protocol("expected SUBACK"));
+        }
+        self.read_exact(&mut suback).await?;
+        if u16::from_be_bytes([suback[0], suback[1]]) != packet_id {
+            return Err(MqttError::Protocol("SUBACK for another packet"));
+        }
+        // 0x80 is failure, anything else the granted QoS
+        if suback[2] == 0x80 {
+            return Err(MqttError::SubscribeFailed);
+        }
+        Ok(())
+    }
+
+    pub async fn ping(&mut self) -> Result<(), MqttError<S::Error>> {
+        write_packet(&mut self.socket, PINGREQ, &[]).await
+    }
+
+    pub async fn disconnect(mut self) -> Result<S, MqttError<S::Error>> {
+        write_packet(&mut self.socket, DISCONNECT, &[]).await?;
+        Ok(self.socket)
+    }
+
+    /*
+     * Waits for the next publish on any subscribed topic, and reads it into topic and payload.
+     * Payloads over max_payload are skipped instead of allocated, and reported as TooLarge, the
+     * connection stays usable.
+     */
+    pub async fn next_publish(
+        &mut self,
+        topic: &mut String,
+        payload: &mut Vec<u8>,
+        max_payload: usize,
+    ) -> Result<(), MqttError<S::Error>> {
+        loop {
+            let (header, length) = self.read_header().await?;
+            match header & 0xF0 {
+                PUBLISH => {}
+                PINGRESP => {
+                    self.skip(length).await?;
+                    continue;
+                }
+                _ => return Err(MqttError::Protocol("unexpected packet")),
+            }
+            let qos = (header >> 1) & 0x03;
+            let mut topic_length = [0u8; 2];
+            self.read_exact(&mut topic_length).await?;
+            let topic_length = u16::from_be_bytes(topic_length) as usize;
+            let packet_id_length = if qos > 0 { 2 } else { 0 };
+            let Some(payload_length) = length.checked_sub(2 + topic_length + packet_id_length)
+            else {
+                return Err(MqttError::Protocol("publish shorter than its topic"));
+            };
+            let mut topic_bytes = alloc::vec![0u8; topic_length];
+            self.read_exact(&mut topic_bytes).await?;
+            *topic = String::from_utf8(topic_bytes)
+                .map_err(|_| MqttError::Protocol("topic isn't UTF-8"))?;
+            let mut packet_id = [0u8; 2];
+            if qos > 0 {
+                self.read_exact(&mut packet_id).await?;
+            }
+            let result = if payload_length > max_payload {
+                self.skip(payload_length).await?;
+                Err(MqttError::TooLarge(payload_length))
+            } else {
+                payload.resize(payload_length, 0);
+                self.read_exact(payload.as_mut_slice()).await?;
+                Ok(())
+            };
+            // We subscribe at QoS 0, but a broker may still send retained messages at 1
+            match qos {
+                0 => {}
+                1 => write_packet(&mut self.socket, PUBACK, &packet_id).await?,
+                _ => return Err(MqttError::Protocol("QoS 2 publish")),
+            }
+            return result;
+        }
+    }
+
+    // Packet type and flags, and the remaining length
+    async fn read_header(&mut self) -> Result<(u8, usize), MqttError<S::Error>> {
+        let mut byte = [0u8; 1];
+        self.read_exact(&mut byte).await?;
+        let header = byte[0];
+        let mut length = 0;
+        for shift in (0..28).step_by(7) {
+            self.read_exact(&mut byte).await?;
+            length |= ((byte[0] & 0x7F) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok((header, length));
+            }
+        }
+        Err(MqttError::Protocol("remaining length too long"))
+    }
+
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), MqttError<S::Error>> {
+        self.socket
+            .read_exact(buf)
+            .await
+            .map_err(MqttError::ReadError)
+    }
+
+    async fn skip(&mut self, mut length: usize) -> Result<(), MqttError<S::Error>> {
+        let mut buffer = [0u8; 64];
+        while length > 0 {
+            let chunk = length.min(buffer.len());
+            self.read_exact(&mut buffer[..chunk]).await?;
+            length -= chunk;
+        }
+        Ok(())
+    }
+}
+
+fn put_string(packet: &mut Vec<u8>, value: &str) {
+    packet.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    packet.extend_from_slice(value.as_bytes());
+}
+
+async fn write_packet<S: Write>(
+    socket: &mut S,
+    header: u8,
+    body: &[u8],
+) -> Result<(), MqttError<S::Error>> {
+    // Remaining length, 7 bits at a time with a continuation bit
+    let mut fixed = [header, 0, 0, 0, 0];
+    let mut fixed_length = 1;
+    let mut length = body.len();
+    loop {
+        let mut byte = (length & 0x7F) as u8;
+        length >>= 7;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        fixed[fixed_length] = byte;
+        fixed_length += 1;
+        if length == 0 {
+            break;
+        }
+    }
+    socket
+        .write_all(&fixed[..fixed_length])
+        .await
+        .map_err(MqttError::WriteError)?;
+    socket
+        .write_all(body)
+        .await
+        .map_err(MqttError::WriteError)?;
+    socket.flush().await.map_err(MqttError::WriteError)
+}
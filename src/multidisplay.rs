@@ -0,0 +1,87 @@
+/*
+ * Drives several GDEP073E01 panels from one MCU, e.g. for signage built from multiple reTerminals.
+ * Every panel gets its own SPI device (own CS) on the shared bus, plus its own DC/BUSY/RST. A
+ * refresh takes tens of seconds, nearly all of it waiting on BUSY, so the panels are refreshed
+ * concurrently: their SPI transactions take turns on the bus, while the busy-waits overlap.
+ */
+use crate::displayinterface::DisplayInterfaceAsyncError;
+use crate::gdep073e01::Gdep073e01;
+use crate::spectra6::Spectra6Color;
+use embassy_futures::join::join_array;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiDevice;
+
+pub struct MultiDisplay<const N: usize, SPI, BUSY, DC, RST, DELAY> {
+    panels: [Gdep073e01<SPI, BUSY, DC, RST, DELAY>; N],
+}
+
+impl<const N: usize, SPI, BUSY, DC, RST, DELAY> MultiDisplay<N, SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub fn new(panels: [Gdep073e01<SPI, BUSY, DC, RST, DELAY>; N]) -> Self {
+        MultiDisplay { panels }
+    }
+
+    // For anything not coordinated here, spis[i] belongs to panels()[i]
+    pub fn panels(&mut self) -> &mut [Gdep073e01<SPI, BUSY, DC, RST, DELAY>; N] {
+        &mut self.panels
+    }
+
+    pub fn into_panels(self) -> [Gdep073e01<SPI, BUSY, DC, RST, DELAY>; N] {
+        self.panels
+    }
+
+    // One after the other, as they share the delay, but none of it waits on BUSY anyway
+    pub async fn reset_and_init(
+        &mut self,
+        spis: &mut [SPI; N],
+        delay: &mut DELAY,
+    ) -> [Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>>; N] {
+        let mut results = [const { Ok(()) }; N];
+        for ((panel, spi), result) in self.panels.iter_mut().zip(spis).zip(&mut results) {
+            *result = async {
+                panel.reset(delay).await?;
+                panel.init(spi).await
+            }
+            .await;
+        }
+        results
+    }
+
+    /*
+     * Uploads frames[i] to panel i and runs the full power on, refresh, power off cycle on all of
+     * them at once. A failing panel doesn't stop the others, each gets its own result.
+     */
+    pub async fn refresh<I>(
+        &mut self,
+        spis: &mut [SPI; N],
+        frames: [I; N],
+    ) -> [Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>>; N]
+    where
+        I: IntoIterator<Item = Spectra6Color>,
+    {
+        let mut spis = spis.iter_mut();
+        let mut frames = frames.into_iter();
+        let refreshes = self.panels.each_mut().map(|panel| {
+            let spi = spis.next().unwrap();
+            let frame = frames.next().unwrap();
+            async move {
+                panel.update_frame(spi, frame).await?;
+                panel.power_on(spi).await?;
+                panel.wait_until_idle().await?;
+                panel.display_frame(spi).await?;
+                panel.wait_until_idle().await?;
+                panel.power_off(spi).await?;
+                panel.wait_until_idle().await
+            }
+        });
+        join_array(refreshes).await
+    }
+}
@@ -0,0 +1,151 @@
+/*
+ * Band-parallel error diffusion, so both cores can dither at the same time. The frame is split
+ * into horizontal bands that are dithered independently. Error diffusion carries error down the
+ * image, so every band but the first starts overlap rows early and throws those rows away: by the
+ * time it gets to its own rows the error has settled to about what it would have been, and the
+ * seam doesn't show. Dithers that only depend on the pixel and its position, like the firmware's
+ * noise dither, need no overlap at all.
+ */
+use crate::dither::{DitherPalette, ForwardErrorDiffusion, ForwardErrorDiffusionMethod};
+use crate::spectra6::{Spectra6Color, SpectraPacker};
+use core::ops::Range;
+
+// Enough for the error to settle with all of the diffusion methods
+pub const DEFAULT_OVERLAP: usize = 8;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Band {
+    // Where dithering starts, rows before start are only there for the error
+    pub first_row: usize,
+    pub start_row: usize,
+    pub end_row: usize,
+}
+
+impl Band {
+    // The rows this band outputs
+    pub fn rows(&self) -> Range<usize> {
+        self.start_row..self.end_row
+    }
+
+    // The rows the source has to yield, including the overlap
+    pub fn source_rows(&self) -> Range<usize> {
+        self.first_row..self.end_row
+    }
+
+    // source has to start at first_row, the overlap rows are dithered but not yielded
+    pub fn dither<PALETTE, METHOD, I>(
+        self,
+        palette: PALETTE,
+        method: METHOD,
+        source: I,
+        width: usize,
+    ) -> impl Iterator<Item = PALETTE::TargetColor>
+    where
+        PALETTE: DitherPalette,
+        METHOD: ForwardErrorDiffusionMethod,
+        I: Iterator<Item = PALETTE::SourceColor>,
+    {
+        let source = source.take(self.source_rows().len() * width);
+        ForwardErrorDiffusion::new(palette, method, source, width)
+            .skip((self.start_row - self.first_row) * width)
+    }
+}
+
+// Splits height rows into count bands of (about) the same height
+pub fn split(height: usize, count: usize, overlap: usize) -> impl Iterator<Item = Band> {
+    let count = count.max(1);
+    (0..count).map(move |index| {
+        let start_row = height * index / count;
+        Band {
+            first_row: start_row.saturating_sub(overlap),
+            start_row,
+            end_row: height * (index + 1) / count,
+        }
+    })
+}
+
+/*
+ * Fills out (packed for the panel) with two bands at the same time. top and bottom get their band
+ * and yield the pixels of its rows. run gets the work for the bottom band and the top band: it has
+ * to start the first on the other core, run the second on this one, and only return once both are
+ * done. E.g. start the first with esp-hal's CpuControl::start_app_core, and wait for a flag it sets
+ * at the end. width has to be even, so bands start on a whole byte.
+ */
+#[allow(clippy::too_many_arguments)]
+pub fn packed_dual<T, B, TI, BI, R>(
+    width: usize,
+    height: usize,
+    overlap: usize,
+    out: &mut [u8],
+    top: T,
+    bottom: B,
+    run: R,
+) where
+    T: FnOnce(Band) -> TI,
+    B: FnOnce(Band) -> BI + Send,
+    TI: Iterator<Item = Spectra6Color>,
+    BI: Iterator<Item = Spectra6Color>,
+    R: FnOnce(&mut (dyn FnMut() + Send), &mut dyn FnMut()),
+{
+    debug_assert!(width.is_multiple_of(2));
+    let mut bands = split(height, 2, overlap);
+    let (top_band, bottom_band) = (bands.next().unwrap(), bands.next().unwrap());
+    let (top_out, bottom_out) = out.split_at_mut(top_band.rows().len() * width / 2);
+
+    // FnMut, as the closures are called through a reference, but each only runs once
+    let mut bottom_work = Some((bottom, bottom_out));
+    let mut top_work = Some((top, top_out));
+    run(
+        &mut || {
+            if let Some((bottom, out)) = bottom_work.take() {
+                fill(out, SpectraPacker(bottom(bottom_band)));
+            }
+        },
+        &mut || {
+            if let Some((top, out)) = top_work.take() {
+                fill(out, SpectraPacker(top(top_band)));
+            }
+        },
+    );
+}
+
+/*
+ * Error diffusion with packed_dual, each band starting overlap rows early. source(row) yields the
+ * pixels from that row to the end of the frame.
+ */
+#[allow(clippy::too_many_arguments)]
+pub fn dither_packed_dual<PALETTE, METHOD, I, S, R>(
+    palettes: [PALETTE; 2],
+    methods: [METHOD; 2],
+    source: S,
+    width: usize,
+    height: usize,
+    overlap: usize,
+    out: &mut [u8],
+    run: R,
+) where
+    PALETTE: DitherPalette<TargetColor = Spectra6Color> + Send,
+    METHOD: ForwardErrorDiffusionMethod + Send,
+    I: Iterator<Item = PALETTE::SourceColor>,
+    S: Fn(usize) -> I + Sync,
+    R: FnOnce(&mut (dyn FnMut() + Send), &mut dyn FnMut()),
+{
+    let [top_palette, bottom_palette] = palettes;
+    let [top_method, bottom_method] = methods;
+    let source = &source;
+    packed_dual(
+        width,
+        height,
+        overlap,
+        out,
+        move |band| band.dither(top_palette, top_method, source(band.first_row), width),
+        move |band| band.dither(bottom_palette, bottom_method, source(band.first_row), width),
+        run,
+    );
+}
+
+fn fill(out: &mut [u8], bytes: impl Iterator<Item = u8>) {
+    for (out, byte) in out.iter_mut().zip(bytes) {
+        *out = byte;
+    }
+}
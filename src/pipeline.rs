@@ -0,0 +1,456 @@
+/*
+ * Image pipeline as swappable stages: decode -> transform(s) -> quantize -> pack. Downstream crates
+ * can register their own stages, e.g. an extra decoder, or a branding overlay transform inserted
+ * before the letterbox step, without forking the firmware.
+ */
+use crate::adjust::AdjustExt;
+use crate::analysis::tone_stats;
+#[cfg(feature = "jpeg")]
+use crate::budget::jpeg_dimensions;
+#[cfg(feature = "jpeg")]
+use crate::dither::LUMA_WEIGHTS;
+use crate::dither::{FloydSteinberg, ForwardErrorDiffusion, LinearRgbToPalette};
+use crate::image::{AutoRotate, Matting, PartialImage, RgbImage, Rotation};
+use crate::qoi::{self, qoi_dimensions};
+use crate::spectra6::{Palette, Spectra6Color, SpectraPacker};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PipelineError {
+    // None of the registered decoders accepts the data
+    NoDecoder,
+    DecodeFailed,
+    // More pixels than the decoder is set up for
+    TooLarge { width: u32, height: u32 },
+    // The format is known, but not this variant of it, e.g. arithmetic coded JPEG
+    Unsupported,
+}
+
+pub trait Decoder {
+    fn name(&self) -> &'static str;
+    // Cheap check, usually on the magic bytes
+    fn accepts(&self, data: &[u8]) -> bool;
+    fn decode(&self, data: &[u8]) -> Result<RgbImage, PipelineError>;
+}
+
+pub trait Transform {
+    fn name(&self) -> &'static str;
+    fn apply(&self, image: RgbImage) -> RgbImage;
+}
+
+pub trait Quantizer {
+    fn quantize(&self, image: RgbImage) -> Vec<Spectra6Color>;
+}
+
+pub trait Packer {
+    fn pack(&self, pixels: Vec<Spectra6Color>) -> Vec<u8>;
+}
+
+pub struct PngDecoder {
+    // What transparent parts are composited onto
+    pub background: Rgb888,
+}
+
+impl Default for PngDecoder {
+    fn default() -> Self {
+        PngDecoder {
+            background: Rgb888::WHITE,
+        }
+    }
+}
+
+impl Decoder for PngDecoder {
+    fn name(&self) -> &'static str {
+        "png"
+    }
+
+    fn accepts(&self, data: &[u8]) -> bool {
+        data.starts_with(b"\x89PNG\r\n\x1a\n")
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<RgbImage, PipelineError> {
+        let (header, pixels) =
+            png_decoder::decode(data).map_err(|_| PipelineError::DecodeFailed)?;
+        Ok(RgbImage::from_rgba_over(
+            header.width as usize,
+            header.height as usize,
+            pixels,
+            self.background,
+        ))
+    }
+}
+
+pub struct QoiDecoder {
+    // What transparent parts are composited onto
+    pub background: Rgb888,
+}
+
+impl Default for QoiDecoder {
+    fn default() -> Self {
+        QoiDecoder {
+            background: Rgb888::WHITE,
+        }
+    }
+}
+
+impl Decoder for QoiDecoder {
+    fn name(&self) -> &'static str {
+        "qoi"
+    }
+
+    fn accepts(&self, data: &[u8]) -> bool {
+        qoi_dimensions(data).is_some()
+    }
+
+    // A truncated file still shows what's there, the rest in the background color
+    fn decode(&self, data: &[u8]) -> Result<RgbImage, PipelineError> {
+        let (width, height, pixels) = qoi::decode(data).ok_or(PipelineError::DecodeFailed)?;
+        let (width, height) = (width as usize, height as usize);
+        let partial = PartialImage::from_rgba_over(width, height, pixels, self.background);
+        if partial.valid_pixels == 0 && !partial.is_complete() {
+            return Err(PipelineError::DecodeFailed);
+        }
+        Ok(partial.image)
+    }
+}
+
+// Baseline and progressive JPEG, grayscale and CMYK come out as RGB
+#[cfg(feature = "jpeg")]
+pub struct JpegDecoder {
+    // What a truncated file's missing part is filled with
+    pub background: Rgb888,
+}
+
+#[cfg(feature = "jpeg")]
+impl Default for JpegDecoder {
+    fn default() -> Self {
+        JpegDecoder {
+            background: Rgb888::WHITE,
+        }
+    }
+}
+
+#[cfg(feature = "jpeg")]
+impl Decoder for JpegDecoder {
+    fn name(&self) -> &'static str {
+        "jpeg"
+    }
+
+    fn accepts(&self, data: &[u8]) -> bool {
+        data.starts_with(&[0xFF, 0xD8, 0xFF])
+    }
+
+    /*
+     * A truncated download, or a progressive file cut off after a few scans, still shows what's
+     * there, the rest in the background color.
+     */
+    fn decode(&self, data: &[u8]) -> Result<RgbImage, PipelineError> {
+        let map_error = |e| match e {
+            zune_jpeg::errors::DecodeErrors::LargeDimensions(_) => jpeg_dimensions(data)
+                .map_or(PipelineError::DecodeFailed, |(width, height)| {
+                    PipelineError::TooLarge { width, height }
+                }),
+            zune_jpeg::errors::DecodeErrors::Unsupported(_) => PipelineError::Unsupported,
+            _ => PipelineError::DecodeFailed,
+        };
+        let options = zune_core::options::DecoderOptions::default()
+            .jpeg_set_out_colorspace(zune_core::colorspace::ColorSpace::RGB);
+        let mut decoder = zune_jpeg::JpegDecoder::new_with_options(data, options);
+        decoder.decode_headers().map_err(map_error)?;
+        let complete = jpeg_complete(data);
+        let mended;
+        if !complete {
+            let progressive = decoder.info().is_some_and(|info| info.sof.is_progressive());
+            mended = mend_truncated_jpeg(data, progressive);
+            decoder = zune_jpeg::JpegDecoder::new_with_options(&mended, options);
+            decoder.decode_headers().map_err(map_error)?;
+        }
+        let (width, height) = decoder.dimensions().ok_or(PipelineError::DecodeFailed)?;
+        let size = decoder
+            .output_buffer_size()
+            .ok_or(PipelineError::DecodeFailed)?;
+
+        // Filled with the background first. RGB as asked for, except grayscale, which stays luma.
+        let [r, g, b] = [
+            self.background.r(),
+            self.background.g(),
+            self.background.b(),
+        ];
+        let [wr, wg, wb] = LUMA_WEIGHTS.map(u32::from);
+        let fill = match size / (width * height).max(1) {
+            1 => alloc::vec![((r as u32 * wr + g as u32 * wg + b as u32 * wb) >> 8) as u8],
+            3 => alloc::vec![r, g, b],
+            _ => return Err(PipelineError::Unsupported),
+        };
+        let mut decoded: Vec<u8> = fill.iter().copied().cycle().take(size).collect();
+        decoder.decode_into(&mut decoded).map_err(map_error)?;
+
+        // What the decoder didn't get to is the trailing rows still all fill
+        let row_size = width * fill.len();
+        let missing_rows = if complete {
+            0
+        } else {
+            decoded
+                .chunks(row_size.max(1))
+                .rev()
+                .take_while(|row| row.chunks(fill.len()).all(|pixel| pixel == fill))
+                .count()
+        };
+        let pixels = decoded
+            .chunks_exact(fill.len())
+            .take((height - missing_rows) * width)
+            .map(|pixel| match *pixel {
+                [luma] => [luma, luma, luma, 0xFF],
+                [r, g, b] => [r, g, b, 0xFF],
+                _ => unreachable!(),
+            });
+        let partial = PartialImage::from_rgba_over(width, height, pixels, self.background);
+        if partial.valid_pixels == 0 && !partial.is_complete() {
+            return Err(PipelineError::DecodeFailed);
+        }
+        Ok(partial.image)
+    }
+}
+
+/*
+ * Whether the image's data runs up to the end of image marker. Markers can't occur in the entropy
+ * coded data, but a thumbnail in the EXIF header has its own, so it has to come after the last
+ * start of scan.
+ */
+#[cfg(feature = "jpeg")]
+fn jpeg_complete(data: &[u8]) -> bool {
+    let start_of_scan = data.windows(2).rposition(|pair| pair == [0xFF, 0xDA]);
+    let end_of_image = data.windows(2).rposition(|pair| pair == [0xFF, 0xD9]);
+    matches!((start_of_scan, end_of_image), (Some(start), Some(end)) if end > start)
+}
+
+/*
+ * zune-jpeg makes up what's missing of a truncated file from zero bits, which shows as garbage.
+ * Another start of scan where the data ends stops it there instead, leaving the rest of a baseline
+ * image as it was. A progressive one is better off without the cut off scan, the ones before it
+ * cover the whole image, just not in full detail.
+ */
+#[cfg(feature = "jpeg")]
+fn mend_truncated_jpeg(data: &[u8], progressive: bool) -> Vec<u8> {
+    let is_start_of_scan = |pair: &[u8]| pair == [0xFF, 0xDA];
+    let mut mended = Vec::with_capacity(data.len() + 32);
+    match data.windows(2).rposition(is_start_of_scan) {
+        Some(start) if progressive && data[..start].windows(2).any(is_start_of_scan) => {
+            mended.extend_from_slice(&data[..start]);
+        }
+        Some(start) if !progressive => {
+            // A fill byte at the very end would run into the marker
+            mended.extend_from_slice(data.strip_suffix(&[0xFF]).unwrap_or(data));
+            let length = data.get(start + 2..start + 4).map_or(0, |length| {
+                u16::from_be_bytes([length[0], length[1]]) as usize
+            });
+            if let Some(header) = data.get(start..start + 2 + length) {
+                mended.extend_from_slice(header);
+            }
+        }
+        _ => mended.extend_from_slice(data),
+    }
+    mended.extend_from_slice(&[0xFF, 0xD9]);
+    mended
+}
+
+// Rotates (optionally automatically) and letterboxes to the panel size
+pub struct Letterbox {
+    pub width: usize,
+    pub height: usize,
+    pub auto_rotate: Option<AutoRotate>,
+    pub matting: Matting,
+}
+
+impl Transform for Letterbox {
+    fn name(&self) -> &'static str {
+        "letterbox"
+    }
+
+    fn apply(&self, image: RgbImage) -> RgbImage {
+        let rotation = self
+            .auto_rotate
+            .map(|auto_rotate| {
+                auto_rotate.rotation_for(image.width, image.height, self.width, self.height)
+            })
+            .unwrap_or(Rotation::None);
+        let pixels = image
+            .letterboxed_pixels(rotation, self.width, self.height, self.matting)
+            .collect();
+        RgbImage::new(self.width, self.height, pixels)
+    }
+}
+
+/*
+ * Contrast, exposure and saturation tuned to the image itself, see ToneStats. Insert it before the
+ * letterbox, so the bars don't count.
+ */
+pub struct AutoTune;
+
+impl Transform for AutoTune {
+    fn name(&self) -> &'static str {
+        "auto-tune"
+    }
+
+    fn apply(&self, image: RgbImage) -> RgbImage {
+        let stats = tone_stats(image.pixels.iter().copied());
+        let pixels = image
+            .pixels
+            .into_iter()
+            .adjust((stats.contrast(), stats.saturation()))
+            .collect();
+        RgbImage::new(image.width, image.height, pixels)
+    }
+}
+
+// Nearest palette color, no dithering
+pub struct NearestQuantizer;
+
+impl Quantizer for NearestQuantizer {
+    fn quantize(&self, image: RgbImage) -> Vec<Spectra6Color> {
+        image
+            .pixels
+            .into_iter()
+            .map(Spectra6Color::from_rgb_threshold)
+            .collect()
+    }
+}
+
+/*
+ * Floyd-Steinberg dithering with the error worked out and diffused in linear light, so dithered
+ * gradients are as bright as the source instead of visibly darker.
+ */
+pub struct LinearDitherQuantizer {
+    palette: Palette,
+}
+
+impl LinearDitherQuantizer {
+    pub fn new(palette: Palette) -> Self {
+        LinearDitherQuantizer { palette }
+    }
+}
+
+impl Quantizer for LinearDitherQuantizer {
+    fn quantize(&self, image: RgbImage) -> Vec<Spectra6Color> {
+        let palette = LinearRgbToPalette::new(&self.palette);
+        ForwardErrorDiffusion::new(
+            palette,
+            FloydSteinberg,
+            image.pixels.into_iter(),
+            image.width,
+        )
+        .collect()
+    }
+}
+
+// Two pixels per byte, as the GDEP073E01 expects
+pub struct Spectra6Packer;
+
+impl Packer for Spectra6Packer {
+    fn pack(&self, pixels: Vec<Spectra6Color>) -> Vec<u8> {
+        SpectraPacker(pixels.into_iter()).collect()
+    }
+}
+
+pub struct Pipeline {
+    decoders: Vec<Box<dyn Decoder>>,
+    transforms: Vec<Box<dyn Transform>>,
+    quantizer: Box<dyn Quantizer>,
+    packer: Box<dyn Packer>,
+}
+
+impl Pipeline {
+    pub fn new(quantizer: Box<dyn Quantizer>, packer: Box<dyn Packer>) -> Self {
+        Pipeline {
+            decoders: Vec::new(),
+            transforms: Vec::new(),
+            quantizer,
+            packer,
+        }
+    }
+
+    // PNG or QOI in, letterboxed to the panel, nearest color, packed for the GDEP073E01
+    pub fn spectra6(letterbox: Letterbox) -> Self {
+        let mut pipeline = Self::new(Box::new(NearestQuantizer), Box::new(Spectra6Packer));
+        pipeline.register_decoder(Box::new(PngDecoder::default()));
+        pipeline.register_decoder(Box::new(QoiDecoder::default()));
+        #[cfg(feature = "jpeg")]
+        pipeline.register_decoder(Box::new(JpegDecoder::default()));
+        pipeline.register_transform(Box::new(letterbox));
+        pipeline
+    }
+
+    // Like spectra6, but dithered in linear light with palette
+    pub fn spectra6_linear(letterbox: Letterbox, palette: Palette) -> Self {
+        let quantizer = LinearDitherQuantizer::new(palette);
+        let mut pipeline = Self::new(Box::new(quantizer), Box::new(Spectra6Packer));
+        pipeline.register_decoder(Box::new(PngDecoder::default()));
+        pipeline.register_decoder(Box::new(QoiDecoder::default()));
+        #[cfg(feature = "jpeg")]
+        pipeline.register_decoder(Box::new(JpegDecoder::default()));
+        pipeline.register_transform(Box::new(letterbox));
+        pipeline
+    }
+
+    // Decoders are tried in registration order
+    pub fn register_decoder(&mut self, decoder: Box<dyn Decoder>) {
+        self.decoders.push(decoder);
+    }
+
+    // Appends a transform, they're applied in order
+    pub fn register_transform(&mut self, transform: Box<dyn Transform>) {
+        self.transforms.push(transform);
+    }
+
+    // Inserts a transform before the one called name, or at the end if there's none by that name
+    pub fn insert_transform_before(&mut self, name: &str, transform: Box<dyn Transform>) {
+        let index = self
+            .transforms
+            .iter()
+            .position(|existing| existing.name() == name)
+            .unwrap_or(self.transforms.len());
+        self.transforms.insert(index, transform);
+    }
+
+    pub fn remove_transform(&mut self, name: &str) -> Option<Box<dyn Transform>> {
+        let index = self
+            .transforms
+            .iter()
+            .position(|existing| existing.name() == name)?;
+        Some(self.transforms.remove(index))
+    }
+
+    pub fn set_quantizer(&mut self, quantizer: Box<dyn Quantizer>) {
+        self.quantizer = quantizer;
+    }
+
+    pub fn set_packer(&mut self, packer: Box<dyn Packer>) {
+        self.packer = packer;
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<RgbImage, PipelineError> {
+        self.decoders
+            .iter()
+            .find(|decoder| decoder.accepts(data))
+            .ok_or(PipelineError::NoDecoder)?
+            .decode(data)
+    }
+
+    // Everything up to (and including) quantization, for feeding update_frame directly
+    pub fn render(&self, data: &[u8]) -> Result<Vec<Spectra6Color>, PipelineError> {
+        let image = self
+            .transforms
+            .iter()
+            .fold(self.decode(data)?, |image, transform| {
+                transform.apply(image)
+            });
+        Ok(self.quantizer.quantize(image))
+    }
+
+    // The whole pipeline, returns a packed frame
+    pub fn run(&self, data: &[u8]) -> Result<Vec<u8>, PipelineError> {
+        Ok(self.packer.pack(self.render(data)?))
+    }
+}
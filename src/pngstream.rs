@@ -0,0 +1,511 @@
+/*
+ * PNG decoding a row at a time, straight from an async reader like the HTTP body, so the dithering
+ * can start before the download is done. Only the inflate window, the palette and two rows are
+ * kept, about 40KB for a full 800x480 frame, instead of the compressed file plus 1.5MB of RGBA.
+ *
+ * Interlaced (Adam7) images aren't supported, their rows only come together at the very end. Chunk
+ * CRCs aren't checked, a corrupted download mostly fails to inflate anyway.
+ */
+use crate::image::composite;
+use crate::inflate::{InflateError, Inflater};
+use crate::spectra6::Spectra6Color;
+use alloc::vec::Vec;
+use core::convert::Infallible;
+use core::fmt::Debug;
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_io_async::{ErrorType, Read, ReadExactError};
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/*
+ * Width and height of a PNG that PngRows can stream, from the first bytes of the file. None if
+ * they aren't a PNG, are too short to tell, or the image is interlaced.
+ */
+pub fn streamable_size(head: &[u8]) -> Option<(usize, usize)> {
+    if !head.starts_with(SIGNATURE) || head.get(12..16)? != b"IHDR" || *head.get(28)? != 0 {
+        return None;
+    }
+    let width = u32::from_be_bytes(head.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(head.get(20..24)?.try_into().ok()?);
+    Some((width as usize, height as usize))
+}
+
+pub enum PngStreamError<E> {
+    Read(E),
+    UnexpectedEof,
+    Invalid(&'static str),
+    Unsupported(&'static str),
+}
+
+impl<E: Debug> Debug for PngStreamError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Read(x) => write!(f, "Read({:?})", x),
+            Self::UnexpectedEof => write!(f, "UnexpectedEof"),
+            Self::Invalid(x) => write!(f, "Invalid({:?})", x),
+            Self::Unsupported(x) => write!(f, "Unsupported({:?})", x),
+        }
+    }
+}
+
+impl<E> From<ReadExactError<E>> for PngStreamError<E> {
+    fn from(value: ReadExactError<E>) -> Self {
+        match value {
+            ReadExactError::UnexpectedEof => Self::UnexpectedEof,
+            ReadExactError::Other(x) => Self::Read(x),
+        }
+    }
+}
+
+impl<E> From<ReadExactError<InflateError<E>>> for PngStreamError<E> {
+    fn from(value: ReadExactError<InflateError<E>>) -> Self {
+        match value {
+            ReadExactError::UnexpectedEof | ReadExactError::Other(InflateError::UnexpectedEof) => {
+                Self::UnexpectedEof
+            }
+            ReadExactError::Other(InflateError::Read(x)) => Self::Read(x),
+            ReadExactError::Other(InflateError::Invalid(x)) => Self::Invalid(x),
+        }
+    }
+}
+
+// The data of consecutive IDAT chunks, as one stream
+struct IdatReader<R> {
+    source: R,
+    // Left in the current chunk
+    remaining: u32,
+    done: bool,
+}
+
+impl<R: Read> ErrorType for IdatReader<R> {
+    type Error = R::Error;
+}
+
+impl<R: Read> Read for IdatReader<R> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        while self.remaining == 0 && !self.done {
+            // CRC of this chunk, then the next chunk's header
+            let mut header = [0u8; 12];
+            match self.source.read_exact(&mut header).await {
+                Ok(()) => {}
+                Err(ReadExactError::UnexpectedEof) => self.done = true,
+                Err(ReadExactError::Other(x)) => return Err(x),
+            }
+            if &header[8..12] == b"IDAT" {
+                self.remaining = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+            } else {
+                self.done = true;
+            }
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let length = buf.len().min(self.remaining as usize);
+        let read = self.source.read(&mut buf[..length]).await?;
+        if read == 0 {
+            self.done = true;
+        }
+        self.remaining -= read as u32;
+        Ok(read)
+    }
+}
+
+pub struct PngRows<R> {
+    inflater: Inflater<IdatReader<R>>,
+    pub width: usize,
+    pub height: usize,
+    color_type: u8,
+    bit_depth: u8,
+    // Bytes per complete pixel, at least 1, what the filters work in
+    filter_step: usize,
+    palette: Vec<[u8; 4]>,
+    // Color that's fully transparent, from tRNS for gray and RGB images
+    transparent: Option<[u16; 3]>,
+    background: Rgb888,
+    previous: Vec<u8>,
+    current: Vec<u8>,
+    row: Vec<Rgb888>,
+    y: usize,
+}
+
+impl<R: Read> PngRows<R> {
+    /*
+     * Reads up to the image data, transparent parts are composited onto background. Images wider
+     * than max_width are refused before anything is allocated for their rows.
+     */
+    pub async fn open(
+        mut source: R,
+        background: Rgb888,
+        max_width: usize,
+    ) -> Result<Self, PngStreamError<R::Error>> {
+        let mut signature = [0u8; 8];
+        source.read_exact(&mut signature).await?;
+        if &signature != SIGNATURE {
+            return Err(PngStreamError::Invalid("not a PNG"));
+        }
+        let mut header = None;
+        let mut palette = Vec::new();
+        let mut trns = Vec::new();
+        loop {
+            let mut chunk = [0u8; 8];
+            source.read_exact(&mut chunk).await?;
+            let length = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize;
+            match &chunk[4..8] {
+                b"IHDR" if length == 13 => {
+                    let mut data = [0u8; 13];
+                    source.read_exact(&mut data).await?;
+                    header = Some(data);
+                }
+                b"PLTE" if length <= 768 && length.is_multiple_of(3) => {
+                    let mut data = [0u8; 768];
+                    source.read_exact(&mut data[..length]).await?;
+                    palette = data[..length]
+                        .chunks_exact(3)
+                        .map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+                        .collect();
+                }
+                b"tRNS" if length <= 256 => {
+                    trns.resize(length, 0);
+                    source.read_exact(&mut trns).await?;
+                }
+                b"IDAT" => {
+                    let header = header.ok_or(PngStreamError::Invalid("IDAT before IHDR"))?;
+                    let idat = IdatReader {
+                        source,
+                        remaining: length as u32,
+                        done: false,
+                    };
+                    return Self::new(idat, header, palette, &trns, background, max_width);
+                }
+                b"IEND" => return Err(PngStreamError::Invalid("no image data")),
+                _ => skip(&mut source, length).await?,
+            }
+            skip(&mut source, 4).await?;
+        }
+    }
+
+    fn new(
+        idat: IdatReader<R>,
+        header: [u8; 13],
+        mut palette: Vec<[u8; 4]>,
+        trns: &[u8],
+        background: Rgb888,
+        max_width: usize,
+    ) -> Result<Self, PngStreamError<R::Error>> {
+        let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let [bit_depth, color_type, compression, filter, interlace] =
+            [header[8], header[9], header[10], header[11], header[12]];
+        let channels = match (color_type, bit_depth) {
+            (0, 1 | 2 | 4 | 8 | 16) => 1,
+            (3, 1 | 2 | 4 | 8) => 1,
+            (4, 8 | 16) => 2,
+            (2, 8 | 16) => 3,
+            (6, 8 | 16) => 4,
+            _ => return Err(PngStreamError::Invalid("bad color type or bit depth")),
+        };
+        if compression != 0 || filter != 0 {
+            return Err(PngStreamError::Invalid(
+                "unknown compression or filter method",
+            ));
+        }
+        if interlace != 0 {
+            return Err(PngStreamError::Unsupported("interlaced"));
+        }
+        if width > max_width {
+            return Err(PngStreamError::Unsupported("too wide"));
+        }
+        if color_type == 3 && palette.is_empty() {
+            return Err(PngStreamError::Invalid("no palette"));
+        }
+        // Compared as if scaled to 16 bits, the same as samples are read
+        let sample = |index: usize| {
+            let value = u16::from_be_bytes([trns[index * 2], trns[index * 2 + 1]]);
+            scale_to_16(value, bit_depth)
+        };
+        let transparent = match color_type {
+            0 if trns.len() >= 2 => Some([sample(0); 3]),
+            2 if trns.len() >= 6 => Some([sample(0), sample(1), sample(2)]),
+            _ => None,
+        };
+        if color_type == 3 {
+            for (entry, alpha) in palette.iter_mut().zip(trns) {
+                entry[3] = *alpha;
+            }
+        }
+        let bits_per_pixel = channels * bit_depth as usize;
+        let row_bytes = (width * bits_per_pixel).div_ceil(8);
+        Ok(PngRows {
+            inflater: Inflater::new(idat),
+            width,
+            height,
+            color_type,
+            bit_depth,
+            filter_step: bits_per_pixel.div_ceil(8),
+            palette,
+            transparent,
+            background,
+            previous: alloc::vec![0; row_bytes],
+            current: alloc::vec![0; row_bytes],
+            row: Vec::with_capacity(width),
+            y: 0,
+        })
+    }
+
+    pub fn rows_read(&self) -> usize {
+        self.y
+    }
+
+    pub async fn next_row(&mut self) -> Result<Option<&[Rgb888]>, PngStreamError<R::Error>> {
+        if self.y >= self.height {
+            return Ok(None);
+        }
+        let mut filter = [0u8; 1];
+        self.inflater.read_exact(&mut filter).await?;
+        self.inflater.read_exact(&mut self.current).await?;
+        self.unfilter(filter[0])?;
+        self.convert_row();
+        core::mem::swap(&mut self.previous, &mut self.current);
+        self.y += 1;
+        Ok(Some(&self.row))
+    }
+
+    fn unfilter(&mut self, filter: u8) -> Result<(), PngStreamError<R::Error>> {
+        let step = self.filter_step;
+        let (current, previous) = (&mut self.current, &self.previous);
+        match filter {
+            0 => {}
+            1 => {
+                for index in step..current.len() {
+                    current[index] = current[index].wrapping_add(current[index - step]);
+                }
+            }
+            2 => {
+                for (value, above) in current.iter_mut().zip(previous) {
+                    *value = value.wrapping_add(*above);
+                }
+            }
+            3 => {
+                for index in 0..current.len() {
+                    let left = index.checked_sub(step).map_or(0, |left| current[left]);
+                    let average = (left as u16 + previous[index] as u16) / 2;
+                    current[index] = current[index].wrapping_add(average as u8);
+                }
+            }
+            4 => {
+                for index in 0..current.len() {
+                    let (left, upper_left) = index
+                        .checked_sub(step)
+                        .map_or((0, 0), |left| (current[left], previous[left]));
+                    let predicted = paeth(left, previous[index], upper_left);
+                    current[index] = current[index].wrapping_add(predicted);
+                }
+            }
+            _ => return Err(PngStreamError::Invalid("unknown filter type")),
+        }
+        Ok(())
+    }
+
+    // Sample index of the row as 16 bits, or as is for palette indices
+    fn sample(&self, index: usize) -> u16 {
+        let data = &self.current;
+        match self.bit_depth {
+            16 => u16::from_be_bytes([data[index * 2], data[index * 2 + 1]]),
+            8 if self.color_type == 3 => data[index] as u16,
+            8 => scale_to_16(data[index] as u16, 8),
+            depth => {
+                let bit = index * depth as usize;
+                let value = (data[bit / 8] >> (8 - depth as usize - bit % 8)) as u16;
+                if self.color_type == 3 {
+                    value & ((1 << depth) - 1)
+                } else {
+                    scale_to_16(value, depth)
+                }
+            }
+        }
+    }
+
+    fn convert_row(&mut self) {
+        let mut row = core::mem::take(&mut self.row);
+        row.clear();
+        for x in 0..self.width {
+            let rgba = match self.color_type {
+                0 | 4 => {
+                    let gray = self.sample(x * (1 + (self.color_type == 4) as usize));
+                    let alpha = if self.color_type == 4 {
+                        self.sample(x * 2 + 1)
+                    } else if self.transparent == Some([gray; 3]) {
+                        0
+                    } else {
+                        65535
+                    };
+                    [gray, gray, gray, alpha].map(|value| (value >> 8) as u8)
+                }
+                3 => {
+                    let index = self.sample(x) as usize;
+                    self.palette.get(index).copied().unwrap_or([0, 0, 0, 255])
+                }
+                _ => {
+                    let channels = if self.color_type == 6 { 4 } else { 3 };
+                    let [r, g, b] = [0, 1, 2].map(|channel| self.sample(x * channels + channel));
+                    let alpha = if channels == 4 {
+                        self.sample(x * 4 + 3)
+                    } else if self.transparent == Some([r, g, b]) {
+                        0
+                    } else {
+                        65535
+                    };
+                    [r, g, b, alpha].map(|value| (value >> 8) as u8)
+                }
+            };
+            row.push(composite(rgba, self.background));
+        }
+        self.row = row;
+    }
+}
+
+/*
+ * A bit_depth sample scaled to 16 bits. Bits above bit_depth are ignored, a malformed tRNS chunk
+ * can have them set.
+ */
+fn scale_to_16(value: u16, bit_depth: u8) -> u16 {
+    if bit_depth >= 16 {
+        return value;
+    }
+    let max = (1u32 << bit_depth) - 1;
+    ((value as u32 & max) * (65535 / max)) as u16
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+async fn skip<R: Read>(source: &mut R, mut length: usize) -> Result<(), PngStreamError<R::Error>> {
+    let mut buffer = [0u8; 64];
+    while length > 0 {
+        let chunk = length.min(buffer.len());
+        source.read_exact(&mut buffer[..chunk]).await?;
+        length -= chunk;
+    }
+    Ok(())
+}
+
+/*
+ * A streamed PNG centered on a width x height frame, quantized per pixel with quantize(x, y, color)
+ * and packed two pixels per byte, as a Read for update_frame_from_reader. Images larger than the
+ * frame aren't scaled, so new refuses them.
+ *
+ * If the image turns out to be broken halfway, the rest of the frame is fill, the same as for a
+ * truncated download (see error).
+ */
+pub struct PackedPngFrame<R: Read, F> {
+    rows: PngRows<R>,
+    quantize: F,
+    height: usize,
+    fill: Rgb888,
+    left: usize,
+    top: usize,
+    error: Option<PngStreamError<R::Error>>,
+    pixels: Vec<Rgb888>,
+    packed: Vec<u8>,
+    position: usize,
+    y: usize,
+}
+
+impl<R: Read, F: FnMut(usize, usize, Rgb888) -> Spectra6Color> PackedPngFrame<R, F> {
+    pub fn new(
+        rows: PngRows<R>,
+        width: usize,
+        height: usize,
+        fill: Rgb888,
+        quantize: F,
+    ) -> Option<Self> {
+        if rows.width > width || rows.height > height {
+            return None;
+        }
+        Some(PackedPngFrame {
+            left: (width - rows.width) / 2,
+            top: (height - rows.height) / 2,
+            rows,
+            quantize,
+            height,
+            fill,
+            error: None,
+            pixels: alloc::vec![fill; width],
+            packed: Vec::with_capacity(width.div_ceil(2)),
+            position: 0,
+            y: 0,
+        })
+    }
+
+    // Why the image stopped early, if it did
+    pub fn error(&self) -> Option<&PngStreamError<R::Error>> {
+        self.error.as_ref()
+    }
+
+    async fn next_packed_row(&mut self) {
+        self.pixels.fill(self.fill);
+        let image_row = self.y.checked_sub(self.top);
+        if let Some(image_row) = image_row
+            && image_row == self.rows.rows_read()
+            && self.error.is_none()
+        {
+            match self.rows.next_row().await {
+                Ok(Some(row)) => {
+                    self.pixels[self.left..self.left + row.len()].copy_from_slice(row);
+                }
+                Ok(None) => {}
+                Err(e) => self.error = Some(e),
+            }
+        }
+        self.packed.clear();
+        let y = self.y;
+        let mut colors = self
+            .pixels
+            .iter()
+            .enumerate()
+            .map(|(x, color)| (self.quantize)(x, y, *color) as u8);
+        while let Some(left) = colors.next() {
+            let right = colors.next().unwrap_or(Spectra6Color::White as u8);
+            self.packed.push(left << 4 | right);
+        }
+        self.position = 0;
+        self.y += 1;
+    }
+}
+
+impl<R: Read, F> ErrorType for PackedPngFrame<R, F> {
+    type Error = Infallible;
+}
+
+impl<R: Read, F: FnMut(usize, usize, Rgb888) -> Spectra6Color> Read for PackedPngFrame<R, F> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut written = 0;
+        while written < buf.len() {
+            if self.position >= self.packed.len() {
+                if self.y >= self.height {
+                    break;
+                }
+                self.next_packed_row().await;
+            }
+            let count = (buf.len() - written).min(self.packed.len() - self.position);
+            buf[written..written + count]
+                .copy_from_slice(&self.packed[self.position..self.position + count]);
+            written += count;
+            self.position += count;
+        }
+        Ok(written)
+    }
+}
@@ -0,0 +1,294 @@
+/*
+ * Setup over Wi-Fi, for when the device has no settings or can't reach its network. It opens an
+ * access point and acts like a hotel captive portal: the DHCP server hands out addresses with the
+ * device as DNS server, every name resolves to the device, and every page is the setup form. Phones
+ * notice that and pop the form up as soon as they join.
+ *
+ * The form posts to SETUP_PATH as application/x-www-form-urlencoded, the device saves the settings
+ * and restarts as a station.
+ */
+use crate::config::{Config, InvalidSetting, MAX_SLEEP_INTERVAL};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use embassy_time::Duration;
+
+pub const DNS_PORT: u16 = 53;
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+// The device itself, clients get addresses after it in the same /24
+pub const ADDRESS: [u8; 4] = [192, 168, 4, 1];
+pub const PREFIX_LENGTH: u8 = 24;
+
+pub const SETUP_PATH: &str = "/setup";
+
+// Answers to DNS queries are only needed while setting up
+const DNS_TTL: u32 = 60;
+const LEASE_TIME: u32 = 60 * 60;
+// Phones, laptops, ... that joined the access point, leases are reused in turn after that
+const MAX_LEASES: usize = 8;
+
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTIONS_OFFSET: usize = 240;
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+
+/*
+ * Settings from the posted form, on top of the current ones. The form never shows the password, so
+ * an empty one keeps it, and only the "open" checkbox removes it.
+ */
+pub fn apply_form(config: &Config, body: &[u8]) -> Result<Config, InvalidSetting> {
+    let mut config = config.clone();
+    let body = core::str::from_utf8(body).map_err(|_| InvalidSetting("form isn't UTF-8"))?;
+    let mut password = String::new();
+    let mut open = false;
+    for field in body.split('&') {
+        let (name, value) = field.split_once('=').unwrap_or((field, ""));
+        let value = url_decode(value).ok_or(InvalidSetting("form isn't valid"))?;
+        match name {
+            "ssid" => config.set_ssid(&value)?,
+            "password" => password = value,
+            // Only sent when checked
+            "open" => open = true,
+            // One per line, more than one makes a slideshow
+            "url" => {
+                let urls = value.lines().map(str::trim).filter(|url| !url.is_empty());
+                config.set_image_urls(urls)?
+            }
+            "minutes" => {
+                let minutes: u64 = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| InvalidSetting("sleep interval isn't a number"))?;
+                // Checked before it becomes a Duration, which would overflow
+                let seconds = minutes
+                    .checked_mul(60)
+                    .filter(|seconds| *seconds <= MAX_SLEEP_INTERVAL.as_secs())
+                    .ok_or(InvalidSetting("sleep interval out of range"))?;
+                config.set_sleep_interval(Duration::from_secs(seconds))?
+            }
+            _ => {}
+        }
+    }
+    if open {
+        config.set_password("")?;
+    } else if !password.is_empty() {
+        config.set_password(&password)?;
+    }
+    if !config.is_provisioned() {
+        return Err(InvalidSetting("SSID and image URL have to be set"));
+    }
+    Ok(config)
+}
+
+// The setup form with the current settings filled in, except the password
+pub fn setup_page(config: &Config, error: Option<&str>) -> String {
+    let mut page = String::new();
+    page.push_str(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width\">\
+         <title>reTerminal setup</title></head><body><h1>reTerminal setup</h1>",
+    );
+    if let Some(error) = error {
+        page.push_str("<p><b>");
+        push_escaped(&mut page, error);
+        page.push_str("</b></p>");
+    }
+    let _ = write!(page, "<form method=\"post\" action=\"{}\">", SETUP_PATH);
+    page.push_str("<p>Wi-Fi network<br><input name=\"ssid\" maxlength=\"32\" value=\"");
+    push_escaped(&mut page, config.ssid());
+    page.push_str("\"></p>");
+    page.push_str("<p>Password, empty to keep the current one<br>");
+    page.push_str("<input name=\"password\" type=\"password\"><br>");
+    page.push_str("<label><input name=\"open\" type=\"checkbox\"> Open network</label></p>");
+    page.push_str("<p>Image URLs, one per line<br>");
+    page.push_str("<textarea name=\"url\" rows=\"4\" cols=\"40\">");
+    for url in config.image_urls() {
+        push_escaped(&mut page, url);
+        page.push('\n');
+    }
+    page.push_str("</textarea></p>");
+    let _ = write!(
+        page,
+        "<p>Minutes between refreshes<br><input name=\"minutes\" type=\"number\" value=\"{}\">\
+         </p><p><button>Save</button></p></form></body></html>",
+        config.sleep_interval().as_secs() / 60
+    );
+    page
+}
+
+pub fn saved_page() -> &'static str {
+    "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>reTerminal setup</title></head>\
+     <body><h1>Saved</h1><p>The display restarts and connects to your network.</p></body></html>"
+}
+
+/*
+ * Answer to a DNS query, with our own address for any A record. Other record types get an empty
+ * answer. None if it isn't a query.
+ */
+pub fn dns_reply(query: &[u8]) -> Option<Vec<u8>> {
+    let header = query.get(..12)?;
+    let is_query = header[2] & 0x80 == 0;
+    let question_count = u16::from_be_bytes([header[4], header[5]]);
+    if !is_query || question_count == 0 {
+        return None;
+    }
+    // Labels up to the root, then type and class
+    let mut end = 12;
+    loop {
+        let length = *query.get(end)? as usize;
+        end += 1;
+        if length == 0 {
+            break;
+        }
+        // No compression in questions from clients
+        if length & 0xC0 != 0 {
+            return None;
+        }
+        end += length;
+    }
+    let question = query.get(12..end + 4)?;
+    let record_type = u16::from_be_bytes([query[end], query[end + 1]]);
+    let is_a = record_type == 1;
+    let mut reply = Vec::with_capacity(12 + question.len() + 16);
+    reply.extend_from_slice(&header[..2]);
+    // Response, recursion desired copied over, recursion available
+    reply.extend_from_slice(&[0x80 | (header[2] & 0x01), 0x80]);
+    reply.extend_from_slice(&[0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+    reply.extend_from_slice(question);
+    if is_a {
+        // Name as a pointer to the question
+        reply.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+        reply.extend_from_slice(&DNS_TTL.to_be_bytes());
+        reply.extend_from_slice(&[0, 4]);
+        reply.extend_from_slice(&ADDRESS);
+    }
+    Some(reply)
+}
+
+// Hands out addresses on the access point, one per client
+#[derive(Default)]
+pub struct DhcpServer {
+    // Hardware addresses, a client gets the address at its index after ADDRESS
+    leases: Vec<[u8; 6]>,
+    // Lease to give away next once they're all taken, so the others keep their addresses
+    next_reused: usize,
+}
+
+impl DhcpServer {
+    // Offer or acknowledgement to broadcast back, None for anything else
+    pub fn reply(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        if request.len() < DHCP_OPTIONS_OFFSET || request[0] != 1 {
+            return None;
+        }
+        if request[236..DHCP_OPTIONS_OFFSET] != DHCP_MAGIC {
+            return None;
+        }
+        let reply_type = match dhcp_option(&request[DHCP_OPTIONS_OFFSET..], 53)? {
+            [DHCP_DISCOVER] => DHCP_OFFER,
+            [DHCP_REQUEST] => DHCP_ACK,
+            _ => return None,
+        };
+        let hardware_address: [u8; 6] = request[28..34].try_into().unwrap();
+        let address = self.lease(hardware_address);
+        let mut reply = alloc::vec![0u8; DHCP_OPTIONS_OFFSET];
+        // Reply, with hardware type, address length, transaction ID, and flags copied over
+        reply[0] = 2;
+        reply[1..3].copy_from_slice(&request[1..3]);
+        reply[4..8].copy_from_slice(&request[4..8]);
+        reply[10..12].copy_from_slice(&request[10..12]);
+        reply[16..20].copy_from_slice(&address);
+        reply[20..24].copy_from_slice(&ADDRESS);
+        reply[28..44].copy_from_slice(&request[28..44]);
+        reply[236..DHCP_OPTIONS_OFFSET].copy_from_slice(&DHCP_MAGIC);
+        let mask = (!0u32 << (32 - PREFIX_LENGTH)).to_be_bytes();
+        let mut option = |code: u8, value: &[u8]| {
+            reply.extend_from_slice(&[code, value.len() as u8]);
+            reply.extend_from_slice(value);
+        };
+        option(53, &[reply_type]);
+        option(54, &ADDRESS);
+        option(51, &LEASE_TIME.to_be_bytes());
+        option(1, &mask);
+        option(3, &ADDRESS);
+        option(6, &ADDRESS);
+        reply.push(255);
+        Some(reply)
+    }
+
+    fn lease(&mut self, hardware_address: [u8; 6]) -> [u8; 4] {
+        let index = match self.leases.iter().position(|x| *x == hardware_address) {
+            Some(index) => index,
+            None if self.leases.len() < MAX_LEASES => {
+                self.leases.push(hardware_address);
+                self.leases.len() - 1
+            }
+            None => {
+                let index = self.next_reused;
+                self.leases[index] = hardware_address;
+                self.next_reused = (index + 1) % MAX_LEASES;
+                index
+            }
+        };
+        let [a, b, c, d] = ADDRESS;
+        [a, b, c, d + 1 + index as u8]
+    }
+}
+
+fn dhcp_option(mut options: &[u8], code: u8) -> Option<&[u8]> {
+    loop {
+        match options {
+            [255, ..] | [] => return None,
+            // Padding
+            [0, rest @ ..] => options = rest,
+            [option, length, rest @ ..] => {
+                let (value, rest) = rest.split_at_checked(*length as usize)?;
+                if *option == code {
+                    return Some(value);
+                }
+                options = rest;
+            }
+            [_] => return None,
+        }
+    }
+}
+
+// application/x-www-form-urlencoded, None for invalid escapes or UTF-8
+fn url_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let [byte, tail @ ..] = rest {
+        match byte {
+            b'+' => {
+                bytes.push(b' ');
+                rest = tail;
+            }
+            b'%' => {
+                let hex = core::str::from_utf8(tail.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(*byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn push_escaped(page: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => page.push_str("&amp;"),
+            '<' => page.push_str("&lt;"),
+            '>' => page.push_str("&gt;"),
+            '"' => page.push_str("&quot;"),
+            '\'' => page.push_str("&#39;"),
+            c => page.push(c),
+        }
+    }
+}
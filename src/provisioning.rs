@@ -0,0 +1,95 @@
+/*
+ * Provisioning a fresh device from a phone over BLE. The device advertises a GATT service with one
+ * writable characteristic per setting, the app writes them one by one and then writes anything to
+ * the apply characteristic. The settings are saved to flash (see config) and BLE is shut down, the
+ * radio is needed for Wi-Fi after that.
+ *
+ * Values are UTF-8 strings, except the sleep interval which is a u32 number of seconds, little
+ * endian. A write with a value the setting doesn't accept is answered with an error, so the app can
+ * tell the user right away instead of after a failed Wi-Fi connection. The password is only taken
+ * over an encrypted link, a write before that is refused with "insufficient encryption", which
+ * makes the phone pair and write it again.
+ */
+use crate::config::{Config, InvalidSetting};
+use embassy_time::Duration;
+
+// Random base, the characteristics count up from the service in the first byte
+pub const SERVICE_UUID: u128 = 0x5e7a0000_6d2c_4f1b_9a43_0c1e8d2b7f10;
+pub const SSID_UUID: u128 = SERVICE_UUID + 1;
+pub const PASSWORD_UUID: u128 = SERVICE_UUID + 2;
+pub const IMAGE_URL_UUID: u128 = SERVICE_UUID + 3;
+pub const SLEEP_INTERVAL_UUID: u128 = SERVICE_UUID + 4;
+pub const APPLY_UUID: u128 = SERVICE_UUID + 5;
+
+// Longest value any characteristic takes, a URL
+pub const MAX_VALUE_LENGTH: usize = 256;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Characteristic {
+    Ssid,
+    Password,
+    ImageUrl,
+    SleepInterval,
+    Apply,
+}
+
+impl Characteristic {
+    pub fn uuid(&self) -> u128 {
+        match self {
+            Self::Ssid => SSID_UUID,
+            Self::Password => PASSWORD_UUID,
+            Self::ImageUrl => IMAGE_URL_UUID,
+            Self::SleepInterval => SLEEP_INTERVAL_UUID,
+            Self::Apply => APPLY_UUID,
+        }
+    }
+
+    // Not to be written in the clear, anyone nearby could listen in
+    pub fn needs_encryption(&self) -> bool {
+        matches!(self, Self::Password)
+    }
+}
+
+// Settings written so far, on top of what the device had before
+pub struct Provisioning {
+    config: Config,
+}
+
+impl Provisioning {
+    pub fn new(config: Config) -> Self {
+        Provisioning { config }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // The settings once the app wrote apply, and they're enough to connect and fetch an image
+    pub fn write(
+        &mut self,
+        characteristic: Characteristic,
+        value: &[u8],
+    ) -> Result<Option<Config>, InvalidSetting> {
+        let text = || core::str::from_utf8(value).map_err(|_| InvalidSetting("not UTF-8"));
+        match characteristic {
+            Characteristic::Ssid => self.config.set_ssid(text()?)?,
+            Characteristic::Password => self.config.set_password(text()?)?,
+            Characteristic::ImageUrl => self.config.set_image_url(text()?.trim())?,
+            Characteristic::SleepInterval => {
+                let seconds = value
+                    .try_into()
+                    .map_err(|_| InvalidSetting("sleep interval has to be 4 bytes"))?;
+                let seconds = u32::from_le_bytes(seconds) as u64;
+                self.config
+                    .set_sleep_interval(Duration::from_secs(seconds))?
+            }
+            Characteristic::Apply => {
+                if !self.config.is_provisioned() {
+                    return Err(InvalidSetting("SSID and image URL have to be set first"));
+                }
+                return Ok(Some(self.config.clone()));
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,119 @@
+/*
+ * QOI ("Quite OK Image") decoding, see https://qoiformat.org/qoi-specification.pdf. Compresses
+ * about as well as PNG for screenshots and renders, but decodes in one pass over the data with a
+ * 64 entry cache of recent colors, no inflate window or row filters. Pixels are streamed, so they
+ * can go straight into the dither pipeline.
+ */
+const MAGIC: &[u8; 4] = b"qoif";
+const HEADER_SIZE: usize = 14;
+
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RUN: u8 = 0xC0;
+const OP_RGB: u8 = 0xFE;
+const OP_RGBA: u8 = 0xFF;
+const OP_MASK: u8 = 0xC0;
+
+// Width and height from the header
+pub fn qoi_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(MAGIC) {
+        return None;
+    }
+    let width = u32::from_be_bytes(data.get(4..8)?.try_into().ok()?);
+    let height = u32::from_be_bytes(data.get(8..12)?.try_into().ok()?);
+    Some((width, height))
+}
+
+// Width, height, and the pixels as [r, g, b, a]. Truncated data just ends the pixels early.
+pub fn decode(data: &[u8]) -> Option<(u32, u32, QoiPixels<'_>)> {
+    let (width, height) = qoi_dimensions(data)?;
+    let pixels = QoiPixels {
+        data: data.get(HEADER_SIZE..)?,
+        remaining: width as u64 * height as u64,
+        cache: [[0; 4]; 64],
+        previous: [0, 0, 0, 255],
+        run: 0,
+    };
+    Some((width, height, pixels))
+}
+
+pub struct QoiPixels<'t> {
+    data: &'t [u8],
+    remaining: u64,
+    cache: [[u8; 4]; 64],
+    previous: [u8; 4],
+    // Repeats of previous still to come
+    run: u8,
+}
+
+impl QoiPixels<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (bytes, rest) = self.data.split_first_chunk::<N>()?;
+        self.data = rest;
+        Some(*bytes)
+    }
+}
+
+fn hash([r, g, b, a]: [u8; 4]) -> usize {
+    (r as usize * 3 + g as usize * 5 + b as usize * 7 + a as usize * 11) % 64
+}
+
+impl Iterator for QoiPixels<'_> {
+    type Item = [u8; 4];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        if self.run > 0 {
+            self.run -= 1;
+        } else {
+            let [op] = self.take::<1>()?;
+            let [r, g, b, a] = self.previous;
+            let pixel = match op {
+                OP_RGB => {
+                    let [r, g, b] = self.take::<3>()?;
+                    [r, g, b, a]
+                }
+                OP_RGBA => self.take::<4>()?,
+                _ => match op & OP_MASK {
+                    OP_DIFF => {
+                        let diff = |shift: u8, value: u8| {
+                            value.wrapping_add((op >> shift) & 0x03).wrapping_sub(2)
+                        };
+                        [diff(4, r), diff(2, g), diff(0, b), a]
+                    }
+                    OP_LUMA => {
+                        let [byte] = self.take::<1>()?;
+                        let green = (op & 0x3F).wrapping_sub(32);
+                        let red = green.wrapping_add(byte >> 4).wrapping_sub(8);
+                        let blue = green.wrapping_add(byte & 0x0F).wrapping_sub(8);
+                        [
+                            r.wrapping_add(red),
+                            g.wrapping_add(green),
+                            b.wrapping_add(blue),
+                            a,
+                        ]
+                    }
+                    // This pixel and op & 0x3F more
+                    OP_RUN => {
+                        self.run = op & 0x3F;
+                        self.previous
+                    }
+                    // OP_INDEX
+                    _ => self.cache[op as usize],
+                },
+            };
+            self.cache[hash(pixel)] = pixel;
+            self.previous = pixel;
+        }
+        self.remaining -= 1;
+        Some(self.previous)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // Fewer if the data is truncated
+        let upper = self.remaining.min(usize::MAX as u64) as usize;
+        (0, Some(upper))
+    }
+}
@@ -0,0 +1,114 @@
+/*
+ * QR codes on the panel, for joining the setup network or whatever a server wants a phone to pick
+ * up. Encoding is done by qrcodegen, this draws the result: every module is a square
+ * of whole pixels in black or white, never dithered, with the quiet zone around it in white so a
+ * phone can find it next to other content.
+ */
+use crate::spectra6::Spectra6Color;
+use alloc::string::String;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
+
+// Modules of white around the code, what the standard asks for
+pub const QUIET_ZONE: u32 = 4;
+
+#[derive(Debug)]
+pub enum QrError<E> {
+    // More than the largest QR code holds
+    TooLong,
+    // Not even one pixel per module fits in the area
+    TooSmall,
+    Draw(E),
+}
+
+/*
+ * The payload as a QR code, as large as fits in area and centered in it. Returns where it went,
+ * quiet zone included. Text is encoded in the most compact mode that fits it, anything that isn't
+ * UTF-8 as bytes.
+ */
+pub fn draw_qr_code<D: DrawTarget<Color = Spectra6Color>>(
+    target: &mut D,
+    payload: &[u8],
+    area: Rectangle,
+) -> Result<Rectangle, QrError<D::Error>> {
+    let buffer_len = Version::MAX.buffer_len();
+    // Too large for the stack
+    let mut temp = alloc::vec![0u8; buffer_len];
+    let mut out = alloc::vec![0u8; buffer_len];
+    let encoded = match core::str::from_utf8(payload) {
+        Ok(text) => QrCode::encode_text(
+            text,
+            &mut temp,
+            &mut out,
+            QrCodeEcc::Medium,
+            Version::MIN,
+            Version::MAX,
+            None,
+            true,
+        ),
+        Err(_) if payload.len() <= buffer_len => {
+            temp[..payload.len()].copy_from_slice(payload);
+            QrCode::encode_binary(
+                &mut temp,
+                payload.len(),
+                &mut out,
+                QrCodeEcc::Medium,
+                Version::MIN,
+                Version::MAX,
+                None,
+                true,
+            )
+        }
+        Err(_) => return Err(QrError::TooLong),
+    };
+    let code = encoded.map_err(|_| QrError::TooLong)?;
+    let modules = code.size() as u32 + QUIET_ZONE * 2;
+    let scale = area.size.width.min(area.size.height) / modules;
+    if scale == 0 {
+        return Err(QrError::TooSmall);
+    }
+    let placed = Rectangle::with_center(area.center(), Size::new_equal(modules * scale));
+    target
+        .fill_solid(&placed, Spectra6Color::White)
+        .map_err(QrError::Draw)?;
+    let origin = placed.top_left + Point::new_equal((QUIET_ZONE * scale) as i32);
+    for y in 0..code.size() {
+        for x in 0..code.size() {
+            if code.get_module(x, y) {
+                let module = Rectangle::new(
+                    origin + Point::new(x, y) * scale as i32,
+                    Size::new_equal(scale),
+                );
+                target
+                    .fill_solid(&module, Spectra6Color::Black)
+                    .map_err(QrError::Draw)?;
+            }
+        }
+    }
+    Ok(placed)
+}
+
+// What phones read as "join this Wi-Fi network", an empty password for an open one
+pub fn wifi_payload(ssid: &str, password: &str) -> String {
+    let mut payload = String::from("WIFI:S:");
+    push_escaped(&mut payload, ssid);
+    if password.is_empty() {
+        payload.push_str(";T:nopass;;");
+    } else {
+        payload.push_str(";T:WPA;P:");
+        push_escaped(&mut payload, password);
+        payload.push_str(";;");
+    }
+    payload
+}
+
+// Backslashes before the characters that separate fields
+fn push_escaped(payload: &mut String, value: &str) {
+    for c in value.chars() {
+        if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+            payload.push('\\');
+        }
+        payload.push(c);
+    }
+}
@@ -0,0 +1,193 @@
+/*
+ * Container for frames the server already dithered and packed, the fastest way to update: no
+ * decoding or dithering on the device, and the payload goes straight from the download buffer to
+ * the panel. All numbers are little endian.
+ *
+ *   magic "S6FR", version (1), packing, flags, reserved (0), width (u16), height (u16), payload
+ *
+ * Packing 0 is two pixels per byte, high nibble first, as the panel takes it. Packing 1 is one
+ * Spectra6Color value per byte, easier to produce, half the size after RLE anyway. Flag bit 0 means
+ * the payload is PackBits coded, see spectra6::rle_encode. Other flag bits and the reserved byte have
+ * to be 0, frames using them are refused rather than shown wrong.
+ */
+use crate::gdep073e01::{HEIGHT, WIDTH};
+use crate::spectra6::{RleDecoder, Spectra6Color, rle_decode};
+use core::iter::Copied;
+use core::slice::Iter;
+
+pub const MAGIC: &[u8; 4] = b"S6FR";
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 12;
+pub const FLAG_RLE: u8 = 0x01;
+
+const FRAME_BYTES: usize = WIDTH * HEIGHT / 2;
+// Short payloads are padded with this
+const WHITE_PAIR: u8 = (Spectra6Color::White as u8) << 4 | Spectra6Color::White as u8;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Packing {
+    TwoPerByte = 0,
+    OnePerByte = 1,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RawFrameError {
+    // No magic, so probably an image
+    NotRawFrame,
+    UnsupportedVersion(u8),
+    UnknownPacking(u8),
+    // Flag bits other than FLAG_RLE, from a newer format this can't show correctly
+    UnknownFlags(u8),
+    // Has to be 0 for now, it may mean something in a later version
+    Reserved(u8),
+    // Frames have to be made for this panel, there's no scaling
+    WrongSize { width: u16, height: u16 },
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RawFrameHeader {
+    pub packing: Packing,
+    pub rle: bool,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl RawFrameHeader {
+    // For this panel
+    pub fn new(packing: Packing, rle: bool) -> Self {
+        RawFrameHeader {
+            packing,
+            rle,
+            width: WIDTH as u16,
+            height: HEIGHT as u16,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut ret = [0u8; HEADER_SIZE];
+        ret[..4].copy_from_slice(MAGIC);
+        ret[4] = VERSION;
+        ret[5] = self.packing as u8;
+        ret[6] = if self.rle { FLAG_RLE } else { 0 };
+        ret[8..10].copy_from_slice(&self.width.to_le_bytes());
+        ret[10..12].copy_from_slice(&self.height.to_le_bytes());
+        ret
+    }
+}
+
+pub struct RawFrame<'t> {
+    pub header: RawFrameHeader,
+    pub payload: &'t [u8],
+}
+
+pub fn is_raw_frame(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn parse(data: &[u8]) -> Result<RawFrame<'_>, RawFrameError> {
+    if !is_raw_frame(data) || data.len() < HEADER_SIZE {
+        return Err(RawFrameError::NotRawFrame);
+    }
+    if data[4] != VERSION {
+        return Err(RawFrameError::UnsupportedVersion(data[4]));
+    }
+    let packing = match data[5] {
+        0 => Packing::TwoPerByte,
+        1 => Packing::OnePerByte,
+        other => return Err(RawFrameError::UnknownPacking(other)),
+    };
+    if data[6] & !FLAG_RLE != 0 {
+        return Err(RawFrameError::UnknownFlags(data[6]));
+    }
+    if data[7] != 0 {
+        return Err(RawFrameError::Reserved(data[7]));
+    }
+    let width = u16::from_le_bytes([data[8], data[9]]);
+    let height = u16::from_le_bytes([data[10], data[11]]);
+    if width as usize != WIDTH || height as usize != HEIGHT {
+        return Err(RawFrameError::WrongSize { width, height });
+    }
+    Ok(RawFrame {
+        header: RawFrameHeader {
+            packing,
+            rle: data[6] == FLAG_RLE,
+            width,
+            height,
+        },
+        payload: &data[HEADER_SIZE..],
+    })
+}
+
+impl<'t> RawFrame<'t> {
+    /*
+     * Exactly one frame of packed bytes for update_frame_raw. A short payload is padded with
+     * white, extra data is ignored, and values that aren't colors come out white.
+     */
+    pub fn bytes(&self) -> RawFrameBytes<'t> {
+        let bytes = self.payload.iter().copied();
+        RawFrameBytes {
+            payload: if self.header.rle {
+                Payload::Rle(rle_decode(bytes))
+            } else {
+                Payload::Plain(bytes)
+            },
+            packing: self.header.packing,
+            remaining: FRAME_BYTES,
+        }
+    }
+}
+
+enum Payload<'t> {
+    Plain(Copied<Iter<'t, u8>>),
+    Rle(RleDecoder<Copied<Iter<'t, u8>>>),
+}
+
+impl Iterator for Payload<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        match self {
+            Payload::Plain(bytes) => bytes.next(),
+            Payload::Rle(bytes) => bytes.next(),
+        }
+    }
+}
+
+pub struct RawFrameBytes<'t> {
+    payload: Payload<'t>,
+    packing: Packing,
+    remaining: usize,
+}
+
+impl Iterator for RawFrameBytes<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(match self.packing {
+            Packing::TwoPerByte => match self.payload.next() {
+                Some(pair) => color(pair >> 4) << 4 | color(pair & 0x0F),
+                None => WHITE_PAIR,
+            },
+            Packing::OnePerByte => {
+                let mut next = || color(self.payload.next().unwrap_or(Spectra6Color::White as u8));
+                let left = next();
+                left << 4 | next()
+            }
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for RawFrameBytes<'_> {}
+
+// The panel's value for a color, white for anything that isn't one
+fn color(value: u8) -> u8 {
+    Spectra6Color::try_from(value).unwrap_or(Spectra6Color::White) as u8
+}
@@ -0,0 +1,366 @@
+/*
+ * Resampling a row-major pixel stream to another size on the fly, e.g. to the panel resolution
+ * straight out of the decoder, so the server doesn't have to send exactly 800x480. Only two source
+ * rows are kept. Resize stretches to the new size, Fit keeps the aspect ratio.
+ *
+ * Bilinear is fine for upscaling and for downscaling up to 2x, beyond that it skips source pixels
+ * and aliases just like nearest neighbour does. BoxDownscale averages every source pixel instead,
+ * for shrinking large photos.
+ */
+use crate::color::{LinearLut, linear_to_srgb};
+use crate::image::scaled_size;
+use alloc::vec::Vec;
+use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+}
+
+// Fractional bits of source positions
+const FRACTION_BITS: u32 = 8;
+const ONE: u32 = 1 << FRACTION_BITS;
+
+pub struct Resize<I> {
+    source: I,
+    filter: Filter,
+    source_width: usize,
+    source_height: usize,
+    width: usize,
+    height: usize,
+    // Source rows rows_read - 2 and rows_read - 1
+    upper: Vec<Rgb888>,
+    lower: Vec<Rgb888>,
+    rows_read: usize,
+    x: usize,
+    y: usize,
+}
+
+impl<I: Iterator<Item = Rgb888>> Resize<I> {
+    /*
+     * source yields source_width x source_height pixels. When it runs out early, the last row it
+     * did yield is repeated (black if there was none).
+     */
+    pub fn new(
+        source: I,
+        source_width: usize,
+        source_height: usize,
+        width: usize,
+        height: usize,
+        filter: Filter,
+    ) -> Self {
+        Resize {
+            source,
+            filter,
+            source_width: source_width.max(1),
+            source_height: source_height.max(1),
+            width,
+            height,
+            upper: alloc::vec![Rgb888::BLACK; source_width.max(1)],
+            lower: alloc::vec![Rgb888::BLACK; source_width.max(1)],
+            rows_read: 0,
+            x: 0,
+            y: 0,
+        }
+    }
+
+    // Skips count target pixels without working them out
+    fn advance(&mut self, count: usize) {
+        let position = self.y * self.width + self.x + count;
+        self.x = position % self.width.max(1);
+        self.y = position / self.width.max(1);
+    }
+
+    // Reads on until row is in lower
+    fn read_until(&mut self, row: usize) {
+        while self.rows_read <= row {
+            core::mem::swap(&mut self.upper, &mut self.lower);
+            self.lower.copy_from_slice(&self.upper);
+            for pixel in self.lower.iter_mut() {
+                match self.source.next() {
+                    Some(color) => *pixel = color,
+                    None => break,
+                }
+            }
+            self.rows_read += 1;
+        }
+    }
+
+    // Rows above and below position (in source rows, fixed point)
+    fn rows(&mut self, position: u32) -> (&[Rgb888], &[Rgb888]) {
+        let row = (position >> FRACTION_BITS) as usize;
+        let next = (row + 1).min(self.source_height - 1);
+        self.read_until(next);
+        if next == row {
+            (&self.lower, &self.lower)
+        } else {
+            (&self.upper, &self.lower)
+        }
+    }
+}
+
+// Source position (fixed point) of the center of target pixel index, clamped to the source
+fn source_position(index: usize, target_len: usize, source_len: usize) -> u32 {
+    let center = ((2 * index + 1) * source_len) as u64 * ONE as u64 / (2 * target_len) as u64;
+    let position = center.saturating_sub(ONE as u64 / 2);
+    position.min(((source_len - 1) as u64) << FRACTION_BITS) as u32
+}
+
+fn lerp(a: Rgb888, b: Rgb888, fraction: u32) -> Rgb888 {
+    let mix =
+        |a: u8, b: u8| ((a as u32 * (ONE - fraction) + b as u32 * fraction) >> FRACTION_BITS) as u8;
+    Rgb888::new(mix(a.r(), b.r()), mix(a.g(), b.g()), mix(a.b(), b.b()))
+}
+
+impl<I: Iterator<Item = Rgb888>> Iterator for Resize<I> {
+    type Item = Rgb888;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.y >= self.height || self.width == 0 {
+            return None;
+        }
+        let x = source_position(self.x, self.width, self.source_width);
+        let y = source_position(self.y, self.height, self.source_height);
+        let color = match self.filter {
+            Filter::Nearest => {
+                // Round to the closest source pixel
+                let row = ((y + ONE / 2) >> FRACTION_BITS) as usize;
+                self.read_until(row.min(self.source_height - 1));
+                self.lower[(((x + ONE / 2) >> FRACTION_BITS) as usize).min(self.source_width - 1)]
+            }
+            Filter::Bilinear => {
+                let column = (x >> FRACTION_BITS) as usize;
+                let next_column = (column + 1).min(self.source_width - 1);
+                let (x_fraction, y_fraction) = (x & (ONE - 1), y & (ONE - 1));
+                let (upper, lower) = self.rows(y);
+                lerp(
+                    lerp(upper[column], upper[next_column], x_fraction),
+                    lerp(lower[column], lower[next_column], x_fraction),
+                    y_fraction,
+                )
+            }
+        };
+        self.x += 1;
+        if self.x >= self.width {
+            self.x = 0;
+            self.y += 1;
+        }
+        Some(color)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.height.saturating_sub(self.y) * self.width).saturating_sub(self.x);
+        (remaining, Some(remaining))
+    }
+}
+
+impl<I: Iterator<Item = Rgb888>> ExactSizeIterator for Resize<I> {}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FitMode {
+    // Scale to fill the target, cutting off what sticks out on either side
+    Crop,
+    // Scale to fit inside the target, with bars of this color around it
+    Letterbox(Rgb888),
+}
+
+/*
+ * Scales a source_width x source_height stream onto width x height keeping the aspect ratio, and
+ * centers it. Yields exactly width x height pixels whatever the source size.
+ */
+pub struct Fit<I> {
+    resize: Resize<I>,
+    mode: FitMode,
+    width: usize,
+    height: usize,
+    // Scaled size, and where the target starts in it (Crop) or it starts in the target (Letterbox)
+    scaled_width: usize,
+    scaled_height: usize,
+    offset_x: usize,
+    offset_y: usize,
+    index: usize,
+}
+
+impl<I: Iterator<Item = Rgb888>> Fit<I> {
+    pub fn new(
+        source: I,
+        source_width: usize,
+        source_height: usize,
+        width: usize,
+        height: usize,
+        mode: FitMode,
+        filter: Filter,
+    ) -> Self {
+        let (source_width, source_height) = (source_width.max(1), source_height.max(1));
+        // Same scaling as RgbImage::letterboxed_pixels, so streamed and decoded images line up
+        let (scaled_width, scaled_height) = scaled_size(
+            (source_width, source_height),
+            (width, height),
+            mode == FitMode::Crop,
+        );
+        let (offset_x, offset_y) = (
+            scaled_width.abs_diff(width) / 2,
+            scaled_height.abs_diff(height) / 2,
+        );
+        let mut resize = Resize::new(
+            source,
+            source_width,
+            source_height,
+            scaled_width,
+            scaled_height,
+            filter,
+        );
+        if mode == FitMode::Crop {
+            resize.advance(offset_y * scaled_width + offset_x);
+        }
+        Fit {
+            resize,
+            mode,
+            width,
+            height,
+            scaled_width,
+            scaled_height,
+            offset_x,
+            offset_y,
+            index: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Rgb888>> Iterator for Fit<I> {
+    type Item = Rgb888;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.width * self.height {
+            return None;
+        }
+        let (x, y) = (self.index % self.width, self.index / self.width);
+        self.index += 1;
+        match self.mode {
+            FitMode::Crop => {
+                let color = self.resize.next();
+                if x == self.width - 1 {
+                    // On to the same column in the next scaled row
+                    self.resize.advance(self.scaled_width - self.width);
+                }
+                color
+            }
+            FitMode::Letterbox(fill) => {
+                let inside = (self.offset_x..self.offset_x + self.scaled_width).contains(&x)
+                    && (self.offset_y..self.offset_y + self.scaled_height).contains(&y);
+                if inside {
+                    self.resize.next()
+                } else {
+                    Some(fill)
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.width * self.height - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<I: Iterator<Item = Rgb888>> ExactSizeIterator for Fit<I> {}
+
+/*
+ * Shrinks a source_width x source_height stream to width x height by averaging all source pixels
+ * that fall in each target pixel, in linear light so fine detail like foliage doesn't come out
+ * darker than it is. Keeps a row of sums, not source rows. Only for shrinking, width and height
+ * have to be at most the source's.
+ */
+pub struct BoxDownscale<I> {
+    source: I,
+    source_width: usize,
+    source_height: usize,
+    width: usize,
+    height: usize,
+    lut: LinearLut,
+    // Linear light sums and pixel counts of the target row
+    sums: Vec<[f32; 3]>,
+    counts: Vec<u32>,
+    row: Vec<Rgb888>,
+    source_y: usize,
+    x: usize,
+    y: usize,
+}
+
+impl<I: Iterator<Item = Rgb888>> BoxDownscale<I> {
+    pub fn new(
+        source: I,
+        source_width: usize,
+        source_height: usize,
+        width: usize,
+        height: usize,
+    ) -> Self {
+        debug_assert!(width <= source_width && height <= source_height);
+        BoxDownscale {
+            source,
+            source_width,
+            source_height,
+            width,
+            height,
+            lut: LinearLut::new(),
+            sums: alloc::vec![[0.0; 3]; width],
+            counts: alloc::vec![0; width],
+            row: Vec::with_capacity(width),
+            source_y: 0,
+            x: 0,
+            y: 0,
+        }
+    }
+
+    // Reads the source rows that make up target row y
+    fn next_row(&mut self) {
+        self.sums.fill([0.0; 3]);
+        self.counts.fill(0);
+        while self.source_y < self.source_height
+            && self.source_y * self.height / self.source_height <= self.y
+        {
+            for (x, color) in self.source.by_ref().take(self.source_width).enumerate() {
+                let column = x * self.width / self.source_width;
+                let linear = self.lut.rgb([color.r(), color.g(), color.b()]);
+                for (sum, value) in self.sums[column].iter_mut().zip(linear) {
+                    *sum += value;
+                }
+                self.counts[column] += 1;
+            }
+            self.source_y += 1;
+        }
+        self.row.clear();
+        for (sum, count) in self.sums.iter().zip(&self.counts) {
+            let [r, g, b] = sum.map(|sum| linear_to_srgb(sum / (*count).max(1) as f32));
+            self.row.push(Rgb888::new(r, g, b));
+        }
+    }
+}
+
+impl<I: Iterator<Item = Rgb888>> Iterator for BoxDownscale<I> {
+    type Item = Rgb888;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.y >= self.height || self.width == 0 {
+            return None;
+        }
+        if self.x == 0 {
+            self.next_row();
+        }
+        let color = self.row[self.x];
+        self.x += 1;
+        if self.x >= self.width {
+            self.x = 0;
+            self.y += 1;
+        }
+        Some(color)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.height.saturating_sub(self.y) * self.width).saturating_sub(self.x);
+        (remaining, Some(remaining))
+    }
+}
+
+impl<I: Iterator<Item = Rgb888>> ExactSizeIterator for BoxDownscale<I> {}
@@ -0,0 +1,98 @@
+/*
+ * When and how often to retry fetching the image. Access points that just woke up, DNS servers that
+ * time out once and servers answering 503 while they render are all common, and a second try a bit
+ * later usually works. A 404 or a bad certificate won't get better, so those give up right away and
+ * the device sleeps until the next wake-up.
+ *
+ * Delays double with each attempt, with jitter so a room full of displays waking up together don't
+ * keep hitting the server at the same moment.
+ */
+use core::fmt::{Display, Formatter};
+use embassy_time::Duration;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FetchFailure {
+    // Resolving the host name
+    Dns,
+    // Setting up the TCP connection (or TLS on top of it)
+    Connect,
+    // The connection failed halfway through the request or response
+    Transfer,
+    // The server answered, but not with 2xx
+    Status(u16),
+    // Bad URL, certificate that doesn't verify, response that isn't HTTP, ...
+    Permanent,
+}
+
+impl FetchFailure {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Dns | Self::Connect | Self::Transfer => true,
+            // Timeout, too many requests, and server errors like 503 while rendering
+            Self::Status(status) => matches!(status, 408 | 429 | 500..=599),
+            Self::Permanent => false,
+        }
+    }
+}
+
+impl Display for FetchFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Dns => write!(f, "DNS lookup failed"),
+            Self::Connect => write!(f, "couldn't connect"),
+            Self::Transfer => write!(f, "connection failed during transfer"),
+            Self::Status(status) => write!(f, "HTTP status {}", status),
+            Self::Permanent => write!(f, "request can't succeed"),
+        }
+    }
+}
+
+impl core::error::Error for FetchFailure {}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    // Including the first one, at least 1
+    pub attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub const fn new(attempts: u32, initial_delay: Duration, max_delay: Duration) -> Self {
+        RetryPolicy {
+            attempts,
+            initial_delay,
+            max_delay,
+        }
+    }
+
+    /*
+     * How long to wait before trying again after attempt (0 for the first) failed, or None to give
+     * up. random is any random number, the delay is somewhere between half and all of the
+     * exponential one. A DNS failure at least waits the initial delay twice, resolvers tend to need
+     * a moment after the link comes up.
+     */
+    pub fn backoff(&self, attempt: u32, failure: FetchFailure, random: u32) -> Option<Duration> {
+        if !failure.is_retryable() || attempt + 1 >= self.attempts {
+            return None;
+        }
+        let attempt = match failure {
+            FetchFailure::Dns => attempt.max(1),
+            _ => attempt,
+        };
+        let delay = self
+            .initial_delay
+            .as_millis()
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay.as_millis());
+        let half = delay / 2;
+        let jitter = random as u64 % (half + 1);
+        Some(Duration::from_millis(delay - half + jitter))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(1), Duration::from_secs(30))
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ * State kept in RTC fast memory across deep sleep, for things that change every wake-up and would
+ * wear out flash: what was shown last, how many fetches failed in a row, where a slideshow is and
+ * why the device went to sleep. RTC memory survives deep sleep but not a power cut or reset, and
+ * reads as garbage after one, hence the magic and CRC. Without a valid copy the state starts over.
+ *
+ *   magic "RTS1", flags, next wake, fetch failures (u16), last image hash (u32),
+ *   slideshow index (u16), reserved (0, 0), CRC-32 of the preceding bytes (u32)
+ *
+ * Flag bit 0 means the last image hash is set. All numbers are little endian.
+ */
+use crate::framepush::crc32;
+
+pub const MAGIC: &[u8; 4] = b"RTS1";
+pub const STORED_SIZE: usize = 20;
+
+const FLAG_IMAGE_HASH: u8 = 0x01;
+
+// Why the device went to sleep, so it knows what the timer wake-up is for
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum NextWake {
+    // The regular refresh
+    #[default]
+    Refresh = 0,
+    // Fetching failed, trying again
+    Retry = 1,
+    // Sleeping longer until the battery is charged
+    LowBattery = 2,
+    // Waiting for settings
+    Unprovisioned = 3,
+}
+
+impl NextWake {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Refresh),
+            1 => Some(Self::Retry),
+            2 => Some(Self::LowBattery),
+            3 => Some(Self::Unprovisioned),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RtcState {
+    // CRC-32 of the last downloaded image or frame, None once something else was drawn over it
+    pub last_image_hash: Option<u32>,
+    // Failed fetches in a row, 0 after a successful one
+    pub fetch_failures: u16,
+    pub slideshow_index: u16,
+    pub next_wake: NextWake,
+}
+
+impl RtcState {
+    // None after a power cut or reset, or if the layout changed
+    pub fn load(stored: &[u8; STORED_SIZE]) -> Option<Self> {
+        let (data, crc) = stored.split_at(STORED_SIZE - 4);
+        if &data[..4] != MAGIC || crc32(data).to_le_bytes() != crc {
+            return None;
+        }
+        let flags = data[4];
+        let hash = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+        Some(RtcState {
+            last_image_hash: (flags & FLAG_IMAGE_HASH != 0).then_some(hash),
+            fetch_failures: u16::from_le_bytes([data[6], data[7]]),
+            slideshow_index: u16::from_le_bytes([data[12], data[13]]),
+            next_wake: NextWake::from_u8(data[5])?,
+        })
+    }
+
+    // Something else is on the panel, so the next image is shown even if it didn't change
+    pub fn forget_image(&mut self) {
+        self.last_image_hash = None;
+    }
+
+    // Moves the slideshow by step pages, wrapping around at count
+    pub fn step_slideshow(&mut self, step: isize, count: usize) {
+        if count == 0 {
+            return;
+        }
+        let index = (self.slideshow_index as isize + step).rem_euclid(count as isize);
+        self.slideshow_index = index as u16;
+    }
+
+    pub fn store(&self) -> [u8; STORED_SIZE] {
+        let mut stored = [0u8; STORED_SIZE];
+        stored[..4].copy_from_slice(MAGIC);
+        stored[4] = if self.last_image_hash.is_some() {
+            FLAG_IMAGE_HASH
+        } else {
+            0
+        };
+        stored[5] = self.next_wake as u8;
+        stored[6..8].copy_from_slice(&self.fetch_failures.to_le_bytes());
+        stored[8..12].copy_from_slice(&self.last_image_hash.unwrap_or(0).to_le_bytes());
+        stored[12..14].copy_from_slice(&self.slideshow_index.to_le_bytes());
+        let crc = crc32(&stored[..STORED_SIZE - 4]);
+        stored[STORED_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+        stored
+    }
+}
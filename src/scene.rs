@@ -0,0 +1,141 @@
+/*
+ * Dashboard scenes: a set of widgets that each refetch their data at their own pace, e.g. a clock
+ * every minute, weather every hour and a calendar once a day. Every wake-up the scene works out
+ * which widgets are due, and when to wake up next. Widgets that are nearly due are pulled forward
+ * into the current wake-up, so the radio is turned on as few times as possible. All times are in
+ * seconds, on any clock as long as it's the same one throughout.
+ *
+ * Refreshing is all-or-nothing: due widgets are fetched and rendered off-screen first, and the
+ * panel is only refreshed when all of them worked, so a failed fetch doesn't leave a half-broken
+ * dashboard. Widgets marked optional don't hold back the refresh.
+ */
+use alloc::vec::Vec;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum UpdateKind {
+    // Only the widget's area is redrawn, on panels that support it
+    Partial,
+    Full,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Widget<'a> {
+    pub name: &'a str,
+    pub refetch_secs: u64,
+    pub update: UpdateKind,
+    // Clocks and the like don't need to fetch anything
+    pub needs_network: bool,
+    // Shown with its previous content if it fails, rather than keeping the whole previous frame
+    pub optional: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Scene<'a> {
+    pub widgets: &'a [Widget<'a>],
+    // Widgets due within this many seconds are fetched now rather than on a wake-up of their own
+    pub coalesce_secs: u64,
+}
+
+// What to do with the panel once the due widgets have been rendered
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Commit {
+    Refresh(UpdateKind),
+    // A required widget failed (or all of them did), the panel keeps showing the previous frame
+    KeepPrevious,
+    // Nothing was due
+    Nothing,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WakePlan {
+    // Indices into Scene::widgets to refetch now
+    pub due: Vec<usize>,
+    // None if nothing is due
+    pub update: Option<UpdateKind>,
+    pub needs_network: bool,
+    pub next_wake: u64,
+}
+
+impl WakePlan {
+    pub fn seconds_until_next(&self, now: u64) -> u64 {
+        self.next_wake.saturating_sub(now)
+    }
+
+    /*
+     * succeeded[i] is whether widget due[i] was fetched and rendered. Only record the fetch time
+     * of the ones that succeeded, so failed ones are due again on the next wake-up.
+     */
+    pub fn commit(&self, scene: &Scene<'_>, succeeded: &[bool]) -> Commit {
+        if self.due.is_empty() {
+            return Commit::Nothing;
+        }
+        let succeeded = |position: usize| succeeded.get(position).copied().unwrap_or(false);
+        let required_failed = self
+            .due
+            .iter()
+            .enumerate()
+            .any(|(position, &index)| !succeeded(position) && !scene.widgets[index].optional);
+        let update = self
+            .due
+            .iter()
+            .enumerate()
+            .filter(|&(position, _)| succeeded(position))
+            .map(|(_, &index)| scene.widgets[index].update)
+            .max();
+        match update {
+            Some(update) if !required_failed => Commit::Refresh(update),
+            _ => Commit::KeepPrevious,
+        }
+    }
+}
+
+impl Scene<'_> {
+    // Never fetched (None), e.g. after a power loss, means due right away
+    fn due_at(&self, index: usize, last_fetches: &[Option<u64>]) -> u64 {
+        let last_fetch = last_fetches.get(index).copied().flatten();
+        last_fetch.map_or(0, |last| {
+            last.saturating_add(self.widgets[index].refetch_secs)
+        })
+    }
+
+    /*
+     * last_fetches[i] is when widget i was last fetched. Nearly due widgets only tag along when
+     * something is due anyway, and network widgets only when the radio is going to be on.
+     */
+    pub fn plan(&self, now: u64, last_fetches: &[Option<u64>]) -> WakePlan {
+        let indices = 0..self.widgets.len();
+        let is_due = |index: usize| self.due_at(index, last_fetches) <= now;
+        let any_due = indices.clone().any(is_due);
+        let needs_network = indices
+            .clone()
+            .any(|index| is_due(index) && self.widgets[index].needs_network);
+        let coalesce_until = now.saturating_add(self.coalesce_secs);
+        let due: Vec<usize> = indices
+            .clone()
+            .filter(|&index| {
+                is_due(index)
+                    || (any_due
+                        && self.due_at(index, last_fetches) <= coalesce_until
+                        && (needs_network || !self.widgets[index].needs_network))
+            })
+            .collect();
+        let update = due.iter().map(|&index| self.widgets[index].update).max();
+        // Assumes the due widgets do get fetched now
+        let next_wake = indices
+            .map(|index| {
+                if due.contains(&index) {
+                    now.saturating_add(self.widgets[index].refetch_secs)
+                } else {
+                    self.due_at(index, last_fetches)
+                }
+            })
+            .min()
+            .unwrap_or(u64::MAX);
+        WakePlan {
+            due,
+            update,
+            needs_network,
+            next_wake,
+        }
+    }
+}
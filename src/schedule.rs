@@ -0,0 +1,51 @@
+/*
+ * When to wake up next, if the server has an opinion. A calendar only changes daily, a dashboard
+ * every few minutes, and the server knows which one it sent. It says so with X-Next-Refresh-Seconds,
+ * or with the Cache-Control max-age it would send to a browser anyway. Without either the device
+ * keeps its configured interval.
+ *
+ * Whatever the server says is kept within the limits of the sleep interval setting, so a typo on
+ * the server can't drain the battery or leave the device asleep for weeks.
+ */
+use crate::config::{MAX_SLEEP_INTERVAL, MIN_SLEEP_INTERVAL};
+use embassy_time::Duration;
+
+pub const NEXT_REFRESH_HEADER: &str = "X-Next-Refresh-Seconds";
+
+/*
+ * Next wake-up from the response headers, as (name, value). X-Next-Refresh-Seconds wins over
+ * Cache-Control, which is often set by a proxy or framework and not meant for the device.
+ */
+pub fn next_refresh<'t>(
+    headers: impl IntoIterator<Item = (&'t str, &'t [u8])>,
+) -> Option<Duration> {
+    let mut next_refresh = None;
+    let mut max_age = None;
+    for (name, value) in headers {
+        let Ok(value) = core::str::from_utf8(value) else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case(NEXT_REFRESH_HEADER) {
+            next_refresh = value.trim().parse::<u64>().ok();
+        } else if name.eq_ignore_ascii_case("cache-control") {
+            max_age = parse_max_age(value).or(max_age);
+        }
+    }
+    // Clamped in seconds, a huge value would overflow converting to ticks
+    next_refresh.or(max_age).map(|seconds| {
+        Duration::from_secs(
+            seconds.clamp(MIN_SLEEP_INTERVAL.as_secs(), MAX_SLEEP_INTERVAL.as_secs()),
+        )
+    })
+}
+
+// Seconds in max-age from a Cache-Control value like "public, max-age=300"
+pub fn parse_max_age(value: &str) -> Option<u64> {
+    value.split(',').find_map(|directive| {
+        let (name, seconds) = directive.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("max-age") {
+            return None;
+        }
+        seconds.trim().trim_matches('"').parse().ok()
+    })
+}
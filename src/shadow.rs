@@ -0,0 +1,90 @@
+use alloc::vec::Vec;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// FNV-1a, cheap enough to run inline while streaming a frame out over SPI
+pub fn frame_hash(data: impl IntoIterator<Item = u8>) -> u64 {
+    data.into_iter().fold(FNV_OFFSET_BASIS, fnv1a_step)
+}
+
+fn fnv1a_step(hash: u64, byte: u8) -> u64 {
+    (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+}
+
+// What is remembered about the last frame sent to the panel
+pub enum ShadowFrame {
+    // Only a hash, small enough to keep around in RTC memory during deep sleep
+    Hash(Option<u64>),
+    // Byte-for-byte copy of the last packed frame
+    Copy(Vec<u8>),
+}
+
+impl ShadowFrame {
+    // Make sure the next frame is seen as changed, e.g. after a failed transmission
+    pub fn invalidate(&mut self) {
+        match self {
+            ShadowFrame::Hash(hash) => *hash = None,
+            ShadowFrame::Copy(frame) => frame.clear(),
+        }
+    }
+
+    // Wraps a packed frame, comparing and recording it while it's passed through
+    pub fn track<I: Iterator<Item = u8>>(&mut self, source: I) -> ShadowTracker<'_, I> {
+        ShadowTracker {
+            shadow: self,
+            source,
+            hash: FNV_OFFSET_BASIS,
+            index: 0,
+            changed: false,
+        }
+    }
+}
+
+pub struct ShadowTracker<'a, I> {
+    shadow: &'a mut ShadowFrame,
+    source: I,
+    hash: u64,
+    index: usize,
+    changed: bool,
+}
+
+impl<I> ShadowTracker<'_, I> {
+    // Call once the whole frame went through, returns if it differs from the previous one
+    pub fn finish(self) -> bool {
+        match self.shadow {
+            ShadowFrame::Hash(hash) => {
+                let changed = *hash != Some(self.hash);
+                *hash = Some(self.hash);
+                changed
+            }
+            ShadowFrame::Copy(frame) => {
+                let changed = self.changed || frame.len() != self.index;
+                frame.truncate(self.index);
+                changed
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = u8>> Iterator for ShadowTracker<'_, I> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.source.next()?;
+        match self.shadow {
+            ShadowFrame::Hash(_) => self.hash = fnv1a_step(self.hash, byte),
+            ShadowFrame::Copy(frame) => {
+                if let Some(old) = frame.get_mut(self.index) {
+                    self.changed |= *old != byte;
+                    *old = byte;
+                } else {
+                    self.changed = true;
+                    frame.push(byte);
+                }
+            }
+        }
+        self.index += 1;
+        Some(byte)
+    }
+}
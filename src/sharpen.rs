@@ -0,0 +1,173 @@
+/*
+ * Unsharp masking on a row-major pixel stream, before dithering. Dithering smears fine detail over
+ * a few pixels, so text in screenshots ends up hard to read. Adding back a bit of the difference
+ * between the image and a blurred copy of it makes edges stand out enough to survive that.
+ *
+ * The blur is a binomial kernel over 3 or 5 rows, so only that many source rows are kept. Edges
+ * repeat the outermost pixels.
+ */
+use alloc::vec::Vec;
+use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kernel {
+    // 1 2 1, for detail of about a pixel, like text
+    Three,
+    // 1 4 6 4 1, for slightly coarser detail
+    Five,
+}
+
+impl Kernel {
+    fn weights(&self) -> &'static [u32] {
+        match self {
+            Kernel::Three => &[1, 2, 1],
+            Kernel::Five => &[1, 4, 6, 4, 1],
+        }
+    }
+}
+
+pub struct Unsharp<I> {
+    source: I,
+    width: usize,
+    kernel: Kernel,
+    amount: i32,
+    // The last kernel-size rows read, by row number modulo the kernel size
+    rows: Vec<Vec<Rgb888>>,
+    rows_read: usize,
+    // Pixels the source yielded, the output stops at the same count
+    pixels_read: usize,
+    // Output row y, and the next pixel of it to yield
+    out: Vec<Rgb888>,
+    y: usize,
+    x: usize,
+}
+
+impl<I: Iterator<Item = Rgb888>> Unsharp<I> {
+    // amount is how much of the detail is added back in percent, 50 to 100 is mild
+    pub fn new(source: I, width: usize, amount: u16, kernel: Kernel) -> Self {
+        let size = kernel.weights().len();
+        Unsharp {
+            source,
+            width,
+            kernel,
+            amount: amount as i32,
+            rows: (0..size)
+                .map(|_| alloc::vec![Rgb888::BLACK; width])
+                .collect(),
+            rows_read: 0,
+            pixels_read: 0,
+            out: Vec::with_capacity(width),
+            y: 0,
+            x: 0,
+        }
+    }
+
+    // Reads on until row is in (or the source is done)
+    fn read_until(&mut self, row: usize) {
+        while self.rows_read <= row && self.pixels_read == self.rows_read * self.width {
+            let size = self.rows.len();
+            let mut count = 0;
+            for (pixel, color) in self.rows[self.rows_read % size]
+                .iter_mut()
+                .zip(self.source.by_ref())
+            {
+                *pixel = color;
+                count += 1;
+            }
+            if count == 0 {
+                return;
+            }
+            // Partial last row, repeat the last pixel it did have
+            let row = &mut self.rows[self.rows_read % size];
+            let last = row[count - 1];
+            row[count..].fill(last);
+            self.pixels_read += count;
+            self.rows_read += 1;
+        }
+    }
+
+    // Source row clamped to the rows there are
+    fn row(&self, row: isize) -> &[Rgb888] {
+        let row = row.clamp(0, self.rows_read as isize - 1) as usize;
+        &self.rows[row % self.rows.len()]
+    }
+
+    fn sharpen_row(&mut self) {
+        let weights = self.kernel.weights();
+        let radius = weights.len() / 2;
+        self.read_until(self.y + radius);
+        // Vertical pass, then horizontal on those sums
+        let vertical: Vec<[u32; 3]> = (0..self.width)
+            .map(|x| {
+                let mut sum = [0u32; 3];
+                for (offset, weight) in weights.iter().enumerate() {
+                    let color = self.row(self.y as isize + offset as isize - radius as isize)[x];
+                    for (sum, value) in sum.iter_mut().zip([color.r(), color.g(), color.b()]) {
+                        *sum += value as u32 * weight;
+                    }
+                }
+                sum
+            })
+            .collect();
+        let total: u32 = weights.iter().sum::<u32>().pow(2);
+        let current = self.row(self.y as isize).to_vec();
+        self.out.clear();
+        for (x, color) in current.into_iter().enumerate() {
+            let mut blurred = [0u32; 3];
+            for (offset, weight) in weights.iter().enumerate() {
+                let column = (x + offset).saturating_sub(radius).min(self.width - 1);
+                for (blurred, value) in blurred.iter_mut().zip(vertical[column]) {
+                    *blurred += value * weight;
+                }
+            }
+            let sharpen = |value: u8, blurred: u32| {
+                let blurred = ((blurred + total / 2) / total) as i32;
+                let value = value as i32;
+                (value + (value - blurred) * self.amount / 100).clamp(0, 255) as u8
+            };
+            self.out.push(Rgb888::new(
+                sharpen(color.r(), blurred[0]),
+                sharpen(color.g(), blurred[1]),
+                sharpen(color.b(), blurred[2]),
+            ));
+        }
+    }
+}
+
+impl<I: Iterator<Item = Rgb888>> Iterator for Unsharp<I> {
+    type Item = Rgb888;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.width == 0 {
+            return None;
+        }
+        if self.x == 0 {
+            self.read_until(self.y);
+            if self.y >= self.rows_read {
+                return None;
+            }
+            self.sharpen_row();
+        }
+        if self.y * self.width + self.x >= self.pixels_read {
+            return None;
+        }
+        let color = self.out[self.x];
+        self.x += 1;
+        if self.x >= self.width {
+            self.x = 0;
+            self.y += 1;
+        }
+        Some(color)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let buffered = self.pixels_read - (self.y * self.width + self.x).min(self.pixels_read);
+        let (lower, upper) = self.source.size_hint();
+        (
+            lower.saturating_add(buffered),
+            upper.and_then(|upper| upper.checked_add(buffered)),
+        )
+    }
+}
+
+impl<I: ExactSizeIterator<Item = Rgb888>> ExactSizeIterator for Unsharp<I> {}
@@ -1,7 +1,15 @@
+use crate::dither::{DitherPalette, ForwardErrorDiffusion, ForwardErrorDiffusionMethod};
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+use arrayvec::ArrayVec;
+use core::convert::Infallible;
+use embedded_graphics::Pixel;
+use embedded_graphics::draw_target::DrawTarget;
+use embedded_graphics::geometry::{OriginDimensions, Size};
 use embedded_graphics::pixelcolor::raw::RawU4;
 use embedded_graphics::pixelcolor::{PixelColor, Rgb888, RgbColor};
 
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Spectra6Color {
     Black = 0,
     White = 1,
@@ -16,8 +24,26 @@ impl PixelColor for Spectra6Color {
     type Raw = RawU4;
 }
 
-impl From<Rgb888> for Spectra6Color {
-    fn from(value: Rgb888) -> Self {
+// The value is handed back if it isn't a color, 4 and anything past 7 aren't
+impl TryFrom<u8> for Spectra6Color {
+    type Error = u8;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Spectra6Color::Black),
+            1 => Ok(Spectra6Color::White),
+            2 => Ok(Spectra6Color::Yellow),
+            3 => Ok(Spectra6Color::Red),
+            5 => Ok(Spectra6Color::Blue),
+            6 => Ok(Spectra6Color::Green),
+            7 => Ok(Spectra6Color::Clean),
+            _ => Err(value),
+        }
+    }
+}
+
+impl Spectra6Color {
+    // Quick decision tree to the closest color, no matter how far off
+    pub fn from_rgb_threshold(value: Rgb888) -> Self {
         if value.r() < 105 {
             // Distance 145
             if value.b() < 109 {
@@ -47,6 +73,64 @@ impl From<Rgb888> for Spectra6Color {
     }
 }
 
+// Measured color, Clean shows as white
+impl From<Spectra6Color> for Rgb888 {
+    fn from(value: Spectra6Color) -> Self {
+        let value = match value {
+            Spectra6Color::Clean => Spectra6Color::White,
+            value => value,
+        };
+        SPECTRA_6_PALETTE
+            .iter()
+            .find(|(_, color)| *color == value)
+            .map(|(rgb, _)| *rgb)
+            .unwrap_or(Rgb888::WHITE)
+    }
+}
+
+// Squared RGB distance from a palette color (measured or saturated) that still counts as that color
+pub const MAX_MATCH_DISTANCE_SQUARED: u32 = 48 * 48;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NoCloseMatch {
+    pub nearest: Spectra6Color,
+    pub distance_squared: u32,
+}
+
+/*
+ * Nearest color, for RGB that is meant to be a panel color, e.g. a frame rendered with the
+ * palette. Fails for anything further off, use from_rgb_threshold or dithering for photos.
+ */
+impl TryFrom<Rgb888> for Spectra6Color {
+    type Error = NoCloseMatch;
+    fn try_from(value: Rgb888) -> Result<Self, Self::Error> {
+        let distance_squared = |rgb: &Rgb888| {
+            [
+                (rgb.r(), value.r()),
+                (rgb.g(), value.g()),
+                (rgb.b(), value.b()),
+            ]
+            .iter()
+            .map(|&(a, b)| (a.abs_diff(b) as u32).pow(2))
+            .sum::<u32>()
+        };
+        let (distance_squared, nearest) = SPECTRA_6_PALETTE
+            .iter()
+            .chain(SPECTRA_6_PALETTE_SATURATED)
+            .map(|(rgb, color)| (distance_squared(rgb), *color))
+            .min_by_key(|&(distance_squared, _)| distance_squared)
+            .unwrap_or((u32::MAX, Spectra6Color::White));
+        if distance_squared <= MAX_MATCH_DISTANCE_SQUARED {
+            Ok(nearest)
+        } else {
+            Err(NoCloseMatch {
+                nearest,
+                distance_squared,
+            })
+        }
+    }
+}
+
 pub struct SpectraPacker<T>(pub T);
 
 impl<T> Iterator for SpectraPacker<T>
@@ -59,11 +143,360 @@ where
         let right = self.0.next().unwrap_or(Spectra6Color::White);
         Some((left as u8) << 4 | (right as u8))
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        packed_size_hint(self.0.size_hint())
+    }
+}
+
+impl<T> ExactSizeIterator for SpectraPacker<T> where T: ExactSizeIterator<Item = Spectra6Color> {}
+
+// Two pixels per byte, rounded up
+fn packed_size_hint((lower, upper): (usize, Option<usize>)) -> (usize, Option<usize>) {
+    (lower.div_ceil(2), upper.map(|upper| upper.div_ceil(2)))
+}
+
+/*
+ * Dithers and packs in one go, the same bytes as SpectraPacker over a ForwardErrorDiffusion, but
+ * both pixels of a byte are dithered in the same loop iteration, which the compiler does better
+ * with than the two nested iterators.
+ */
+pub struct DitherPack<PALETTE, METHOD, I>
+where
+    PALETTE: DitherPalette<TargetColor = Spectra6Color>,
+    METHOD: ForwardErrorDiffusionMethod,
+    I: Iterator<Item = PALETTE::SourceColor>,
+{
+    dither: ForwardErrorDiffusion<PALETTE, METHOD, I>,
+}
+
+impl<PALETTE, METHOD, I> DitherPack<PALETTE, METHOD, I>
+where
+    PALETTE: DitherPalette<TargetColor = Spectra6Color>,
+    METHOD: ForwardErrorDiffusionMethod,
+    I: Iterator<Item = PALETTE::SourceColor>,
+{
+    pub fn new(palette: PALETTE, method: METHOD, source: I, width: usize) -> Self {
+        Self::from_diffusion(ForwardErrorDiffusion::new(palette, method, source, width))
+    }
+
+    // For a ForwardErrorDiffusion with its options already set
+    pub fn from_diffusion(dither: ForwardErrorDiffusion<PALETTE, METHOD, I>) -> Self {
+        DitherPack { dither }
+    }
+}
+
+impl<PALETTE, METHOD, I> Iterator for DitherPack<PALETTE, METHOD, I>
+where
+    PALETTE: DitherPalette<TargetColor = Spectra6Color>,
+    METHOD: ForwardErrorDiffusionMethod,
+    I: Iterator<Item = PALETTE::SourceColor>,
+{
+    type Item = u8;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let left = self.dither.next()?;
+        let right = self.dither.next().unwrap_or(Spectra6Color::White);
+        Some((left as u8) << 4 | (right as u8))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        packed_size_hint(self.dither.size_hint())
+    }
+}
+
+impl<PALETTE, METHOD, I> ExactSizeIterator for DitherPack<PALETTE, METHOD, I>
+where
+    PALETTE: DitherPalette<TargetColor = Spectra6Color>,
+    METHOD: ForwardErrorDiffusionMethod,
+    I: ExactSizeIterator<Item = PALETTE::SourceColor>,
+{
+}
+
+/*
+ * Inverse of SpectraPacker: two colors per byte, high nibble first. Always yields an even number
+ * of colors, so take() the pixel count if that's odd. Nibbles that aren't a color come out as
+ * white, same as the padding SpectraPacker adds.
+ */
+pub struct SpectraUnpacker<T> {
+    bytes: T,
+    // Low nibble of the last byte, if not yielded yet
+    pending: Option<Spectra6Color>,
+}
+
+impl<T> SpectraUnpacker<T> {
+    pub fn new(bytes: T) -> Self {
+        SpectraUnpacker {
+            bytes,
+            pending: None,
+        }
+    }
+}
+
+impl<T> Iterator for SpectraUnpacker<T>
+where
+    T: Iterator<Item = u8>,
+{
+    type Item = Spectra6Color;
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(right) = self.pending.take() {
+            return Some(right);
+        }
+        let byte = self.bytes.next()?;
+        let nibble = |value: u8| Spectra6Color::try_from(value).unwrap_or(Spectra6Color::White);
+        self.pending = Some(nibble(byte & 0x0F));
+        Some(nibble(byte >> 4))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let pending = self.pending.is_some() as usize;
+        let (lower, upper) = self.bytes.size_hint();
+        (
+            lower.saturating_mul(2).saturating_add(pending),
+            upper.and_then(|upper| upper.checked_mul(2)?.checked_add(pending)),
+        )
+    }
+}
+
+/*
+ * A packed frame (as SpectraPacker makes it) as a pixel surface, without unpacking it. Pixels
+ * are numbered row-major across the whole frame, so with an odd width a byte can span two rows.
+ * Also an embedded-graphics DrawTarget, to draw overlays straight into a stored frame.
+ */
+pub struct Spectra6Framebuffer<B> {
+    buffer: B,
+    width: usize,
+    height: usize,
+}
+
+impl<B: AsRef<[u8]>> Spectra6Framebuffer<B> {
+    // None if the buffer is too small for width x height
+    pub fn new(buffer: B, width: usize, height: usize) -> Option<Self> {
+        if buffer.as_ref().len() < (width * height).div_ceil(2) {
+            return None;
+        }
+        Some(Spectra6Framebuffer {
+            buffer,
+            width,
+            height,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn into_inner(self) -> B {
+        self.buffer
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buffer.as_ref()
+    }
+
+    // Panics when out of bounds, like slice indexing. Nibbles that aren't a color come out white.
+    pub fn get_pixel(&self, x: usize, y: usize) -> Spectra6Color {
+        assert!(x < self.width && y < self.height);
+        let index = x + y * self.width;
+        let byte = self.buffer.as_ref()[index / 2];
+        let nibble = if index.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        Spectra6Color::try_from(nibble).unwrap_or(Spectra6Color::White)
+    }
+
+    pub fn row(&self, y: usize) -> impl ExactSizeIterator<Item = Spectra6Color> + '_ {
+        (0..self.width).map(move |x| self.get_pixel(x, y))
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = impl ExactSizeIterator<Item = Spectra6Color> + '_> {
+        (0..self.height).map(|y| self.row(y))
+    }
+
+    // Row-major pixels of a rectangle, clipped to the frame
+    pub fn region(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> impl Iterator<Item = Spectra6Color> + '_ {
+        let x_end = x.saturating_add(width).min(self.width);
+        let y_end = y.saturating_add(height).min(self.height);
+        (y..y_end).flat_map(move |y| (x..x_end).map(move |x| self.get_pixel(x, y)))
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> Spectra6Framebuffer<B> {
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Spectra6Color) {
+        assert!(x < self.width && y < self.height);
+        let index = x + y * self.width;
+        let byte = &mut self.buffer.as_mut()[index / 2];
+        *byte = if index.is_multiple_of(2) {
+            (*byte & 0x0F) | (color as u8) << 4
+        } else {
+            (*byte & 0xF0) | color as u8
+        };
+    }
+}
+
+impl<B> OriginDimensions for Spectra6Framebuffer<B> {
+    fn size(&self) -> Size {
+        Size::new(self.width as u32, self.height as u32)
+    }
+}
+
+// Pixels outside the frame are left out
+impl<B: AsRef<[u8]> + AsMut<[u8]>> DrawTarget for Spectra6Framebuffer<B> {
+    type Color = Spectra6Color;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let (Ok(x), Ok(y)) = (usize::try_from(point.x), usize::try_from(point.y))
+                && x < self.width
+                && y < self.height
+            {
+                self.set_pixel(x, y, color);
+            }
+        }
+        Ok(())
+    }
+}
+
+/*
+ * PackBits run-length coding of packed frames, for keeping frames in flash or sending them over
+ * slow links. Each packet starts with a header byte n: 0..=127 means n + 1 literal bytes follow,
+ * 129..=255 means the next byte repeats 257 - n times, 128 is skipped.
+ */
+pub fn rle_encode<I: IntoIterator<Item = u8>>(bytes: I) -> RleEncoder<I::IntoIter> {
+    RleEncoder {
+        bytes: bytes.into_iter(),
+        lookahead: ArrayVec::new(),
+        packet: ArrayVec::new(),
+        position: 0,
+    }
+}
+
+pub fn rle_decode<I: IntoIterator<Item = u8>>(bytes: I) -> RleDecoder<I::IntoIter> {
+    RleDecoder {
+        bytes: bytes.into_iter(),
+        literal: 0,
+        run: None,
+    }
+}
+
+pub struct RleEncoder<I: Iterator<Item = u8>> {
+    bytes: I,
+    // Enough to see a run of three coming, shorter ones are cheaper inside a literal
+    lookahead: ArrayVec<u8, 3>,
+    packet: ArrayVec<u8, 129>,
+    position: usize,
+}
+
+impl<I: Iterator<Item = u8>> RleEncoder<I> {
+    fn peek(&mut self, index: usize) -> Option<u8> {
+        while self.lookahead.len() <= index {
+            self.lookahead.push(self.bytes.next()?);
+        }
+        Some(self.lookahead[index])
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        self.peek(0)?;
+        Some(self.lookahead.remove(0))
+    }
+
+    fn run_ahead(&mut self) -> bool {
+        let first = self.peek(0);
+        first.is_some() && self.peek(1) == first && self.peek(2) == first
+    }
+
+    fn fill_packet(&mut self) -> Option<()> {
+        self.packet.clear();
+        self.position = 0;
+        let first = self.next_byte()?;
+        let mut run = 1;
+        while run < 128 && self.peek(0) == Some(first) {
+            self.next_byte();
+            run += 1;
+        }
+        if run > 1 {
+            self.packet.push((257 - run) as u8);
+            self.packet.push(first);
+            return Some(());
+        }
+        self.packet.push(0);
+        self.packet.push(first);
+        while !self.packet.is_full() && !self.run_ahead() {
+            match self.next_byte() {
+                Some(byte) => self.packet.push(byte),
+                None => break,
+            }
+        }
+        self.packet[0] = (self.packet.len() - 2) as u8;
+        Some(())
+    }
+}
+
+impl<I: Iterator<Item = u8>> Iterator for RleEncoder<I> {
+    type Item = u8;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.packet.len() {
+            self.fill_packet()?;
+        }
+        self.position += 1;
+        Some(self.packet[self.position - 1])
+    }
+}
+
+// Stops at the end of the data, also when that's halfway a packet
+pub struct RleDecoder<I> {
+    bytes: I,
+    // Literal bytes left in the current packet
+    literal: usize,
+    run: Option<(u8, usize)>,
+}
+
+impl<I: Iterator<Item = u8>> Iterator for RleDecoder<I> {
+    type Item = u8;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((byte, count)) = self.run {
+                self.run = (count > 1).then_some((byte, count - 1));
+                return Some(byte);
+            }
+            if self.literal > 0 {
+                self.literal -= 1;
+                return self.bytes.next();
+            }
+            match self.bytes.next()? {
+                header @ 0..=127 => self.literal = header as usize + 1,
+                128 => {}
+                header => self.run = Some((self.bytes.next()?, 257 - header as usize)),
+            }
+        }
+    }
+}
+
+// Every pixel Clean, the full-panel clean cycle as a frame
+pub fn clean_frame(width: usize, height: usize) -> impl ExactSizeIterator<Item = Spectra6Color> {
+    core::iter::repeat_n(Spectra6Color::Clean, width * height)
 }
 
 /* Quick test pattern for Spectra 6 display */
 #[allow(dead_code)]
-pub fn test_screen(width: usize, height: usize) -> impl Iterator<Item = Spectra6Color> {
+pub fn test_screen(width: usize, height: usize) -> impl ExactSizeIterator<Item = Spectra6Color> {
     (0..width * height).map(move |index| {
         let x = index % width;
         let y = index / width;
@@ -96,3 +529,148 @@ pub const SPECTRA_6_PALETTE_SATURATED: &[(Rgb888, Spectra6Color)] = &[
     (Rgb888::new(178, 19, 24), Spectra6Color::Red),
     (Rgb888::new(239, 222, 68), Spectra6Color::Yellow),
 ];
+
+/*
+ * Measured palette with Clean in place of White, for deghosting passes: dither the content with
+ * this, and the light areas get the clean waveform rather than the white one, without a separate
+ * full clean cycle first.
+ */
+pub const SPECTRA_6_PALETTE_DEGHOST: &[(Rgb888, Spectra6Color)] = &DEGHOST;
+
+// Built from SPECTRA_6_PALETTE, so a new measurement updates both
+const DEGHOST: [(Rgb888, Spectra6Color); 6] = {
+    let mut palette = [(Rgb888::new(0, 0, 0), Spectra6Color::Black); 6];
+    let mut index = 0;
+    while index < palette.len() {
+        let (rgb, color) = SPECTRA_6_PALETTE[index];
+        palette[index] = match color {
+            Spectra6Color::White => (rgb, Spectra6Color::Clean),
+            _ => (rgb, color),
+        };
+        index += 1;
+    }
+    palette
+};
+
+/*
+ * Measured on a panel with the photo-based calibration the firmware has been using, rounded from
+ * fractions of 255. Black and yellow came out slightly negative in one channel, clipped to 0, as
+ * the firmware did. Rounding moves a channel by at most half a step, far less than panels differ
+ * from each other.
+ */
+pub const SPECTRA_6_PALETTE_CALIBRATED: &[(Rgb888, Spectra6Color)] = &[
+    (Rgb888::new(58, 0, 66), Spectra6Color::Black),
+    (Rgb888::new(179, 208, 200), Spectra6Color::White),
+    (Rgb888::new(61, 38, 152), Spectra6Color::Blue),
+    (Rgb888::new(96, 104, 86), Spectra6Color::Green),
+    (Rgb888::new(151, 38, 44), Spectra6Color::Red),
+    (Rgb888::new(215, 233, 0), Spectra6Color::Yellow),
+];
+
+pub type Palette = Cow<'static, [(Rgb888, Spectra6Color)]>;
+
+// The colors every palette lists, in order, Clean may stand in for White
+const PALETTE_ORDER: [Spectra6Color; 6] = [
+    Spectra6Color::Black,
+    Spectra6Color::White,
+    Spectra6Color::Blue,
+    Spectra6Color::Green,
+    Spectra6Color::Red,
+    Spectra6Color::Yellow,
+];
+
+// Not six colors in PALETTE_ORDER, the decomposition needs exactly those
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InvalidPalette;
+
+/*
+ * Named palettes to pick from at runtime, as panels vary from batch to batch. Starts out with the
+ * factory ones, a user-calibrated palette can be added (or replace one) under any name. All
+ * palettes list black, white and then blue, green, red and yellow, in that (cyclic) order.
+ */
+#[derive(Clone, Debug)]
+pub struct PaletteSet {
+    palettes: Vec<(Cow<'static, str>, Palette)>,
+    selected: usize,
+}
+
+impl Default for PaletteSet {
+    fn default() -> Self {
+        Self::factory()
+    }
+}
+
+impl PaletteSet {
+    // "calibrated" is selected
+    pub fn factory() -> Self {
+        let palettes = [
+            ("calibrated", SPECTRA_6_PALETTE_CALIBRATED),
+            ("measured", SPECTRA_6_PALETTE),
+            ("saturated", SPECTRA_6_PALETTE_SATURATED),
+            ("deghost", SPECTRA_6_PALETTE_DEGHOST),
+        ];
+        PaletteSet {
+            palettes: palettes
+                .into_iter()
+                .map(|(name, palette)| (Cow::Borrowed(name), Cow::Borrowed(palette)))
+                .collect(),
+            selected: 0,
+        }
+    }
+
+    // Replaces a palette with the same name, the selection stays where it was
+    pub fn insert(
+        &mut self,
+        name: impl Into<Cow<'static, str>>,
+        palette: impl Into<Palette>,
+    ) -> Result<(), InvalidPalette> {
+        let name = name.into();
+        let palette = palette.into();
+        let in_order = |(&(_, color), expected): (&(Rgb888, Spectra6Color), Spectra6Color)| {
+            color == expected || (expected == Spectra6Color::White && color == Spectra6Color::Clean)
+        };
+        let in_order = palette.iter().zip(PALETTE_ORDER).all(in_order);
+        if palette.len() != PALETTE_ORDER.len() || !in_order {
+            return Err(InvalidPalette);
+        }
+        match self.position(&name) {
+            Some(index) => self.palettes[index].1 = palette,
+            None => self.palettes.push((name, palette)),
+        }
+        Ok(())
+    }
+
+    // False (and the selection unchanged) if there's no palette with that name
+    pub fn select(&mut self, name: &str) -> bool {
+        match self.position(name) {
+            Some(index) => {
+                self.selected = index;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn selected(&self) -> &[(Rgb888, Spectra6Color)] {
+        &self.palettes[self.selected].1
+    }
+
+    pub fn selected_name(&self) -> &str {
+        &self.palettes[self.selected].0
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[(Rgb888, Spectra6Color)]> {
+        self.position(name)
+            .map(|index| self.palettes[index].1.as_ref())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.palettes.iter().map(|(name, _)| name.as_ref())
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.palettes
+            .iter()
+            .position(|(existing, _)| existing == name)
+    }
+}
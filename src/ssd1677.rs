@@ -0,0 +1,468 @@
+use crate::displayinterface::{
+    DisplayInterfaceAsync, DisplayInterfaceAsyncError, SpiWiring, Timeouts,
+};
+use crate::typestate::{
+    Panel, StateBusy, StatePowerOff, StatePowerOn, StateReset, StateUnknown, TypestateDriver,
+    TypestateError, TypestateResult,
+};
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiDevice;
+
+const SINGLE_BYTE_WRITE: bool = false;
+const IS_BUSY_LOW: bool = false;
+
+#[allow(non_camel_case_types, dead_code)]
+#[derive(Copy, Clone)]
+// Datasheet: https://www.good-display.com/companyfile/101.html (SSD1677)
+enum Command {
+    DriverOutputControl = 0x01,
+    BoosterSoftStart = 0x0C,
+    DeepSleep = 0x10,
+    DataEntryMode = 0x11,
+    SwReset = 0x12,
+    TemperatureSensor = 0x18,
+    MasterActivation = 0x20,
+    DisplayUpdateControl1 = 0x21,
+    DisplayUpdateControl2 = 0x22,
+    WriteRamBw = 0x24,
+    WriteRamRed = 0x26,
+    BorderWaveform = 0x3C,
+    RamXRange = 0x44,
+    RamYRange = 0x45,
+    RamXCounter = 0x4E,
+    RamYCounter = 0x4F,
+}
+
+impl crate::displayinterface::Command for Command {
+    fn address(self) -> u8 {
+        self as u8
+    }
+}
+
+// Sequences for DisplayUpdateControl2, followed by MasterActivation
+const UPDATE_POWER_ON: u8 = 0xE0; // Clock + analog on, load temperature
+const UPDATE_POWER_OFF: u8 = 0x83; // Analog + clock off
+const UPDATE_DISPLAY: u8 = 0xF4; // Load temperature + LUT, display (mode 1), stay powered
+
+/*
+ * Packs 1bpp pixels, MSB first. BinaryColor::On is white (a set bit in the BW RAM) or red (in the
+ * red RAM). Panel widths are multiples of 8, so rows never share a byte.
+ */
+pub struct BinaryPacker<T>(pub T);
+
+impl<T> Iterator for BinaryPacker<T>
+where
+    T: Iterator<Item = BinaryColor>,
+{
+    type Item = u8;
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.0.next()?;
+        let mut byte = (first == BinaryColor::On) as u8;
+        for _ in 1..8 {
+            let bit = self.0.next().is_none_or(|x| x == BinaryColor::On);
+            byte = byte << 1 | bit as u8;
+        }
+        Some(byte)
+    }
+}
+
+const INIT_SEQUENCE: &[(Command, &[u8])] = &[
+    (Command::TemperatureSensor, &[0x80]), // Internal sensor
+    (Command::BoosterSoftStart, &[0xAE, 0xC7, 0xC3, 0xC0, 0x80]),
+    (Command::BorderWaveform, &[0x01]),
+    (Command::DisplayUpdateControl1, &[0x00, 0x00]), // Normal BW and red RAM content
+    (Command::DataEntryMode, &[0x03]),               // X increment, Y increment
+];
+
+/*
+ * SSD1677, found on larger monochrome (BW RAM only) and black/white/red (BW + red RAM) panels.
+ * On monochrome panels the red RAM holds the previous frame for partial refreshes, full refreshes
+ * don't need it.
+ */
+pub struct Ssd1677<SPI, BUSY, DC, RST, DELAY> {
+    interface: DisplayInterfaceAsync<SPI, BUSY, DC, RST, DELAY, SINGLE_BYTE_WRITE>,
+    width: u16,
+    height: u16,
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> Ssd1677<SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub fn new(
+        _: &mut SPI,
+        busy: BUSY,
+        dc: DC,
+        rst: RST,
+        _: &mut DELAY,
+        width: u16,
+        height: u16,
+    ) -> Self {
+        Ssd1677 {
+            interface: DisplayInterfaceAsync::new(busy, dc, rst),
+            width,
+            height,
+        }
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.interface.set_timeouts(timeouts)
+    }
+
+    pub fn set_wiring(&mut self, wiring: SpiWiring) {
+        self.interface.set_wiring(wiring)
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    fn frame_bytes(&self) -> usize {
+        self.width as usize * self.height as usize / 8
+    }
+
+    pub async fn reset(
+        &mut self,
+        delay: &mut DELAY,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface.reset(delay, 10_000, 10_000, 10_000).await
+    }
+
+    pub async fn init(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        // NOTE: Call after reset
+        self.interface.cmd(spi, Command::SwReset).await?;
+        self.wait_until_idle().await?;
+
+        self.interface.run_init_sequence(spi, INIT_SEQUENCE).await?;
+        // The rest depends on the panel size
+        let [gates_low, gates_high] = (self.height - 1).to_le_bytes();
+        self.interface
+            .cmd_with_data(
+                spi,
+                Command::DriverOutputControl,
+                &[gates_low, gates_high, 0x02],
+            )
+            .await?;
+        let [x_end_low, x_end_high] = (self.width - 1).to_le_bytes();
+        self.interface
+            .cmd_with_data(
+                spi,
+                Command::RamXRange,
+                &[0x00, 0x00, x_end_low, x_end_high],
+            )
+            .await?;
+        let [y_end_low, y_end_high] = (self.height - 1).to_le_bytes();
+        self.interface
+            .cmd_with_data(
+                spi,
+                Command::RamYRange,
+                &[0x00, 0x00, y_end_low, y_end_high],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn wait_until_idle(
+        &mut self,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface.wait_until_idle(IS_BUSY_LOW).await
+    }
+
+    async fn reset_ram_counters(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface
+            .cmd_with_data(spi, Command::RamXCounter, &[0x00, 0x00])
+            .await?;
+        self.interface
+            .cmd_with_data(spi, Command::RamYCounter, &[0x00, 0x00])
+            .await
+    }
+
+    pub async fn update_bw_raw(
+        &mut self,
+        spi: &mut SPI,
+        data: impl IntoIterator<Item = u8>,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.reset_ram_counters(spi).await?;
+        self.interface.cmd(spi, Command::WriteRamBw).await?;
+        self.interface.data_iter(spi, data).await
+    }
+
+    pub async fn update_red_raw(
+        &mut self,
+        spi: &mut SPI,
+        data: impl IntoIterator<Item = u8>,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.reset_ram_counters(spi).await?;
+        self.interface.cmd(spi, Command::WriteRamRed).await?;
+        self.interface.data_iter(spi, data).await
+    }
+
+    // BinaryColor::On is white
+    pub async fn update_frame(
+        &mut self,
+        spi: &mut SPI,
+        pixels: impl IntoIterator<Item = BinaryColor>,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.update_bw_raw(spi, BinaryPacker(pixels.into_iter()))
+            .await
+    }
+
+    // BinaryColor::On is red, only meaningful on tri-color panels
+    pub async fn update_red_frame(
+        &mut self,
+        spi: &mut SPI,
+        pixels: impl IntoIterator<Item = BinaryColor>,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.update_red_raw(spi, BinaryPacker(pixels.into_iter()))
+            .await
+    }
+
+    // Fills the BW RAM with white and clears the red RAM
+    pub async fn clear_frame(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        let len = self.frame_bytes();
+        self.update_bw_raw(spi, (0..len).map(|_| 0xFF)).await?;
+        self.update_red_raw(spi, (0..len).map(|_| 0x00)).await
+    }
+
+    async fn activate(
+        &mut self,
+        spi: &mut SPI,
+        sequence: u8,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface
+            .cmd_with_data(spi, Command::DisplayUpdateControl2, &[sequence])
+            .await?;
+        self.interface.cmd(spi, Command::MasterActivation).await
+    }
+
+    pub async fn display_frame(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.activate(spi, UPDATE_DISPLAY).await
+        // NOTE: Must wait here
+    }
+
+    pub async fn power_on(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.activate(spi, UPDATE_POWER_ON).await
+        // NOTE: Must wait here
+    }
+
+    pub async fn power_off(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.activate(spi, UPDATE_POWER_OFF).await
+        // NOTE: Must wait here
+    }
+
+    // Only a reset wakes the controller up again, RAM content is lost
+    pub async fn deep_sleep(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface
+            .cmd_with_data(spi, Command::DeepSleep, &[0x01])
+            .await
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> Panel for Ssd1677<SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    type Delay = DELAY;
+    type Error = DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>;
+
+    async fn reset(&mut self, delay: &mut DELAY) -> Result<(), Self::Error> {
+        Ssd1677::reset(self, delay).await
+    }
+
+    async fn wait_until_idle(&mut self) -> Result<(), Self::Error> {
+        Ssd1677::wait_until_idle(self).await
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) {
+        Ssd1677::set_timeouts(self, timeouts)
+    }
+
+    fn set_wiring(&mut self, wiring: SpiWiring) {
+        Ssd1677::set_wiring(self, wiring)
+    }
+}
+
+pub type Ssd1677State<STATE, SPI, BUSY, DC, RST, DELAY> =
+    TypestateDriver<Ssd1677<SPI, BUSY, DC, RST, DELAY>, STATE>;
+
+pub type Ssd1677StateError<SPI, BUSY, DC, RST, DELAY> = TypestateError<
+    Ssd1677<SPI, BUSY, DC, RST, DELAY>,
+    DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>,
+>;
+
+type Ssd1677StateResult<STATE, SPI, BUSY, DC, RST, DELAY> = TypestateResult<
+    Ssd1677<SPI, BUSY, DC, RST, DELAY>,
+    STATE,
+    DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>,
+>;
+
+impl<SPI, BUSY, DC, RST, DELAY> Ssd1677State<StateUnknown, SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub fn new(
+        spi: &mut SPI,
+        busy: BUSY,
+        dc: DC,
+        rst: RST,
+        delay: &mut DELAY,
+        width: u16,
+        height: u16,
+    ) -> Self {
+        Self::from_display(Ssd1677::new(spi, busy, dc, rst, delay, width, height))
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> Ssd1677State<StateReset, SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub async fn init(
+        mut self,
+        spi: &mut SPI,
+    ) -> Ssd1677StateResult<StatePowerOff, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.init(spi).await;
+        self.map_state_from_result(res, |_, _| StatePowerOff)
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> Ssd1677State<StatePowerOff, SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub async fn power_on_no_wait(
+        mut self,
+        spi: &mut SPI,
+    ) -> Ssd1677StateResult<StateBusy<StatePowerOn>, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.power_on(spi).await;
+        self.map_state_from_result(res, |_, _| StateBusy(StatePowerOn))
+    }
+
+    pub async fn power_on(
+        self,
+        spi: &mut SPI,
+    ) -> Ssd1677StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
+        self.power_on_no_wait(spi).await?.wait().await
+    }
+
+    pub async fn deep_sleep(
+        mut self,
+        spi: &mut SPI,
+    ) -> Ssd1677StateResult<StateUnknown, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.deep_sleep(spi).await;
+        self.map_state_from_result(res, |_, _| StateUnknown)
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> Ssd1677State<StatePowerOn, SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub async fn power_off_no_wait(
+        mut self,
+        spi: &mut SPI,
+    ) -> Ssd1677StateResult<StateBusy<StatePowerOff>, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.power_off(spi).await;
+        self.map_state_from_result(res, |_, _| StateBusy(StatePowerOff))
+    }
+
+    pub async fn power_off(
+        self,
+        spi: &mut SPI,
+    ) -> Ssd1677StateResult<StatePowerOff, SPI, BUSY, DC, RST, DELAY> {
+        self.power_off_no_wait(spi).await?.wait().await
+    }
+
+    pub async fn update_frame(
+        mut self,
+        spi: &mut SPI,
+        pixels: impl IntoIterator<Item = BinaryColor>,
+    ) -> Ssd1677StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.update_frame(spi, pixels).await;
+        self.map_state_from_result(res, |s, _| s)
+    }
+
+    pub async fn update_red_frame(
+        mut self,
+        spi: &mut SPI,
+        pixels: impl IntoIterator<Item = BinaryColor>,
+    ) -> Ssd1677StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.update_red_frame(spi, pixels).await;
+        self.map_state_from_result(res, |s, _| s)
+    }
+
+    pub async fn clear(
+        mut self,
+        spi: &mut SPI,
+    ) -> Ssd1677StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.clear_frame(spi).await;
+        self.map_state_from_result(res, |s, _| s)
+    }
+
+    pub async fn display_frame_no_wait(
+        mut self,
+        spi: &mut SPI,
+    ) -> Ssd1677StateResult<StateBusy<StatePowerOn>, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.display_frame(spi).await;
+        self.map_state_from_result(res, |s, _| StateBusy(s))
+    }
+
+    pub async fn display_frame(
+        self,
+        spi: &mut SPI,
+    ) -> Ssd1677StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
+        self.display_frame_no_wait(spi).await?.wait().await
+    }
+}
@@ -0,0 +1,68 @@
+/*
+ * Stack watermarking, to find out how much stack the display path really uses. A stack overflow on
+ * the ESP32 doesn't get reported nicely, it just corrupts whatever is below the stack and resets
+ * some time later. Paint the free stack below the current frame with a pattern, run the code in
+ * question, and see how far down the pattern got overwritten.
+ *
+ * Interrupts and anything else that runs on the same stack in between count too, so the result is
+ * an upper bound for the measured code. Stacks are assumed to grow down, as on Xtensa and RISC-V.
+ */
+use core::mem::size_of;
+
+const PATTERN: u32 = 0x5AA5_C33C;
+
+// Kept free just below the painting function's frame, as its callees still need some stack
+const MARGIN: usize = 256;
+
+pub struct StackWatermark {
+    // Lowest painted address
+    bottom: usize,
+    // Highest painted address (exclusive), just below where paint was called from
+    top: usize,
+}
+
+impl StackWatermark {
+    /// Paints depth bytes below the caller's frame.
+    ///
+    /// # Safety
+    /// There has to be at least depth bytes of free stack below the caller, and nothing may keep
+    /// pointers into that (unused) part of the stack.
+    #[inline(never)]
+    pub unsafe fn paint(depth: usize) -> Self {
+        let top = (stack_pointer() - MARGIN) & !(size_of::<u32>() - 1);
+        let bottom = top.saturating_sub(depth);
+        let mut address = bottom;
+        while address < top {
+            // Safety: caller promised this is unused stack
+            unsafe { core::ptr::write_volatile(address as *mut u32, PATTERN) };
+            address += size_of::<u32>();
+        }
+        StackWatermark { bottom, top }
+    }
+
+    // Most bytes of the painted area that have been in use since painting
+    #[inline(never)]
+    pub fn used(&self) -> usize {
+        let mut address = self.bottom;
+        while address < self.top {
+            // Safety: only reads, the area was checked to be stack when painting
+            if unsafe { core::ptr::read_volatile(address as *const u32) } != PATTERN {
+                break;
+            }
+            address += size_of::<u32>();
+        }
+        self.top - address
+    }
+
+    // Everything painted got overwritten, so the real usage may well be more than used()
+    pub fn is_exhausted(&self) -> bool {
+        self.used() >= self.top - self.bottom
+    }
+}
+
+// Roughly the current stack pointer: the address of a local in a frame that isn't inlined
+#[inline(never)]
+fn stack_pointer() -> usize {
+    let marker = 0u8;
+    core::hint::black_box(&marker) as *const u8 as usize
+}
@@ -0,0 +1,82 @@
+/*
+ * Root certificates for fetching images over HTTPS. They're baked into the firmware as a PEM bundle,
+ * the same format as a ca-certificates file, and handed to the TLS client as DER. A bundle with
+ * only the roots the image host chains up to (Amazon's for S3, DigiCert's or Sectigo's for GitHub)
+ * keeps the firmware small.
+ */
+use alloc::vec::Vec;
+
+// Largest TLS record (16KB of data plus header and tag), both directions need a buffer this size
+pub const TLS_RECORD_BUFFER_SIZE: usize = 16384 + 256;
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PemError {
+    // BEGIN without a matching END
+    Unterminated,
+    InvalidBase64,
+}
+
+pub fn is_https(url: &str) -> bool {
+    url.get(..8)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"))
+}
+
+// Each certificate in bundle as DER, anything outside BEGIN/END (like comments) is skipped
+pub fn pem_certificates(bundle: &str) -> PemCertificates<'_> {
+    PemCertificates { rest: bundle }
+}
+
+pub struct PemCertificates<'t> {
+    rest: &'t str,
+}
+
+impl Iterator for PemCertificates<'_> {
+    type Item = Result<Vec<u8>, PemError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.rest.find(PEM_BEGIN)? + PEM_BEGIN.len();
+        let Some(length) = self.rest[start..].find(PEM_END) else {
+            self.rest = "";
+            return Some(Err(PemError::Unterminated));
+        };
+        let base64 = &self.rest[start..start + length];
+        self.rest = &self.rest[start + length + PEM_END.len()..];
+        Some(decode_base64(base64).ok_or(PemError::InvalidBase64))
+    }
+}
+
+// Whitespace is ignored, padding is optional
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut ret = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    let mut padding = false;
+    for byte in text.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => {
+                padding = true;
+                continue;
+            }
+            _ if byte.is_ascii_whitespace() => continue,
+            _ => return None,
+        };
+        if padding {
+            return None;
+        }
+        bits = (bits << 6) | value as u32;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            ret.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(ret)
+}
@@ -0,0 +1,126 @@
+/*
+ * Shared typestate wrapper for panel drivers. A driver only implements its raw operations on D,
+ * and the states it can be in are tracked at compile time in S. When an operation fails, the
+ * driver is handed back in StateUnknown, so the only thing left to do with it is a reset.
+ */
+use crate::displayinterface::{SpiWiring, Timeouts};
+use core::future::Future;
+
+pub struct StateUnknown;
+pub struct StateReset;
+pub struct StatePowerOff;
+pub struct StateBusy<T>(pub(crate) T);
+pub struct StatePowerOn;
+// Powered off, but with the frame RAM still valid
+pub struct StateStandby;
+
+// Operations every panel driver has, used for the transitions that are the same for all of them
+pub trait Panel {
+    type Delay;
+    type Error;
+
+    fn reset(&mut self, delay: &mut Self::Delay) -> impl Future<Output = Result<(), Self::Error>>;
+    fn wait_until_idle(&mut self) -> impl Future<Output = Result<(), Self::Error>>;
+    fn set_timeouts(&mut self, timeouts: Timeouts);
+    fn set_wiring(&mut self, wiring: SpiWiring);
+}
+
+pub struct TypestateDriver<D, S> {
+    pub(crate) display: D,
+    pub(crate) state: S,
+}
+
+pub struct TypestateError<D, E> {
+    display: TypestateDriver<D, StateUnknown>,
+    error: E,
+}
+
+impl<D, E> TypestateError<D, E> {
+    pub fn error(&self) -> &E {
+        &self.error
+    }
+
+    // Gets the driver back, e.g. to try again after a reset
+    pub fn into_driver(self) -> TypestateDriver<D, StateUnknown> {
+        self.display
+    }
+
+    pub fn into_error(self) -> E {
+        self.error
+    }
+
+    // The same failure with the error converted, e.g. into crate::error::Error, keeping the driver
+    pub fn map_error<F>(self, f: impl FnOnce(E) -> F) -> TypestateError<D, F> {
+        TypestateError {
+            display: self.display,
+            error: f(self.error),
+        }
+    }
+}
+
+impl<D, E> core::fmt::Debug for TypestateError<D, E>
+where
+    E: core::fmt::Debug,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+pub type TypestateResult<D, S, E> = Result<TypestateDriver<D, S>, TypestateError<D, E>>;
+
+impl<D> TypestateDriver<D, StateUnknown> {
+    pub fn from_display(display: D) -> Self {
+        TypestateDriver {
+            display,
+            state: StateUnknown,
+        }
+    }
+}
+
+impl<D, S> TypestateDriver<D, S> {
+    // Moves to the state f returns on success, or to StateUnknown (wrapped in the error) on failure
+    pub(crate) fn map_state_from_result<R, E, NEWSTATE, F: FnOnce(S, R) -> NEWSTATE>(
+        self,
+        ret: Result<R, E>,
+        f: F,
+    ) -> TypestateResult<D, NEWSTATE, E> {
+        match ret {
+            Ok(result) => Ok(TypestateDriver {
+                display: self.display,
+                state: f(self.state, result),
+            }),
+            Err(error) => Err(TypestateError {
+                display: TypestateDriver {
+                    display: self.display,
+                    state: StateUnknown,
+                },
+                error,
+            }),
+        }
+    }
+}
+
+impl<D: Panel, S> TypestateDriver<D, S> {
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.display.set_timeouts(timeouts);
+        self
+    }
+
+    pub fn with_wiring(mut self, wiring: SpiWiring) -> Self {
+        self.display.set_wiring(wiring);
+        self
+    }
+
+    pub async fn reset(mut self, delay: &mut D::Delay) -> TypestateResult<D, StateReset, D::Error> {
+        let res = self.display.reset(delay).await;
+        self.map_state_from_result(res, |_, _| StateReset)
+    }
+}
+
+impl<D: Panel, DONESTATE> TypestateDriver<D, StateBusy<DONESTATE>> {
+    pub async fn wait(mut self) -> TypestateResult<D, DONESTATE, D::Error> {
+        let res = self.display.wait_until_idle().await;
+        self.map_state_from_result(res, |StateBusy(x), _| x)
+    }
+}
@@ -0,0 +1,424 @@
+use crate::displayinterface::{
+    DisplayInterfaceAsync, DisplayInterfaceAsyncError, SpiWiring, Timeouts,
+};
+use crate::ssd1677::BinaryPacker;
+use crate::typestate::{
+    Panel, StateBusy, StatePowerOff, StatePowerOn, StateReset, StateUnknown, TypestateDriver,
+    TypestateError, TypestateResult,
+};
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::pixelcolor::raw::RawU2;
+use embedded_graphics::pixelcolor::{PixelColor, Rgb888, RgbColor};
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiDevice;
+
+const SINGLE_BYTE_WRITE: bool = false;
+const IS_BUSY_LOW: bool = true;
+
+#[allow(non_camel_case_types, dead_code)]
+#[derive(Copy, Clone)]
+// UC8276, black/white/red controller as found on e.g. 4.2" B/W/R panels
+enum Command {
+    PanelSetting = 0x00,           // PSR
+    PowerSetting = 0x01,           // PWR
+    PowerOff = 0x02,               // POF
+    PowerOn = 0x04,                // PON
+    BoosterSoftStart = 0x06,       // BTST
+    DeepSleep = 0x07,              // DSLP
+    DataStartTransmission1 = 0x10, // DTM1, black/white plane
+    DisplayRefresh = 0x12,         // DRF
+    DataStartTransmission2 = 0x13, // DTM2, red plane
+    VcomDataInterval = 0x50,       // CDI
+    GetStatus = 0x71,              // FLG
+}
+
+impl crate::displayinterface::Command for Command {
+    fn address(self) -> u8 {
+        self as u8
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum TriColor {
+    Black,
+    White,
+    Red,
+}
+
+impl PixelColor for TriColor {
+    type Raw = RawU2;
+}
+
+impl From<Rgb888> for TriColor {
+    fn from(value: Rgb888) -> Self {
+        let (r, g, b) = (value.r() as u16, value.g() as u16, value.b() as u16);
+        if r > 128 && g < 100 && b < 100 {
+            TriColor::Red
+        } else if r + g + b > 3 * 128 {
+            TriColor::White
+        } else {
+            TriColor::Black
+        }
+    }
+}
+
+/*
+ * The controller takes a frame as two 1bpp planes. In both a cleared bit marks the pixel: black in
+ * the first plane, red in the second, where red wins if both are cleared.
+ */
+pub fn black_plane(pixels: impl Iterator<Item = TriColor>) -> impl Iterator<Item = u8> {
+    BinaryPacker(pixels.map(|color| BinaryColor::from(color != TriColor::Black)))
+}
+
+pub fn red_plane(pixels: impl Iterator<Item = TriColor>) -> impl Iterator<Item = u8> {
+    BinaryPacker(pixels.map(|color| BinaryColor::from(color != TriColor::Red)))
+}
+
+// Same as most example code, resolution and waveforms come from the panel OTP
+const INIT_SEQUENCE: &[(Command, &[u8])] = &[
+    (Command::BoosterSoftStart, &[0x17, 0x17, 0x17]),
+    (Command::PanelSetting, &[0x0F]), // LUT from OTP, KWR mode
+    (Command::VcomDataInterval, &[0x77]),
+];
+
+pub struct Uc8276<SPI, BUSY, DC, RST, DELAY> {
+    interface: DisplayInterfaceAsync<SPI, BUSY, DC, RST, DELAY, SINGLE_BYTE_WRITE>,
+    width: u16,
+    height: u16,
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> Uc8276<SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub fn new(
+        _: &mut SPI,
+        busy: BUSY,
+        dc: DC,
+        rst: RST,
+        _: &mut DELAY,
+        width: u16,
+        height: u16,
+    ) -> Self {
+        Uc8276 {
+            interface: DisplayInterfaceAsync::new(busy, dc, rst),
+            width,
+            height,
+        }
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.interface.set_timeouts(timeouts)
+    }
+
+    pub fn set_wiring(&mut self, wiring: SpiWiring) {
+        self.interface.set_wiring(wiring)
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    fn frame_bytes(&self) -> usize {
+        self.width as usize * self.height as usize / 8
+    }
+
+    pub async fn reset(
+        &mut self,
+        delay: &mut DELAY,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface.reset(delay, 10_000, 10_000, 10_000).await
+    }
+
+    pub async fn init(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        // NOTE: Call after reset
+        self.interface.run_init_sequence(spi, INIT_SEQUENCE).await
+    }
+
+    pub async fn wait_until_idle(
+        &mut self,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface.wait_until_idle(IS_BUSY_LOW).await
+    }
+
+    pub async fn update_black_raw(
+        &mut self,
+        spi: &mut SPI,
+        data: impl IntoIterator<Item = u8>,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface
+            .cmd(spi, Command::DataStartTransmission1)
+            .await?;
+        self.interface.data_iter(spi, data).await
+    }
+
+    pub async fn update_red_raw(
+        &mut self,
+        spi: &mut SPI,
+        data: impl IntoIterator<Item = u8>,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface
+            .cmd(spi, Command::DataStartTransmission2)
+            .await?;
+        self.interface.data_iter(spi, data).await
+    }
+
+    // Both planes are sent one after the other, so pixels is iterated twice
+    pub async fn update_frame<I>(
+        &mut self,
+        spi: &mut SPI,
+        pixels: I,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>>
+    where
+        I: IntoIterator<Item = TriColor>,
+        I::IntoIter: Clone,
+    {
+        let pixels = pixels.into_iter();
+        self.update_black_raw(spi, black_plane(pixels.clone()))
+            .await?;
+        self.update_red_raw(spi, red_plane(pixels)).await
+    }
+
+    pub async fn clear_frame(
+        &mut self,
+        spi: &mut SPI,
+        color: TriColor,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        let len = self.frame_bytes();
+        let black = if color == TriColor::Black { 0x00 } else { 0xFF };
+        let red = if color == TriColor::Red { 0x00 } else { 0xFF };
+        self.update_black_raw(spi, (0..len).map(|_| black)).await?;
+        self.update_red_raw(spi, (0..len).map(|_| red)).await
+    }
+
+    pub async fn read_status(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<u8, DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        let mut flags = [0u8];
+        self.interface
+            .cmd_read(spi, Command::GetStatus, &mut flags)
+            .await?;
+        Ok(flags[0])
+    }
+
+    pub async fn display_frame(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface.cmd(spi, Command::DisplayRefresh).await
+        // NOTE: Must wait here
+    }
+
+    pub async fn power_on(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface.cmd(spi, Command::PowerOn).await
+        // NOTE: Must wait here
+    }
+
+    pub async fn power_off(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface.cmd(spi, Command::PowerOff).await
+        // NOTE: Must wait here
+    }
+
+    // Only a reset wakes the controller up again, RAM content is lost
+    pub async fn deep_sleep(
+        &mut self,
+        spi: &mut SPI,
+    ) -> Result<(), DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>> {
+        self.interface
+            .cmd_with_data(spi, Command::DeepSleep, &[0xA5])
+            .await
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> Panel for Uc8276<SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    type Delay = DELAY;
+    type Error = DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>;
+
+    async fn reset(&mut self, delay: &mut DELAY) -> Result<(), Self::Error> {
+        Uc8276::reset(self, delay).await
+    }
+
+    async fn wait_until_idle(&mut self) -> Result<(), Self::Error> {
+        Uc8276::wait_until_idle(self).await
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) {
+        Uc8276::set_timeouts(self, timeouts)
+    }
+
+    fn set_wiring(&mut self, wiring: SpiWiring) {
+        Uc8276::set_wiring(self, wiring)
+    }
+}
+
+pub type Uc8276State<STATE, SPI, BUSY, DC, RST, DELAY> =
+    TypestateDriver<Uc8276<SPI, BUSY, DC, RST, DELAY>, STATE>;
+
+pub type Uc8276StateError<SPI, BUSY, DC, RST, DELAY> = TypestateError<
+    Uc8276<SPI, BUSY, DC, RST, DELAY>,
+    DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>,
+>;
+
+type Uc8276StateResult<STATE, SPI, BUSY, DC, RST, DELAY> = TypestateResult<
+    Uc8276<SPI, BUSY, DC, RST, DELAY>,
+    STATE,
+    DisplayInterfaceAsyncError<SPI, BUSY, DC, RST>,
+>;
+
+impl<SPI, BUSY, DC, RST, DELAY> Uc8276State<StateUnknown, SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub fn new(
+        spi: &mut SPI,
+        busy: BUSY,
+        dc: DC,
+        rst: RST,
+        delay: &mut DELAY,
+        width: u16,
+        height: u16,
+    ) -> Self {
+        Self::from_display(Uc8276::new(spi, busy, dc, rst, delay, width, height))
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> Uc8276State<StateReset, SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub async fn init(
+        mut self,
+        spi: &mut SPI,
+    ) -> Uc8276StateResult<StatePowerOff, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.init(spi).await;
+        self.map_state_from_result(res, |_, _| StatePowerOff)
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> Uc8276State<StatePowerOff, SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub async fn power_on_no_wait(
+        mut self,
+        spi: &mut SPI,
+    ) -> Uc8276StateResult<StateBusy<StatePowerOn>, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.power_on(spi).await;
+        self.map_state_from_result(res, |_, _| StateBusy(StatePowerOn))
+    }
+
+    pub async fn power_on(
+        self,
+        spi: &mut SPI,
+    ) -> Uc8276StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
+        self.power_on_no_wait(spi).await?.wait().await
+    }
+
+    pub async fn deep_sleep(
+        mut self,
+        spi: &mut SPI,
+    ) -> Uc8276StateResult<StateUnknown, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.deep_sleep(spi).await;
+        self.map_state_from_result(res, |_, _| StateUnknown)
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> Uc8276State<StatePowerOn, SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    pub async fn power_off_no_wait(
+        mut self,
+        spi: &mut SPI,
+    ) -> Uc8276StateResult<StateBusy<StatePowerOff>, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.power_off(spi).await;
+        self.map_state_from_result(res, |_, _| StateBusy(StatePowerOff))
+    }
+
+    pub async fn power_off(
+        self,
+        spi: &mut SPI,
+    ) -> Uc8276StateResult<StatePowerOff, SPI, BUSY, DC, RST, DELAY> {
+        self.power_off_no_wait(spi).await?.wait().await
+    }
+
+    pub async fn update_frame<I>(
+        mut self,
+        spi: &mut SPI,
+        pixels: I,
+    ) -> Uc8276StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY>
+    where
+        I: IntoIterator<Item = TriColor>,
+        I::IntoIter: Clone,
+    {
+        let res = self.display.update_frame(spi, pixels).await;
+        self.map_state_from_result(res, |s, _| s)
+    }
+
+    pub async fn clear(
+        mut self,
+        spi: &mut SPI,
+        color: TriColor,
+    ) -> Uc8276StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.clear_frame(spi, color).await;
+        self.map_state_from_result(res, |s, _| s)
+    }
+
+    pub async fn display_frame_no_wait(
+        mut self,
+        spi: &mut SPI,
+    ) -> Uc8276StateResult<StateBusy<StatePowerOn>, SPI, BUSY, DC, RST, DELAY> {
+        let res = self.display.display_frame(spi).await;
+        self.map_state_from_result(res, |s, _| StateBusy(s))
+    }
+
+    pub async fn display_frame(
+        self,
+        spi: &mut SPI,
+    ) -> Uc8276StateResult<StatePowerOn, SPI, BUSY, DC, RST, DELAY> {
+        self.display_frame_no_wait(spi).await?.wait().await
+    }
+}
@@ -0,0 +1,274 @@
+/*
+ * epd-waveshare compatible front for the GDEP073E01, for applications written against its
+ * (blocking) WaveshareDisplay trait. Frame buffers are in epd-waveshare's OctColor format, as used
+ * by its 7-color displays, and are translated to Spectra 6 on the fly.
+ *
+ * InternalWiAdditions is crate private in epd-waveshare, so only WaveshareDisplay is provided;
+ * new() already does the full init like the upstream drivers do.
+ *
+ * WaveshareDisplay can only return the SPI device's error, so the SPI device goes in wrapped in
+ * WaveshareSpi, whose error also covers the pins, timeouts and what the panel can't do:
+ *
+ *   let mut spi = WaveshareSpi(spi_device);
+ *   let mut epd = Gdep073e01Waveshare::new(&mut spi, busy, dc, rst, &mut delay, None)?;
+ */
+use crate::blocking::{Blocking, block_on};
+use crate::displayinterface::DisplayInterfaceAsyncError;
+use crate::gdep073e01::{Gdep073e01, HEIGHT, WIDTH};
+use crate::spectra6::Spectra6Color;
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::spi::{ErrorKind, ErrorType, Operation, SpiDevice};
+use epd_waveshare::color::OctColor;
+use epd_waveshare::prelude::{RefreshLut, WaveshareDisplay};
+
+// Spectra 6 code for each OctColor nibble, orange has no equivalent and becomes yellow
+const OCT_TO_SPECTRA6: [u8; 8] = [
+    Spectra6Color::Black as u8,
+    Spectra6Color::White as u8,
+    Spectra6Color::Green as u8,
+    Spectra6Color::Blue as u8,
+    Spectra6Color::Red as u8,
+    Spectra6Color::Yellow as u8,
+    Spectra6Color::Yellow as u8,
+    Spectra6Color::Clean as u8,
+];
+
+fn oct_to_spectra6(byte: u8) -> u8 {
+    OCT_TO_SPECTRA6[(byte >> 4) as usize & 7] << 4 | OCT_TO_SPECTRA6[byte as usize & 7]
+}
+
+type BlockingGdep073e01<SPI, BUSY, DC, RST, DELAY> =
+    Gdep073e01<Blocking<SPI>, Blocking<BUSY>, DC, RST, Blocking<DELAY>>;
+
+pub struct Gdep073e01Waveshare<SPI, BUSY, DC, RST, DELAY> {
+    display: BlockingGdep073e01<SPI, BUSY, DC, RST, DELAY>,
+    background: OctColor,
+}
+
+#[derive(Debug)]
+pub enum WaveshareError<E> {
+    Spi(E),
+    Busy,
+    Pin,
+    Timeout,
+    // Partial updates, the panel only refreshes as a whole
+    Unsupported,
+}
+
+impl<E: embedded_hal::spi::Error> embedded_hal::spi::Error for WaveshareError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Spi(e) => e.kind(),
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+// SPI device for the WaveshareDisplay implementation, only there for its error type
+#[repr(transparent)]
+pub struct WaveshareSpi<SPI>(pub SPI);
+
+impl<SPI: ErrorType> ErrorType for WaveshareSpi<SPI> {
+    type Error = WaveshareError<SPI::Error>;
+}
+
+impl<SPI: SpiDevice> SpiDevice for WaveshareSpi<SPI> {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+        self.0.transaction(operations).map_err(WaveshareError::Spi)
+    }
+}
+
+fn waveshare_error<SPI, BUSY, DC, RST>(
+    error: DisplayInterfaceAsyncError<Blocking<SPI>, Blocking<BUSY>, DC, RST>,
+) -> WaveshareError<SPI::Error>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    match error {
+        DisplayInterfaceAsyncError::SPIError(e) => WaveshareError::Spi(e),
+        DisplayInterfaceAsyncError::BUSYError(_) => WaveshareError::Busy,
+        DisplayInterfaceAsyncError::DCError(_) | DisplayInterfaceAsyncError::RSTError(_) => {
+            WaveshareError::Pin
+        }
+        DisplayInterfaceAsyncError::Timeout => WaveshareError::Timeout,
+    }
+}
+
+type WaveshareResult<T, SPI> = Result<T, WaveshareError<<SPI as ErrorType>::Error>>;
+
+impl<SPI, BUSY, DC, RST, DELAY> Gdep073e01Waveshare<SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    fn reset_and_init(&mut self, spi: &mut SPI, delay: &mut DELAY) -> WaveshareResult<(), SPI> {
+        let spi = Blocking::from_mut(spi);
+        block_on(async {
+            self.display.reset(Blocking::from_mut(delay)).await?;
+            self.display.init(spi).await
+        })
+        .map_err(waveshare_error)
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY> WaveshareDisplay<WaveshareSpi<SPI>, BUSY, DC, RST, DELAY>
+    for Gdep073e01Waveshare<SPI, BUSY, DC, RST, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+{
+    type DisplayColor = OctColor;
+
+    fn new(
+        spi: &mut WaveshareSpi<SPI>,
+        busy: BUSY,
+        dc: DC,
+        rst: RST,
+        delay: &mut DELAY,
+        _delay_us: Option<u32>,
+    ) -> WaveshareResult<Self, SPI> {
+        let mut epd = Gdep073e01Waveshare {
+            display: Gdep073e01::new(
+                Blocking::from_mut(&mut spi.0),
+                Blocking(busy),
+                dc,
+                rst,
+                Blocking::from_mut(delay),
+            ),
+            background: OctColor::White,
+        };
+        epd.reset_and_init(&mut spi.0, delay)?;
+        Ok(epd)
+    }
+
+    fn sleep(
+        &mut self,
+        spi: &mut WaveshareSpi<SPI>,
+        _delay: &mut DELAY,
+    ) -> WaveshareResult<(), SPI> {
+        block_on(self.display.deep_sleep(Blocking::from_mut(&mut spi.0))).map_err(waveshare_error)
+    }
+
+    fn wake_up(
+        &mut self,
+        spi: &mut WaveshareSpi<SPI>,
+        delay: &mut DELAY,
+    ) -> WaveshareResult<(), SPI> {
+        self.reset_and_init(&mut spi.0, delay)
+    }
+
+    fn set_background_color(&mut self, color: OctColor) {
+        self.background = color;
+    }
+
+    fn background_color(&self) -> &OctColor {
+        &self.background
+    }
+
+    fn width(&self) -> u32 {
+        WIDTH as u32
+    }
+
+    fn height(&self) -> u32 {
+        HEIGHT as u32
+    }
+
+    fn update_frame(
+        &mut self,
+        spi: &mut WaveshareSpi<SPI>,
+        buffer: &[u8],
+        _delay: &mut DELAY,
+    ) -> WaveshareResult<(), SPI> {
+        let data = buffer.iter().copied().map(oct_to_spectra6);
+        block_on(
+            self.display
+                .update_frame_raw(Blocking::from_mut(&mut spi.0), data),
+        )
+        .map_err(waveshare_error)
+    }
+
+    // The panel has no partial refresh
+    fn update_partial_frame(
+        &mut self,
+        _spi: &mut WaveshareSpi<SPI>,
+        _delay: &mut DELAY,
+        _buffer: &[u8],
+        _x: u32,
+        _y: u32,
+        _width: u32,
+        _height: u32,
+    ) -> WaveshareResult<(), SPI> {
+        Err(WaveshareError::Unsupported)
+    }
+
+    // Powers up just for the refresh, like the upstream 7-color drivers
+    fn display_frame(
+        &mut self,
+        spi: &mut WaveshareSpi<SPI>,
+        _delay: &mut DELAY,
+    ) -> WaveshareResult<(), SPI> {
+        let spi = Blocking::from_mut(&mut spi.0);
+        block_on(async {
+            self.display.power_on(spi).await?;
+            self.display.wait_until_idle().await?;
+            self.display.display_frame(spi).await?;
+            self.display.wait_until_idle().await?;
+            self.display.power_off(spi).await?;
+            self.display.wait_until_idle().await
+        })
+        .map_err(waveshare_error)
+    }
+
+    fn update_and_display_frame(
+        &mut self,
+        spi: &mut WaveshareSpi<SPI>,
+        buffer: &[u8],
+        delay: &mut DELAY,
+    ) -> WaveshareResult<(), SPI> {
+        self.update_frame(spi, buffer, delay)?;
+        self.display_frame(spi, delay)
+    }
+
+    fn clear_frame(
+        &mut self,
+        spi: &mut WaveshareSpi<SPI>,
+        delay: &mut DELAY,
+    ) -> WaveshareResult<(), SPI> {
+        let color = OCT_TO_SPECTRA6[self.background.get_nibble() as usize & 7];
+        let data = (0..WIDTH * HEIGHT / 2).map(|_| color << 4 | color);
+        block_on(
+            self.display
+                .update_frame_raw(Blocking::from_mut(&mut spi.0), data),
+        )
+        .map_err(waveshare_error)?;
+        self.display_frame(spi, delay)
+    }
+
+    // Waveforms are fixed in the panel, nothing to set
+    fn set_lut(
+        &mut self,
+        _spi: &mut WaveshareSpi<SPI>,
+        _delay: &mut DELAY,
+        _refresh_rate: Option<RefreshLut>,
+    ) -> WaveshareResult<(), SPI> {
+        Ok(())
+    }
+
+    fn wait_until_idle(
+        &mut self,
+        _spi: &mut WaveshareSpi<SPI>,
+        _delay: &mut DELAY,
+    ) -> WaveshareResult<(), SPI> {
+        block_on(self.display.wait_until_idle()).map_err(waveshare_error)
+    }
+}
@@ -0,0 +1,305 @@
+/*
+ * Delta updates against in-memory flash: patches that apply, and the ones that have to be refused
+ * before anything gets booted, malformed, cut off, too large, or made for another base.
+ *
+ * Host only, without the firmware and its ESP32-S3 dependencies:
+ * cargo +stable test --target <host triple> --no-default-features --features delta-ota --test delta
+ */
+// Empty on the device target like the panel model test, and without the feature
+#![cfg(all(feature = "delta-ota", not(target_os = "none")))]
+
+use core::convert::Infallible;
+
+use embassy_futures::block_on;
+use embedded_io_async::{ReadExactError, Write};
+use embedded_storage_async::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use sha2::{Digest, Sha256};
+
+use reterminal_e100x::delta::{
+    DeltaError, HEADER_LEN, MAGIC, PartitionWriteError, PartitionWriter, PatchHeader, apply,
+};
+
+const SECTOR: usize = 4096;
+
+// NOR flash as the ESP32-S3 has it: 4 byte reads and writes, 4 KiB sectors, bits only go 1 -> 0
+struct MemFlash {
+    data: Vec<u8>,
+    erases: usize,
+}
+
+impl MemFlash {
+    fn new(data: &[u8], capacity: usize) -> Self {
+        let mut flash = vec![0xFF; capacity];
+        flash[..data.len()].copy_from_slice(data);
+        MemFlash {
+            data: flash,
+            erases: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MemFlashError(NorFlashErrorKind);
+
+impl NorFlashError for MemFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        self.0
+    }
+}
+
+impl ErrorType for MemFlash {
+    type Error = MemFlashError;
+}
+
+impl ReadNorFlash for MemFlash {
+    const READ_SIZE: usize = 4;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        if !offset.is_multiple_of(Self::READ_SIZE) || !bytes.len().is_multiple_of(Self::READ_SIZE) {
+            return Err(MemFlashError(NorFlashErrorKind::NotAligned));
+        }
+        let data = self
+            .data
+            .get(offset..offset + bytes.len())
+            .ok_or(MemFlashError(NorFlashErrorKind::OutOfBounds))?;
+        bytes.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for MemFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = SECTOR;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let (from, to) = (from as usize, to as usize);
+        if !from.is_multiple_of(SECTOR) || !to.is_multiple_of(SECTOR) {
+            return Err(MemFlashError(NorFlashErrorKind::NotAligned));
+        }
+        self.data
+            .get_mut(from..to)
+            .ok_or(MemFlashError(NorFlashErrorKind::OutOfBounds))?
+            .fill(0xFF);
+        self.erases += 1;
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        if !offset.is_multiple_of(Self::WRITE_SIZE) || !bytes.len().is_multiple_of(Self::WRITE_SIZE)
+        {
+            return Err(MemFlashError(NorFlashErrorKind::NotAligned));
+        }
+        let data = self
+            .data
+            .get_mut(offset..offset + bytes.len())
+            .ok_or(MemFlashError(NorFlashErrorKind::OutOfBounds))?;
+        // Writing over data that wasn't erased first
+        assert!(data.iter().all(|byte| *byte == 0xFF));
+        data.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+// One control: target[..diff] as a diff against base, the rest as extra bytes
+fn make_patch(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let diff_len = base.len().min(target.len());
+    let diff: Vec<u8> = target[..diff_len]
+        .iter()
+        .zip(base)
+        .map(|(target, base)| target.wrapping_sub(*base))
+        .collect();
+    let extra = &target[diff_len..];
+    let mut patch = header(base, target);
+    push_control(&mut patch, diff_len as u32, extra.len() as u32, 0, &diff);
+    patch.extend_from_slice(extra);
+    patch
+}
+
+fn push_control(patch: &mut Vec<u8>, diff: u32, extra: u32, seek: i32, data: &[u8]) {
+    patch.extend_from_slice(&diff.to_le_bytes());
+    patch.extend_from_slice(&extra.to_le_bytes());
+    patch.extend_from_slice(&seek.to_le_bytes());
+    patch.extend_from_slice(data);
+}
+
+fn header(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&(base.len() as u32).to_le_bytes());
+    header.extend_from_slice(&Sha256::digest(base));
+    header.extend_from_slice(&(target.len() as u32).to_le_bytes());
+    header.extend_from_slice(&Sha256::digest(target));
+    header
+}
+
+// Not too regular, so a wrong offset shows
+fn firmware(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+type Applied =
+    Result<PatchHeader, DeltaError<Infallible, MemFlashError, PartitionWriteError<MemFlashError>>>;
+
+// Applies patch to base, into a fresh partition of capacity bytes
+fn run(patch: &[u8], base: &[u8], capacity: usize) -> (Applied, MemFlash) {
+    let mut base = MemFlash::new(base, base.len().next_multiple_of(SECTOR));
+    let mut target = PartitionWriter::new(MemFlash::new(&[], capacity));
+    let mut patch = patch;
+    let result = block_on(apply(&mut patch, &mut base, &mut target));
+    (result, target.into_inner())
+}
+
+#[test]
+fn rebuilds_the_target() {
+    let base = firmware(3 * SECTOR + 123, 1);
+    let mut target = base.clone();
+    // A routine update: a few changed bytes and a longer image
+    target[10] ^= 0x55;
+    target[2 * SECTOR + 7] = 0;
+    target.extend_from_slice(&firmware(SECTOR + 5, 2));
+    let patch = make_patch(&base, &target);
+    let (result, flash) = run(&patch, &base, 8 * SECTOR);
+    let header = result.unwrap();
+    assert_eq!(header.target_len as usize, target.len());
+    assert_eq!(&flash.data[..target.len()], target.as_slice());
+    // Padded to the write size, and the rest is left erased
+    assert!(flash.data[target.len()..].iter().all(|byte| *byte == 0xFF));
+    assert_eq!(flash.erases, target.len().div_ceil(SECTOR));
+}
+
+#[test]
+fn seeks_in_the_base() {
+    let base = firmware(1000, 3);
+    // base[500..600] as is, then base[100..200] with one byte changed
+    let mut target = base[500..600].to_vec();
+    target.extend_from_slice(&base[100..200]);
+    target[150] = target[150].wrapping_add(1);
+    let mut changed = [0u8; 100];
+    changed[50] = 1;
+    let mut patch = header(&base, &target);
+    // Only a seek to get to 500
+    push_control(&mut patch, 0, 0, 500, &[]);
+    push_control(&mut patch, 100, 0, -500, &[0; 100]);
+    push_control(&mut patch, 100, 0, 0, &changed);
+    let (result, flash) = run(&patch, &base, SECTOR);
+    result.unwrap();
+    assert_eq!(&flash.data[..target.len()], target.as_slice());
+}
+
+#[test]
+fn refuses_another_base() {
+    let base = firmware(2000, 4);
+    let target = firmware(2100, 5);
+    let patch = make_patch(&base, &target);
+    let other_base = firmware(2000, 6);
+    let (result, flash) = run(&patch, &other_base, SECTOR);
+    assert!(matches!(result, Err(DeltaError::BaseMismatch)));
+    // Checked before anything is written, the partition is untouched
+    assert_eq!(flash.erases, 0);
+}
+
+#[test]
+fn refuses_a_base_longer_than_the_partition() {
+    let base = firmware(2000, 4);
+    let mut patch = make_patch(&base, &base);
+    patch[4..8].copy_from_slice(&(SECTOR as u32 + 1).to_le_bytes());
+    let (result, _) = run(&patch, &base, SECTOR);
+    assert!(matches!(result, Err(DeltaError::BaseMismatch)));
+}
+
+#[test]
+fn refuses_malformed_patches() {
+    let base = firmware(500, 7);
+    let target = firmware(600, 8);
+    let mut patch = make_patch(&base, &target);
+    patch[..4].copy_from_slice(b"BSDF");
+    let (result, _) = run(&patch, &base, SECTOR);
+    assert!(matches!(result, Err(DeltaError::InvalidPatch)));
+}
+
+#[test]
+fn refuses_truncated_patches() {
+    let base = firmware(500, 7);
+    let target = firmware(600, 8);
+    let patch = make_patch(&base, &target);
+    // In the header, in the control, and in the data
+    for len in [10, HEADER_LEN + 5, patch.len() - 1] {
+        let (result, _) = run(&patch[..len], &base, SECTOR);
+        assert!(
+            matches!(
+                result,
+                Err(DeltaError::PatchError(ReadExactError::UnexpectedEof))
+            ),
+            "cut off at {}",
+            len
+        );
+    }
+}
+
+#[test]
+fn refuses_controls_past_the_end() {
+    let base = firmware(500, 9);
+    let target = firmware(600, 10);
+    let control = HEADER_LEN;
+    // More diff and extra than the target has room for
+    let mut patch = make_patch(&base, &target);
+    patch[control + 4..control + 8].copy_from_slice(&101u32.to_le_bytes());
+    let (result, _) = run(&patch, &base, SECTOR);
+    assert!(matches!(result, Err(DeltaError::InvalidPatch)));
+    // A diff reading past the end of the base
+    let mut patch = make_patch(&base, &target);
+    patch[control..control + 4].copy_from_slice(&501u32.to_le_bytes());
+    patch[control + 4..control + 8].copy_from_slice(&99u32.to_le_bytes());
+    let (result, _) = run(&patch, &base, SECTOR);
+    assert!(matches!(result, Err(DeltaError::InvalidPatch)));
+}
+
+#[test]
+fn refuses_a_corrupted_result() {
+    let base = firmware(500, 11);
+    let target = firmware(600, 12);
+    let mut patch = make_patch(&base, &target);
+    let last = patch.len() - 1;
+    patch[last] ^= 1;
+    let (result, _) = run(&patch, &base, SECTOR);
+    assert!(matches!(result, Err(DeltaError::TargetMismatch)));
+}
+
+#[test]
+fn refuses_targets_larger_than_the_partition() {
+    let base = firmware(500, 13);
+    let target = firmware(SECTOR + 1, 14);
+    let patch = make_patch(&base, &target);
+    let (result, _) = run(&patch, &base, SECTOR);
+    assert!(matches!(
+        result,
+        Err(DeltaError::WriteError(PartitionWriteError::Full))
+    ));
+}
+
+#[test]
+fn writes_in_whole_units() {
+    let mut writer = PartitionWriter::new(MemFlash::new(&[], SECTOR));
+    block_on(async {
+        for byte in 0..7u8 {
+            writer.write_all(&[byte]).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+    });
+    assert_eq!(writer.position(), 8);
+    let flash = writer.into_inner();
+    assert_eq!(&flash.data[..8], &[0, 1, 2, 3, 4, 5, 6, 0xFF]);
+}
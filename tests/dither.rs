@@ -0,0 +1,76 @@
+/*
+ * Tables and error handling of the dithers that don't need a palette to check: Ostromoukhov's
+ * weights are the paper's, and the error limit never lets the error grow or wrap around.
+ *
+ * Host only, without the firmware and its ESP32-S3 dependencies:
+ * cargo +stable test --target <host triple> --no-default-features --test dither
+ */
+// Empty on the device target like the panel model test
+#![cfg(not(target_os = "none"))]
+
+use reterminal_e100x::dither::{
+    DefaultQuantizationError, ErrorLimit, ForwardErrorDiffusionMethod, LimitQuantizationError,
+    Ostromoukhov,
+};
+
+// Right, down left, down
+fn ostromoukhov_weights(intensity: u8) -> Vec<usize> {
+    Ostromoukhov
+        .get_targets_for(intensity)
+        .map(|(_, _, weight)| weight)
+        .collect()
+}
+
+#[test]
+fn ostromoukhov_weights_add_up_to_the_divisor() {
+    for intensity in 0..=255 {
+        let weights = ostromoukhov_weights(intensity);
+        assert_eq!(weights.iter().sum::<usize>(), Ostromoukhov.get_divisor());
+    }
+}
+
+#[test]
+fn ostromoukhov_follows_the_paper() {
+    // 5, 3 and 2 tenths, scaled to 64ths
+    for intensity in (95..=107).chain(148..=160) {
+        assert_eq!(ostromoukhov_weights(intensity), [32, 19, 13], "{intensity}");
+    }
+    // 4, 1 and 1 sixths around the middle
+    for intensity in 127..=128 {
+        assert_eq!(ostromoukhov_weights(intensity), [43, 11, 10], "{intensity}");
+    }
+    // 395, 104 and 101 six hundredths
+    for intensity in [126, 129] {
+        assert_eq!(ostromoukhov_weights(intensity), [42, 11, 11], "{intensity}");
+    }
+}
+
+#[test]
+fn decay_keeps_at_most_all_of_the_error() {
+    assert_eq!(ErrorLimit::decay(80).keep_percent, 80);
+    assert_eq!(ErrorLimit::decay(100).keep_percent, 100);
+    assert_eq!(ErrorLimit::decay(250).keep_percent, 100);
+}
+
+#[test]
+fn limit_clamps_and_scales() {
+    let error = DefaultQuantizationError::<i16, 3>([300, -300, 50]);
+    assert_eq!(error.clone().limit(100, 50).0, [50, -50, 25]);
+    assert_eq!(error.limit(i32::MAX, 100).0, [300, -300, 50]);
+}
+
+#[test]
+fn limit_never_grows_the_error() {
+    let error = DefaultQuantizationError::<i16, 3>([i16::MAX, i16::MIN, -7]);
+    assert_eq!(error.limit(i32::MAX, 255).0, [i16::MAX, i16::MIN, -7]);
+    let error = DefaultQuantizationError::<i32, 3>([i32::MAX, -i32::MAX, 7]);
+    assert_eq!(error.limit(i32::MAX, 200).0, [i32::MAX, -i32::MAX, 7]);
+}
+
+#[test]
+fn limit_saturates_a_clamp_past_the_channel() {
+    // A max past what i16 holds still gives the extremes, not whatever the value wraps to
+    let error = DefaultQuantizationError::<i16, 2>([i16::MAX, i16::MIN]);
+    assert_eq!(error.clone().limit(100_000, 100).0, [i16::MAX, i16::MIN]);
+    assert_eq!(error.limit(100_000, 50).0, [i16::MAX / 2, i16::MIN / 2]);
+}
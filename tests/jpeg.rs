@@ -0,0 +1,229 @@
+/*
+ * JPEG downloads that got cut off: a baseline file shows what arrived with the rest in the
+ * background color, a progressive one shows the scans that arrived. The files are made here, flat
+ * 8x8 grayscale blocks, one per row, so what they decode to is known exactly.
+ *
+ * Host only, without the firmware and its ESP32-S3 dependencies:
+ * cargo +stable test --target <host triple> --no-default-features --features jpeg --test jpeg
+ */
+// Empty on the device target like the panel model test, and without the feature
+#![cfg(all(feature = "jpeg", not(target_os = "none")))]
+
+use embedded_graphics::pixelcolor::Rgb888;
+
+use reterminal_e100x::pipeline::{Decoder, JpegDecoder, PipelineError};
+
+const BACKGROUND: Rgb888 = Rgb888::new(255, 0, 0);
+
+// ITU T.81 table K.3, luminance DC differences
+const DC_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+// Nothing but end of block, as every block is flat
+const AC_BITS: [u8; 16] = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+const AC_VALUES: [u8; 1] = [0x00];
+
+// Block rows of the test image, none of them the background's luma
+const LEVELS: [u8; 16] = [
+    20, 200, 90, 140, 30, 250, 60, 180, 110, 10, 230, 160, 40, 120, 210, 70,
+];
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    pending: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn push(&mut self, (code, length): (u16, u32)) {
+        for bit in (0..length).rev() {
+            self.pending = self.pending << 1 | (code as u32 >> bit & 1);
+            self.count += 1;
+            if self.count == 8 {
+                self.bytes.push(self.pending as u8);
+                // Byte stuffing, so data never looks like a marker
+                if self.pending == 0xFF {
+                    self.bytes.push(0);
+                }
+                self.pending = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    // Pads the last byte with ones
+    fn finish(mut self) -> Vec<u8> {
+        while self.count != 0 {
+            self.push((1, 1));
+        }
+        self.bytes
+    }
+}
+
+// Code and length of each symbol, as a decoder builds them from the bit counts
+fn huffman_codes(bits: &[u8; 16], values: &[u8]) -> Vec<(u8, (u16, u32))> {
+    let mut codes = Vec::new();
+    let mut code = 0u16;
+    let mut values = values.iter();
+    for (length, count) in bits.iter().enumerate() {
+        for _ in 0..*count {
+            codes.push((*values.next().unwrap(), (code, length as u32 + 1)));
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+fn push_segment(out: &mut Vec<u8>, marker: u8, body: &[u8]) {
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+fn push_scan(out: &mut Vec<u8>, spectral: (u8, u8), data: Vec<u8>) {
+    push_segment(out, 0xDA, &[1, 1, 0x00, spectral.0, spectral.1, 0]);
+    out.extend_from_slice(&data);
+}
+
+// 8 pixels wide, a flat block of each level below the one before
+fn jpeg(levels: &[u8], progressive: bool) -> Vec<u8> {
+    let dc_codes = huffman_codes(&DC_BITS, &DC_VALUES);
+    let dc_code = |category: u8| {
+        dc_codes
+            .iter()
+            .find(|(value, _)| *value == category)
+            .unwrap()
+            .1
+    };
+    let end_of_block = (0, 1);
+    let mut out = vec![0xFF, 0xD8];
+    // All 8, so the DC coefficient of a flat block is its level - 128
+    let mut quantization = vec![0];
+    quantization.extend_from_slice(&[8; 64]);
+    push_segment(&mut out, 0xDB, &quantization);
+    let height = (levels.len() * 8) as u16;
+    let mut frame = vec![8];
+    frame.extend_from_slice(&height.to_be_bytes());
+    frame.extend_from_slice(&8u16.to_be_bytes());
+    frame.extend_from_slice(&[1, 1, 0x11, 0]);
+    push_segment(&mut out, if progressive { 0xC2 } else { 0xC0 }, &frame);
+    for (class, bits, values) in [
+        (0x00, &DC_BITS, &DC_VALUES[..]),
+        (0x10, &AC_BITS, &AC_VALUES),
+    ] {
+        let mut table = vec![class];
+        table.extend_from_slice(bits);
+        table.extend_from_slice(values);
+        push_segment(&mut out, 0xC4, &table);
+    }
+
+    let mut bits = BitWriter::default();
+    let mut previous = 0;
+    for level in levels {
+        let coefficient = *level as i32 - 128;
+        let difference = coefficient - previous;
+        previous = coefficient;
+        let category = 32 - difference.unsigned_abs().leading_zeros();
+        bits.push(dc_code(category as u8));
+        if category != 0 {
+            let value = if difference < 0 {
+                difference + (1 << category) - 1
+            } else {
+                difference
+            };
+            bits.push((value as u16, category));
+        }
+        if !progressive {
+            bits.push(end_of_block);
+        }
+    }
+    if progressive {
+        push_scan(&mut out, (0, 0), bits.finish());
+        let mut bits = BitWriter::default();
+        for _ in levels {
+            bits.push(end_of_block);
+        }
+        push_scan(&mut out, (1, 63), bits.finish());
+    } else {
+        push_scan(&mut out, (0, 63), bits.finish());
+    }
+    out.extend_from_slice(&[0xFF, 0xD9]);
+    out
+}
+
+// Where the entropy coded data of the last scan starts
+fn last_scan_data(data: &[u8]) -> usize {
+    data.windows(2)
+        .rposition(|pair| pair == [0xFF, 0xDA])
+        .unwrap()
+        + 10
+}
+
+fn decode(data: &[u8]) -> Result<Vec<Rgb888>, PipelineError> {
+    let decoder = JpegDecoder {
+        background: BACKGROUND,
+    };
+    let image = decoder.decode(data)?;
+    assert_eq!((image.width, image.height), (8, LEVELS.len() * 8));
+    // One pixel per row is enough, rows are flat
+    Ok(image.pixels.chunks(8).map(|row| row[0]).collect())
+}
+
+fn gray(level: u8) -> Rgb888 {
+    Rgb888::new(level, level, level)
+}
+
+fn expected() -> Vec<Rgb888> {
+    LEVELS.iter().flat_map(|level| [gray(*level); 8]).collect()
+}
+
+#[test]
+fn complete_baseline() {
+    assert_eq!(decode(&jpeg(&LEVELS, false)).unwrap(), expected());
+}
+
+#[test]
+fn complete_progressive() {
+    assert_eq!(decode(&jpeg(&LEVELS, true)).unwrap(), expected());
+}
+
+#[test]
+fn truncated_baseline_fills_the_rest() {
+    let data = jpeg(&LEVELS, false);
+    let rows = decode(&data[..last_scan_data(&data) + 12]).unwrap();
+    let shown = rows.iter().position(|row| *row == BACKGROUND).unwrap();
+    // Whole blocks made it, the one that was cut off might be only partly right
+    assert!(shown >= 16 && shown < rows.len() - 16, "{shown} rows shown");
+    assert_eq!(rows[..shown / 8 * 8], expected()[..shown / 8 * 8]);
+    assert!(rows[shown..].iter().all(|row| *row == BACKGROUND));
+}
+
+#[test]
+fn truncated_baseline_cut_at_every_byte() {
+    let data = jpeg(&LEVELS, false);
+    for end in last_scan_data(&data) + 1..data.len() {
+        let rows = decode(&data[..end]).unwrap();
+        let shown = rows
+            .iter()
+            .position(|row| *row == BACKGROUND)
+            .unwrap_or(rows.len());
+        assert!(rows[shown..].iter().all(|row| *row == BACKGROUND));
+    }
+}
+
+#[test]
+fn truncated_progressive_keeps_the_earlier_scans() {
+    let data = jpeg(&LEVELS, true);
+    let end = last_scan_data(&data) + 1;
+    assert_eq!(decode(&data[..end]).unwrap(), expected());
+}
+
+#[test]
+fn cut_off_in_the_headers() {
+    let data = jpeg(&LEVELS, false);
+    assert_eq!(
+        decode(&data[..last_scan_data(&data) - 4]),
+        Err(PipelineError::DecodeFailed)
+    );
+}
@@ -0,0 +1,657 @@
+/*
+ * Behavioral model of the UC8159-like controller on the GDEP073E01, used to fuzz the typestate
+ * driver on the host. The model accepts the byte stream the driver produces and records anything
+ * a real controller would choke on: commands before reset, commands while BUSY, data without a
+ * command, too much data for a command, refreshing while powered off, and so on.
+ *
+ * The typestate API makes most illegal sequences unrepresentable, so the fuzzer picks random
+ * operations that are allowed in the current state and checks the model never complains. SPI
+ * failures are injected at random to check errors surface and a reset of the returned driver
+ * recovers.
+ *
+ * Host only, without the firmware and its ESP32-S3 dependencies:
+ * cargo +stable test --target <host triple> --no-default-features --test panel_model
+ */
+// Empty on the device target, which has no std for the model and the test harness
+#![cfg(not(target_os = "none"))]
+
+use core::convert::Infallible;
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use embedded_hal::digital::{ErrorType as DigitalErrorType, InputPin, OutputPin};
+use embedded_hal::spi::{ErrorKind, ErrorType as SpiErrorType, Operation};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiDevice;
+
+use reterminal_e100x::detect::DetectionConfig;
+use reterminal_e100x::displayinterface::{DisplayInterfaceAsyncError, Timeouts};
+use reterminal_e100x::gdep073e01::{
+    Gdep073e01State, HEIGHT, RatedTemperaturePolicy, StateBusy, StatePowerOff, StatePowerOn,
+    StateReset, StateStandby, StateUnknown, UpdateFrameCheckedError, WIDTH,
+};
+use reterminal_e100x::shadow::ShadowFrame;
+use reterminal_e100x::spectra6::{Spectra6Color, test_screen};
+
+const FRAME_BYTES: usize = WIDTH * HEIGHT / 2;
+
+const POWER_OFF: u8 = 0x02;
+const POWER_ON: u8 = 0x04;
+const DATA_START_TRANSMISSION: u8 = 0x10;
+const DISPLAY_REFRESH: u8 = 0x12;
+
+// Number of data bytes each command expects, None for commands the controller doesn't know
+fn expected_data(command: u8) -> Option<usize> {
+    match command {
+        0x00 => Some(2),               // PanelSetting
+        0x01 => Some(1),               // PowerSetting
+        POWER_OFF => Some(1),          // PowerOff
+        0x03 => Some(4),               // POFS
+        POWER_ON => Some(0),           // PowerOn
+        0x05 | 0x06 | 0x08 => Some(4), // BoosterSoftStart
+        DATA_START_TRANSMISSION => Some(FRAME_BYTES),
+        DISPLAY_REFRESH => Some(1), // DisplayRefresh
+        0x30 => Some(1),            // PllControl
+        0x50 => Some(1),            // CDI
+        0x60 => Some(2),            // TCON
+        0x61 => Some(4),            // TRES
+        0x84 => Some(1),            // T_VDCS
+        0xE3 => Some(1),            // PWS
+        0xE5 => Some(1),            // TSSET
+        0xAA => Some(6),            // CMDH
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Model {
+    rst_low: bool,
+    has_been_reset: bool,
+    dc_high: bool,
+    powered: bool,
+    busy: bool,
+    command: Option<u8>,
+    data_received: usize,
+    // Bytes received since the last DataStartTransmission, None if never started
+    frame_bytes: Option<usize>,
+    refreshes: usize,
+    fail_next_write: bool,
+    // Makes the write of this command fail, once
+    fail_command: Option<u8>,
+    violations: Vec<String>,
+}
+
+impl Model {
+    fn violation(&mut self, what: String) {
+        self.violations.push(what);
+    }
+
+    fn reset(&mut self) {
+        *self = Model {
+            has_been_reset: true,
+            violations: core::mem::take(&mut self.violations),
+            refreshes: self.refreshes,
+            ..Default::default()
+        };
+    }
+
+    fn command(&mut self, command: u8) {
+        if !self.has_been_reset {
+            self.violation(format!("command {command:#04X} before reset"));
+        }
+        if self.busy {
+            self.violation(format!("command {command:#04X} while busy"));
+        }
+        if let Some(previous) = self.command {
+            let expected = expected_data(previous).unwrap_or(0);
+            if self.data_received < expected && previous != DATA_START_TRANSMISSION {
+                self.violation(format!(
+                    "command {command:#04X} interrupted {previous:#04X} after {} of {expected} bytes",
+                    self.data_received
+                ));
+            }
+        }
+        let Some(expected) = expected_data(command) else {
+            self.violation(format!("unknown command {command:#04X}"));
+            self.command = None;
+            return;
+        };
+        self.command = Some(command);
+        self.data_received = 0;
+        if command == DATA_START_TRANSMISSION {
+            self.frame_bytes = Some(0);
+        }
+        if expected == 0 {
+            self.complete(command);
+        }
+    }
+
+    fn data(&mut self, byte: u8) {
+        let Some(command) = self.command else {
+            self.violation(format!("data {byte:#04X} without command"));
+            return;
+        };
+        let expected = expected_data(command).unwrap_or(0);
+        if self.data_received >= expected {
+            self.violation(format!("too much data for command {command:#04X}"));
+            return;
+        }
+        self.data_received += 1;
+        if command == DATA_START_TRANSMISSION {
+            self.frame_bytes = Some(self.data_received);
+        }
+        if self.data_received == expected {
+            self.complete(command);
+        }
+    }
+
+    fn complete(&mut self, command: u8) {
+        match command {
+            POWER_ON => {
+                if self.powered {
+                    self.violation("power on while powered".into());
+                }
+                self.powered = true;
+                self.busy = true;
+            }
+            POWER_OFF => {
+                if !self.powered {
+                    self.violation("power off while not powered".into());
+                }
+                self.powered = false;
+                self.busy = true;
+            }
+            DISPLAY_REFRESH => {
+                if !self.powered {
+                    self.violation("refresh while not powered".into());
+                }
+                if let Some(bytes) = self.frame_bytes
+                    && bytes != FRAME_BYTES
+                {
+                    self.violation(format!("refresh with partial frame of {bytes} bytes"));
+                }
+                self.refreshes += 1;
+                self.busy = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+type SharedModel = Rc<RefCell<Model>>;
+
+#[derive(Debug)]
+struct MockSpiError;
+
+impl embedded_hal::spi::Error for MockSpiError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+struct MockSpi(SharedModel);
+
+impl SpiErrorType for MockSpi {
+    type Error = MockSpiError;
+}
+
+impl SpiDevice for MockSpi {
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        let mut model = self.0.borrow_mut();
+        if core::mem::take(&mut model.fail_next_write) {
+            return Err(MockSpiError);
+        }
+        if let Some(command) = model.fail_command
+            && !model.dc_high
+            && operations.iter().any(
+                |operation| matches!(operation, Operation::Write(data) if data.contains(&command)),
+            )
+        {
+            model.fail_command = None;
+            return Err(MockSpiError);
+        }
+        for operation in operations.iter() {
+            if let Operation::Write(data) = operation {
+                for byte in data.iter().copied() {
+                    if model.dc_high {
+                        model.data(byte);
+                    } else {
+                        model.command(byte);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct MockBusy(SharedModel);
+
+impl DigitalErrorType for MockBusy {
+    type Error = Infallible;
+}
+
+impl InputPin for MockBusy {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        // BUSY is active low on this controller
+        Ok(!self.0.borrow().busy)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.0.borrow().busy)
+    }
+}
+
+impl Wait for MockBusy {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().busy = false;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_high().await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+// A panel that never finishes whatever it's doing
+struct StuckBusy;
+
+impl DigitalErrorType for StuckBusy {
+    type Error = Infallible;
+}
+
+impl InputPin for StuckBusy {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+impl Wait for StuckBusy {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        core::future::pending().await
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_high().await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_high().await
+    }
+}
+
+struct MockDc(SharedModel);
+
+impl DigitalErrorType for MockDc {
+    type Error = Infallible;
+}
+
+impl OutputPin for MockDc {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().dc_high = false;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().dc_high = true;
+        Ok(())
+    }
+}
+
+struct MockRst(SharedModel);
+
+impl DigitalErrorType for MockRst {
+    type Error = Infallible;
+}
+
+impl OutputPin for MockRst {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().rst_low = true;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        let mut model = self.0.borrow_mut();
+        if model.rst_low {
+            model.reset();
+        }
+        Ok(())
+    }
+}
+
+struct MockDelay;
+
+impl DelayNs for MockDelay {
+    async fn delay_ns(&mut self, _ns: u32) {}
+}
+
+// All mocks complete immediately, so polling once is enough
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    match future.as_mut().poll(&mut context) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("mock future unexpectedly pending"),
+    }
+}
+
+// xorshift64*, deterministic so failures can be reproduced from the seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+type State<S> = Gdep073e01State<S, MockSpi, MockBusy, MockDc, MockRst, MockDelay>;
+
+enum AnyState {
+    Unknown(State<StateUnknown>),
+    Reset(State<StateReset>),
+    PowerOff(State<StatePowerOff>),
+    PowerOn(State<StatePowerOn>),
+    BusyPowerOff(State<StateBusy<StatePowerOff>>),
+    BusyPowerOn(State<StateBusy<StatePowerOn>>),
+    Standby(State<StateStandby>),
+    BusyStandby(State<StateBusy<StateStandby>>),
+}
+
+fn new_driver(model: &SharedModel, spi: &mut MockSpi) -> AnyState {
+    AnyState::Unknown(Gdep073e01State::new(
+        spi,
+        MockBusy(model.clone()),
+        MockDc(model.clone()),
+        MockRst(model.clone()),
+        &mut MockDelay,
+    ))
+}
+
+fn random_color(rng: &mut Rng) -> Spectra6Color {
+    [
+        Spectra6Color::Black,
+        Spectra6Color::White,
+        Spectra6Color::Yellow,
+        Spectra6Color::Red,
+        Spectra6Color::Blue,
+        Spectra6Color::Green,
+        Spectra6Color::Clean,
+    ][rng.below(7) as usize]
+}
+
+// Performs one random operation allowed in the current state, returns the new state and its name
+fn step(
+    state: AnyState,
+    rng: &mut Rng,
+    spi: &mut MockSpi,
+    shadow: &mut ShadowFrame,
+) -> (AnyState, &'static str) {
+    macro_rules! next {
+        ($name:expr, $result:expr, $variant:ident) => {
+            match block_on($result) {
+                Ok(display) => (AnyState::$variant(display), $name),
+                Err(e) => (
+                    AnyState::Unknown(e.into_driver()),
+                    concat!($name, " (failed)"),
+                ),
+            }
+        };
+    }
+    match state {
+        AnyState::Unknown(display) => next!("reset", display.reset(&mut MockDelay), Reset),
+        AnyState::Reset(display) => next!("init", display.init(spi), PowerOff),
+        AnyState::PowerOff(display) => match rng.below(4) {
+            0 => next!("power_on", display.power_on(spi), PowerOn),
+            1 => next!(
+                "power_on_no_wait",
+                display.power_on_no_wait(spi),
+                BusyPowerOn
+            ),
+            2 => {
+                let config = DetectionConfig::new(&[]);
+                match block_on(display.detect_variant(spi, &mut MockDelay, &config)) {
+                    Ok((display, _)) => (AnyState::PowerOff(display), "detect_variant"),
+                    Err(e) => (
+                        AnyState::Unknown(e.into_driver()),
+                        "detect_variant (failed)",
+                    ),
+                }
+            }
+            _ => {
+                let celsius = rng.below(70) as i8 - 10;
+                let policy = RatedTemperaturePolicy::default();
+                match block_on(display.apply_temperature(spi, celsius, &policy)) {
+                    Ok((display, _)) => (AnyState::PowerOff(display), "apply_temperature"),
+                    Err(e) => (
+                        AnyState::Unknown(e.into_driver()),
+                        "apply_temperature (failed)",
+                    ),
+                }
+            }
+        },
+        AnyState::BusyPowerOff(display) => next!("wait", display.wait(), PowerOff),
+        AnyState::BusyPowerOn(display) => next!("wait", display.wait(), PowerOn),
+        AnyState::BusyStandby(display) => next!("wait", display.wait(), Standby),
+        AnyState::Standby(display) => match rng.below(2) {
+            0 => next!("resume", display.resume(spi), PowerOn),
+            _ => next!("resume_no_wait", display.resume_no_wait(spi), BusyPowerOn),
+        },
+        AnyState::PowerOn(display) => match rng.below(11) {
+            0 => next!("power_off", display.power_off(spi), PowerOff),
+            1 => next!(
+                "power_off_no_wait",
+                display.power_off_no_wait(spi),
+                BusyPowerOff
+            ),
+            2 => {
+                let color = random_color(rng);
+                next!("clear", display.clear(spi, color), PowerOn)
+            }
+            3 => next!(
+                "update_frame",
+                display.update_frame(spi, test_screen(WIDTH, HEIGHT)),
+                PowerOn
+            ),
+            4 => {
+                let byte = rng.next() as u8;
+                let frame = vec![byte; FRAME_BYTES];
+                next!(
+                    "update_frame_from_reader",
+                    display.update_frame_from_reader(spi, frame.as_slice()),
+                    PowerOn
+                )
+            }
+            5 => next!("display_frame", display.display_frame(spi), PowerOn),
+            6 => next!(
+                "display_frame_no_wait",
+                display.display_frame_no_wait(spi),
+                BusyPowerOn
+            ),
+            7 => next!("standby", display.standby(spi), Standby),
+            9 => {
+                // Sometimes a frame that's too short, which must be refused with the panel still on
+                let len = WIDTH * HEIGHT - rng.below(2) as usize * WIDTH;
+                let pixels = test_screen(WIDTH, HEIGHT).take(len);
+                match block_on(display.update_frame_checked(spi, pixels)) {
+                    Ok(display) => (AnyState::PowerOn(display), "update_frame_checked"),
+                    Err(UpdateFrameCheckedError::WrongFrameSize(display, _)) => {
+                        (AnyState::PowerOn(display), "update_frame_checked (refused)")
+                    }
+                    Err(UpdateFrameCheckedError::InterfaceError(e)) => (
+                        AnyState::Unknown(e.into_driver()),
+                        "update_frame_checked (failed)",
+                    ),
+                }
+            }
+            8 => next!("standby_no_wait", display.standby_no_wait(spi), BusyStandby),
+            _ => {
+                let color = random_color(rng);
+                let pixels = (0..WIDTH * HEIGHT).map(move |_| color);
+                match block_on(display.display_if_changed(spi, pixels, shadow)) {
+                    Ok((display, _)) => (AnyState::PowerOn(display), "display_if_changed"),
+                    Err(e) => (
+                        AnyState::Unknown(e.into_driver()),
+                        "display_if_changed (failed)",
+                    ),
+                }
+            }
+        },
+    }
+}
+
+#[test]
+fn fuzz_typestate_driver_against_model() {
+    for seed in 1..=8u64 {
+        let mut rng = Rng(seed.wrapping_mul(0x9E3779B97F4A7C15));
+        let model: SharedModel = Rc::new(RefCell::new(Model::default()));
+        let mut spi = MockSpi(model.clone());
+        let mut shadow = ShadowFrame::Hash(None);
+        let mut state = new_driver(&model, &mut spi);
+        let mut log = Vec::new();
+        for _ in 0..150 {
+            // Occasionally make the next SPI write fail
+            model.borrow_mut().fail_next_write = rng.below(40) == 0;
+            let (next_state, name) = step(state, &mut rng, &mut spi, &mut shadow);
+            state = next_state;
+            log.push(name);
+            let violations = &model.borrow().violations;
+            assert!(
+                violations.is_empty(),
+                "seed {seed}: {violations:?} after {log:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn full_refresh_cycle() {
+    let model: SharedModel = Rc::new(RefCell::new(Model::default()));
+    let mut spi = MockSpi(model.clone());
+    let display = Gdep073e01State::new(
+        &mut spi,
+        MockBusy(model.clone()),
+        MockDc(model.clone()),
+        MockRst(model.clone()),
+        &mut MockDelay,
+    );
+    let display = block_on(display.reset(&mut MockDelay)).unwrap();
+    let display = block_on(display.init(&mut spi)).unwrap();
+    let display = block_on(display.power_on(&mut spi)).unwrap();
+    let display = block_on(display.update_frame(&mut spi, test_screen(WIDTH, HEIGHT))).unwrap();
+    let display = block_on(display.display_frame(&mut spi)).unwrap();
+    let _ = block_on(display.power_off(&mut spi)).unwrap();
+    let model = model.borrow();
+    assert!(model.violations.is_empty(), "{:?}", model.violations);
+    assert_eq!(model.frame_bytes, Some(FRAME_BYTES));
+    assert_eq!(model.refreshes, 1);
+    assert!(!model.powered);
+}
+
+#[test]
+fn failed_refresh_is_refreshed_again() {
+    let model: SharedModel = Rc::new(RefCell::new(Model::default()));
+    let mut spi = MockSpi(model.clone());
+    let mut shadow = ShadowFrame::Hash(None);
+    let display = Gdep073e01State::new(
+        &mut spi,
+        MockBusy(model.clone()),
+        MockDc(model.clone()),
+        MockRst(model.clone()),
+        &mut MockDelay,
+    );
+    let power_on = |display: State<StateUnknown>, spi: &mut MockSpi| {
+        let display = block_on(display.reset(&mut MockDelay)).unwrap();
+        let display = block_on(display.init(spi)).unwrap();
+        block_on(display.power_on(spi)).unwrap()
+    };
+    let display = power_on(display, &mut spi);
+    model.borrow_mut().fail_command = Some(DISPLAY_REFRESH);
+    let frame = || test_screen(WIDTH, HEIGHT);
+    let display = match block_on(display.display_if_changed(&mut spi, frame(), &mut shadow)) {
+        Ok(_) => panic!("refresh went through"),
+        Err(e) => e.into_driver(),
+    };
+    let display = power_on(display, &mut spi);
+    let (display, refreshed) =
+        block_on(display.display_if_changed(&mut spi, frame(), &mut shadow)).unwrap();
+    assert!(
+        refreshed,
+        "same frame after a failed refresh wasn't refreshed"
+    );
+    let (_, refreshed) =
+        block_on(display.display_if_changed(&mut spi, frame(), &mut shadow)).unwrap();
+    assert!(!refreshed);
+    let model = model.borrow();
+    assert!(model.violations.is_empty(), "{:?}", model.violations);
+    assert_eq!(model.refreshes, 1);
+}
+
+#[test]
+fn stuck_busy_times_out() {
+    let model: SharedModel = Rc::new(RefCell::new(Model::default()));
+    let mut spi = MockSpi(model.clone());
+    let display = Gdep073e01State::new(
+        &mut spi,
+        StuckBusy,
+        MockDc(model.clone()),
+        MockRst(model.clone()),
+        &mut MockDelay,
+    )
+    .with_timeouts(Timeouts {
+        busy: embassy_time::Duration::from_millis(10),
+        ..Timeouts::default()
+    });
+    let display = block_on(display.reset(&mut MockDelay)).unwrap();
+    let display = block_on(display.init(&mut spi)).unwrap();
+    // Unlike the mocks, this really waits, so poll until the timeout fires
+    let mut power_on = pin!(display.power_on(&mut spi));
+    let mut context = Context::from_waker(Waker::noop());
+    let result = loop {
+        if let Poll::Ready(result) = power_on.as_mut().poll(&mut context) {
+            break result;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    };
+    match result {
+        Err(e) => assert!(matches!(e.error(), DisplayInterfaceAsyncError::Timeout)),
+        Ok(_) => panic!("power on finished with BUSY stuck"),
+    }
+}